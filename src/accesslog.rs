@@ -0,0 +1,213 @@
+use {
+    crate::logging::{push_json_string, LogFormat},
+    std::{
+        fmt::Write as _,
+        fs::{File, OpenOptions},
+        io::{self, Write},
+        net::IpAddr,
+        path::Path,
+        pin::Pin,
+        sync::Mutex,
+        task::{Context, Poll},
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    },
+    tokio::io::{AsyncRead, AsyncWrite, ReadBuf},
+};
+
+/// A file that an access log line is written to for every request.
+///
+/// The format is inspired by the Common Log Format:
+/// ```text
+/// <ip> - - [<time>] "<request>" <status> <bytes> "<sni host>" <duration>
+/// ```
+/// where the duration is in milliseconds. Fields that are not known, like
+/// the IP address if logging IP addresses is disabled, are written as `-`.
+///
+/// With the JSON log format, every line is a JSON object instead, where
+/// unknown fields are `null`.
+pub(crate) struct AccessLog {
+    file: Mutex<File>,
+    format: LogFormat,
+}
+
+/// The information about a request that is written to the access log.
+pub(crate) struct Entry<'a> {
+    pub time: SystemTime,
+    pub ip: Option<IpAddr>,
+    pub sni: Option<&'a str>,
+    /// The host and path of the requested URL, if the request was valid.
+    pub host: Option<&'a str>,
+    pub path: Option<&'a str>,
+    pub request: &'a str,
+    pub status: Option<u8>,
+    pub bytes: u64,
+    pub duration: Duration,
+    pub tls_version: Option<&'a str>,
+}
+
+impl AccessLog {
+    /// Opens the access log file for appending, creating it if necessary.
+    pub fn open(path: &Path, format: LogFormat) -> Result<Self, String> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("could not open access log {}: {e}", path.display()))?;
+        Ok(Self {
+            file: Mutex::new(file),
+            format,
+        })
+    }
+
+    /// Writes a line for the given request to the access log.
+    pub fn write(&self, entry: &Entry) {
+        let line = match self.format {
+            LogFormat::Text => text_line(entry),
+            LogFormat::Json => json_line(entry),
+        };
+        if let Err(e) = self.file.lock().unwrap().write_all(line.as_bytes()) {
+            log::error!("could not write to access log: {e}");
+        }
+    }
+}
+
+fn text_line(entry: &Entry) -> String {
+    format!(
+        "{} - - [{}] \"{}\" {} {} \"{}\" {}\n",
+        entry.ip.map_or("-".into(), |ip| ip.to_string()),
+        clf_time(entry.time),
+        // the request is user controlled, make sure it does not break
+        // the format
+        entry.request.escape_debug(),
+        entry.status.map_or("-".into(), |status| status.to_string()),
+        entry.bytes,
+        entry.sni.unwrap_or("-"),
+        entry.duration.as_millis(),
+    )
+}
+
+fn json_line(entry: &Entry) -> String {
+    fn optional(line: &mut String, value: Option<&str>) {
+        match value {
+            Some(value) => push_json_string(line, value),
+            None => line.push_str("null"),
+        }
+    }
+
+    let mut line = String::from("{\"time\":");
+    push_json_string(
+        &mut line,
+        &humantime::format_rfc3339_seconds(entry.time).to_string(),
+    );
+    line.push_str(",\"ip\":");
+    optional(&mut line, entry.ip.map(|ip| ip.to_string()).as_deref());
+    line.push_str(",\"host\":");
+    optional(&mut line, entry.host);
+    line.push_str(",\"path\":");
+    optional(&mut line, entry.path);
+    line.push_str(",\"request\":");
+    push_json_string(&mut line, entry.request);
+    line.push_str(",\"status\":");
+    match entry.status {
+        Some(status) => write!(line, "{status}").unwrap(),
+        None => line.push_str("null"),
+    }
+    write!(
+        line,
+        ",\"size\":{},\"duration_ms\":{}",
+        entry.bytes,
+        entry.duration.as_millis()
+    )
+    .unwrap();
+    line.push_str(",\"sni\":");
+    optional(&mut line, entry.sni);
+    line.push_str(",\"tls_version\":");
+    optional(&mut line, entry.tls_version);
+    line.push_str("}\n");
+    line
+}
+
+/// Formats the time like the Common Log Format does, e.g.
+/// `10/Oct/2000:13:55:36 +0000`. The time is always given in UTC.
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // convert days since the epoch to a date in the proleptic Gregorian
+    // calendar, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{day:02}/{}/{year}:{:02}:{:02}:{:02} +0000",
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// A stream that counts the bytes written to it.
+pub(crate) struct Counter<T> {
+    inner: T,
+    written: u64,
+}
+
+impl<T> Counter<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner, written: 0 }
+    }
+
+    /// Returns the number of bytes written so far.
+    pub fn written(&self) -> u64 {
+        self.written
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Counter<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Counter<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(len)) = result {
+            self.written += len as u64;
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
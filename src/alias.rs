@@ -0,0 +1,49 @@
+use std::{path::PathBuf, str::FromStr};
+
+/// A URL path prefix whose files are served from a directory outside of the
+/// content directory.
+#[derive(Debug, Clone)]
+pub(crate) struct Alias {
+    /// The URL path prefix without a trailing slash.
+    pub prefix: String,
+    pub dir: PathBuf,
+}
+
+impl Alias {
+    /// Returns the number of URL path segments of the prefix if the alias
+    /// applies to the URL path.
+    pub fn segments(&self, path: &str) -> Option<usize> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(self.prefix.matches('/').count())
+        } else {
+            // the prefix has to end at a segment boundary
+            None
+        }
+    }
+}
+
+impl FromStr for Alias {
+    type Err = String;
+
+    /// Parses an alias in the form `PREFIX=DIRECTORY`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, dir) = s
+            .split_once('=')
+            .ok_or_else(|| format!("alias {s:?} is not in the form PREFIX=DIRECTORY"))?;
+        let prefix = prefix.trim_end_matches('/');
+        if !prefix.starts_with('/') {
+            return Err(format!(
+                "alias prefix in {s:?} has to start with / and must not be the root"
+            ));
+        }
+        let dir = PathBuf::from(dir);
+        if !dir.is_dir() {
+            return Err(format!("alias directory {dir:?} is not a directory"));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            dir,
+        })
+    }
+}
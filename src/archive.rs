@@ -0,0 +1,425 @@
+use crate::inflate;
+use once_cell::sync::Lazy;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The largest member that is read from an archive, and the largest
+/// decompressed `.tar.gz` file.
+const MAX_SIZE: usize = 1 << 30;
+
+/// The indexes of the archives that were read, which are only read again once
+/// the archive was modified.
+static INDEXES: Lazy<Mutex<HashMap<PathBuf, Arc<Index>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A URL path prefix whose files are served from an archive file.
+#[derive(Debug, Clone)]
+pub(crate) struct Archive {
+    /// The URL path prefix without a trailing slash.
+    pub prefix: String,
+    pub file: PathBuf,
+    format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Tar,
+    TarGz,
+    Zip,
+}
+
+/// A file or directory in an archive.
+pub(crate) enum Member {
+    File(Vec<u8>),
+    Dir,
+}
+
+impl Archive {
+    /// Returns the number of URL path segments of the prefix if the archive
+    /// applies to the URL path.
+    pub fn segments(&self, path: &str) -> Option<usize> {
+        let rest = path.strip_prefix(&self.prefix)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(self.prefix.matches('/').count())
+        } else {
+            // the prefix has to end at a segment boundary
+            None
+        }
+    }
+
+    /// Reads the member with the path, given without leading or trailing
+    /// slashes, from the archive. Returns `None` if there is no such member.
+    pub async fn get(&self, name: &str) -> io::Result<Option<Member>> {
+        let archive = self.clone();
+        let name = name.to_string();
+        tokio::task::spawn_blocking(move || {
+            let index = archive.index()?;
+            match index.files.get(&name) {
+                Some(entry) => Ok(Some(Member::File(index.read(&archive.file, entry)?))),
+                None if index.dirs.contains(&name) => Ok(Some(Member::Dir)),
+                None => Ok(None),
+            }
+        })
+        .await?
+    }
+
+    /// Returns the index of the archive, which is read if the archive was
+    /// not read before or was modified since.
+    fn index(&self) -> io::Result<Arc<Index>> {
+        let modified = std::fs::metadata(&self.file)?.modified()?;
+        if let Some(index) = INDEXES.lock().unwrap().get(&self.file) {
+            if index.modified == modified {
+                return Ok(index.clone());
+            }
+        }
+        let mut file = File::open(&self.file)?;
+        let mut data = None;
+        let files = match self.format {
+            Format::Tar => tar(&mut file)?,
+            Format::TarGz => {
+                let mut compressed = vec![];
+                file.read_to_end(&mut compressed)?;
+                let decompressed = inflate::gunzip(&compressed, MAX_SIZE)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                let files = tar(&mut io::Cursor::new(&decompressed))?;
+                data = Some(decompressed);
+                files
+            }
+            Format::Zip => zip(&mut file)?,
+        };
+        log::info!(
+            "Read the index of {} with {} files",
+            self.file.display(),
+            files.len()
+        );
+        // directories are not always stored in archives
+        let mut dirs = HashSet::from([String::new()]);
+        for name in files.keys() {
+            let mut name = name.as_str();
+            while let Some((parent, _)) = name.rsplit_once('/') {
+                dirs.insert(parent.to_string());
+                name = parent;
+            }
+        }
+        let index = Arc::new(Index {
+            modified,
+            files,
+            dirs,
+            data,
+        });
+        INDEXES
+            .lock()
+            .unwrap()
+            .insert(self.file.clone(), index.clone());
+        Ok(index)
+    }
+}
+
+impl FromStr for Archive {
+    type Err = String;
+
+    /// Parses an archive in the form `PREFIX=FILE`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, file) = s
+            .split_once('=')
+            .ok_or_else(|| format!("archive {s:?} is not in the form PREFIX=FILE"))?;
+        let prefix = prefix.trim_end_matches('/');
+        if !prefix.starts_with('/') {
+            return Err(format!(
+                "archive prefix in {s:?} has to start with / and must not be the root"
+            ));
+        }
+        let name = file.to_ascii_lowercase();
+        let format = if name.ends_with(".tar") {
+            Format::Tar
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Format::TarGz
+        } else if name.ends_with(".zip") {
+            Format::Zip
+        } else {
+            return Err(format!(
+                "archive {file:?} is not a .tar, .tar.gz or .zip file"
+            ));
+        };
+        let file = PathBuf::from(file);
+        if !file.is_file() {
+            return Err(format!("archive {file:?} is not a file"));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            file,
+            format,
+        })
+    }
+}
+
+/// Where the members of an archive are.
+struct Index {
+    /// The modification time of the archive when it was read.
+    modified: SystemTime,
+    files: HashMap<String, Entry>,
+    dirs: HashSet<String>,
+    /// The decompressed content of a `.tar.gz` file.
+    data: Option<Vec<u8>>,
+}
+
+/// The position of a file in an archive.
+struct Entry {
+    offset: u64,
+    size: u64,
+    method: Method,
+}
+
+enum Method {
+    Stored,
+    Deflated { compressed: u64, crc: u32 },
+}
+
+impl Index {
+    fn read(&self, file: &Path, entry: &Entry) -> io::Result<Vec<u8>> {
+        let len = match entry.method {
+            Method::Stored => entry.size,
+            Method::Deflated { compressed, .. } => compressed,
+        };
+        let raw = match &self.data {
+            Some(data) => usize::try_from(entry.offset)
+                .ok()
+                .and_then(|offset| data.get(offset..offset.checked_add(len as usize)?))
+                .ok_or(ErrorKind::UnexpectedEof)?
+                .to_vec(),
+            None => {
+                let mut file = File::open(file)?;
+                file.seek(SeekFrom::Start(entry.offset))?;
+                let mut raw = vec![0; len as usize];
+                file.read_exact(&mut raw)?;
+                raw
+            }
+        };
+        match entry.method {
+            Method::Stored => Ok(raw),
+            Method::Deflated { crc, .. } => {
+                let content = inflate::inflate(&raw, entry.size as usize)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                if content.len() as u64 != entry.size || inflate::crc32(&content) != crc {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "member does not match its size or checksum",
+                    ));
+                }
+                Ok(content)
+            }
+        }
+    }
+}
+
+/// Normalizes the path of a member, so it has no leading, trailing or
+/// duplicate slashes. Returns `None` for paths with `..` segments, which are
+/// not served.
+fn normalize(name: &str) -> Option<String> {
+    let mut segments = vec![];
+    for segment in name.split('/') {
+        match segment {
+            "" | "." => (),
+            ".." => return None,
+            _ => segments.push(segment),
+        }
+    }
+    (!segments.is_empty()).then(|| segments.join("/"))
+}
+
+/// Reads the headers of a tar file. Long names of GNU and pax archives are
+/// supported, links and special files are left out.
+fn tar(reader: &mut (impl Read + Seek)) -> io::Result<HashMap<String, Entry>> {
+    let mut files = HashMap::new();
+    let mut header = [0; 512];
+    let mut offset = 0;
+    // the name from a GNU or pax header for the next member
+    let mut long_name = None;
+    loop {
+        reader.seek(SeekFrom::Start(offset))?;
+        match reader.read_exact(&mut header) {
+            Ok(()) => (),
+            // some archives lack the blocks that mark the end
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let size = tar_size(&header[124..136])?;
+        let data = offset + 512;
+        match header[156] {
+            b'L' => {
+                let name = tar_extension(reader, size)?;
+                let name = name.split(|&b| b == 0).next().unwrap_or_default();
+                long_name = Some(String::from_utf8_lossy(name).into_owned());
+            }
+            b'x' => long_name = pax_path(&tar_extension(reader, size)?).or(long_name),
+            b'0' | b'7' | 0 => {
+                let name = long_name.take().unwrap_or_else(|| {
+                    let name = tar_string(&header[..100]);
+                    // the name may be split in the ustar format
+                    if &header[257..262] == b"ustar" && header[345] != 0 {
+                        format!("{}/{name}", tar_string(&header[345..500]))
+                    } else {
+                        name
+                    }
+                });
+                if let Some(name) = normalize(&name).filter(|_| size <= MAX_SIZE as u64) {
+                    files.insert(
+                        name,
+                        Entry {
+                            offset: data,
+                            size,
+                            method: Method::Stored,
+                        },
+                    );
+                }
+            }
+            _ => long_name = None,
+        }
+        offset = data + size.div_ceil(512) * 512;
+    }
+    Ok(files)
+}
+
+/// Reads the data of a GNU or pax header, which follows the header.
+fn tar_extension(reader: &mut impl Read, size: u64) -> io::Result<Vec<u8>> {
+    if size > 1 << 20 {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "tar header too long",
+        ));
+    }
+    let mut data = vec![0; size as usize];
+    reader.read_exact(&mut data)?;
+    Ok(data)
+}
+
+fn tar_string(field: &[u8]) -> String {
+    let field = field.split(|&b| b == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(field).into_owned()
+}
+
+/// Parses the size of a member, which is octal or, for large members of GNU
+/// archives, big-endian binary.
+fn tar_size(field: &[u8]) -> io::Result<u64> {
+    if field[0] & 0x80 != 0 {
+        return Ok(field[4..]
+            .iter()
+            .fold(0, |size, &b| (size << 8) | u64::from(b)));
+    }
+    let octal = tar_string(field);
+    let octal = octal.trim_matches(|c: char| c == ' ' || c == '\0');
+    if octal.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(octal, 8)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid size in tar header"))
+}
+
+/// Returns the path from the records of a pax extended header, which look
+/// like `LENGTH path=NAME\n`.
+fn pax_path(mut records: &[u8]) -> Option<String> {
+    let mut path = None;
+    while let Some(space) = records.iter().position(|&b| b == b' ') {
+        let len = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        let record = records.get(space + 1..len)?;
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(String::from_utf8_lossy(value).into_owned());
+        }
+        records = &records[len..];
+    }
+    path
+}
+
+/// Reads the central directory of a zip file. Members that are encrypted or
+/// compressed with another method than deflate are left out.
+fn zip(file: &mut File) -> io::Result<HashMap<String, Entry>> {
+    const END_SIGNATURE: &[u8] = &[0x50, 0x4b, 0x05, 0x06];
+    const ENTRY_SIGNATURE: &[u8] = &[0x50, 0x4b, 0x01, 0x02];
+    let invalid = |message| io::Error::new(ErrorKind::InvalidData, message);
+    let u16_at = |buf: &[u8], pos: usize| u16::from_le_bytes([buf[pos], buf[pos + 1]]);
+    let u32_at = |buf: &[u8], pos: usize| {
+        u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+    };
+
+    // the end of central directory record is followed by a comment of up
+    // to 64 KiB
+    let len = file.metadata()?.len();
+    let tail_len = len.min(22 + 0xffff);
+    file.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    file.read_exact(&mut tail)?;
+    let end = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&pos| &tail[pos..pos + 4] == END_SIGNATURE)
+        .ok_or_else(|| invalid("not a zip file"))?;
+    let end = &tail[end..];
+    let (dir_size, dir_offset) = (u32_at(end, 12), u32_at(end, 16));
+    if dir_offset == u32::MAX {
+        return Err(invalid("zip64 files are not supported"));
+    }
+    file.seek(SeekFrom::Start(dir_offset.into()))?;
+    let mut dir = vec![0; dir_size as usize];
+    file.read_exact(&mut dir)?;
+
+    let mut files = HashMap::new();
+    let mut pos = 0;
+    while dir.get(pos..pos + 4) == Some(ENTRY_SIGNATURE) {
+        let header = dir
+            .get(pos..pos + 46)
+            .ok_or_else(|| invalid("truncated zip directory"))?;
+        let flags = u16_at(header, 8);
+        let method = u16_at(header, 10);
+        let crc = u32_at(header, 16);
+        let compressed = u64::from(u32_at(header, 20));
+        let size = u64::from(u32_at(header, 24));
+        let name_len = usize::from(u16_at(header, 28));
+        let extra_len = usize::from(u16_at(header, 30));
+        let comment_len = usize::from(u16_at(header, 32));
+        let local_offset = u64::from(u32_at(header, 42));
+        let name = dir
+            .get(pos + 46..pos + 46 + name_len)
+            .ok_or_else(|| invalid("truncated zip directory"))?;
+        let name = String::from_utf8_lossy(name).into_owned();
+        pos += 46 + name_len + extra_len + comment_len;
+
+        let method = match method {
+            0 => Method::Stored,
+            8 => Method::Deflated { compressed, crc },
+            _ => {
+                log::warn!("not serving {name}, its compression method is not supported");
+                continue;
+            }
+        };
+        // encrypted members and directories are not served
+        if flags & 1 != 0 || name.ends_with('/') || size > MAX_SIZE as u64 {
+            continue;
+        }
+        let Some(name) = normalize(&name) else {
+            continue;
+        };
+        // the data follows the local header, whose extra field may differ
+        let mut local = [0; 30];
+        file.seek(SeekFrom::Start(local_offset))?;
+        file.read_exact(&mut local)?;
+        let offset =
+            local_offset + 30 + u64::from(u16_at(&local, 26)) + u64::from(u16_at(&local, 28));
+        files.insert(
+            name,
+            Entry {
+                offset,
+                size,
+                method,
+            },
+        );
+    }
+    Ok(files)
+}
@@ -0,0 +1,262 @@
+use crate::feed::{self, Post};
+use crate::sitemap::ENCODE_SET;
+use percent_encoding::{percent_decode_str, utf8_percent_encode};
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::SystemTime;
+use url::Url;
+
+/// The line before and after the front matter of a post.
+static DELIMITER: &str = "---";
+
+/// The settings at the start of a post, like
+/// ```text
+/// ---
+/// title: Hello
+/// date: 2024-01-31
+/// tags: gemini, rust
+/// draft: false
+/// ---
+/// ```
+/// Unknown keys are ignored.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct FrontMatter {
+    pub title: Option<String>,
+    pub date: Option<SystemTime>,
+    /// Lower case tags.
+    pub tags: Vec<String>,
+    pub draft: bool,
+}
+
+impl FrontMatter {
+    /// Splits the front matter from the rest of a post, if it has one.
+    pub fn split(content: &str) -> (Option<Self>, &str) {
+        let Some(rest) = content
+            .strip_prefix(DELIMITER)
+            .and_then(|rest| rest.strip_prefix("\r\n").or(rest.strip_prefix('\n')))
+        else {
+            return (None, content);
+        };
+        let mut front_matter = Self::default();
+        let mut pos = 0;
+        for line in rest.split_inclusive('\n') {
+            pos += line.len();
+            let line = line.trim();
+            if line == DELIMITER {
+                return (Some(front_matter), &rest[pos..]);
+            }
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match key.trim() {
+                "title" => front_matter.title = Some(value.to_string()),
+                "date" => front_matter.date = parse_date(value),
+                "tags" => {
+                    front_matter.tags = value
+                        .split(',')
+                        .map(|tag| tag.trim().to_lowercase())
+                        .filter(|tag| !tag.is_empty())
+                        .collect()
+                }
+                "draft" => front_matter.draft = crate::config::parse_bool(value).unwrap_or(false),
+                _ => (),
+            }
+        }
+        // without the closing delimiter, this was not front matter
+        (None, content)
+    }
+}
+
+/// Splits the front matter from the content of a file if it is a post of a
+/// blog. Files elsewhere are returned as they are.
+pub(crate) fn split_post<'a>(url_path: &str, content: &'a str) -> (Option<FrontMatter>, &'a str) {
+    if crate::ARGS.blogs.iter().any(|blog| blog.contains(url_path)) {
+        FrontMatter::split(content)
+    } else {
+        (None, content)
+    }
+}
+
+/// Parses a date like `2024-01-31` or an RFC 3339 timestamp.
+pub(crate) fn parse_date(value: &str) -> Option<SystemTime> {
+    humantime::parse_rfc3339(value)
+        .or_else(|_| humantime::parse_rfc3339(&format!("{value}T00:00:00Z")))
+        .ok()
+}
+
+/// A directory of posts for which Agate generates the index, tag and archive
+/// pages and a feed.
+#[derive(Debug, Clone)]
+pub(crate) struct Blog {
+    /// The URL path of the directory, with a trailing slash.
+    pub dir: String,
+}
+
+impl FromStr for Blog {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') || crate::sanitize::segments(s).is_err() {
+            return Err(format!(
+                "blog directory {s:?} does not start with / or is invalid"
+            ));
+        }
+        Ok(Self {
+            dir: format!("{}/", s.trim_end_matches('/')),
+        })
+    }
+}
+
+/// A generated page of a blog.
+#[derive(Debug)]
+pub(crate) enum Page {
+    /// All posts, newest first.
+    Index,
+    /// All tags.
+    Tags,
+    /// The posts with a tag.
+    Tag(String),
+    /// All years with posts.
+    Archive,
+    /// The posts of a year.
+    Year(String),
+    /// The Atom feed of all posts.
+    Feed,
+}
+
+impl Blog {
+    /// Returns the generated page at the URL path, if there is one.
+    pub fn page(&self, url_path: &str) -> Option<Page> {
+        let rest = url_path.strip_prefix(&self.dir)?;
+        match rest {
+            "" => Some(Page::Index),
+            "tags/" => Some(Page::Tags),
+            "archive/" => Some(Page::Archive),
+            "atom.xml" => Some(Page::Feed),
+            _ => {
+                if let Some(tag) = rest.strip_prefix("tags/") {
+                    let tag = percent_decode_str(tag).decode_utf8().ok()?;
+                    Some(Page::Tag(tag.to_lowercase()))
+                } else {
+                    let year = rest.strip_prefix("archive/")?;
+                    (year.len() == 4 && year.chars().all(|c| c.is_ascii_digit()))
+                        .then(|| Page::Year(year.to_string()))
+                }
+            }
+        }
+    }
+
+    /// Returns whether the URL path is a post of this blog.
+    pub fn contains(&self, url_path: &str) -> bool {
+        url_path
+            .strip_prefix(&self.dir)
+            .is_some_and(|name| !name.is_empty() && !name.contains('/'))
+    }
+
+    /// Generates the page for the posts of the blog in `dir`, which is at
+    /// `dir_url`. The index page starts with the content of the index file
+    /// of the directory, or with the title. Returns the MIME type and the
+    /// page, or `None` if there are no posts for the tag or year.
+    pub fn render(
+        &self,
+        page: &Page,
+        dir: &Path,
+        dir_url: &Url,
+        index_files: &[String],
+    ) -> std::io::Result<Option<(&'static str, String)>> {
+        let posts = feed::posts(dir, index_files)?;
+        let title = feed::dir_title(dir, index_files)
+            .unwrap_or_else(|| dir_url.host_str().unwrap_or_default().to_string());
+        let list = |posts: &mut dyn Iterator<Item = &Post>| {
+            posts
+                .map(|post| {
+                    format!(
+                        "=> {}{} {} - {}\n",
+                        self.dir,
+                        utf8_percent_encode(&post.name, ENCODE_SET),
+                        &humantime::format_rfc3339_seconds(post.date).to_string()[..10],
+                        post.title.replace(char::is_control, " ")
+                    )
+                })
+                .collect::<String>()
+        };
+        let gemtext = match page {
+            Page::Index => {
+                let preamble = index_files
+                    .iter()
+                    .find_map(|name| std::fs::read_to_string(dir.join(name)).ok())
+                    .unwrap_or_else(|| format!("# {title}\n\n"));
+                let mut gemtext = preamble;
+                // the posts follow after a blank line
+                while !gemtext.ends_with("\n\n") {
+                    gemtext.push('\n');
+                }
+                gemtext += &list(&mut posts.iter());
+                gemtext += &format!(
+                    "\n=> {dir}tags/ Tags\n=> {dir}archive/ Archive\n=> {dir}atom.xml Atom feed\n",
+                    dir = self.dir
+                );
+                gemtext
+            }
+            Page::Tags => {
+                let mut tags = BTreeMap::<&str, usize>::new();
+                for tag in posts.iter().flat_map(|post| &post.tags) {
+                    *tags.entry(tag).or_default() += 1;
+                }
+                let mut gemtext = format!("# Tags of {title}\n\n");
+                for (tag, count) in tags {
+                    gemtext += &format!(
+                        "=> {}tags/{} {tag} ({count})\n",
+                        self.dir,
+                        utf8_percent_encode(tag, ENCODE_SET)
+                    );
+                }
+                gemtext
+            }
+            Page::Tag(tag) => {
+                let mut tagged = posts
+                    .iter()
+                    .filter(|post| post.tags.contains(tag))
+                    .peekable();
+                if tagged.peek().is_none() {
+                    return Ok(None);
+                }
+                let tag = tag.replace(char::is_control, " ");
+                format!("# Posts tagged {tag}\n\n{}", list(&mut tagged))
+            }
+            Page::Archive => {
+                let mut years = BTreeMap::<String, usize>::new();
+                for post in &posts {
+                    *years.entry(year(post)).or_default() += 1;
+                }
+                let mut gemtext = format!("# Archive of {title}\n\n");
+                for (year, count) in years.iter().rev() {
+                    gemtext += &format!("=> {}archive/{year} {year} ({count})\n", self.dir);
+                }
+                gemtext
+            }
+            Page::Year(year) => {
+                let mut posts = posts
+                    .iter()
+                    .filter(|post| self::year(post) == *year)
+                    .peekable();
+                if posts.peek().is_none() {
+                    return Ok(None);
+                }
+                format!("# Posts from {year}\n\n{}", list(&mut posts))
+            }
+            Page::Feed => {
+                let feed_url = dir_url.join("atom.xml").unwrap_or_else(|_| dir_url.clone());
+                let xml = feed::generate(feed::Format::Atom, dir_url, &feed_url, &title, &posts);
+                return Ok(Some((feed::Format::Atom.mime(), xml)));
+            }
+        };
+        Ok(Some(("text/gemini", gemtext)))
+    }
+}
+
+fn year(post: &Post) -> String {
+    humantime::format_rfc3339_seconds(post.date).to_string()[..4].to_string()
+}
@@ -0,0 +1,189 @@
+use {
+    crate::metrics::METRICS,
+    std::{
+        collections::{BTreeMap, HashMap},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::{Duration, Instant, SystemTime},
+    },
+};
+
+/// The number of cached URLs after which expired ones are removed.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// A cache for the contents of small files, so popular pages like the front
+/// page are served without reading them from disk every time. When the
+/// cache is full, the least recently used files are evicted.
+pub(crate) struct FileCache {
+    /// The maximum total size of all cached files in bytes.
+    max_size: usize,
+    /// The maximum size of a single cached file in bytes.
+    max_file_size: usize,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    files: HashMap<PathBuf, Entry>,
+    /// The cached files ordered by when they were last used.
+    recent: BTreeMap<u64, PathBuf>,
+    /// Increases every time a file is used.
+    clock: u64,
+    size: usize,
+}
+
+struct Entry {
+    contents: Arc<[u8]>,
+    /// The modification time of the file when it was read, which is checked
+    /// before the contents are used.
+    modified: SystemTime,
+    last_used: u64,
+}
+
+impl FileCache {
+    pub fn new(max_size: usize, max_file_size: usize) -> Self {
+        Self {
+            max_size,
+            max_file_size: max_file_size.min(max_size),
+            inner: Mutex::default(),
+        }
+    }
+
+    /// Returns the contents of the file if it is small enough to be cached,
+    /// reading it only if it is not cached yet or was modified since. Returns
+    /// `None` for larger files and if the file can not be read, so it can be
+    /// handled like any other file.
+    pub async fn get(&self, path: &Path) -> Option<Arc<[u8]>> {
+        let metadata = tokio::fs::metadata(path).await.ok()?;
+        let modified = metadata.modified().ok()?;
+        if !metadata.is_file() || metadata.len() > self.max_file_size as u64 {
+            return None;
+        }
+
+        let cached = self
+            .inner
+            .lock()
+            .unwrap()
+            .get(path, modified, metadata.len());
+        if let Some(contents) = cached {
+            return Some(contents);
+        }
+        // if the file changes while it is read, the modification time
+        // differs the next time and it is read again
+        let contents = Arc::<[u8]>::from(tokio::fs::read(path).await.ok()?);
+        if contents.len() <= self.max_file_size {
+            self.inner.lock().unwrap().insert(
+                path.to_owned(),
+                contents.clone(),
+                modified,
+                self.max_size,
+            );
+        }
+        Some(contents)
+    }
+}
+
+impl Inner {
+    fn get(&mut self, path: &Path, modified: SystemTime, len: u64) -> Option<Arc<[u8]>> {
+        self.clock += 1;
+        let entry = self.files.get_mut(path)?;
+        if entry.modified != modified || entry.contents.len() as u64 != len {
+            return None;
+        }
+        let path = self.recent.remove(&entry.last_used).unwrap();
+        entry.last_used = self.clock;
+        self.recent.insert(self.clock, path);
+        Some(entry.contents.clone())
+    }
+
+    fn insert(
+        &mut self,
+        path: PathBuf,
+        contents: Arc<[u8]>,
+        modified: SystemTime,
+        max_size: usize,
+    ) {
+        self.remove(&path);
+        while self.size + contents.len() > max_size {
+            let Some((_, oldest)) = self.recent.pop_first() else {
+                break;
+            };
+            let entry = self.files.remove(&oldest).unwrap();
+            self.size -= entry.contents.len();
+        }
+
+        self.clock += 1;
+        self.size += contents.len();
+        self.recent.insert(self.clock, path.clone());
+        self.files.insert(
+            path,
+            Entry {
+                contents,
+                modified,
+                last_used: self.clock,
+            },
+        );
+    }
+
+    fn remove(&mut self, path: &Path) {
+        if let Some(entry) = self.files.remove(path) {
+            self.recent.remove(&entry.last_used);
+            self.size -= entry.contents.len();
+        }
+    }
+}
+
+/// A cache for the files that URLs resolve to and their media types, so
+/// repeated requests for the same file skip checking the path, looking up
+/// index files and reading `.meta` configuration. The file itself is still
+/// opened for every request.
+/// A URL resolved to a file, with what is needed to check again that it may
+/// be served.
+#[derive(Clone)]
+pub(crate) struct Resolved {
+    pub path: PathBuf,
+    pub mime: String,
+    /// The directory below which symbolic links in `path` are checked.
+    pub root: PathBuf,
+    /// The percent-decoded URL path segments leading to the file.
+    pub segments: Vec<String>,
+    /// The directories whose status files and settings apply to the file.
+    pub dirs: Vec<PathBuf>,
+}
+
+pub(crate) struct StatCache {
+    /// How long a resolved URL is used before it is resolved again.
+    ttl: Duration,
+    /// The resolved files by host name and URL path.
+    entries: Mutex<HashMap<String, (Instant, Resolved)>>,
+}
+
+impl StatCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// Returns the file the URL resolved to, unless that was too long ago.
+    pub fn get(&self, key: &str) -> Option<Resolved> {
+        let resolved = self
+            .entries
+            .lock()
+            .unwrap()
+            .get(key)
+            .filter(|(resolved, _)| resolved.elapsed() < self.ttl)
+            .map(|(_, resolved)| resolved.clone());
+        METRICS.stat_cache_lookup(resolved.is_some());
+        resolved
+    }
+
+    pub fn insert(&self, key: String, resolved: Resolved) {
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= PRUNE_THRESHOLD {
+            entries.retain(|_, (resolved, _)| resolved.elapsed() < self.ttl);
+        }
+        entries.insert(key, (Instant::now(), resolved));
+    }
+}
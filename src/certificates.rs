@@ -2,11 +2,11 @@ use {
     std::{
         ffi::OsStr,
         fmt::{Display, Formatter},
-        path::Path,
-        sync::Arc,
+        path::{Path, PathBuf},
+        sync::{Arc, RwLock},
+        time::{Duration, SystemTime},
     },
     tokio_rustls::rustls::{
-        self,
         crypto::ring::sign::any_supported_type,
         pki_types::{self, CertificateDer, PrivateKeyDer},
         server::{ClientHello, ResolvesServerCert},
@@ -19,10 +19,15 @@ use {
 #[derive(Debug)]
 pub(crate) struct CertStore {
     /// Stores the certificates and the domains they apply to, sorted by domain
-    /// names, longest matches first
-    certs: Vec<(String, Arc<CertifiedKey>)>,
+    /// names, longest matches first. A domain can have several certificates
+    /// with different key types. They are replaced as a whole when the
+    /// certificates are reloaded.
+    certs: RwLock<Vec<DomainCerts>>,
 }
 
+/// A domain name and its certificates.
+type DomainCerts = (String, Vec<Arc<CertifiedKey>>);
+
 pub static CERT_FILE_NAME: &str = "cert.der";
 pub static KEY_FILE_NAME: &str = "key.der";
 
@@ -33,8 +38,8 @@ pub enum CertLoadError {
     /// no certificates or keys were found
     Empty,
     /// the key file for the specified domain is bad (e.g. does not contain a
-    /// key or is invalid)
-    BadKey(String, rustls::Error),
+    /// key or is invalid), with a description of the problem
+    BadKey(String, String),
     /// the key file for the specified domain is missing (but a certificate
     /// file was present)
     MissingKey(String),
@@ -51,7 +56,7 @@ impl Display for CertLoadError {
         match self {
             Self::NoReadCertDir => write!(f, "Could not read from certificate directory."),
             Self::Empty => write!(f, "No keys or certificates were found in the given directory.\nSpecify the --hostname option to generate these automatically."),
-            Self::BadKey(domain, err) => write!(f, "The key file for {domain} is malformed: {err:?}"),
+            Self::BadKey(domain, err) => write!(f, "The key file for {domain} is invalid: {err}"),
             Self::MissingKey(domain) => write!(f, "The key file for {domain} is missing."),
             Self::MissingCert(domain) => {
                 write!(f, "The certificate file for {domain} is missing.")
@@ -66,54 +71,170 @@ impl Display for CertLoadError {
 
 impl std::error::Error for CertLoadError {}
 
-fn load_domain(certs_dir: &Path, domain: String) -> Result<CertifiedKey, CertLoadError> {
-    let mut path = certs_dir.to_path_buf();
-    path.push(&domain);
-    // load certificate from file
-    path.push(CERT_FILE_NAME);
-    if !path.is_file() {
-        return Err(if !path.with_file_name(KEY_FILE_NAME).is_file() {
-            CertLoadError::EmptyDomain(domain)
-        } else {
-            CertLoadError::MissingCert(domain)
-        });
+/// Loads all certificate/key pairs of a domain, see [`pair_suffixes`].
+fn load_domain(certs_dir: &Path, domain: String) -> Result<Vec<Arc<CertifiedKey>>, CertLoadError> {
+    let dir = certs_dir.join(&domain);
+    let suffixes = pair_suffixes(&dir);
+    if suffixes.is_empty() {
+        return Err(CertLoadError::EmptyDomain(domain));
     }
-    let cert = CertificateDer::from(
-        std::fs::read(&path).map_err(|_| CertLoadError::MissingCert(domain.clone()))?,
-    );
+    suffixes
+        .iter()
+        .map(|suffix| load_pair(&dir, suffix, &domain).map(Arc::new))
+        .collect()
+}
+
+/// Returns the suffixes of the certificate/key pairs in a directory. Besides
+/// `cert.der` and `key.der`, which have an empty suffix, there can be more
+/// pairs with a suffix starting with a dash, e.g. `cert-rsa.der` and
+/// `key-rsa.der`. The empty suffix is always first.
+fn pair_suffixes(dir: &Path) -> Vec<String> {
+    let mut suffixes = dir
+        .read_dir()
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| {
+            let name = entry.file_name().into_string().ok()?;
+            let stem = name.strip_suffix(".der")?;
+            let suffix = stem
+                .strip_prefix("cert")
+                .or_else(|| stem.strip_prefix("key"))?;
+            (suffix.is_empty() || suffix.starts_with('-')).then(|| suffix.to_string())
+        })
+        .collect::<Vec<_>>();
+    suffixes.sort_unstable();
+    suffixes.dedup();
+    suffixes
+}
+
+fn load_pair(dir: &Path, suffix: &str, domain: &str) -> Result<CertifiedKey, CertLoadError> {
+    // load certificate from file
+    let Ok(cert) = std::fs::read(dir.join(format!("cert{suffix}.der"))) else {
+        return Err(CertLoadError::MissingCert(domain.to_string()));
+    };
 
     // load key from file
-    path.set_file_name(KEY_FILE_NAME);
-    let Ok(der) = std::fs::read(&path) else {
-        return Err(CertLoadError::MissingKey(domain));
+    let Ok(der) = std::fs::read(dir.join(format!("key{suffix}.der"))) else {
+        return Err(CertLoadError::MissingKey(domain.to_string()));
     };
 
     // transform key to correct format
-    let key = der_to_private_key(&der).map_err(|e| CertLoadError::BadKey(domain.clone(), e))?;
+    let key = load_private_key(&der).map_err(|e| CertLoadError::BadKey(domain.to_string(), e))?;
 
-    Ok(CertifiedKey::new(vec![cert], key))
+    Ok(CertifiedKey::new(vec![CertificateDer::from(cert)], key))
 }
 
-/// We don't know the key type of the private key DER file, so try each
-/// possible type until we find one that works.
-///
-/// We should probably stop doing this and use a PEM file instead:
-/// <https://github.com/rustls/rustls/issues/1661>
-fn der_to_private_key(der: &[u8]) -> Result<Arc<dyn SigningKey>, rustls::Error> {
-    let keys = [
-        PrivateKeyDer::Pkcs1(pki_types::PrivatePkcs1KeyDer::from(der)),
-        PrivateKeyDer::Sec1(pki_types::PrivateSec1KeyDer::from(der)),
-        PrivateKeyDer::Pkcs8(pki_types::PrivatePkcs8KeyDer::from(der)),
-    ];
-
-    let mut err = None;
-    for key in keys {
-        match any_supported_type(&key) {
-            Ok(key) => return Ok(key),
-            Err(e) => err = Some(e),
+/// How to decrypt an encrypted key. Agate can not load them because ring
+/// does not provide AES-CBC, and a hand-written implementation would not
+/// be constant-time.
+const DECRYPT_HINT: &str = "decrypt it with `openssl pkcs8 -topk8 -nocrypt -in key.der -out key-decrypted.der` and replace the file";
+
+/// The container formats of private keys.
+#[derive(Debug, Clone, Copy)]
+enum KeyFormat {
+    Pkcs1,
+    Sec1,
+    Pkcs8,
+}
+
+impl Display for KeyFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pkcs1 => write!(f, "PKCS#1"),
+            Self::Sec1 => write!(f, "SEC1"),
+            Self::Pkcs8 => write!(f, "PKCS#8"),
+        }
+    }
+}
+
+impl KeyFormat {
+    /// Detects the format of a DER encoded private key from its structure.
+    /// All formats start with a version number, which is followed by
+    /// different fields.
+    fn detect(der: &[u8]) -> Option<Self> {
+        const INTEGER: u8 = 0x02;
+        const OCTET_STRING: u8 = 0x04;
+        const SEQUENCE: u8 = 0x30;
+
+        let (SEQUENCE, key, _) = der_element(der)? else {
+            return None;
+        };
+        let (INTEGER, version, rest) = der_element(key)? else {
+            return None;
+        };
+        match (version, der_element(rest)?.0) {
+            // followed by the algorithm identifier, version 1 also has a
+            // public key
+            ([0 | 1], SEQUENCE) => Some(Self::Pkcs8),
+            // followed by the RSA modulus
+            ([0], INTEGER) => Some(Self::Pkcs1),
+            // followed by the EC private key
+            ([1], OCTET_STRING) => Some(Self::Sec1),
+            _ => None,
         }
     }
-    Err(err.unwrap())
+}
+
+/// Loads a private key, which may be DER or PEM encoded and in any of the
+/// PKCS#1, SEC1 or PKCS#8 formats. If the key can not be used, returns a
+/// description of the problem.
+fn load_private_key(data: &[u8]) -> Result<Arc<dyn SigningKey>, String> {
+    let der = if data.starts_with(b"-----BEGIN ") {
+        match decode_pem(data) {
+            Some((label, _)) if label == "ENCRYPTED PRIVATE KEY" => {
+                return Err(format!("encrypted keys are not supported, {DECRYPT_HINT}"))
+            }
+            // the label is not used to determine the format because it is
+            // easily mixed up
+            Some((_, der)) => der,
+            None if data.windows(22).any(|w| w == b"Proc-Type: 4,ENCRYPTED") => {
+                return Err(format!("encrypted keys are not supported, {DECRYPT_HINT}"))
+            }
+            None => return Err("the PEM encoding is invalid".into()),
+        }
+    } else {
+        data.to_vec()
+    };
+
+    let format = KeyFormat::detect(&der)
+        .ok_or("the file does not contain a private key in PKCS#8, PKCS#1 or SEC1 format")?;
+    let key = match format {
+        KeyFormat::Pkcs1 => PrivateKeyDer::Pkcs1(pki_types::PrivatePkcs1KeyDer::from(der)),
+        KeyFormat::Sec1 => PrivateKeyDer::Sec1(pki_types::PrivateSec1KeyDer::from(der)),
+        KeyFormat::Pkcs8 => PrivateKeyDer::Pkcs8(pki_types::PrivatePkcs8KeyDer::from(der)),
+    };
+    any_supported_type(&key)
+        .map_err(|e| format!("the key is in {format} format, but could not be used: {e}"))
+}
+
+/// Decodes the first block of a PEM file and returns its label and content.
+fn decode_pem(data: &[u8]) -> Option<(String, Vec<u8>)> {
+    let text = std::str::from_utf8(data).ok()?;
+    let (label, rest) = text.strip_prefix("-----BEGIN ")?.split_once("-----")?;
+    let (base64, _) = rest.split_once(&format!("-----END {label}-----"))?;
+
+    let mut der = vec![];
+    let (mut bits, mut len) = (0u32, 0);
+    for c in base64.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = (bits << 6 | u32::from(value)) & 0xffff;
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            der.push((bits >> len) as u8);
+        }
+    }
+    Some((label.to_string(), der))
 }
 
 impl CertStore {
@@ -125,6 +246,82 @@ impl CertStore {
     /// If there are `cert.der` and `key.der` directly in `certs_dir`, these
     /// will be loaded as default certificates.
     pub fn load_from(certs_dir: &Path) -> Result<Self, CertLoadError> {
+        Ok(Self {
+            certs: RwLock::new(Self::load_certs(certs_dir)?),
+        })
+    }
+
+    /// Loads the certificates from the certificate directory again and
+    /// replaces the current ones. If loading fails, the current certificates
+    /// are kept. Connections that are already established are not affected.
+    pub fn reload(&self, certs_dir: &Path) -> Result<(), CertLoadError> {
+        let certs = Self::load_certs(certs_dir)?;
+        *self.certs.write().unwrap() = certs;
+        Ok(())
+    }
+
+    /// Checks if a certificate fitting a specific domain has been loaded.
+    /// The same rules about using a certificate at the level above apply.
+    pub fn has_domain(&self, domain: &str) -> bool {
+        self.certs
+            .read()
+            .unwrap()
+            .iter()
+            .any(|(name, _)| covers(name, domain))
+    }
+
+    /// Checks if the same certificates are used for both domains.
+    pub fn same_certificate(&self, a: &str, b: &str) -> bool {
+        let certs = self.certs.read().unwrap();
+        let position = |domain| certs.iter().position(|(name, _)| covers(name, domain));
+        position(a) == position(b)
+    }
+
+    /// Returns the time the certificates expire, by domain name. If a domain
+    /// has several certificates, this is the earliest time. The domain name is
+    /// empty for the fallback certificate.
+    pub fn expiry(&self) -> Vec<(String, Option<SystemTime>)> {
+        self.certs
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(domain, keys)| {
+                let not_after = keys
+                    .iter()
+                    .map(|key| key.end_entity_cert().ok().and_then(|cert| not_after(cert)))
+                    .collect::<Option<Vec<_>>>()
+                    .and_then(|times| times.into_iter().min());
+                (domain.clone(), not_after)
+            })
+            .collect()
+    }
+
+    /// Logs a warning for every certificate that expires within the given
+    /// time, and an error for every certificate that already expired.
+    pub fn warn_expiring(&self, within: Duration) {
+        let now = SystemTime::now();
+        for (domain, not_after) in self.expiry() {
+            let name = if domain.is_empty() {
+                "fallback certificate".to_string()
+            } else {
+                format!("certificate for {domain}")
+            };
+            let Some(not_after) = not_after else {
+                log::warn!("Could not read the expiry date of the {name}");
+                continue;
+            };
+            let date = humantime::format_rfc3339_seconds(not_after);
+            if not_after <= now {
+                log::error!("The {name} expired on {date}");
+            } else if not_after.duration_since(now).unwrap_or_default() <= within {
+                log::warn!("The {name} expires on {date}");
+            }
+        }
+    }
+
+    /// Loads all certificates from the certificate directory, see
+    /// [`load_from`](Self::load_from).
+    fn load_certs(certs_dir: &Path) -> Result<Vec<DomainCerts>, CertLoadError> {
         // load all certificates from directories
         let mut certs = vec![];
 
@@ -145,7 +342,7 @@ impl CertStore {
             // For the fallback keys there is no domain name to verify them
             // against, so we can skip that step and only have to do it for the
             // other keys below.
-            Ok(key) => certs.push((String::new(), Arc::new(key))),
+            Ok(keys) => certs.push((String::new(), keys)),
         }
 
         for file in certs_dir
@@ -163,9 +360,9 @@ impl CertStore {
                 .unwrap()
                 .to_string();
 
-            let key = load_domain(certs_dir, filename.clone())?;
+            let keys = load_domain(certs_dir, filename.clone())?;
 
-            certs.push((filename, Arc::new(key)));
+            certs.push((filename, keys));
         }
 
         if certs.is_empty() {
@@ -177,6 +374,13 @@ impl CertStore {
             // substring of the other, the `zip` will only compare the smaller
             // length of either a or b and the for loop will not decide.
             for (a_part, b_part) in a.split('.').rev().zip(b.split('.').rev()) {
+                // a wildcard is less specific than any other label
+                match (a_part, b_part) {
+                    ("*", "*") => continue,
+                    ("*", _) => return std::cmp::Ordering::Greater,
+                    (_, "*") => return std::cmp::Ordering::Less,
+                    _ => {}
+                }
                 if a_part != b_part {
                     // Here we have to make sure that the empty string will
                     // always be sorted to the end, so we reverse the usual
@@ -193,13 +397,172 @@ impl CertStore {
             certs.iter().map(|t| &t.0).collect::<Vec<_>>()
         );
 
-        Ok(Self { certs })
+        Ok(certs)
     }
+}
 
-    /// Checks if a certificate fitting a specific domain has been loaded.
-    /// The same rules about using a certificate at the level above apply.
-    pub fn has_domain(&self, domain: &str) -> bool {
-        self.certs.iter().any(|(s, _)| domain.ends_with(s))
+/// Checks if the certificate for the name of a subdirectory of the
+/// certificate directory applies to a domain. A name applies to the same
+/// domain and all its subdomains, where only whole labels are compared. A
+/// name starting with `*.` only applies to domains with exactly one more
+/// label, like a wildcard certificate. The empty name of the fallback
+/// certificate applies to all domains.
+fn covers(name: &str, domain: &str) -> bool {
+    if let Some(parent) = name.strip_prefix("*.") {
+        return matches!(
+            domain.split_once('.'),
+            Some((label, rest)) if !label.is_empty() && rest.eq_ignore_ascii_case(parent)
+        );
+    }
+    if name.is_empty() || domain.eq_ignore_ascii_case(name) {
+        return true;
+    }
+    // the name has to be preceded by a dot, so `example.org` does not apply
+    // to `badexample.org`
+    domain.len() > name.len()
+        && domain.is_char_boundary(domain.len() - name.len())
+        && domain[domain.len() - name.len()..].eq_ignore_ascii_case(name)
+        && domain[..domain.len() - name.len()].ends_with('.')
+}
+
+/// Reads the DER element at the start of the input and returns its tag, its
+/// content and the rest of the input.
+fn der_element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first()?;
+    let (&len, mut rest) = rest.split_first()?;
+    let len = if len < 0x80 {
+        usize::from(len)
+    } else {
+        // long form, the lower bits give the number of length bytes
+        let count = usize::from(len & 0x7f);
+        if count == 0 || count > 4 || rest.len() < count {
+            return None;
+        }
+        let (len, content) = rest.split_at(count);
+        rest = content;
+        len.iter()
+            .fold(0, |len, &byte| len << 8 | usize::from(byte))
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (content, rest) = rest.split_at(len);
+    Some((tag, content, rest))
+}
+
+/// Returns the end of the validity period of a DER encoded X.509 certificate.
+fn not_after(cert: &[u8]) -> Option<SystemTime> {
+    const VERSION: u8 = 0xa0;
+    const UTC_TIME: u8 = 0x17;
+    const GENERALIZED_TIME: u8 = 0x18;
+
+    let (_, cert, _) = der_element(cert)?;
+    let (_, tbs_certificate, _) = der_element(cert)?;
+    // the version is optional
+    let mut fields = match der_element(tbs_certificate)? {
+        (VERSION, _, rest) => rest,
+        _ => tbs_certificate,
+    };
+    // skip the serial number, signature algorithm and issuer
+    for _ in 0..3 {
+        fields = der_element(fields)?.2;
+    }
+    let (_, validity, _) = der_element(fields)?;
+    // skip the start of the validity period
+    let (_, _, validity) = der_element(validity)?;
+    let (tag, time, _) = der_element(validity)?;
+    let time = std::str::from_utf8(time).ok()?.strip_suffix('Z')?;
+    // both formats are in UTC and only differ in the number of digits of
+    // the year, two-digit years are in the range 1950 to 2049
+    let time = match tag {
+        UTC_TIME if time.len() == 12 => {
+            let century = if time[..2].parse::<u8>().ok()? < 50 {
+                "20"
+            } else {
+                "19"
+            };
+            format!("{century}{time}")
+        }
+        GENERALIZED_TIME if time.len() == 14 => time.to_string(),
+        _ => return None,
+    };
+    humantime::parse_rfc3339(&format!(
+        "{}-{}-{}T{}:{}:{}Z",
+        time.get(..4)?,
+        time.get(4..6)?,
+        time.get(6..8)?,
+        time.get(8..10)?,
+        time.get(10..12)?,
+        time.get(12..14)?
+    ))
+    .ok()
+}
+
+/// Checks the expiry dates of the certificates when started and once a day
+/// after that, see [`CertStore::warn_expiring`].
+pub(crate) async fn monitor_expiry(store: Arc<CertStore>, within: Duration) {
+    loop {
+        store.warn_expiring(within);
+        tokio::time::sleep(Duration::from_secs(24 * 60 * 60)).await;
+    }
+}
+
+/// Returns the modification times and sizes of all certificate and key files,
+/// to find out if any of them changed.
+fn snapshot(certs_dir: &Path) -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    let dirs = certs_dir
+        .read_dir()
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir());
+    let mut files = std::iter::once(certs_dir.to_path_buf())
+        .chain(dirs)
+        .flat_map(|dir| {
+            pair_suffixes(&dir).into_iter().flat_map(move |suffix| {
+                [
+                    dir.join(format!("cert{suffix}.der")),
+                    dir.join(format!("key{suffix}.der")),
+                ]
+            })
+        })
+        .filter_map(|path| {
+            let metadata = path.metadata().ok()?;
+            Some((path, metadata.modified().ok(), metadata.len()))
+        })
+        .collect::<Vec<_>>();
+    files.sort();
+    files
+}
+
+/// Checks the certificate directory for changes in the given interval and
+/// reloads the certificates when files were added, removed or changed, so
+/// renewed certificates are used without a restart.
+///
+/// Because renewal scripts usually write the certificate and the key one
+/// after the other, the certificates are only reloaded once the files did
+/// not change for one interval.
+pub(crate) async fn watch(store: Arc<CertStore>, certs_dir: PathBuf, interval: Duration) {
+    let mut loaded = snapshot(&certs_dir);
+    let mut last = loaded.clone();
+    loop {
+        tokio::time::sleep(interval).await;
+        let current = snapshot(&certs_dir);
+        if current != last {
+            // wait until the files do not change anymore
+            last = current;
+            continue;
+        }
+        if current == loaded {
+            continue;
+        }
+        match store.reload(&certs_dir) {
+            Ok(()) => log::info!("Reloaded certificates from {}", certs_dir.display()),
+            Err(e) => log::error!("Could not reload certificates, keeping the previous ones: {e}"),
+        }
+        // do not retry until the files change again
+        loaded = current;
     }
 }
 
@@ -211,10 +574,22 @@ impl ResolvesServerCert for CertStore {
             // appear first. We have to find the first that is either this
             // domain or a parent domain of the current one.
             self.certs
+                .read()
+                .unwrap()
                 .iter()
-                .find(|(s, _)| name.ends_with(s))
-                // only the key is interesting
-                .map(|(_, k)| k)
+                .find(|(s, _)| covers(s, name))
+                // use the first key that supports one of the signature schemes
+                // of the client, e.g. RSA for old clients and ECDSA for new
+                // ones
+                .and_then(|(_, keys)| {
+                    keys.iter()
+                        .find(|key| {
+                            key.key
+                                .choose_scheme(client_hello.signature_schemes())
+                                .is_some()
+                        })
+                        .or(keys.first())
+                })
                 .cloned()
         } else {
             // This kind of resolver requires SNI.
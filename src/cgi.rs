@@ -0,0 +1,284 @@
+use {
+    percent_encoding::percent_decode_str,
+    std::{
+        io::{self, Read},
+        net::SocketAddr,
+        path::Path,
+        process::{Command, Stdio},
+        str::FromStr,
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWrite},
+        sync::mpsc,
+        time::timeout,
+    },
+    url::Url,
+};
+
+/// Size of the chunks the output of a script is read in.
+const CHUNK_SIZE: usize = 8192;
+
+/// Checks if the file at the given path can be executed as a CGI script.
+pub(crate) fn is_script(path: &Path) -> bool {
+    let Ok(metadata) = path.metadata() else {
+        return false;
+    };
+    if !metadata.is_file() {
+        return false;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o111 != 0
+    }
+    #[cfg(not(unix))]
+    true
+}
+
+/// The address of an application server, like an SCGI or FastCGI server.
+#[derive(Debug, Clone)]
+pub(crate) enum Address {
+    /// A TCP address in the form `host:port`.
+    Tcp(String),
+    /// A Unix socket, given as `unix:/path/to/socket`.
+    #[cfg(unix)]
+    Unix(std::path::PathBuf),
+}
+
+impl FromStr for Address {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        #[cfg(unix)]
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Self::Unix(path.into()));
+        }
+        if s.contains(':') {
+            Ok(Self::Tcp(s.to_string()))
+        } else {
+            Err(format!(
+                "invalid backend address {s:?}, expected host:port or unix:path"
+            ))
+        }
+    }
+}
+
+impl std::fmt::Display for Address {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A connection to an application server.
+pub(crate) trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+impl Address {
+    /// Connects to the application server at this address.
+    pub async fn connect(&self) -> io::Result<Box<dyn Stream>> {
+        match self {
+            Self::Tcp(addr) => Ok(Box::new(tokio::net::TcpStream::connect(addr).await?)),
+            #[cfg(unix)]
+            Self::Unix(path) => Ok(Box::new(tokio::net::UnixStream::connect(path).await?)),
+        }
+    }
+}
+
+/// A URL path prefix that is handled by an application server.
+#[derive(Debug, Clone)]
+pub(crate) struct Route {
+    pub prefix: String,
+    pub address: Address,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Parses a route in the form `PREFIX=ADDRESS`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((prefix, address)) = s.split_once('=') else {
+            return Err(format!(
+                "invalid backend route {s:?}, expected PREFIX=ADDRESS"
+            ));
+        };
+        Ok(Self {
+            prefix: prefix.to_string(),
+            address: address.parse()?,
+        })
+    }
+}
+
+/// The output of a CGI script or similar backend, in chunks.
+pub(crate) type Output = mpsc::Receiver<io::Result<Vec<u8>>>;
+
+/// Information about a request that is passed on to a CGI script or similar
+/// backend.
+pub(crate) struct Request<'a> {
+    pub url: &'a Url,
+    /// The part of the URL path that leads to the script.
+    pub script_name: String,
+    /// The percent-decoded part of the URL path after the script name.
+    pub path_info: String,
+    pub local_addr: Option<SocketAddr>,
+    pub peer_addr: Option<SocketAddr>,
+    /// Fingerprint of the client certificate, if one was sent.
+    pub client_cert: Option<&'a str>,
+}
+
+impl Request<'_> {
+    /// Returns the standard CGI variables for this request.
+    pub fn variables(&self) -> Vec<(&'static str, String)> {
+        let mut vars = vec![
+            ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+            ("SERVER_PROTOCOL", "GEMINI".to_string()),
+            (
+                "SERVER_SOFTWARE",
+                concat!("agate/", env!("CARGO_PKG_VERSION")).to_string(),
+            ),
+            ("GEMINI_URL", self.url.to_string()),
+            (
+                "SERVER_NAME",
+                self.url.host_str().unwrap_or_default().to_string(),
+            ),
+            ("SCRIPT_NAME", self.script_name.clone()),
+            ("PATH_INFO", self.path_info.clone()),
+            (
+                "QUERY_STRING",
+                self.url.query().unwrap_or_default().to_string(),
+            ),
+        ];
+        // the answer to an input prompt, unless it can not be passed on
+        if let Some(input) = self
+            .url
+            .query()
+            .map(|query| percent_decode_str(query).decode_utf8_lossy())
+            .filter(|input| !input.contains('\0'))
+        {
+            vars.push(("GEMINI_INPUT", input.into_owned()));
+        }
+        if let Some(addr) = self.local_addr {
+            vars.push(("SERVER_PORT", addr.port().to_string()));
+        }
+        if let Some(addr) = self.peer_addr {
+            vars.push(("REMOTE_ADDR", addr.ip().to_string()));
+            vars.push(("REMOTE_HOST", addr.ip().to_string()));
+        }
+        if let Some(fingerprint) = self.client_cert {
+            vars.push(("AUTH_TYPE", "CERTIFICATE".to_string()));
+            vars.push(("TLS_CLIENT_HASH", fingerprint.to_string()));
+        }
+        vars
+    }
+}
+
+/// Starts the given script with the standard CGI environment variables and
+/// returns a channel that will receive its output in chunks. The script's
+/// standard error is passed through to Agate's standard error. The script is
+/// stopped if it does not send any output for `time_limit`.
+pub(crate) fn spawn(script: &Path, request: &Request, time_limit: Duration) -> io::Result<Output> {
+    // the path might be relative, which would be ambiguous after changing
+    // the working directory
+    let script = script.canonicalize()?;
+    let mut command = Command::new(&script);
+    if let Some(dir) = script.parent() {
+        command.current_dir(dir);
+    }
+    command
+        .env_clear()
+        .env("PATH", std::env::var_os("PATH").unwrap_or_default())
+        .envs(request.variables())
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+
+    let mut child = command.spawn()?;
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+
+    // the standard library process API is blocking, so read the output on
+    // a separate thread
+    let (chunks_tx, mut chunks) = mpsc::channel(4);
+    tokio::task::spawn_blocking(move || loop {
+        let mut buf = vec![0; CHUNK_SIZE];
+        let chunk = match stdout.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                buf.truncate(len);
+                Ok(buf)
+            }
+            Err(e) => Err(e),
+        };
+        let failed = chunk.is_err();
+        if chunks_tx.blocking_send(chunk).is_err() || failed {
+            break;
+        }
+    });
+
+    // the blocking read can not time out, so the output is passed on by a
+    // task that stops the script once it stalls
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        let finished = loop {
+            match timeout(time_limit, chunks.recv()).await {
+                Ok(Some(chunk)) => {
+                    let failed = chunk.is_err();
+                    // the connection may have been closed
+                    if tx.send(chunk).await.is_err() || failed {
+                        break false;
+                    }
+                }
+                Ok(None) => break true,
+                Err(_) => {
+                    let _ = tx
+                        .send(Err(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            "timed out reading the output of the CGI script",
+                        )))
+                        .await;
+                    break false;
+                }
+            }
+        };
+        if !finished {
+            let _ = child.kill();
+        }
+        // reap the process
+        tokio::task::spawn_blocking(move || child.wait());
+    });
+
+    Ok(rx)
+}
+
+/// Reads the response of a backend that is connected via a socket or
+/// similar and returns a channel that will receive it in chunks.
+pub(crate) fn forward<R>(mut reader: R) -> Output
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        loop {
+            let mut buf = vec![0; CHUNK_SIZE];
+            match reader.read(&mut buf).await {
+                Ok(0) => break,
+                Ok(len) => {
+                    buf.truncate(len);
+                    if tx.send(Ok(buf)).await.is_err() {
+                        // the connection was closed
+                        break;
+                    }
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    break;
+                }
+            }
+        }
+    });
+    rx
+}
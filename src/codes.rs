@@ -6,7 +6,27 @@ pub const PROXY_REQUEST_REFUSED: u8 = 53;
 pub const NOT_FOUND: u8 = 51;
 /// The resource requested is no longer available and will not be available again. Search engines and similar tools should remove this resource from their indices. Content aggrefators should stop requesting the resource and convey to their human users that the subscribed resource is gone. (cf HTTP 410)
 pub const GONE: u8 = 52;
+/// The request has failed. There is no response body. The nature of the failure is permanent, i.e. identical future requests will reliably fail for the same reason.
+pub const PERMANENT_FAILURE: u8 = 50;
+/// A proxy request failed because the server was unable to successfully complete a transaction with the remote host.
+pub const PROXY_ERROR: u8 = 43;
+/// Rate limiting is in effect. The <META> line is an integer indicating the number of seconds the client must wait before another request is made to this server.
+pub const SLOW_DOWN: u8 = 44;
+/// The server is unavailable due to overload or maintenance. (cf HTTP 503)
+pub const SERVER_UNAVAILABLE: u8 = 41;
+/// A CGI process, or similar system for generating dynamic content, died unexpectedly or timed out.
+pub const CGI_ERROR: u8 = 42;
+/// The request has failed. There is no response body. The nature of the failure is temporary, i.e. an identical request MAY succeed in the future.
+pub const TEMPORARY_FAILURE: u8 = 40;
+/// The requested resource requires a client certificate to access. If the request was made without a certificate, it should be repeated with one. If the request was made with a certificate, the server did not accept it and the request should be repeated with a different certificate.
+pub const CLIENT_CERTIFICATE_REQUIRED: u8 = 60;
+/// The supplied client certificate is not authorised for accessing the particular requested resource. The problem is not with the certificate itself, which may be authorised for other resources.
+pub const CERTIFICATE_NOT_AUTHORISED: u8 = 61;
 /// The requested resource should be consistently requested from the new URL provided in the future. Tools loke search engine indexers or content aggregators should update their configurations to avoid requesting the old URL, and end-user clients may automatically update bookmarks, etc. Note that clients that only pay attention to the initial digit of status codes will treat this as a temporary redirect. They will still end up at the right place, they just won't be able to make use of the knowledge that this redirect is permanent, so they'll pay a small performance penality by having to follow the redirect each time.
 pub const REDIRECT_PERMANENT: u8 = 31;
+/// The requested resource should be retrieved from the new URL provided, but clients should continue to use the original URL for future requests.
+pub const REDIRECT_TEMPORARY: u8 = 30;
 /// The request was handled successfully and a response body will follow the response header. The <META> line is a MIME media type which applies to the response body.
 pub const SUCCESS: u8 = 20;
+/// The requested resource accepts a line of textual user input. The <META> line is a prompt which should be displayed to the user. The same resource should then be requested again with the user's input included as a query component.
+pub const INPUT: u8 = 10;
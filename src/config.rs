@@ -0,0 +1,209 @@
+use configparser::ini::Ini;
+use getopts::{Fail, Options};
+use std::str::FromStr;
+
+/// Name of the section that holds server wide settings. Settings that are
+/// given before the first section header also belong to this section.
+static MAIN_SECTION: &str = "agate";
+
+/// Options that may also be given in the section of a virtual host.
+static VHOST_OPTIONS: &[&str] = &[
+    "content",
+    "rewrite",
+    "case-insensitive",
+    "charset",
+    "lang",
+    "lang-variant",
+    "robots",
+    "favicon",
+];
+
+/// Command line options combined with the settings from an optional
+/// configuration file.
+///
+/// The configuration file is an INI file, read with the same parser as the
+/// `.meta` files, whose keys are the long names of the command line options,
+/// for example
+/// ```text
+/// # comments start with a hash
+/// content = /srv/gemini/content
+/// addr = [::]:1965
+///     0.0.0.0:1965
+/// lang = en-US
+/// log-ip
+///
+/// [example.com]
+/// ```
+/// Options that can be given multiple times take one value per line, flags
+/// can be given without a value or with a boolean value. Every other section
+/// declares a virtual host with the section name as its hostname and may
+/// contain the options listed in `VHOST_OPTIONS`.
+///
+/// Options given on the command line always take precedence over the same
+/// option in the configuration file.
+pub(crate) struct Matches {
+    cli: getopts::Matches,
+    file: getopts::Matches,
+    /// The hostnames and options of virtual hosts from the configuration
+    /// file, sorted by hostname.
+    vhosts: Vec<(String, getopts::Matches)>,
+}
+
+impl Matches {
+    /// Parses the command line arguments and, if the `config` option is
+    /// present, the configuration file it points to.
+    pub fn parse(opts: &Options, args: &[String]) -> Result<Self, String> {
+        let cli = opts.parse(args).map_err(|f| f.to_string())?;
+
+        let (file_args, vhost_args) = match cli.opt_str("config") {
+            Some(path) => load(opts, &path)?,
+            None => (vec![], vec![]),
+        };
+        let file = opts.parse(file_args).map_err(|f| f.to_string())?;
+        let vhosts = vhost_args
+            .into_iter()
+            .map(|(hostname, args)| {
+                let matches = opts.parse(args).map_err(|f| f.to_string())?;
+                Ok((hostname, matches))
+            })
+            .collect::<Result<_, String>>()?;
+
+        Ok(Self { cli, file, vhosts })
+    }
+
+    /// Returns the hostnames and options of the virtual hosts declared in the
+    /// configuration file.
+    pub fn vhosts(&self) -> &[(String, getopts::Matches)] {
+        &self.vhosts
+    }
+
+    pub fn opt_present(&self, name: &str) -> bool {
+        self.cli.opt_present(name) || self.file.opt_present(name)
+    }
+
+    /// Checks if a flag is set. Flags may have an optional boolean value, so
+    /// e.g. `--log-ip=false` on the command line can unset a flag that was
+    /// set in the configuration file.
+    pub fn opt_flag(&self, name: &str) -> Result<bool, String> {
+        let matches = if self.cli.opt_present(name) {
+            &self.cli
+        } else if self.file.opt_present(name) {
+            &self.file
+        } else {
+            return Ok(false);
+        };
+        match matches.opt_str(name) {
+            None => Ok(true),
+            Some(value) => parse_bool(&value)
+                .ok_or_else(|| format!("{name} is a flag and can only be true or false")),
+        }
+    }
+
+    pub fn opt_str(&self, name: &str) -> Option<String> {
+        self.cli.opt_str(name).or_else(|| self.file.opt_str(name))
+    }
+
+    /// Returns all values of an option that may be given multiple times.
+    /// If the option is given on the command line, the values from the
+    /// configuration file are disregarded.
+    pub fn opt_strs(&self, name: &str) -> Vec<String> {
+        let values = self.cli.opt_strs(name);
+        if values.is_empty() {
+            self.file.opt_strs(name)
+        } else {
+            values
+        }
+    }
+
+    pub fn opt_get_default<T: FromStr>(&self, name: &str, default: T) -> Result<T, T::Err> {
+        match self.opt_str(name) {
+            Some(s) => s.parse(),
+            None => Ok(default),
+        }
+    }
+}
+
+/// Arguments for each virtual host from the configuration file.
+type VHostArgs = Vec<(String, Vec<String>)>;
+
+/// Reads the configuration file at the given path and translates it into the
+/// equivalent command line arguments, so they can be checked against the
+/// same option definitions. The options of virtual hosts are returned
+/// separately.
+fn load(opts: &Options, path: &str) -> Result<(Vec<String>, VHostArgs), String> {
+    let mut ini = Ini::new_cs();
+    ini.set_default_section(MAIN_SECTION);
+    ini.set_comment_symbols(&['#']);
+    ini.set_multiline(true);
+    let mut sections = ini
+        .load(path)
+        .map_err(|e| format!("could not read config file {path:?}: {e}"))?;
+
+    let mut args = vec![];
+    if let Some(main) = sections.remove(MAIN_SECTION) {
+        let mut keys = main.into_iter().collect::<Vec<_>>();
+        // sort so the order of multiple values is the same on every start
+        keys.sort();
+        for (key, value) in keys {
+            args.extend(entry_to_args(opts, &key, value).map_err(|e| format!("{path}: {e}"))?);
+        }
+    }
+
+    let mut sections = sections.into_iter().collect::<Vec<_>>();
+    sections.sort_by(|(a, _), (b, _)| a.cmp(b));
+    let mut vhosts = vec![];
+    for (hostname, settings) in sections {
+        let mut vhost_args = vec![];
+        for (key, value) in settings {
+            if !VHOST_OPTIONS.contains(&key.as_str()) {
+                return Err(format!(
+                    "{path}: option {key:?} can not be used for virtual host {hostname}"
+                ));
+            }
+            vhost_args.extend(
+                entry_to_args(opts, &key, value).map_err(|e| format!("{path}: {hostname}: {e}"))?,
+            );
+        }
+        args.push(format!("--hostname={hostname}"));
+        vhosts.push((hostname, vhost_args));
+    }
+
+    Ok((args, vhosts))
+}
+
+/// Translates a single configuration file entry into command line arguments.
+fn entry_to_args(opts: &Options, key: &str, value: Option<String>) -> Result<Vec<String>, String> {
+    if key == "config" {
+        return Err("the config option can not be used in a config file".into());
+    }
+
+    let Some(value) = value else {
+        // an entry without a value is a flag
+        let args = vec![format!("--{key}")];
+        return opts.parse(&args).map(|_| args).map_err(|f| f.to_string());
+    };
+
+    // multiple values are given on separate lines
+    let args = value
+        .lines()
+        .map(|line| format!("--{key}={}", line.trim()))
+        .collect::<Vec<_>>();
+    match opts.parse(&args) {
+        Ok(_) => Ok(args),
+        // this option is a flag, so the value has to be a boolean
+        Err(Fail::UnexpectedArgument(_)) => match parse_bool(&value) {
+            Some(true) => Ok(vec![format!("--{key}")]),
+            Some(false) => Ok(vec![]),
+            None => Err(format!("{key} is a flag and can only be true or false")),
+        },
+        Err(f) => Err(f.to_string()),
+    }
+}
+
+pub(crate) fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "yes" | "on" => Some(true),
+        "false" | "no" | "off" => Some(false),
+        _ => None,
+    }
+}
@@ -0,0 +1,96 @@
+//! Running in the background without a supervisor, like a traditional Unix
+//! daemon.
+
+use {
+    std::{
+        fs::{self, OpenOptions},
+        io::{self, Read, Write},
+        os::unix::{fs::OpenOptionsExt, net::UnixStream},
+        path::{Path, PathBuf},
+    },
+    tokio::sync::{mpsc, oneshot},
+};
+
+/// Forks into the background and detaches from the terminal. The parent
+/// process exits, so this has to be called before any threads are started.
+pub(crate) fn daemonize() -> io::Result<()> {
+    agate::sys::detach()?;
+
+    // the terminal may go away, log messages are sent to the log file or
+    // the log target instead
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")?;
+    agate::sys::redirect_stdio(&null)?;
+    // nothing can be printed anymore
+    std::panic::set_hook(Box::new(|info| log::error!("{info}")));
+    Ok(())
+}
+
+/// A file containing the process ID, which is removed when dropped.
+pub(crate) struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Writes the ID of the current process to the file.
+    pub fn create(path: &Path) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o644)
+            .open(path)?;
+        writeln!(file, "{}", std::process::id())?;
+        // the file is removed relative to the current directory
+        Ok(Self(std::path::absolute(path)?))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.0) {
+            log::warn!("could not remove PID file {}: {e}", self.0.display());
+        }
+    }
+}
+
+/// Receivers for the signals that Agate handles instead of exiting right away.
+pub(crate) struct Signals {
+    /// Notified when Agate is asked to terminate with `SIGTERM` or `SIGINT`,
+    /// if those are handled.
+    pub terminated: Option<oneshot::Receiver<()>>,
+    /// Notified for every `SIGHUP`, which asks Agate to reload the
+    /// certificates.
+    pub hangup: mpsc::UnboundedReceiver<()>,
+}
+
+/// Installs handlers for `SIGHUP`, and for `SIGTERM` and `SIGINT` if
+/// `terminate` is set. The handlers write the signal number to a socket,
+/// which is read by a thread that notifies the receivers.
+pub(crate) fn signals(terminate: bool) -> io::Result<Signals> {
+    let (sender, mut receiver) = UnixStream::pair()?;
+    let mut handled = vec![libc::SIGHUP];
+    if terminate {
+        handled.extend([libc::SIGTERM, libc::SIGINT]);
+    }
+    agate::sys::forward_signals(&handled, sender)?;
+
+    let (notify_terminated, terminated) = oneshot::channel();
+    let (notify_hangup, hangup) = mpsc::unbounded_channel();
+    std::thread::spawn(move || {
+        let mut signal = [0];
+        while receiver.read_exact(&mut signal).is_ok() {
+            if libc::c_int::from(signal[0]) == libc::SIGHUP {
+                let _ = notify_hangup.send(());
+            } else {
+                log::info!("Stopping");
+                let _ = notify_terminated.send(());
+                break;
+            }
+        }
+    });
+    Ok(Signals {
+        terminated: terminate.then_some(terminated),
+        hangup,
+    })
+}
@@ -0,0 +1,175 @@
+use configparser::ini::Ini;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Name of the file that configures its directory and everything in it.
+static CONFIG_FILENAME: &str = ".agate.ini";
+
+/// Name of the section that holds the settings. Settings that are given
+/// before the first section header also belong to this section.
+static MAIN_SECTION: &str = "agate";
+
+/// Name of the section that maps file extensions to MIME types.
+static MIME_SECTION: &str = "mime";
+
+/// Which requests for files in a directory are answered.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Access {
+    /// Answer all requests.
+    Allow,
+    /// Answer all requests with status 51, as if the files did not exist.
+    Deny,
+    /// Only answer requests with a client certificate that is trusted.
+    Certificate,
+}
+
+/// Settings for a directory and everything in it, read from a file with the
+/// name given by `CONFIG_FILENAME`. The file is an INI file like the `.meta`
+/// files, where `#` starts a comment:
+/// ```text
+/// lang = de
+/// charset = utf-8 # for all text files
+/// dir-listing = true
+/// access = certificate
+///
+/// [mime]
+/// txt = text/plain
+/// ```
+/// Settings in a subdirectory override those of its parent directories.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DirConfig {
+    pub lang: Option<String>,
+    pub charset: Option<String>,
+    /// Whether directories without an index file are listed.
+    pub dir_listing: Option<bool>,
+    pub access: Option<Access>,
+    /// MIME types by lower case file extension.
+    mime: HashMap<String, String>,
+}
+
+impl DirConfig {
+    /// Applies the settings of a subdirectory.
+    pub fn merge(&mut self, other: Self) {
+        self.lang = other.lang.or(self.lang.take());
+        self.charset = other.charset.or(self.charset.take());
+        self.dir_listing = other.dir_listing.or(self.dir_listing);
+        self.access = other.access.or(self.access);
+        self.mime.extend(other.mime);
+    }
+
+    /// Returns the MIME type for the extension of the file, if it is set.
+    pub fn mime_type(&self, path: &Path) -> Option<&str> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        self.mime.get(&ext).map(String::as_str)
+    }
+
+    /// Returns the MIME parameters to add to the MIME type, which may be
+    /// empty. `lang` is the default language.
+    pub fn parameters(&self, lang: Option<&str>) -> String {
+        let mut params = String::new();
+        if let Some(charset) = &self.charset {
+            params.push_str(&format!(";charset={charset}"));
+        }
+        // an empty language removes the default one
+        if let Some(lang) = self
+            .lang
+            .as_deref()
+            .or(lang)
+            .filter(|lang| !lang.is_empty())
+        {
+            params.push_str(&format!(";lang={lang}"));
+        }
+        params
+    }
+
+    fn parse(content: String) -> Result<Self, String> {
+        let mut ini = Ini::new_cs();
+        ini.set_default_section(MAIN_SECTION);
+        ini.set_comment_symbols(&['#']);
+        let mut sections = ini.read(content)?;
+
+        let mut config = Self::default();
+        for (key, value) in sections.remove(MAIN_SECTION).unwrap_or_default() {
+            let value = value.as_deref().unwrap_or_default();
+            match key.as_str() {
+                "lang" => config.lang = Some(value.to_string()),
+                "charset" => config.charset = Some(value.to_string()),
+                "dir-listing" => {
+                    config.dir_listing =
+                        Some(crate::config::parse_bool(value).ok_or_else(|| {
+                            format!("dir-listing has to be true or false, not {value:?}")
+                        })?)
+                }
+                "access" => {
+                    config.access = Some(match value {
+                        "allow" => Access::Allow,
+                        "deny" => Access::Deny,
+                        "certificate" => Access::Certificate,
+                        _ => {
+                            return Err(format!(
+                                "access has to be allow, deny or certificate, not {value:?}"
+                            ))
+                        }
+                    })
+                }
+                _ => return Err(format!("unknown setting {key:?}")),
+            }
+        }
+        for (ext, mime) in sections.remove(MIME_SECTION).unwrap_or_default() {
+            let mime = mime.as_deref().unwrap_or_default();
+            if !mime.contains('/') {
+                return Err(format!("{mime:?} is not a MIME media type"));
+            }
+            config.mime.insert(ext.to_lowercase(), mime.to_string());
+        }
+        if let Some(section) = sections.keys().next() {
+            return Err(format!("unknown section {section:?}"));
+        }
+        Ok(config)
+    }
+}
+
+/// The configuration files that were read, with their modification time.
+static CACHE: Lazy<Mutex<HashMap<PathBuf, (SystemTime, DirConfig)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the settings from the configuration file in the given directory,
+/// if there is one. The file is only read again if it was modified since.
+pub(crate) async fn load(dir: &Path) -> Result<Option<DirConfig>, String> {
+    let file = dir.join(CONFIG_FILENAME);
+    let modified = match tokio::fs::metadata(&file).await {
+        Ok(metadata) if metadata.is_file() => metadata.modified().ok(),
+        Ok(_) => return Ok(None),
+        // the path may also lead to a file instead of a directory
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+            CACHE.lock().unwrap().remove(&file);
+            return Ok(None);
+        }
+        Err(e) => return Err(format!("could not read {file:?}: {e}")),
+    };
+
+    if let Some(modified) = modified {
+        if let Some((read, config)) = CACHE.lock().unwrap().get(&file) {
+            if *read == modified {
+                return Ok(Some(config.clone()));
+            }
+        }
+    }
+
+    log::debug!("reading directory configuration {:?}", file);
+    let content = tokio::fs::read_to_string(&file)
+        .await
+        .map_err(|e| format!("could not read {file:?}: {e}"))?;
+    let config = DirConfig::parse(content).map_err(|e| format!("{}: {e}", file.display()))?;
+    if let Some(modified) = modified {
+        CACHE
+            .lock()
+            .unwrap()
+            .insert(file, (modified, config.clone()));
+    }
+    Ok(Some(config))
+}
@@ -0,0 +1,517 @@
+use {
+    crate::cgi::{self, Address, Request, Stream},
+    once_cell::sync::Lazy,
+    std::{
+        collections::HashMap,
+        io,
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, OnceLock, Weak,
+        },
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncRead, AsyncReadExt, AsyncWriteExt, WriteHalf},
+        sync::mpsc,
+        task::JoinHandle,
+        time::timeout,
+    },
+};
+
+const VERSION: u8 = 1;
+
+// record types
+const BEGIN_REQUEST: u8 = 1;
+const ABORT_REQUEST: u8 = 2;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const GET_VALUES: u8 = 9;
+const GET_VALUES_RESULT: u8 = 10;
+
+/// The request ID of management records, which do not belong to a request.
+const MANAGEMENT_ID: u16 = 0;
+
+const ROLE_RESPONDER: u16 = 1;
+/// Flag for `BEGIN_REQUEST` so the application server keeps the connection
+/// open after the request.
+const KEEP_CONN: u8 = 1;
+
+/// The `protocolStatus` of an `END_REQUEST` record for a request that was
+/// handled normally.
+const REQUEST_COMPLETE: u8 = 0;
+
+/// The name of the variable an application server uses to tell whether it
+/// can handle several requests on one connection at once.
+const MPXS_CONNS: &str = "FCGI_MPXS_CONNS";
+
+/// The maximum number of requests that are sent on one connection at once,
+/// if the application server supports it.
+const MAX_REQUESTS: usize = 32;
+
+/// The maximum number of idle connections that are kept for each address.
+const MAX_IDLE: usize = 8;
+
+/// A record as received from an application server.
+struct Record {
+    kind: u8,
+    id: u16,
+    content: Vec<u8>,
+}
+
+/// An open connection to an application server. Its records are read by a
+/// separate task that passes them on to the requests they belong to.
+struct Connection {
+    /// The address of the application server, as used in the pool.
+    key: String,
+    writer: tokio::sync::Mutex<WriteHalf<Box<dyn Stream>>>,
+    /// The requests in progress by their ID. A request that was given up on
+    /// keeps its ID without a channel until the application server ends it,
+    /// so late records can not be mixed up with a new request.
+    requests: Mutex<HashMap<u16, Option<mpsc::Sender<Record>>>>,
+    /// Whether the application server can handle several requests on this
+    /// connection at once. Until it answered, only one request is sent.
+    multiplexed: AtomicBool,
+    closed: AtomicBool,
+    reader: OnceLock<JoinHandle<()>>,
+}
+
+/// Open connections to application servers, by address.
+type Pool = HashMap<String, Vec<Arc<Connection>>>;
+
+static POOL: Lazy<Mutex<Pool>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Encodes a single record.
+fn record(kind: u8, id: u16, content: &[u8]) -> Vec<u8> {
+    debug_assert!(content.len() <= u16::MAX as usize);
+    let mut record = vec![VERSION, kind];
+    record.extend(id.to_be_bytes());
+    record.extend((content.len() as u16).to_be_bytes());
+    // no padding and a reserved byte
+    record.extend([0, 0]);
+    record.extend(content);
+    record
+}
+
+/// Encodes the length of a name or value in a name-value pair.
+fn encode_length(buf: &mut Vec<u8>, len: usize) {
+    if len < 128 {
+        buf.push(len as u8);
+    } else {
+        buf.extend((len as u32 | 0x8000_0000).to_be_bytes());
+    }
+}
+
+/// Encodes a name-value pair.
+fn encode_pair(buf: &mut Vec<u8>, name: &str, value: &str) {
+    encode_length(buf, name.len());
+    encode_length(buf, value.len());
+    buf.extend(name.as_bytes());
+    buf.extend(value.as_bytes());
+}
+
+/// Decodes the name-value pairs of a record, stopping at the first one that
+/// is malformed.
+fn decode_pairs(mut content: &[u8]) -> Vec<(&[u8], &[u8])> {
+    fn length(content: &mut &[u8]) -> Option<usize> {
+        match content.first()? {
+            len if len & 0x80 == 0 => {
+                *content = &content[1..];
+                Some(*len as usize)
+            }
+            _ => {
+                let bytes = content.get(..4)?;
+                let len = u32::from_be_bytes(bytes.try_into().unwrap()) & 0x7fff_ffff;
+                *content = &content[4..];
+                Some(len as usize)
+            }
+        }
+    }
+
+    let mut pairs = vec![];
+    while let (Some(name_len), Some(value_len)) = (length(&mut content), length(&mut content)) {
+        if content.len() < name_len + value_len {
+            break;
+        }
+        let (name, rest) = content.split_at(name_len);
+        let (value, rest) = rest.split_at(value_len);
+        pairs.push((name, value));
+        content = rest;
+    }
+    pairs
+}
+
+/// Encodes all records necessary for sending the request.
+fn encode_request(request: &Request, id: u16) -> Vec<u8> {
+    let mut begin = ROLE_RESPONDER.to_be_bytes().to_vec();
+    begin.extend([KEEP_CONN, 0, 0, 0, 0, 0]);
+    let mut buf = record(BEGIN_REQUEST, id, &begin);
+
+    let mut params = vec![];
+    for (name, value) in request.variables() {
+        encode_pair(&mut params, name, &value);
+    }
+    for chunk in params.chunks(u16::MAX as usize) {
+        buf.extend(record(PARAMS, id, chunk));
+    }
+    // empty records terminate the parameter and input streams
+    buf.extend(record(PARAMS, id, &[]));
+    buf.extend(record(STDIN, id, &[]));
+    buf
+}
+
+/// Reads a single record.
+async fn read_record(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Record> {
+    let mut header = [0; 8];
+    stream.read_exact(&mut header).await?;
+    let id = u16::from_be_bytes([header[2], header[3]]);
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let padding = header[6] as usize;
+
+    let mut content = vec![0; len + padding];
+    stream.read_exact(&mut content).await?;
+    content.truncate(len);
+    Ok(Record {
+        kind: header[1],
+        id,
+        content,
+    })
+}
+
+impl Connection {
+    /// Connects to the application server and asks it whether it can handle
+    /// several requests on one connection at once.
+    async fn open(address: &Address, key: String, time_limit: Duration) -> io::Result<Arc<Self>> {
+        let mut stream = timeout(time_limit, address.connect())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+        let mut query = vec![];
+        encode_pair(&mut query, MPXS_CONNS, "");
+        timeout(
+            time_limit,
+            stream.write_all(&record(GET_VALUES, MANAGEMENT_ID, &query)),
+        )
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+        let (reader, writer) = tokio::io::split(stream);
+        let connection = Arc::new(Self {
+            key,
+            writer: tokio::sync::Mutex::new(writer),
+            requests: Mutex::new(HashMap::new()),
+            multiplexed: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            reader: OnceLock::new(),
+        });
+        let task = tokio::spawn(Self::read(Arc::downgrade(&connection), reader));
+        let _ = connection.reader.set(task);
+        Ok(connection)
+    }
+
+    /// Reads the records from the application server and passes them on to
+    /// the requests they belong to, until the connection is closed.
+    async fn read(connection: Weak<Self>, mut reader: impl AsyncRead + Unpin) {
+        while let Ok(record) = read_record(&mut reader).await {
+            let Some(connection) = connection.upgrade() else {
+                return;
+            };
+            if record.id == MANAGEMENT_ID {
+                if record.kind == GET_VALUES_RESULT {
+                    let multiplexed = decode_pairs(&record.content)
+                        .iter()
+                        .any(|&(name, value)| name == MPXS_CONNS.as_bytes() && value == b"1");
+                    connection.multiplexed.store(multiplexed, Ordering::Relaxed);
+                }
+                // other management records, like the answer that the
+                // application server does not know the query, are ignored
+                continue;
+            }
+
+            let id = record.id;
+            let end = record.kind == END_REQUEST;
+            let sender = connection
+                .requests
+                .lock()
+                .unwrap()
+                .get(&id)
+                .cloned()
+                .flatten();
+            if let Some(sender) = sender {
+                // the request might have been given up on in the meantime
+                let _ = sender.send(record).await;
+            }
+            if end {
+                connection.requests.lock().unwrap().remove(&id);
+                connection.release();
+            }
+        }
+        if let Some(connection) = connection.upgrade() {
+            connection.close();
+        }
+    }
+
+    /// Reserves a request ID on this connection, if it can take another
+    /// request, and returns the channel the records for it will be sent to.
+    fn register(&self) -> Option<(u16, mpsc::Receiver<Record>)> {
+        if self.closed.load(Ordering::Relaxed) {
+            return None;
+        }
+        let mut requests = self.requests.lock().unwrap();
+        let capacity = if self.multiplexed.load(Ordering::Relaxed) {
+            MAX_REQUESTS
+        } else {
+            1
+        };
+        if requests.len() >= capacity {
+            return None;
+        }
+        let id = (1..=u16::MAX).find(|id| !requests.contains_key(id))?;
+        let (tx, rx) = mpsc::channel(4);
+        requests.insert(id, Some(tx));
+        Some((id, rx))
+    }
+
+    /// Gives up on a request. The application server is asked to stop it,
+    /// but its ID stays reserved until the application server ended it.
+    fn abort(self: &Arc<Self>, id: u16) {
+        if let Some(sender) = self.requests.lock().unwrap().get_mut(&id) {
+            *sender = None;
+        } else {
+            return;
+        }
+        let connection = self.clone();
+        tokio::spawn(async move {
+            let mut writer = connection.writer.lock().await;
+            if writer
+                .write_all(&record(ABORT_REQUEST, id, &[]))
+                .await
+                .is_err()
+            {
+                drop(writer);
+                connection.close();
+            }
+        });
+    }
+
+    /// Closes the connection if there are too many idle connections to the
+    /// same application server after a request ended.
+    fn release(self: &Arc<Self>) {
+        let mut pool = POOL.lock().unwrap();
+        let Some(connections) = pool.get_mut(&self.key) else {
+            return;
+        };
+        let idle = connections
+            .iter()
+            .filter(|connection| connection.requests.lock().unwrap().is_empty())
+            .count();
+        if idle > MAX_IDLE && self.requests.lock().unwrap().is_empty() {
+            connections.retain(|connection| !Arc::ptr_eq(connection, self));
+            drop(pool);
+            self.close();
+        }
+    }
+
+    /// Marks the connection as closed, which ends all requests on it.
+    fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        // dropping the channels tells the requests that no more records will
+        // arrive
+        self.requests.lock().unwrap().clear();
+        if let Some(reader) = self.reader.get() {
+            reader.abort();
+        }
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        // stop reading, which also closes the connection
+        if let Some(reader) = self.reader.get() {
+            reader.abort();
+        }
+    }
+}
+
+/// A request in progress on a connection. If it is dropped before the
+/// application server ended the request, the request is aborted.
+struct Pending {
+    connection: Arc<Connection>,
+    id: u16,
+    records: mpsc::Receiver<Record>,
+    ended: bool,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if !self.ended {
+            self.connection.abort(self.id);
+        }
+    }
+}
+
+/// Reserves a request on an open connection to the application server, or
+/// on a new one if all are busy or `fresh` is set. Also returns whether an
+/// existing connection was used.
+async fn acquire(
+    address: &Address,
+    key: &str,
+    time_limit: Duration,
+    fresh: bool,
+) -> io::Result<(Pending, bool)> {
+    if !fresh {
+        let mut pool = POOL.lock().unwrap();
+        let connections = pool.entry(key.to_string()).or_default();
+        connections.retain(|connection| !connection.closed.load(Ordering::Relaxed));
+        for connection in connections.iter() {
+            if let Some((id, records)) = connection.register() {
+                let pending = Pending {
+                    connection: connection.clone(),
+                    id,
+                    records,
+                    ended: false,
+                };
+                return Ok((pending, true));
+            }
+        }
+    }
+
+    let connection = Connection::open(address, key.to_string(), time_limit).await?;
+    let (id, records) = connection
+        .register()
+        .ok_or_else(|| io::Error::from(io::ErrorKind::ConnectionAborted))?;
+    POOL.lock()
+        .unwrap()
+        .entry(key.to_string())
+        .or_default()
+        .push(connection.clone());
+    let pending = Pending {
+        connection,
+        id,
+        records,
+        ended: false,
+    };
+    Ok((pending, false))
+}
+
+/// Sends the request on the connection and waits for the first record of the
+/// response. Returns `None` if the connection was closed before that.
+async fn send(
+    pending: &mut Pending,
+    request: &Request<'_>,
+    time_limit: Duration,
+) -> io::Result<Option<Record>> {
+    let records = encode_request(request, pending.id);
+    let written = {
+        let mut writer = pending.connection.writer.lock().await;
+        timeout(time_limit, writer.write_all(&records)).await
+    };
+    match written {
+        Ok(Ok(())) => (),
+        Ok(Err(_)) => {
+            pending.connection.close();
+            return Ok(None);
+        }
+        Err(_) => return Err(io::ErrorKind::TimedOut.into()),
+    }
+    timeout(time_limit, pending.records.recv())
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))
+}
+
+/// Sends the request to the FastCGI application server at the given address
+/// and returns a channel that will receive its response in chunks.
+///
+/// Connections are kept open and reused for later requests, and several
+/// requests are sent on one connection at once if the application server
+/// supports it. Connecting, sending the request and every read of the
+/// response must finish within `time_limit`.
+pub(crate) async fn request(
+    address: &Address,
+    request: &Request<'_>,
+    time_limit: Duration,
+) -> io::Result<cgi::Output> {
+    let key = address.to_string();
+
+    let (mut pending, reused) = acquire(address, &key, time_limit, false).await?;
+    let first = match send(&mut pending, request, time_limit).await? {
+        Some(first) => first,
+        // an open connection might have been closed by the application
+        // server in the meantime, so try once more on a new one
+        None if reused => {
+            let (mut pending_again, _) = acquire(address, &key, time_limit, true).await?;
+            let first = send(&mut pending_again, request, time_limit).await?;
+            pending = pending_again;
+            first.ok_or_else(closed)?
+        }
+        None => return Err(closed()),
+    };
+
+    let (tx, rx) = mpsc::channel(4);
+    tokio::spawn(async move {
+        if let Err(e) = read_response(&mut pending, first, &tx, time_limit).await {
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    Ok(rx)
+}
+
+fn closed() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the application server closed the connection",
+    )
+}
+
+/// Handles the records of a response, starting with `first`, and sends the
+/// content of `STDOUT` records on the channel until the request has ended.
+async fn read_response(
+    pending: &mut Pending,
+    first: Record,
+    tx: &mpsc::Sender<io::Result<Vec<u8>>>,
+    time_limit: Duration,
+) -> io::Result<()> {
+    let mut record = first;
+    loop {
+        match record.kind {
+            // an empty record ends the output stream
+            STDOUT if !record.content.is_empty() => {
+                let sent = tx.send(Ok(record.content)).await;
+                if sent.is_err() {
+                    // the client went away, so the request is aborted when
+                    // it is dropped
+                    return Ok(());
+                }
+            }
+            STDERR => {
+                log::warn!(
+                    "FastCGI application server: {}",
+                    String::from_utf8_lossy(&record.content).trim_end()
+                );
+            }
+            END_REQUEST => {
+                pending.ended = true;
+                return match record.content.get(4) {
+                    Some(&REQUEST_COMPLETE) => Ok(()),
+                    Some(1) => Err(io::Error::other(
+                        "the application server can not handle several requests on one connection",
+                    )),
+                    Some(2) => Err(io::Error::other("the application server is overloaded")),
+                    Some(3) => Err(io::Error::other(
+                        "the application server does not support the responder role",
+                    )),
+                    _ => Err(io::Error::other(
+                        "the application server refused the request",
+                    )),
+                };
+            }
+            // ignore unknown records
+            _ => (),
+        }
+        record = timeout(time_limit, pending.records.recv())
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+            .ok_or_else(closed)?;
+    }
+}
@@ -0,0 +1,266 @@
+use crate::blog::FrontMatter;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// A feed of the gemtext posts in a directory.
+#[derive(Debug, Clone)]
+pub(crate) struct Feed {
+    /// The URL path the feed is served at.
+    pub path: String,
+    /// The URL path of the directory with the posts, with a trailing slash.
+    pub dir: String,
+    pub format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    Atom,
+    Rss,
+    /// A gemtext page for Gemini feed readers, following the subscription
+    /// companion specification.
+    Gemini,
+}
+
+impl Format {
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Atom => "application/atom+xml",
+            Self::Rss => "application/rss+xml",
+            Self::Gemini => "text/gemini",
+        }
+    }
+}
+
+impl FromStr for Feed {
+    type Err = String;
+
+    /// Parses a feed in the form `PATH=DIR`. The feed is a gemtext page if
+    /// the path ends in `.gmi`, an RSS feed if the file name in the path
+    /// contains `rss`, e.g. `/rss.xml`, and an Atom feed otherwise.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (path, dir) = s
+            .split_once('=')
+            .ok_or_else(|| format!("feed {s:?} is not in the form PATH=DIR"))?;
+        if !path.starts_with('/') || path.ends_with('/') {
+            return Err(format!(
+                "feed path {path:?} has to start with / and must not end with /"
+            ));
+        }
+        if !dir.starts_with('/') || crate::sanitize::segments(dir).is_err() {
+            return Err(format!(
+                "feed directory {dir:?} does not start with / or is invalid"
+            ));
+        }
+        let name = path.rsplit('/').next().unwrap_or_default();
+        Ok(Self {
+            path: path.to_string(),
+            dir: format!("{}/", dir.trim_end_matches('/')),
+            format: if name.ends_with(".gmi") {
+                Format::Gemini
+            } else if name.to_ascii_lowercase().contains("rss") {
+                Format::Rss
+            } else {
+                Format::Atom
+            },
+        })
+    }
+}
+
+/// A gemtext file in a directory of posts.
+#[derive(Debug)]
+pub(crate) struct Post {
+    pub name: String,
+    /// The title from the front matter, the first heading of the file, or
+    /// the file name.
+    pub title: String,
+    /// The date from the front matter, the date at the start of the file
+    /// name, or the modification time.
+    pub date: SystemTime,
+    /// The tags from the front matter.
+    pub tags: Vec<String>,
+}
+
+/// Returns the date of a file name that starts with a date like
+/// `2024-01-31`.
+fn date_prefix(name: &str) -> Option<SystemTime> {
+    let date = name.get(..10)?;
+    humantime::parse_rfc3339(&format!("{date}T00:00:00Z")).ok()
+}
+
+/// Returns the text of the first heading of a gemtext file.
+pub(crate) fn title(content: &str) -> Option<&str> {
+    content
+        .lines()
+        .find(|line| line.starts_with('#'))
+        .map(|line| line.trim_start_matches('#').trim())
+        .filter(|title| !title.is_empty())
+}
+
+/// Reads the title of the directory from its index file, if there is one.
+pub(crate) fn dir_title(dir: &Path, index_files: &[String]) -> Option<String> {
+    index_files.iter().find_map(|name| {
+        let content = std::fs::read_to_string(dir.join(name)).ok()?;
+        title(&content).map(str::to_string)
+    })
+}
+
+/// Reads the gemtext files in the directory, except for index files,
+/// unlisted files and drafts, newest first.
+pub(crate) fn posts(dir: &Path, index_files: &[String]) -> std::io::Result<Vec<Post>> {
+    let mut posts = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let Ok(name) = entry.file_name().into_string() else {
+            continue;
+        };
+        if !name.ends_with(".gmi")
+            || index_files.contains(&name)
+            || !crate::ARGS.hidden_files.listed(&name)
+        {
+            continue;
+        }
+        let metadata = std::fs::metadata(entry.path())?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let content = std::fs::read_to_string(entry.path()).unwrap_or_default();
+        let (front_matter, content) = FrontMatter::split(&content);
+        let front_matter = front_matter.unwrap_or_default();
+        if front_matter.draft {
+            continue;
+        }
+        let date = front_matter
+            .date
+            .or_else(|| date_prefix(&name))
+            .unwrap_or_else(|| metadata.modified().unwrap_or(UNIX_EPOCH));
+        posts.push(Post {
+            title: front_matter
+                .title
+                .unwrap_or_else(|| title(content).unwrap_or(&name).to_string()),
+            name,
+            date,
+            tags: front_matter.tags,
+        });
+    }
+    posts.sort_by(|a, b| b.date.cmp(&a.date).then_with(|| b.name.cmp(&a.name)));
+    Ok(posts)
+}
+
+/// Generates the feed with the title for the posts in the directory at
+/// `dir_url`, which is served at `feed_url`.
+pub(crate) fn generate(
+    format: Format,
+    dir_url: &Url,
+    feed_url: &Url,
+    title: &str,
+    posts: &[Post],
+) -> String {
+    let updated = posts
+        .iter()
+        .map(|post| post.date)
+        .max()
+        .unwrap_or_else(SystemTime::now);
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    match format {
+        Format::Atom => {
+            xml += "<feed xmlns=\"http://www.w3.org/2005/Atom\">\n";
+            xml += &format!("  <id>{}</id>\n", escape(dir_url.as_str()));
+            xml += &format!("  <title>{}</title>\n", escape(title));
+            xml += &format!("  <updated>{}</updated>\n", rfc3339(updated));
+            xml += &format!(
+                "  <author><name>{}</name></author>\n",
+                escape(dir_url.host_str().unwrap_or_default())
+            );
+            xml += &format!("  <link href=\"{}\"/>\n", escape(dir_url.as_str()));
+            xml += &format!(
+                "  <link rel=\"self\" href=\"{}\"/>\n",
+                escape(feed_url.as_str())
+            );
+            for post in posts {
+                let Ok(url) = dir_url.join(&post.name) else {
+                    continue;
+                };
+                xml += "  <entry>\n";
+                xml += &format!("    <id>{}</id>\n", escape(url.as_str()));
+                xml += &format!("    <title>{}</title>\n", escape(&post.title));
+                xml += &format!("    <updated>{}</updated>\n", rfc3339(post.date));
+                xml += &format!("    <link href=\"{}\"/>\n", escape(url.as_str()));
+                xml += "  </entry>\n";
+            }
+            xml += "</feed>\n";
+        }
+        Format::Rss => {
+            xml += "<rss version=\"2.0\">\n<channel>\n";
+            xml += &format!("  <title>{}</title>\n", escape(title));
+            xml += &format!("  <link>{}</link>\n", escape(dir_url.as_str()));
+            xml += &format!("  <description>{}</description>\n", escape(title));
+            xml += &format!("  <lastBuildDate>{}</lastBuildDate>\n", rfc822(updated));
+            for post in posts {
+                let Ok(url) = dir_url.join(&post.name) else {
+                    continue;
+                };
+                xml += "  <item>\n";
+                xml += &format!("    <title>{}</title>\n", escape(&post.title));
+                xml += &format!("    <link>{}</link>\n", escape(url.as_str()));
+                xml += &format!("    <guid>{}</guid>\n", escape(url.as_str()));
+                xml += &format!("    <pubDate>{}</pubDate>\n", rfc822(post.date));
+                xml += "  </item>\n";
+            }
+            xml += "</channel>\n</rss>\n";
+        }
+        Format::Gemini => return gemtext(dir_url, title, posts),
+    }
+    xml
+}
+
+/// Generates a gemtext page with the title as heading and a link line for
+/// each post, whose text starts with the date of the post.
+fn gemtext(dir_url: &Url, title: &str, posts: &[Post]) -> String {
+    let mut page = format!("# {title}\n\n");
+    for post in posts {
+        let Ok(url) = dir_url.join(&post.name) else {
+            continue;
+        };
+        // line breaks would end the link line
+        let text = post.title.replace(char::is_control, " ");
+        page += &format!("=> {url} {} - {text}\n", &rfc3339(post.date)[..10]);
+    }
+    page
+}
+
+/// Escapes the characters that have a special meaning in XML.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn rfc3339(time: SystemTime) -> String {
+    humantime::format_rfc3339_seconds(time).to_string()
+}
+
+/// Formats the time like `Wed, 31 Jan 2024 12:00:00 +0000` for RSS.
+fn rfc822(time: SystemTime) -> String {
+    const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs();
+    // the day of the week follows from the days since 1970-01-01, which was a
+    // Thursday; the date is taken from the RFC 3339 format
+    let weekday = WEEKDAYS[(secs / 86400 % 7) as usize];
+    let date = rfc3339(UNIX_EPOCH + Duration::from_secs(secs));
+    let month = MONTHS[date[5..7].parse::<usize>().unwrap_or(1) - 1];
+    format!(
+        "{weekday}, {} {month} {} {} +0000",
+        &date[8..10],
+        &date[..4],
+        &date[11..19]
+    )
+}
@@ -0,0 +1,104 @@
+use {std::fmt::Write, url::Url};
+
+/// The default port of Gopher servers.
+pub(crate) const DEFAULT_PORT: u16 = 70;
+
+/// The media type of Gopher menus, as used by Gopher+. Responses do not have
+/// a header, so it is only logged.
+pub(crate) const MENU_MIME: &str = "application/gopher-menu";
+
+/// Returns the Gopher item type for a file with the given MIME media type.
+pub(crate) fn item_type(mime: &str) -> char {
+    match mime {
+        "text/gemini" => '1',
+        "text/html" => 'h',
+        "image/gif" => 'g',
+        _ if mime.starts_with("text/") => '0',
+        _ if mime.starts_with("image/") => 'I',
+        _ => '9',
+    }
+}
+
+/// Appends a menu line to the given menu. Tabs and line breaks are removed
+/// from the display string because they would break the format.
+pub(crate) fn menu_line(
+    menu: &mut String,
+    item_type: char,
+    display: &str,
+    selector: &str,
+    host: &str,
+    port: u16,
+) {
+    let display = display.replace(['\t', '\r', '\n'], " ");
+    write!(menu, "{item_type}{display}\t{selector}\t{host}\t{port}\r\n").unwrap();
+}
+
+/// Appends an informational line, which is not a link, to the given menu.
+pub(crate) fn info_line(menu: &mut String, text: &str) {
+    menu_line(menu, 'i', text, "", "error.host", 1);
+}
+
+/// Returns a menu that only contains the given error message.
+pub(crate) fn error(message: &str) -> String {
+    let mut menu = String::new();
+    menu_line(&mut menu, '3', message, "", "error.host", 1);
+    menu.push_str(".\r\n");
+    menu
+}
+
+/// Converts a gemtext document to a Gopher menu. Text lines become
+/// informational lines, links to the same server become menu items and
+/// other links become `h` items with a `URL:` selector.
+///
+/// `url` is the Gopher URL of the document and is used to resolve relative
+/// links. `guess_mime` guesses the MIME media type from a link path, to
+/// determine the item type.
+pub(crate) fn convert_gemtext(
+    gemtext: &str,
+    url: &Url,
+    guess_mime: impl Fn(&str) -> String,
+) -> String {
+    let host = url.host_str().unwrap_or_default();
+    let port = url.port().unwrap_or(DEFAULT_PORT);
+
+    let mut menu = String::new();
+    let mut preformatted = false;
+    for line in gemtext.lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+        let link = if preformatted {
+            None
+        } else {
+            line.strip_prefix("=>")
+        };
+        let Some(link) = link else {
+            info_line(&mut menu, line);
+            continue;
+        };
+
+        let link = link.trim();
+        let (target, label) = link
+            .split_once(char::is_whitespace)
+            .map_or((link, link), |(target, label)| (target, label.trim()));
+        match url.join(target) {
+            Ok(target)
+                if matches!(target.scheme(), "gopher" | "gemini")
+                    && target.host_str() == Some(host) =>
+            {
+                let item_type = if target.path().ends_with('/') {
+                    '1'
+                } else {
+                    item_type(&guess_mime(target.path()))
+                };
+                menu_line(&mut menu, item_type, label, target.path(), host, port);
+            }
+            Ok(target) => menu_line(&mut menu, 'h', label, &format!("URL:{target}"), host, port),
+            // the link is invalid, only keep the label
+            Err(_) => info_line(&mut menu, label),
+        }
+    }
+    menu.push_str(".\r\n");
+    menu
+}
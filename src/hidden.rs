@@ -0,0 +1,78 @@
+use glob::Pattern;
+use std::str::FromStr;
+
+/// What happens to hidden files, i.e. files and directories whose name
+/// starts with a dot or matches one of the patterns given with `--hide`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Mode {
+    /// Hidden files are served and listed like any other file.
+    Serve,
+    /// Hidden files are served, but not listed.
+    Unlisted,
+    /// Requests for hidden files are answered with status 52, unless there
+    /// is an entry for them in a `.meta` file, and they are not listed.
+    Hide,
+    /// Requests for hidden files are always answered with status 51 and they
+    /// are not listed.
+    Deny,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "serve" => Ok(Self::Serve),
+            "unlisted" => Ok(Self::Unlisted),
+            "hide" => Ok(Self::Hide),
+            "deny" => Ok(Self::Deny),
+            _ => Err(format!(
+                "unknown mode {s:?} for hidden files, expected serve, unlisted, hide or deny"
+            )),
+        }
+    }
+}
+
+/// The policy for hidden files.
+#[derive(Debug)]
+pub(crate) struct HiddenFiles {
+    pub mode: Mode,
+    /// Patterns for names of files and directories that are hidden although
+    /// they do not start with a dot.
+    patterns: Vec<Pattern>,
+}
+
+impl HiddenFiles {
+    /// Parses the patterns, where a trailing slash is ignored, so `.git/`
+    /// hides a directory named `.git` and everything in it.
+    pub fn new(mode: Mode, patterns: &[String]) -> Result<Self, String> {
+        let patterns = patterns
+            .iter()
+            .map(|pattern| {
+                Pattern::new(pattern.trim_end_matches('/'))
+                    .map_err(|e| format!("invalid pattern {pattern:?} for hidden files: {e}"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { mode, patterns })
+    }
+
+    /// Returns whether the file or directory with the given name is hidden.
+    pub fn is_hidden(&self, name: &str) -> bool {
+        name.starts_with('.') || self.patterns.iter().any(|pattern| pattern.matches(name))
+    }
+
+    /// Returns whether one of the decoded URL path segments is hidden.
+    pub fn in_path(&self, segments: &[String]) -> bool {
+        segments.iter().any(|segment| self.is_hidden(segment))
+    }
+
+    /// Returns whether hidden files are served without further checks.
+    pub fn served(&self) -> bool {
+        matches!(self.mode, Mode::Serve | Mode::Unlisted)
+    }
+
+    /// Returns whether the file or directory with the given name is listed.
+    pub fn listed(&self, name: &str) -> bool {
+        self.mode == Mode::Serve || !self.is_hidden(name)
+    }
+}
@@ -0,0 +1,263 @@
+use {
+    crate::{
+        cgi,
+        codes::*,
+        sitemap::ENCODE_SET,
+        timeout::{self, IdleTimeout},
+    },
+    percent_encoding::utf8_percent_encode,
+    std::{io, str::FromStr, time::Duration},
+    tokio::{
+        io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::TcpStream,
+        sync::mpsc,
+    },
+    url::Url,
+};
+
+/// The maximum size of the status line and headers of an HTTP response.
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+
+/// A URL path prefix that is handled by an HTTP backend.
+#[derive(Debug, Clone)]
+pub(crate) struct Route {
+    pub prefix: String,
+    /// The URL the prefix is mapped to. The path always ends with a slash.
+    pub backend: Url,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Parses a route in the form `PREFIX=URL`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((prefix, backend)) = s.split_once('=') else {
+            return Err(format!(
+                "invalid HTTP gateway route {s:?}, expected PREFIX=URL"
+            ));
+        };
+        let mut backend =
+            Url::parse(backend).map_err(|e| format!("invalid backend URL {backend:?}: {e}"))?;
+        if backend.scheme() != "http" || backend.host_str().is_none() {
+            return Err(format!("backend URL {backend} must be an http:// URL"));
+        }
+        if !backend.path().ends_with('/') {
+            backend.set_path(&format!("{}/", backend.path()));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            backend,
+        })
+    }
+}
+
+impl Route {
+    /// Returns the backend URL for the requested Gemini URL, given its
+    /// normalized path the route was matched against.
+    fn backend_url(&self, url: &Url, url_path: &str) -> Url {
+        let rest = url_path
+            .strip_prefix(self.prefix.as_str())
+            .unwrap_or_default()
+            .trim_start_matches('/');
+        let rest = utf8_percent_encode(rest, ENCODE_SET);
+        let mut backend = self.backend.clone();
+        backend.set_path(&format!("{}{rest}", self.backend.path()));
+        backend.set_query(url.query());
+        backend
+    }
+
+    /// Maps a URL of the backend back to the Gemini URL it is available at,
+    /// if it is handled by this route.
+    fn gemini_url(&self, backend: &Url, url: &Url) -> Option<Url> {
+        if backend.origin() != self.backend.origin() {
+            return None;
+        }
+        let rest = backend.path().strip_prefix(self.backend.path())?;
+        let mut gemini = url.clone();
+        let prefix = self.prefix.trim_end_matches('/');
+        gemini.set_path(&format!("{prefix}/{rest}"));
+        gemini.set_query(backend.query());
+        Some(gemini)
+    }
+}
+
+/// Translates an HTTP status code to a Gemini status code.
+fn status(code: u16) -> u8 {
+    match code {
+        200..=299 => SUCCESS,
+        301 | 308 => REDIRECT_PERMANENT,
+        300..=399 => REDIRECT_TEMPORARY,
+        400 => BAD_REQUEST,
+        401 => CLIENT_CERTIFICATE_REQUIRED,
+        403 => CERTIFICATE_NOT_AUTHORISED,
+        404 => NOT_FOUND,
+        410 => GONE,
+        _ if code < 500 => PERMANENT_FAILURE,
+        _ => PROXY_ERROR,
+    }
+}
+
+/// Sends the request to the HTTP backend of the route and returns a channel
+/// that will receive a Gemini response in chunks, translated from the HTTP
+/// response. The fingerprint of the client certificate is sent in the
+/// `X-Gemini-Client-Hash` header. The request fails if the backend stalls for
+/// `time_limit`.
+pub(crate) async fn request(
+    route: &Route,
+    url: &Url,
+    url_path: &str,
+    client_cert: Option<&str>,
+    time_limit: Duration,
+) -> io::Result<cgi::Output> {
+    let backend = route.backend_url(url, url_path);
+    let host = backend.host_str().expect("backend URL has no host");
+    let port = backend.port_or_known_default().unwrap_or(80);
+
+    let mut request = format!("GET {}", backend.path());
+    if let Some(query) = backend.query() {
+        request.push('?');
+        request.push_str(query);
+    }
+    request.push_str(" HTTP/1.1\r\nHost: ");
+    request.push_str(host);
+    if let Some(port) = backend.port() {
+        request.push_str(&format!(":{port}"));
+    }
+    request.push_str(concat!(
+        "\r\nUser-Agent: agate/",
+        env!("CARGO_PKG_VERSION"),
+        "\r\nAccept: */*\r\nConnection: close\r\n"
+    ));
+    if let Some(fingerprint) = client_cert {
+        request.push_str(&format!("X-Gemini-Client-Hash: {fingerprint}\r\n"));
+    }
+    request.push_str("\r\n");
+
+    let stream = timeout::limit(time_limit, TcpStream::connect((host, port))).await?;
+    let mut stream = IdleTimeout::new(stream, time_limit, time_limit);
+    stream.write_all(request.as_bytes()).await?;
+    let mut reader = BufReader::new(stream);
+
+    // read the status line and headers
+    let mut head = String::new();
+    loop {
+        let len = (&mut reader)
+            .take(MAX_HEADER_SIZE - head.len() as u64)
+            .read_line(&mut head)
+            .await?;
+        if len == 0 {
+            return Err(invalid_data("incomplete HTTP response header"));
+        } else if head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+            break;
+        }
+    }
+    let mut lines = head.lines();
+    let code = lines
+        .next()
+        .and_then(|line| line.split(' ').nth(1))
+        .and_then(|code| code.parse::<u16>().ok())
+        .ok_or_else(|| invalid_data("invalid HTTP status line"))?;
+    let mut content_type = None;
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut location = None;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-type" => content_type = Some(value.to_string()),
+            "content-length" => content_length = value.parse::<u64>().ok(),
+            "transfer-encoding" => chunked = value.eq_ignore_ascii_case("chunked"),
+            "location" => location = Some(value.to_string()),
+            _ => (),
+        }
+    }
+
+    let status = status(code);
+    let header = match status {
+        SUCCESS => format!(
+            "{status} {}\r\n",
+            content_type
+                .as_deref()
+                .unwrap_or("application/octet-stream")
+        ),
+        REDIRECT_TEMPORARY | REDIRECT_PERMANENT => {
+            let target = location
+                .and_then(|location| backend.join(&location).ok())
+                .ok_or_else(|| invalid_data("HTTP redirect without valid location"))?;
+            match route.gemini_url(&target, url) {
+                Some(gemini) => format!("{status} {gemini}\r\n"),
+                None => format!("{status} {target}\r\n"),
+            }
+        }
+        _ => format!("{status} HTTP status {code}\r\n"),
+    };
+
+    let (tx, rx) = mpsc::channel(4);
+    tx.send(Ok(header.into_bytes())).await.ok();
+    // only successful responses have a body in Gemini
+    if status == SUCCESS {
+        tokio::spawn(async move {
+            let result = if chunked {
+                forward_chunked(&mut reader, &tx).await
+            } else if let Some(len) = content_length {
+                forward_body(&mut reader.take(len), &tx).await
+            } else {
+                forward_body(&mut reader, &tx).await
+            };
+            if let Err(e) = result {
+                let _ = tx.send(Err(e)).await;
+            }
+        });
+    }
+    Ok(rx)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Sends everything read from the reader on the channel.
+async fn forward_body<R>(reader: &mut R, tx: &mpsc::Sender<io::Result<Vec<u8>>>) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let mut buf = vec![0; 8192];
+        let len = reader.read(&mut buf).await?;
+        if len == 0 {
+            return Ok(());
+        }
+        buf.truncate(len);
+        if tx.send(Ok(buf)).await.is_err() {
+            // the connection was closed
+            return Ok(());
+        }
+    }
+}
+
+/// Decodes a body with chunked transfer encoding and sends the content on
+/// the channel.
+async fn forward_chunked<R>(
+    reader: &mut BufReader<R>,
+    tx: &mpsc::Sender<io::Result<Vec<u8>>>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line).await?;
+        // ignore chunk extensions
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = u64::from_str_radix(size, 16).map_err(|_| invalid_data("invalid chunk size"))?;
+        if size == 0 {
+            return Ok(());
+        }
+        forward_body(&mut (&mut *reader).take(size), tx).await?;
+        // the chunk is followed by a line break
+        reader.read_line(&mut line).await?;
+    }
+}
@@ -0,0 +1,131 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// How deep included files may include further files.
+const MAX_DEPTH: usize = 8;
+
+/// The start and end of a line that includes another file, with the path of
+/// the file between them.
+static DIRECTIVE: (&str, &str) = ("<!--#include file=\"", "\"-->");
+
+/// The content of an included file and its modification time.
+type Cached = (SystemTime, Vec<u8>);
+
+/// The included files that were read.
+static CACHE: Lazy<Mutex<HashMap<PathBuf, Cached>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Reads the gemtext file and replaces each line of the form
+/// ```text
+/// <!--#include file="nav.gmi"-->
+/// ```
+/// with the content of the named file, which may include further files. The
+/// path is relative to the directory of the file that includes it and has to
+/// stay inside `root`, or inside the directory of the file if it is not in
+/// `root`. Includes that can not be resolved are logged and left out, so the
+/// page itself is still served.
+pub(crate) async fn expand(root: &Path, file: &Path) -> std::io::Result<Vec<u8>> {
+    let root = match file.parent() {
+        Some(dir) if !file.starts_with(root) => dir.to_path_buf(),
+        _ => root.to_path_buf(),
+    };
+    let file = file.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let content = std::fs::read(&file)?;
+        let mut output = Vec::with_capacity(content.len());
+        expand_into(&root, &file, &content, &mut vec![file.clone()], &mut output);
+        Ok(output)
+    })
+    .await?
+}
+
+fn expand_into(
+    root: &Path,
+    file: &Path,
+    content: &[u8],
+    stack: &mut Vec<PathBuf>,
+    output: &mut Vec<u8>,
+) {
+    for line in content.split_inclusive(|&b| b == b'\n') {
+        let Some(name) = directive(line) else {
+            output.extend_from_slice(line);
+            continue;
+        };
+        let Some(included) = resolve(root, file, name) else {
+            log::error!("{}: invalid include {:?}", file.display(), name);
+            continue;
+        };
+        if stack.len() > MAX_DEPTH || stack.contains(&included) {
+            log::error!(
+                "{}: not including {} more than {MAX_DEPTH} levels deep or recursively",
+                file.display(),
+                included.display()
+            );
+            continue;
+        }
+        if !crate::ARGS.symlinks.allows(root, &included) {
+            log::warn!("{}: not including {}", file.display(), included.display());
+            continue;
+        }
+        match read_cached(&included) {
+            Ok(content) => {
+                stack.push(included.clone());
+                expand_into(root, &included, &content, stack, output);
+                stack.pop();
+            }
+            Err(e) => log::error!(
+                "{}: could not include {}: {e}",
+                file.display(),
+                included.display()
+            ),
+        }
+    }
+}
+
+/// Returns the path in an include directive, if the line is one.
+fn directive(line: &[u8]) -> Option<&str> {
+    std::str::from_utf8(line)
+        .ok()?
+        .trim()
+        .strip_prefix(DIRECTIVE.0)?
+        .strip_suffix(DIRECTIVE.1)
+}
+
+/// Resolves the path of an included file relative to the directory of the
+/// file that includes it, without leaving `root`.
+fn resolve(root: &Path, file: &Path, name: &str) -> Option<PathBuf> {
+    let mut path = file.parent()?.to_path_buf();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(name) => path.push(name),
+            Component::CurDir => (),
+            Component::ParentDir if path != root => {
+                path.pop();
+            }
+            _ => return None,
+        }
+    }
+    path.starts_with(root).then_some(path)
+}
+
+/// Reads an included file, which is only read again if it was modified.
+fn read_cached(file: &Path) -> std::io::Result<Vec<u8>> {
+    let modified = std::fs::metadata(file)?.modified().ok();
+    if let Some(modified) = modified {
+        if let Some((read, content)) = CACHE.lock().unwrap().get(file) {
+            if *read == modified {
+                return Ok(content.clone());
+            }
+        }
+    }
+    let content = std::fs::read(file)?;
+    if let Some(modified) = modified {
+        CACHE
+            .lock()
+            .unwrap()
+            .insert(file.to_path_buf(), (modified, content.clone()));
+    }
+    Ok(content)
+}
@@ -0,0 +1,340 @@
+//! Decompression of DEFLATE streams (RFC 1951) and gzip files (RFC 1952),
+//! which are used by compressed archives.
+
+/// The longest Huffman code.
+const MAX_BITS: usize = 15;
+
+/// Base lengths and extra bits of the length symbols 257 to 285.
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+
+/// Base distances and extra bits of the distance symbols 0 to 29.
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+
+/// The order in which the lengths of the code length codes are stored.
+const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+type Result<T> = std::result::Result<T, &'static str>;
+
+/// Reads bits from the input, least significant bit first.
+struct Bits<'a> {
+    input: &'a [u8],
+    pos: usize,
+    buffer: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(input: &'a [u8]) -> Self {
+        Self {
+            input,
+            pos: 0,
+            buffer: 0,
+            count: 0,
+        }
+    }
+
+    fn bits(&mut self, n: u32) -> Result<u32> {
+        while self.count < n {
+            let byte = *self.input.get(self.pos).ok_or("unexpected end of data")?;
+            self.pos += 1;
+            self.buffer |= u32::from(byte) << self.count;
+            self.count += 8;
+        }
+        let value = self.buffer & ((1 << n) - 1);
+        self.buffer >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Skips the bits up to the next byte boundary.
+    fn align(&mut self) {
+        self.buffer = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code, given by the number of codes of each length and
+/// the symbols ordered by their code.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    /// Builds the code from the code length of each symbol, where 0 means
+    /// that the symbol is not used. Incomplete codes are allowed, since a
+    /// single distance code is valid.
+    fn new(lengths: &[u8]) -> Result<Self> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &length in lengths {
+            counts[usize::from(length)] += 1;
+        }
+        counts[0] = 0;
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - i32::from(count);
+            if left < 0 {
+                return Err("over-subscribed Huffman code");
+            }
+        }
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for length in 1..=MAX_BITS {
+            offsets[length + 1] = offsets[length] + counts[length];
+        }
+        let mut symbols = vec![0; usize::from(offsets[MAX_BITS + 1])];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                let offset = &mut offsets[usize::from(length)];
+                symbols[usize::from(*offset)] = symbol as u16;
+                *offset += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for &count in &self.counts[1..] {
+            code |= bits.bits(1)? as i32;
+            let count = i32::from(count);
+            if code - count < first {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err("invalid Huffman code")
+    }
+}
+
+/// Decompresses a raw DEFLATE stream. Fails if the output would be larger
+/// than `limit` bytes, so a small archive can not use up the memory.
+pub(crate) fn inflate(input: &[u8], limit: usize) -> Result<Vec<u8>> {
+    inflate_bits(&mut Bits::new(input), limit)
+}
+
+/// Decompresses the DEFLATE stream at the start of the input. Afterwards, the
+/// position of the reader is the byte after the stream.
+fn inflate_bits(bits: &mut Bits, limit: usize) -> Result<Vec<u8>> {
+    let input = bits.input;
+    let mut output = vec![];
+    loop {
+        let last = bits.bits(1)? == 1;
+        match bits.bits(2)? {
+            0 => {
+                bits.align();
+                let header = input
+                    .get(bits.pos..bits.pos + 4)
+                    .ok_or("unexpected end of data")?;
+                let len = usize::from(u16::from_le_bytes([header[0], header[1]]));
+                if len != usize::from(!u16::from_le_bytes([header[2], header[3]])) {
+                    return Err("invalid length of stored block");
+                }
+                bits.pos += 4;
+                let data = input
+                    .get(bits.pos..bits.pos + len)
+                    .ok_or("unexpected end of data")?;
+                if output.len() + len > limit {
+                    return Err("decompressed data too large");
+                }
+                output.extend_from_slice(data);
+                bits.pos += len;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let literals = Huffman::new(&lengths)?;
+                let distances = Huffman::new(&[5; 30])?;
+                codes(bits, &literals, &distances, &mut output, limit)?;
+            }
+            2 => {
+                let (literals, distances) = dynamic_codes(bits)?;
+                codes(bits, &literals, &distances, &mut output, limit)?;
+            }
+            _ => return Err("invalid block type"),
+        }
+        if last {
+            return Ok(output);
+        }
+    }
+}
+
+/// Reads the Huffman codes of a block with dynamic codes.
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman)> {
+    let literals = bits.bits(5)? as usize + 257;
+    let distances = bits.bits(5)? as usize + 1;
+    let code_lengths = bits.bits(4)? as usize + 4;
+    if literals > 286 || distances > 30 {
+        return Err("too many codes");
+    }
+    let mut lengths = [0u8; 19];
+    for &symbol in &CODE_LENGTH_ORDER[..code_lengths] {
+        lengths[symbol] = bits.bits(3)? as u8;
+    }
+    let code_length_code = Huffman::new(&lengths)?;
+
+    let mut lengths = vec![0u8; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code_length_code.decode(bits)?;
+        let (length, repeat) = match symbol {
+            0..=15 => (symbol as u8, 1),
+            16 => {
+                let previous = *lengths[..i].last().ok_or("repeat without length")?;
+                (previous, 3 + bits.bits(2)? as usize)
+            }
+            17 => (0, 3 + bits.bits(3)? as usize),
+            _ => (0, 11 + bits.bits(7)? as usize),
+        };
+        if i + repeat > lengths.len() {
+            return Err("too many code lengths");
+        }
+        lengths[i..i + repeat].fill(length);
+        i += repeat;
+    }
+    if lengths[256] == 0 {
+        return Err("missing end of block code");
+    }
+    Ok((
+        Huffman::new(&lengths[..literals])?,
+        Huffman::new(&lengths[literals..])?,
+    ))
+}
+
+/// Decodes the literals and back references of a compressed block.
+fn codes(
+    bits: &mut Bits,
+    literals: &Huffman,
+    distances: &Huffman,
+    output: &mut Vec<u8>,
+    limit: usize,
+) -> Result<()> {
+    loop {
+        let symbol = usize::from(literals.decode(bits)?);
+        if symbol < 256 {
+            if output.len() >= limit {
+                return Err("decompressed data too large");
+            }
+            output.push(symbol as u8);
+            continue;
+        }
+        if symbol == 256 {
+            return Ok(());
+        }
+        let symbol = symbol - 257;
+        if symbol >= LENGTH_BASE.len() {
+            return Err("invalid length symbol");
+        }
+        let length =
+            usize::from(LENGTH_BASE[symbol]) + bits.bits(u32::from(LENGTH_EXTRA[symbol]))? as usize;
+        let symbol = usize::from(distances.decode(bits)?);
+        if symbol >= DISTANCE_BASE.len() {
+            return Err("invalid distance symbol");
+        }
+        let distance = usize::from(DISTANCE_BASE[symbol])
+            + bits.bits(u32::from(DISTANCE_EXTRA[symbol]))? as usize;
+        if distance > output.len() {
+            return Err("distance too far back");
+        }
+        if output.len() + length > limit {
+            return Err("decompressed data too large");
+        }
+        // the referenced bytes may overlap with the copied ones
+        let start = output.len() - distance;
+        for i in 0..length {
+            output.push(output[start + i]);
+        }
+    }
+}
+
+/// Decompresses a gzip file, which may consist of several members.
+pub(crate) fn gunzip(mut input: &[u8], limit: usize) -> Result<Vec<u8>> {
+    const FHCRC: u8 = 2;
+    const FEXTRA: u8 = 4;
+    const FNAME: u8 = 8;
+    const FCOMMENT: u8 = 16;
+
+    let mut output = vec![];
+    while !input.is_empty() {
+        if input.len() < 18 || input[..3] != [0x1f, 0x8b, 8] {
+            return Err("not a gzip file");
+        }
+        let flags = input[3];
+        let mut pos = 10;
+        if flags & FEXTRA != 0 {
+            let len = input.get(pos..pos + 2).ok_or("unexpected end of data")?;
+            pos += 2 + usize::from(u16::from_le_bytes([len[0], len[1]]));
+        }
+        for flag in [FNAME, FCOMMENT] {
+            if flags & flag != 0 {
+                let rest = input.get(pos..).ok_or("unexpected end of data")?;
+                pos += rest
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or("unexpected end of data")?
+                    + 1;
+            }
+        }
+        if flags & FHCRC != 0 {
+            pos += 2;
+        }
+        let data = input.get(pos..).ok_or("unexpected end of data")?;
+        let mut bits = Bits::new(data);
+        let member = inflate_bits(&mut bits, limit - output.len())?;
+        let trailer = data
+            .get(bits.pos..bits.pos + 8)
+            .ok_or("unexpected end of data")?;
+        let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+        if crc != crc32(&member) {
+            return Err("CRC mismatch");
+        }
+        output.extend_from_slice(&member);
+        input = &data[bits.pos + 8..];
+    }
+    Ok(output)
+}
+
+/// The CRC-32 checksum used by gzip and zip.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    static TABLE: [u32; 256] = {
+        let mut table = [0; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    0xedb8_8320 ^ (crc >> 1)
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+    !data.iter().fold(!0, |crc, &byte| {
+        TABLE[usize::from(crc as u8 ^ byte)] ^ (crc >> 8)
+    })
+}
@@ -0,0 +1,40 @@
+use {
+    std::{
+        fmt::Write as _,
+        fs::{File, OpenOptions},
+        io::Write as _,
+        path::Path,
+        sync::Mutex,
+    },
+    tokio_rustls::rustls::KeyLog,
+};
+
+/// Writes the TLS secrets to a file in the NSS key log format, which e.g.
+/// Wireshark can use to decrypt the traffic.
+#[derive(Debug)]
+pub(crate) struct KeyLogFile(Mutex<File>);
+
+impl KeyLogFile {
+    /// Opens the file, new secrets are appended to it.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self(Mutex::new(file)))
+    }
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line = format!("{label} ");
+        for byte in client_random {
+            write!(line, "{byte:02x}").unwrap();
+        }
+        line.push(' ');
+        for byte in secret {
+            write!(line, "{byte:02x}").unwrap();
+        }
+        line.push('\n');
+        if let Err(e) = self.0.lock().unwrap().write_all(line.as_bytes()) {
+            log::warn!("could not write to the key log file: {e}");
+        }
+    }
+}
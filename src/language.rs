@@ -0,0 +1,55 @@
+/// Checks that a language code only consists of letters, digits and
+/// hyphens, like `de` or `en-GB`, so it can be used in file names.
+pub(crate) fn check(lang: String) -> Result<String, String> {
+    if !lang.is_empty()
+        && !lang.starts_with('-')
+        && lang.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    {
+        Ok(lang)
+    } else {
+        Err(format!("invalid language code {lang:?}"))
+    }
+}
+
+/// Returns the name of the variant of a file for a language, with the
+/// language code before the extension, e.g. `page.de.gmi` for `page.gmi`.
+fn suffixed(name: &str, lang: &str) -> String {
+    match name.rsplit_once('.') {
+        // a leading dot does not start an extension
+        Some((stem, ext)) if !stem.is_empty() => format!("{stem}.{lang}.{ext}"),
+        _ => format!("{name}.{lang}"),
+    }
+}
+
+/// Looks for a variant of a file that does not exist in one of the
+/// languages, in order of preference. For each language, a file with the
+/// language code before the extension in the same directory is preferred
+/// over the same path in a directory named like the language code, e.g.
+/// `page.de.gmi` over `de/page.gmi` for `page.gmi`.
+///
+/// `segments` are the decoded URL path segments of the file and `exists`
+/// checks if there is a file or directory for some segments. Returns the
+/// segments of the variant and its language.
+pub(crate) fn variant<'a>(
+    segments: &[String],
+    languages: &'a [String],
+    exists: impl Fn(&[String]) -> bool,
+) -> Option<(Vec<String>, &'a str)> {
+    if exists(segments) {
+        return None;
+    }
+    languages.iter().find_map(|lang| {
+        if let Some((name, dir)) = segments.split_last().filter(|(name, _)| !name.is_empty()) {
+            let mut variant = dir.to_vec();
+            variant.push(suffixed(name, lang));
+            if exists(&variant) {
+                return Some((variant, lang.as_str()));
+            }
+        }
+        let variant = std::iter::once(lang)
+            .chain(segments)
+            .cloned()
+            .collect::<Vec<_>>();
+        exists(&variant).then_some((variant, lang.as_str()))
+    })
+}
@@ -0,0 +1,10 @@
+//! The system calls Agate makes directly, wrapped in safe functions.
+//!
+//! The server itself forbids unsafe code, so the little that is necessary to
+//! use the operating system is kept in the `sys` module of this library.
+
+#![deny(unsafe_code)]
+
+#[allow(unsafe_code)]
+#[deny(clippy::undocumented_unsafe_blocks)]
+pub mod sys;
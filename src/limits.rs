@@ -0,0 +1,158 @@
+use {
+    std::{
+        collections::{BTreeMap, HashMap},
+        net::IpAddr,
+        sync::atomic::{AtomicU64, Ordering},
+        sync::Arc,
+        sync::Mutex,
+    },
+    tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore},
+};
+
+/// Limits the number of simultaneous connections, in total and per client IP
+/// address.
+pub(crate) struct ConnectionLimits {
+    /// Slots for all connections, if the total number is limited.
+    total: Option<Arc<Semaphore>>,
+    /// The maximum number of connections per IP address, if limited.
+    per_ip: Option<usize>,
+    /// The number of open connections per IP address.
+    connections: Mutex<HashMap<IpAddr, usize>>,
+    /// Connections that have not sent a complete request yet, in the order
+    /// they were accepted.
+    idle: Mutex<BTreeMap<u64, Arc<Notify>>>,
+    /// The number of the next idle connection.
+    next_idle: AtomicU64,
+}
+
+impl ConnectionLimits {
+    pub fn new(total: Option<usize>, per_ip: Option<usize>) -> Self {
+        Self {
+            total: total.map(|total| Arc::new(Semaphore::new(total))),
+            per_ip,
+            connections: Mutex::new(HashMap::new()),
+            idle: Mutex::new(BTreeMap::new()),
+            next_idle: AtomicU64::new(0),
+        }
+    }
+
+    /// Registers a new connection as idle until it sent a complete request.
+    pub fn idle(&'static self) -> Idle {
+        let id = self.next_idle.fetch_add(1, Ordering::Relaxed);
+        let shed = Arc::new(Notify::new());
+        self.idle.lock().unwrap().insert(id, shed.clone());
+        Idle {
+            limits: self,
+            id,
+            shed,
+        }
+    }
+
+    /// Closes up to the given number of the oldest idle connections, to free
+    /// resources for new ones. Returns how many are closed.
+    pub fn shed_idle(&self, count: usize) -> usize {
+        let mut idle = self.idle.lock().unwrap();
+        let mut shed = 0;
+        while shed < count {
+            let Some((_, connection)) = idle.pop_first() else {
+                break;
+            };
+            connection.notify_one();
+            shed += 1;
+        }
+        shed
+    }
+
+    /// Waits until another connection may be accepted, so connections over
+    /// the total limit are deferred. Returns a slot that has to be passed
+    /// to [`admit`](Self::admit) with the accepted connection.
+    pub async fn slot(&self) -> Slot {
+        let permit = match &self.total {
+            Some(total) => Some(
+                total
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("connection semaphore closed"),
+            ),
+            None => None,
+        };
+        Slot { _permit: permit }
+    }
+
+    /// Admits an accepted connection from the given IP address, unless the
+    /// address already has too many open connections. The returned guard
+    /// has to be kept until the connection is closed.
+    pub fn admit(&'static self, slot: Slot, ip: Option<IpAddr>) -> Option<Connection> {
+        let ip = match (self.per_ip, ip) {
+            (Some(max), Some(ip)) => {
+                let ip = ip.to_canonical();
+                let mut connections = self.connections.lock().unwrap();
+                let count = connections.entry(ip).or_default();
+                if *count >= max {
+                    return None;
+                }
+                *count += 1;
+                Some(ip)
+            }
+            _ => None,
+        };
+        Some(Connection {
+            limits: self,
+            ip,
+            _slot: slot,
+        })
+    }
+}
+
+/// A slot for a connection within the total limit.
+pub(crate) struct Slot {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// An admitted connection, which is counted until it is dropped.
+pub(crate) struct Connection {
+    limits: &'static ConnectionLimits,
+    ip: Option<IpAddr>,
+    _slot: Slot,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        if let Some(ip) = self.ip {
+            let mut connections = self.limits.connections.lock().unwrap();
+            if let Some(count) = connections.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// A connection that may be closed by [`shed_idle`](ConnectionLimits::shed_idle)
+/// until it is busy handling a request.
+pub(crate) struct Idle {
+    limits: &'static ConnectionLimits,
+    id: u64,
+    shed: Arc<Notify>,
+}
+
+impl Idle {
+    /// Marks the connection as busy, so it is not closed anymore.
+    pub fn busy(&self) {
+        self.limits.idle.lock().unwrap().remove(&self.id);
+    }
+
+    /// Waits until the connection should be closed.
+    pub async fn shed(&self) {
+        self.shed.notified().await;
+    }
+}
+
+impl Drop for Idle {
+    fn drop(&mut self) {
+        self.busy();
+    }
+}
@@ -0,0 +1,401 @@
+use {
+    log::{
+        kv::{self, Key, Value, VisitSource, VisitValue},
+        Record,
+    },
+    std::{
+        fmt::Write as _, fs::OpenOptions, io::Write as _, path::Path, str::FromStr,
+        time::SystemTime,
+    },
+};
+
+#[cfg(unix)]
+use {
+    log::Level,
+    std::{io, os::unix::net::UnixDatagram},
+};
+
+#[cfg(windows)]
+use {
+    log::Level,
+    std::{ffi::OsStr, io},
+    windows_sys::Win32::System::EventLog::{
+        EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+    },
+};
+
+/// The socket of the local syslog daemon.
+#[cfg(unix)]
+const SYSLOG_SOCKET: &str = "/dev/log";
+/// The socket for the native protocol of systemd-journald.
+#[cfg(unix)]
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// The format of log messages and access log lines.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, also known as NDJSON.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("invalid log format {s:?}, expected text or json")),
+        }
+    }
+}
+
+/// Where log messages are sent to.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum LogTarget {
+    /// The standard error stream.
+    #[default]
+    Stderr,
+    /// The local syslog daemon.
+    #[cfg(unix)]
+    Syslog,
+    /// The systemd journal, including the structured fields of messages.
+    #[cfg(unix)]
+    Journald,
+    /// The Windows event log.
+    #[cfg(windows)]
+    EventLog,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stderr" => Ok(Self::Stderr),
+            #[cfg(unix)]
+            "syslog" => Ok(Self::Syslog),
+            #[cfg(unix)]
+            "journald" => Ok(Self::Journald),
+            #[cfg(windows)]
+            "eventlog" => Ok(Self::EventLog),
+            _ if cfg!(unix) => Err(format!(
+                "invalid log target {s:?}, expected stderr, syslog or journald"
+            )),
+            _ if cfg!(windows) => Err(format!(
+                "invalid log target {s:?}, expected stderr or eventlog"
+            )),
+            _ => Err(format!("invalid log target {s:?}, expected stderr")),
+        }
+    }
+}
+
+/// Sets up the logger. By default only messages of agate itself with at least
+/// the given level are logged, this can be overridden with the `RUST_LOG`
+/// environment variable. Messages for standard error are appended to the
+/// file instead, if one is given.
+pub(crate) fn init(
+    level: log::LevelFilter,
+    format: LogFormat,
+    target: LogTarget,
+    file: Option<&Path>,
+) -> Result<(), String> {
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::default().default_filter_or(format!("agate={level}")),
+    );
+    match target {
+        LogTarget::Stderr => {
+            if let Some(path) = file {
+                let file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .map_err(|e| format!("could not open log file {}: {e}", path.display()))?;
+                builder.target(env_logger::Target::Pipe(Box::new(file)));
+            }
+            if format == LogFormat::Json {
+                builder.format(|buf, record| writeln!(buf, "{}", json_record(record)));
+            }
+        }
+        #[cfg(unix)]
+        LogTarget::Syslog => {
+            builder
+                .target(env_logger::Target::Pipe(Box::new(Datagram::connect(
+                    SYSLOG_SOCKET,
+                )?)))
+                .format(move |buf, record| {
+                    let message = match format {
+                        LogFormat::Text => record.args().to_string(),
+                        LogFormat::Json => json_record(record),
+                    };
+                    // the facility is always "daemon"
+                    write!(
+                        buf,
+                        "<{}>agate[{}]: {message}",
+                        3 * 8 + severity(record.level()),
+                        std::process::id()
+                    )
+                });
+        }
+        #[cfg(unix)]
+        LogTarget::Journald => {
+            builder
+                .target(env_logger::Target::Pipe(Box::new(Datagram::connect(
+                    JOURNALD_SOCKET,
+                )?)))
+                .format(|buf, record| buf.write_all(&journal_entry(record)));
+        }
+        #[cfg(windows)]
+        LogTarget::EventLog => {
+            builder
+                .target(env_logger::Target::Pipe(Box::new(EventLog::register()?)))
+                .format(move |buf, record| {
+                    let message = match format {
+                        LogFormat::Text => record.args().to_string(),
+                        LogFormat::Json => json_record(record),
+                    };
+                    // the level is taken off again when the event is reported
+                    buf.write_all(&[record.level() as u8])?;
+                    write!(buf, "{message}")
+                });
+        }
+    }
+    builder.init();
+    Ok(())
+}
+
+/// Formats the log record as a JSON object.
+fn json_record(record: &Record) -> String {
+    let mut line = String::from("{\"time\":");
+    push_json_string(
+        &mut line,
+        &humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+    );
+    line.push_str(",\"level\":");
+    push_json_string(&mut line, record.level().as_str());
+    line.push_str(",\"target\":");
+    push_json_string(&mut line, record.target());
+    line.push_str(",\"message\":");
+    push_json_string(&mut line, &record.args().to_string());
+    // structured fields of the record, like those of requests
+    let _ = record.key_values().visit(&mut JsonFields(&mut line));
+    line.push('}');
+    line
+}
+
+/// Returns the syslog severity of the log level.
+#[cfg(unix)]
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Formats the log record for the native protocol of systemd-journald. The
+/// structured fields of the record are added as journal fields with upper
+/// case names, fields without a value are left out.
+#[cfg(unix)]
+fn journal_entry(record: &Record) -> Vec<u8> {
+    let mut entry = vec![];
+    journal_field(&mut entry, "MESSAGE", &record.args().to_string());
+    journal_field(
+        &mut entry,
+        "PRIORITY",
+        &severity(record.level()).to_string(),
+    );
+    journal_field(&mut entry, "SYSLOG_IDENTIFIER", "agate");
+    journal_field(&mut entry, "TARGET", record.target());
+    let _ = record.key_values().visit(&mut JournalFields(&mut entry));
+    entry
+}
+
+/// Appends a field in the format of the native journald protocol.
+#[cfg(unix)]
+fn journal_field(entry: &mut Vec<u8>, name: &str, value: &str) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        // values with line breaks have to be sent with an explicit length
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value.as_bytes());
+    entry.push(b'\n');
+}
+
+/// Appends the key-value pairs of a log record as journal fields.
+#[cfg(unix)]
+struct JournalFields<'a>(&'a mut Vec<u8>);
+
+#[cfg(unix)]
+impl<'kvs> VisitSource<'kvs> for JournalFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        struct IsNull(bool);
+        impl VisitValue<'_> for IsNull {
+            fn visit_any(&mut self, _: Value) -> Result<(), kv::Error> {
+                Ok(())
+            }
+            fn visit_null(&mut self) -> Result<(), kv::Error> {
+                self.0 = true;
+                Ok(())
+            }
+        }
+
+        let mut is_null = IsNull(false);
+        value.visit(&mut is_null)?;
+        if !is_null.0 {
+            let name = key.as_str().to_ascii_uppercase();
+            journal_field(self.0, &name, &value.to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A connection to a local datagram socket. Everything written until the
+/// next flush is sent as one datagram, because the logger flushes after
+/// every message.
+#[cfg(unix)]
+struct Datagram {
+    socket: UnixDatagram,
+    buf: Vec<u8>,
+}
+
+#[cfg(unix)]
+impl Datagram {
+    fn connect(path: &str) -> Result<Self, String> {
+        let socket = UnixDatagram::unbound()
+            .and_then(|socket| socket.connect(path).map(|()| socket))
+            .map_err(|e| format!("could not connect to {path}: {e}"))?;
+        Ok(Self {
+            socket,
+            buf: vec![],
+        })
+    }
+}
+
+#[cfg(unix)]
+impl io::Write for Datagram {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let result = self.socket.send(&self.buf);
+        self.buf.clear();
+        result.map(|_| ())
+    }
+}
+
+/// An event source of the Windows event log. Everything written until the
+/// next flush is reported as one event, with the log level as the first
+/// byte.
+#[cfg(windows)]
+struct EventLog {
+    handle: isize,
+    buf: Vec<u8>,
+}
+
+#[cfg(windows)]
+impl EventLog {
+    fn register() -> Result<Self, String> {
+        let handle = agate::sys::register_event_source(OsStr::new(crate::service::NAME))
+            .map_err(|e| format!("could not register the event source: {e}"))?;
+        Ok(Self {
+            handle,
+            buf: vec![],
+        })
+    }
+}
+
+#[cfg(windows)]
+impl io::Write for EventLog {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let Some((&level, message)) = self.buf.split_first() else {
+            return Ok(());
+        };
+        let event_type = match level {
+            l if l == Level::Error as u8 => EVENTLOG_ERROR_TYPE,
+            l if l == Level::Warn as u8 => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = String::from_utf8_lossy(message).into_owned();
+        let result = agate::sys::report_event(self.handle, event_type, OsStr::new(&message));
+        self.buf.clear();
+        result
+    }
+}
+
+/// Appends the string as a quoted and escaped JSON string.
+pub(crate) fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Appends the key-value pairs of a log record as JSON object members.
+struct JsonFields<'a>(&'a mut String);
+
+impl<'kvs> VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.push(',');
+        push_json_string(self.0, key.as_str());
+        self.0.push(':');
+        value.visit(JsonValue(self.0))
+    }
+}
+
+/// Appends a value of a log record as a JSON value.
+struct JsonValue<'a>(&'a mut String);
+
+impl<'v> VisitValue<'v> for JsonValue<'_> {
+    fn visit_any(&mut self, value: Value) -> Result<(), kv::Error> {
+        push_json_string(self.0, &value.to_string());
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0.push_str("null");
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        write!(self.0, "{value}").unwrap();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        write!(self.0, "{value}").unwrap();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        write!(self.0, "{value}").unwrap();
+        Ok(())
+    }
+}
@@ -1,34 +1,98 @@
 #![forbid(unsafe_code)]
 
+mod accesslog;
+mod alias;
+mod archive;
+mod blog;
+mod cache;
 mod certificates;
+mod cgi;
 mod codes;
+mod config;
+#[cfg(unix)]
+mod daemon;
+mod dirconfig;
+mod fastcgi;
+mod feed;
+mod gopher;
+mod hidden;
+mod http;
+mod include;
+mod inflate;
+mod keylog;
+mod language;
+mod limits;
+mod logging;
+mod maintenance;
+mod markdown;
 mod metadata;
+mod metrics;
+mod mimetypes;
+#[cfg(unix)]
+mod privileges;
+mod proxy;
+mod proxyprotocol;
+mod ratelimit;
+mod redirects;
+mod regex;
+mod rewrite;
+mod robots;
+#[cfg(any(
+    all(target_os = "openbsd", feature = "openbsd-sandbox"),
+    target_os = "linux"
+))]
+mod sandbox;
+mod sanitize;
+mod scgi;
+mod search;
+#[cfg(windows)]
+mod service;
+mod sitemap;
+mod snippets;
+mod sockopts;
+mod spartan;
+mod symlinks;
+#[cfg(unix)]
+mod systemd;
+mod template;
+mod throttle;
+mod tickets;
+mod timeout;
+mod titan;
+mod tofu;
+mod userdir;
 use codes::*;
 use metadata::{FileOptions, PresetMeta};
 
 use {
+    futures_util::FutureExt,
     once_cell::sync::Lazy,
     percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS},
     rcgen::{CertificateParams, DnType, KeyPair},
+    socket2::{Domain, Protocol as SockProtocol, Socket, Type},
     std::{
-        borrow::Cow,
+        collections::BTreeMap,
         error::Error,
-        ffi::OsStr,
         fmt::Write,
         fs::{self, File},
-        io::Write as _,
+        io::{ErrorKind, Write as _},
         net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
-        path::{self, Component, Path, PathBuf},
+        path::{self, Path, PathBuf},
         sync::Arc,
+        time::{Duration, Instant, SystemTime},
     },
     tokio::{
-        io::{AsyncReadExt, AsyncWriteExt},
+        io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
         net::{TcpListener, TcpStream},
-        runtime::Runtime,
         sync::Mutex,
     },
     tokio_rustls::{
-        rustls::{server::ServerConfig, version::TLS13},
+        rustls::{
+            crypto::CryptoProvider,
+            server::{NoServerSessionStorage, ServerConfig},
+            version::TLS13,
+            ProtocolVersion, SupportedProtocolVersion, DEFAULT_VERSIONS,
+        },
         server::TlsStream,
         TlsAcceptor,
     },
@@ -44,12 +108,122 @@ use {
 static DEFAULT_PORT: u16 = 1965;
 
 fn main() {
-    env_logger::Builder::from_env(
-        // by default only turn on logging for agate
-        env_logger::Env::default().default_filter_or("agate=info"),
-    )
-    .init();
-    Runtime::new()
+    // parse arguments first, this also sets up logging
+    Lazy::force(&ARGS);
+
+    #[cfg(windows)]
+    match &ARGS.service {
+        Some(service::Command::Run) => {
+            service::run(|stop| {
+                run(async move {
+                    let _ = stop.await;
+                })
+            })
+            .unwrap_or_else(|e| panic!("Failed to run as a service: {e}"));
+            return;
+        }
+        Some(service::Command::Install(args)) => {
+            service::install(args).unwrap_or_else(|e| panic!("Failed to install the service: {e}"));
+            log::info!("Installed the service {}", service::NAME);
+            return;
+        }
+        Some(service::Command::Uninstall) => {
+            service::uninstall().unwrap_or_else(|e| panic!("Failed to uninstall the service: {e}"));
+            log::info!("Uninstalled the service {}", service::NAME);
+            return;
+        }
+        None => (),
+    }
+
+    run(std::future::pending());
+}
+
+/// Serves requests until `stop` completes.
+fn run(stop: impl std::future::Future<Output = ()>) {
+    // take the sockets passed by systemd before other threads are started,
+    // because this changes the environment
+    #[cfg(unix)]
+    let activated =
+        systemd::listeners().unwrap_or_else(|e| panic!("Failed to use sockets from systemd: {e}"));
+    #[cfg(unix)]
+    let notifier = systemd::Notifier::from_env()
+        .unwrap_or_else(|e| panic!("Failed to use the systemd notification socket: {e}"));
+
+    // all listeners are bound before any threads are started, so the
+    // privileges of the whole process can be dropped in between
+    #[cfg(unix)]
+    let listeners = listen(activated);
+    #[cfg(not(unix))]
+    let listeners = listen();
+
+    // the PID file is written and removed by the process in the background
+    #[cfg(unix)]
+    if ARGS.daemon {
+        daemon::daemonize().unwrap_or_else(|e| panic!("Failed to run in the background: {e}"));
+    }
+    #[cfg(unix)]
+    let pid_file = ARGS.pid_file.as_ref().map(|path| {
+        daemon::PidFile::create(path)
+            .unwrap_or_else(|e| panic!("Failed to write PID file {}: {e}", path.display()))
+    });
+
+    #[cfg(unix)]
+    if let Some(root) = &ARGS.chroot {
+        std::os::unix::fs::chroot(root)
+            .and_then(|()| std::env::set_current_dir("/"))
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Failed to change the root directory to {}: {e}",
+                    root.display()
+                )
+            });
+        log::info!("Changed the root directory to {}", root.display());
+    }
+
+    #[cfg(unix)]
+    if let Some(account) = &ARGS.account {
+        account
+            .switch()
+            .unwrap_or_else(|e| panic!("Failed to drop privileges: {e}"));
+        log::info!("Switched to {account}");
+    }
+
+    #[cfg(all(target_os = "openbsd", feature = "openbsd-sandbox"))]
+    sandbox::restrict().unwrap_or_else(|e| panic!("Failed to set up the sandbox: {e}"));
+    #[cfg(target_os = "linux")]
+    if ARGS.sandbox {
+        sandbox::restrict().unwrap_or_else(|e| panic!("Failed to set up the sandbox: {e}"));
+        log::info!("Restricted file access and system calls");
+    }
+
+    // the PID file can only be removed when exiting normally
+    #[cfg(unix)]
+    let daemon::Signals {
+        terminated,
+        mut hangup,
+    } = daemon::signals(pid_file.is_some())
+        .unwrap_or_else(|e| panic!("Failed to handle signals: {e}"));
+    #[cfg(unix)]
+    let stop = async move {
+        match terminated {
+            Some(terminated) => {
+                let stopped = std::pin::pin!(stop);
+                futures_util::future::select(stopped, terminated).await;
+            }
+            None => stop.await,
+        }
+    };
+
+    let mut runtime = tokio::runtime::Builder::new_multi_thread();
+    if let Some(threads) = ARGS.worker_threads {
+        runtime.worker_threads(threads);
+    }
+    if let Some(threads) = ARGS.blocking_threads {
+        runtime.max_blocking_threads(threads);
+    }
+    runtime
+        .enable_all()
+        .build()
         .expect("could not start tokio runtime")
         .block_on(async {
             let default = PresetMeta::Parameters(
@@ -59,96 +233,190 @@ fn main() {
             );
             let mimetypes = Arc::new(Mutex::new(FileOptions::new(default)));
 
-            // some systems automatically listen in dual stack if the IPv6 unspecified
-            // address is used, so don't fail if the second unspecified address gets
-            // an error when trying to start
-            let mut listening_unspecified = false;
-
             let mut handles = vec![];
-            for addr in &ARGS.addrs {
+            for listener in listeners {
                 let arc = mimetypes.clone();
-
-                let listener = match TcpListener::bind(addr).await {
-                    Err(e) => {
-                        if !(addr.ip().is_unspecified() && listening_unspecified) {
-                            panic!("Failed to listen on {addr}: {e}")
-                        } else {
-                            // already listening on the other unspecified address
-                            log::warn!("Could not start listener on {}, but already listening on another unspecified address. Probably your system automatically listens in dual stack?", addr);
-                            continue;
-                        }
+                let server = match listener {
+                    Listener::Gemini(listener) => {
+                        TcpListener::from_std(listener).map(|listener| serve(listener, arc).boxed())
                     }
-                    Ok(listener) => listener,
-                };
-                listening_unspecified |= addr.ip().is_unspecified();
-
-                handles.push(tokio::spawn(async move {
-                    log::info!("Started listener on {}", addr);
-
-                    loop {
-                        let (stream, _) = listener.accept().await.unwrap_or_else(|e| {
-                            panic!("could not accept new connection on {addr}: {e}")
-                        });
-                        let arc = arc.clone();
-                        tokio::spawn(async {
-                            match RequestHandle::new(stream, arc).await {
-                                Ok(handle) => match handle.handle().await {
-                                    Ok(info) => log::info!("{}", info),
-                                    Err(err) => log::warn!("{}", err),
-                                },
-                                Err(log_line) => {
-                                    log::warn!("{}", log_line);
-                                }
-                            }
-                        });
+                    #[cfg(unix)]
+                    Listener::Unix(listener, tls) => UnixListener::from_std(listener)
+                        .map(|listener| serve_unix(listener, tls, arc).boxed()),
+                    Listener::Plain(listener, protocol) => TcpListener::from_std(listener)
+                        .map(|listener| serve_plain(listener, protocol, arc).boxed()),
+                    Listener::Metrics(listener) => {
+                        TcpListener::from_std(listener).map(|listener| {
+                            log::info!(
+                                "Started metrics listener on {}",
+                                listener.local_addr().unwrap()
+                            );
+                            metrics::serve(listener).boxed()
+                        })
                     }
-                }))
-            };
+                };
+                let server = server.unwrap_or_else(|e| panic!("Failed to use listener: {e}"));
+                handles.push(tokio::spawn(server));
+            }
+
+            tokio::spawn(certificates::monitor_expiry(
+                ARGS.certs.clone(),
+                ARGS.cert_expiry_warning,
+            ));
 
+            if let Some(maintenance) = &ARGS.maintenance {
+                tokio::spawn(maintenance.watch());
+            }
+
+            if let Some(sitemaps) = &ARGS.sitemaps {
+                tokio::spawn(sitemaps.watch());
+            }
+
+            if let Some(search) = &ARGS.search {
+                tokio::spawn(search.watch());
+            }
+
+            if !ARGS.cert_reload_interval.is_zero() {
+                tokio::spawn(certificates::watch(
+                    ARGS.certs.clone(),
+                    ARGS.certs_dir.clone(),
+                    ARGS.cert_reload_interval,
+                ));
+            }
+
+            // SIGHUP asks for the certificates to be reloaded right away
             #[cfg(unix)]
-            for socketpath in &ARGS.sockets {
-                let arc = mimetypes.clone();
+            tokio::spawn(async move {
+                while hangup.recv().await.is_some() {
+                    if ARGS.chroot.is_some() {
+                        log::warn!("Certificates can not be reloaded because of --chroot");
+                        continue;
+                    }
+                    match ARGS.certs.reload(&ARGS.certs_dir) {
+                        Ok(()) => {
+                            log::info!("Reloaded certificates from {}", ARGS.certs_dir.display())
+                        }
+                        Err(e) => log::error!(
+                            "Could not reload certificates, keeping the previous ones: {e}"
+                        ),
+                    }
+                }
+            });
 
-                if socketpath.exists() && socketpath.metadata()
-                        .expect("Failed to get existing socket metadata")
-                        .file_type()
-                        .is_socket() {
-                    log::warn!("Socket already exists, attempting to remove {}", socketpath.display());
-                    let _ = std::fs::remove_file(socketpath);
+            // all listeners are bound and the certificates are loaded
+            #[cfg(unix)]
+            if let Some(notifier) = notifier {
+                if let Err(e) = notifier.notify("READY=1") {
+                    log::warn!("could not notify systemd: {e}");
                 }
+                tokio::spawn(notifier.watchdog());
+            }
 
-                let listener = match UnixListener::bind(socketpath) {
-                    Err(e) => {
-                        panic!("Failed to listen on {}: {}", socketpath.display(), e)
-                    }
-                    Ok(listener) => listener,
-                };
+            let servers = futures_util::future::join_all(handles);
+            futures_util::future::select(servers, std::pin::pin!(stop)).await;
+        });
+}
 
-                handles.push(tokio::spawn(async move {
-                    log::info!("Started listener on {}", socketpath.display());
-
-                    loop {
-                        let (stream, _) = listener.accept().await.unwrap_or_else(|e| {
-                            panic!("could not accept new connection on {}: {}", socketpath.display(), e)
-                        });
-                        let arc = arc.clone();
-                        tokio::spawn(async {
-                            match RequestHandle::new_unix(stream, arc).await {
-                                Ok(handle) => match handle.handle().await {
-                                    Ok(info) => log::info!("{}", info),
-                                    Err(err) => log::warn!("{}", err),
-                                },
-                                Err(log_line) => {
-                                    log::warn!("{}", log_line);
-                                }
-                            }
-                        });
-                    }
-                }))
-            };
+/// A bound listener that is not served yet.
+enum Listener {
+    Gemini(std::net::TcpListener),
+    /// A Unix socket for Gemini, with or without TLS.
+    #[cfg(unix)]
+    Unix(std::os::unix::net::UnixListener, bool),
+    /// A listener for a protocol without TLS, like Spartan or Gopher.
+    Plain(std::net::TcpListener, Protocol),
+    Metrics(std::net::TcpListener),
+}
+
+/// Binds all listeners. Sockets passed by systemd replace the configured
+/// Gemini addresses and Unix sockets.
+fn listen(#[cfg(unix)] activated: Vec<systemd::Listener>) -> Vec<Listener> {
+    let mut listeners = vec![];
+
+    #[cfg(unix)]
+    let socket_activated = !activated.is_empty();
+    #[cfg(not(unix))]
+    let socket_activated = false;
 
-            futures_util::future::join_all(handles).await;
+    #[cfg(unix)]
+    for listener in activated {
+        listeners.push(match listener {
+            systemd::Listener::Tcp(listener) => Listener::Gemini(listener),
+            systemd::Listener::Unix(listener) => Listener::Unix(listener, true),
         });
+    }
+
+    #[cfg(target_os = "linux")]
+    let acceptors = ARGS.acceptors;
+    #[cfg(not(target_os = "linux"))]
+    let acceptors = 1;
+    for addr in ARGS.addrs.iter().filter(|_| !socket_activated) {
+        let listener = bind(*addr, &ARGS.addrs, acceptors > 1)
+            .unwrap_or_else(|e| panic!("Failed to listen on {addr}: {e}"));
+        // with port 0, the other sockets have to use the port chosen for the
+        // first one
+        let addr = listener.local_addr().unwrap();
+        listeners.push(Listener::Gemini(listener));
+        // the kernel distributes new connections between the sockets
+        for _ in 1..acceptors {
+            let listener = bind(addr, &ARGS.addrs, true)
+                .unwrap_or_else(|e| panic!("Failed to listen on {addr}: {e}"));
+            listeners.push(Listener::Gemini(listener));
+        }
+    }
+
+    #[cfg(unix)]
+    let sockets = ARGS
+        .sockets
+        .iter()
+        .map(|path| (path, true))
+        .chain(ARGS.plain_sockets.iter().map(|path| (path, false)))
+        .filter(|_| !socket_activated);
+    #[cfg(unix)]
+    for (socketpath, tls) in sockets {
+        if socketpath.exists()
+            && socketpath
+                .metadata()
+                .expect("Failed to get existing socket metadata")
+                .file_type()
+                .is_socket()
+        {
+            log::warn!(
+                "Socket already exists, attempting to remove {}",
+                socketpath.display()
+            );
+            let _ = std::fs::remove_file(socketpath);
+        }
+
+        let listener = std::os::unix::net::UnixListener::bind(socketpath)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+            .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", socketpath.display(), e));
+        listeners.push(Listener::Unix(listener, tls));
+    }
+
+    let plain_addrs = ARGS
+        .spartan_addrs
+        .iter()
+        .map(|addr| (addr, Protocol::Spartan))
+        .chain(
+            ARGS.gopher_addrs
+                .iter()
+                .map(|addr| (addr, Protocol::Gopher)),
+        );
+    for (addr, protocol) in plain_addrs {
+        let listener = bind(*addr, &ARGS.addrs, false)
+            .unwrap_or_else(|e| panic!("Failed to listen on {addr}: {e}"));
+        listeners.push(Listener::Plain(listener, protocol));
+    }
+
+    if let Some(addr) = ARGS.metrics_addr {
+        let listener = std::net::TcpListener::bind(addr)
+            .and_then(|listener| listener.set_nonblocking(true).map(|()| listener))
+            .unwrap_or_else(|e| panic!("Failed to listen on {addr}: {e}"));
+        listeners.push(Listener::Metrics(listener));
+    }
+
+    listeners
 }
 
 type Result<T = (), E = Box<dyn Error + Send + Sync>> = std::result::Result<T, E>;
@@ -162,34 +430,405 @@ static ARGS: Lazy<Args> = Lazy::new(|| {
 
 struct Args {
     addrs: Vec<SocketAddr>,
+    /// Addresses to listen on for the Spartan protocol.
+    spartan_addrs: Vec<SocketAddr>,
+    /// Addresses to listen on for the Gopher protocol.
+    gopher_addrs: Vec<SocketAddr>,
     #[cfg(unix)]
     sockets: Vec<PathBuf>,
+    /// Unix sockets for Gemini requests without TLS, which is terminated by
+    /// a reverse proxy.
+    #[cfg(unix)]
+    plain_sockets: Vec<PathBuf>,
+    /// The account to switch to after the listeners are bound.
+    #[cfg(unix)]
+    account: Option<privileges::Account>,
+    /// The directory to change the root directory to after the listeners
+    /// are bound. The content directories are then relative to it.
+    #[cfg(unix)]
+    chroot: Option<PathBuf>,
+    /// Whether to restrict file access and system calls after the listeners
+    /// are bound.
+    #[cfg(target_os = "linux")]
+    sandbox: bool,
+    /// Whether to fork into the background after the listeners are bound.
+    #[cfg(unix)]
+    daemon: bool,
+    /// The file to write the process ID to.
+    #[cfg(unix)]
+    pid_file: Option<PathBuf>,
+    /// Whether to run as a Windows service, or install or uninstall it.
+    #[cfg(windows)]
+    service: Option<service::Command>,
     content_dir: PathBuf,
     certs: Arc<certificates::CertStore>,
+    /// The directory the certificates were loaded from.
+    certs_dir: PathBuf,
+    /// Warn about certificates that expire within this time.
+    cert_expiry_warning: Duration,
+    /// How often to check the certificate directory for changes, or zero to
+    /// never reload the certificates.
+    cert_reload_interval: Duration,
     hostnames: Vec<Host>,
+    /// Settings of virtual hosts from the configuration file, by hostname.
+    vhosts: BTreeMap<String, VHost>,
+    /// Language of text/gemini files.
     language: Option<String>,
-    serve_secret: bool,
+    /// Languages of variants of files that do not exist, in order of
+    /// preference, for hosts without their own.
+    lang_variants: Vec<String>,
+    /// Whether include directives in gemtext files are replaced with the
+    /// included files.
+    includes: bool,
+    /// Whether gemtext templates are rendered.
+    templates: bool,
+    /// Whether Markdown files are converted to gemtext.
+    markdown: bool,
+    /// Charset to add to the MIME type of text files.
+    charset: Option<String>,
+    /// File names to look for when a directory is requested, in order of
+    /// preference.
+    index_files: Vec<String>,
+    mime_types: mimetypes::MimeTypes,
+    /// Rules for rewriting the URL paths of requests, for hosts without their
+    /// own rules.
+    rewrite_rules: Vec<rewrite::Rule>,
+    /// Rules for redirecting requests to other URLs.
+    redirects: redirects::Redirects,
+    /// Rules for the generated robots.txt, for hosts without their own.
+    robots_rules: Vec<robots::Rule>,
+    /// Emoji that is served as /favicon.txt.
+    favicon: Option<String>,
+    /// URL path prefixes of content that was removed on purpose.
+    gone: Vec<redirects::Gone>,
+    /// URL path prefixes that are served from other directories.
+    aliases: Vec<alias::Alias>,
+    /// URL path prefixes that are served from archive files.
+    archives: Vec<archive::Archive>,
+    /// Feeds of directories with posts, by the URL paths they are served at.
+    feeds: Vec<feed::Feed>,
+    /// Directories with posts for which pages are generated.
+    blogs: Vec<blog::Blog>,
+    /// Site maps of the content directories, if any are served.
+    sitemaps: Option<sitemap::Sitemaps>,
+    /// Full-text search of the content directories, if it is served.
+    search: Option<search::Search>,
+    /// Directories that are searched in order for files that do not exist in
+    /// the content directory.
+    content_layers: Vec<PathBuf>,
+    /// Where the directories of URL paths starting with `~USER` are.
+    user_dirs: Option<userdir::UserDirs>,
+    /// Maintenance mode, if a file to turn it on is given.
+    maintenance: Option<maintenance::Maintenance>,
+    /// URL path prefixes in which files are executed as CGI scripts.
+    cgi_paths: Vec<String>,
+    /// Whether executable files ending in `.cgi` are executed anywhere.
+    cgi: bool,
+    /// Rules to forward requests to upstream Gemini servers.
+    proxy_routes: Vec<proxy::Route>,
+    /// URL path prefixes that are handled by SCGI application servers.
+    scgi_routes: Vec<cgi::Route>,
+    /// URL path prefixes that are handled by FastCGI application servers.
+    fastcgi_routes: Vec<cgi::Route>,
+    /// Time limit for connecting to and reading from CGI scripts and other
+    /// backends except FastCGI application servers.
+    backend_timeout: Duration,
+    /// Time limit for connecting to and reading from FastCGI application
+    /// servers.
+    fastcgi_timeout: Duration,
+    /// Time limit for establishing the TLS session of a connection.
+    handshake_timeout: Duration,
+    /// Time limit for receiving the request after the connection was
+    /// established, and for each read of an upload.
+    request_timeout: Duration,
+    /// Time limit for the client to accept more of the response.
+    write_timeout: Duration,
+    /// The maximum number of bytes per second sent to a connection.
+    bandwidth: Option<u64>,
+    /// Bandwidths for URL path prefixes that differ from `bandwidth`.
+    bandwidth_limits: Vec<throttle::Limit>,
+    /// Options for accepted TCP connections.
+    socket_options: sockopts::SocketOptions,
+    /// The maximum number of TCP connections waiting to be accepted.
+    listen_backlog: i32,
+    /// URL path prefixes that are handled by HTTP backends.
+    http_routes: Vec<http::Route>,
+    /// URL path prefixes that require a client certificate.
+    client_cert_areas: Vec<String>,
+    tofu: tofu::TofuStore,
+    /// URL path prefixes that files may be uploaded to with Titan.
+    titan_areas: Vec<titan::Area>,
+    /// Token that Titan uploads have to include, if any.
+    titan_token: Option<String>,
+    /// Maximum size of Titan uploads in bytes.
+    titan_max_size: u64,
+    /// Media types that may be uploaded with Titan, or empty to allow all.
+    titan_mime_types: Vec<String>,
+    /// Small files kept in memory, if enabled.
+    file_cache: Option<cache::FileCache>,
+    /// The files URLs resolve to, if enabled.
+    stat_cache: Option<cache::StatCache>,
+    /// The size of the chunks files are sent in.
+    file_buffer_size: usize,
+    /// The maximum size of files that are sent.
+    max_response_size: Option<u64>,
+    /// Whether larger files are cut off at the maximum size instead of not
+    /// being sent at all.
+    truncate_large_responses: bool,
+    /// The number of threads handling connections, by default one per core.
+    worker_threads: Option<usize>,
+    /// The maximum number of threads for file access.
+    blocking_threads: Option<usize>,
+    /// The number of sockets for every Gemini address.
+    #[cfg(target_os = "linux")]
+    acceptors: usize,
+    /// Which files are hidden and what happens to them.
+    hidden_files: hidden::HiddenFiles,
+    /// Which symbolic links are followed.
+    symlinks: symlinks::Mode,
+    /// Whether URL paths are matched to files regardless of their case for
+    /// all hosts.
+    case_insensitive: bool,
+    serve_dir_listings: bool,
+    dir_listing_details: bool,
     log_ips: bool,
     only_tls13: bool,
+    /// The file the TLS secrets are written to for debugging.
+    key_log: Option<Arc<keylog::KeyLogFile>>,
+    /// Whether TLS sessions can be resumed.
+    session_resumption: bool,
+    /// The lifetime of stateless session tickets, if they are enabled.
+    session_tickets: Option<Duration>,
+    /// The default crypto provider with only the allowed cipher suites and
+    /// key exchange groups.
+    tls_provider: Arc<CryptoProvider>,
     central_config: bool,
     skip_port_check: bool,
+    /// Whether TCP connections start with a PROXY protocol header.
+    proxy_protocol: bool,
+    access_log: Option<accesslog::AccessLog>,
+    /// Limits for the number of simultaneous connections.
+    connection_limits: limits::ConnectionLimits,
+    /// Limits the requests per client IP address, if enabled.
+    rate_limit: Option<ratelimit::RateLimiter>,
+    /// Address to serve metrics for Prometheus on.
+    metrics_addr: Option<SocketAddr>,
+}
+
+/// Settings for a single virtual host.
+struct VHost {
+    /// Content directory of this host, if it is not the default.
+    content_dir: Option<PathBuf>,
+    /// Rules for rewriting URL paths, used instead of the global ones if
+    /// there are any.
+    rewrite_rules: Vec<rewrite::Rule>,
+    /// Whether URL paths are matched to files regardless of their case.
+    case_insensitive: bool,
+    /// Charset of text files, if it is not the default.
+    charset: Option<String>,
+    /// Language of text/gemini files, if it is not the default.
+    language: Option<String>,
+    /// Languages of variants of files, used instead of the global ones if
+    /// there are any.
+    lang_variants: Vec<String>,
+    /// Rules for the generated robots.txt, used instead of the global ones if
+    /// there are any.
+    robots_rules: Vec<robots::Rule>,
+    /// Emoji that is served as /favicon.txt, if it is not the default.
+    favicon: Option<String>,
+}
+
+impl Args {
+    /// Checks if the file at the given filesystem path, requested with the
+    /// given normalized URL path, should be run as a CGI script.
+    fn is_cgi_script(&self, url_path: &str, path: &Path) -> bool {
+        let allowed = self
+            .cgi_paths
+            .iter()
+            .any(|prefix| sanitize::has_prefix(url_path, prefix))
+            || (self.cgi && path.extension() == Some("cgi".as_ref()));
+        allowed && cgi::is_script(path)
+    }
+
+    /// Checks whether a request for the normalized URL path is answered with
+    /// a file from the content directory without a client certificate, as far
+    /// as the settings for URL path prefixes go. These are the checks
+    /// `send_response` makes before looking for the file, so generated lists
+    /// of files like the site map do not reveal other files.
+    fn is_public(&self, url_path: &str) -> bool {
+        let matches = |prefix: &str| sanitize::has_prefix(url_path, prefix);
+        !(self.gone.iter().any(|gone| matches(&gone.prefix))
+            || self
+                .proxy_routes
+                .iter()
+                .any(|route| route.host.is_none() && matches(&route.prefix))
+            || self.client_cert_areas.iter().any(|prefix| matches(prefix))
+            || self
+                .scgi_routes
+                .iter()
+                .chain(&self.fastcgi_routes)
+                .any(|route| matches(&route.prefix))
+            || self.http_routes.iter().any(|route| matches(&route.prefix)))
+    }
+
+    /// Returns the rewrite rules for the given host.
+    fn rewrite_rules(&self, host: &str) -> &[rewrite::Rule] {
+        match self.vhosts.get(host) {
+            Some(vhost) if !vhost.rewrite_rules.is_empty() => &vhost.rewrite_rules,
+            _ => &self.rewrite_rules,
+        }
+    }
+
+    /// Returns the rules for the generated robots.txt of the given host.
+    fn robots_rules(&self, host: &str) -> &[robots::Rule] {
+        match self.vhosts.get(host) {
+            Some(vhost) if !vhost.robots_rules.is_empty() => &vhost.robots_rules,
+            _ => &self.robots_rules,
+        }
+    }
+
+    /// Returns the emoji to serve as /favicon.txt for the given host, if any.
+    fn favicon(&self, host: &str) -> Option<&str> {
+        self.vhosts
+            .get(host)
+            .and_then(|v| v.favicon.as_deref())
+            .or(self.favicon.as_deref())
+    }
+
+    /// Returns whether URL paths for the given host are matched to files
+    /// regardless of their case.
+    fn case_insensitive(&self, host: &str) -> bool {
+        self.case_insensitive || self.vhosts.get(host).is_some_and(|v| v.case_insensitive)
+    }
+
+    /// Returns the language of files of the given host, if any.
+    fn language(&self, host: &str) -> Option<&str> {
+        self.vhosts
+            .get(host)
+            .and_then(|v| v.language.as_deref())
+            .or(self.language.as_deref())
+    }
+
+    /// Returns the languages of variants of files for the given host.
+    fn lang_variants(&self, host: &str) -> &[String] {
+        match self.vhosts.get(host) {
+            Some(vhost) if !vhost.lang_variants.is_empty() => &vhost.lang_variants,
+            _ => &self.lang_variants,
+        }
+    }
+
+    /// Returns the charset to add to the MIME type of text files of the given
+    /// host, if any.
+    fn charset(&self, host: &str) -> Option<&str> {
+        self.vhosts
+            .get(host)
+            .and_then(|v| v.charset.as_deref())
+            .or(self.charset.as_deref())
+    }
+
+    /// Returns the content directory for the given hostname.
+    fn content_dir(&self, host: &str) -> PathBuf {
+        if let Some(dir) = self.vhosts.get(host).and_then(|v| v.content_dir.as_ref()) {
+            dir.clone()
+        } else if self.hostnames.len() > 1 {
+            // basic vhosts
+            self.content_dir.join(host)
+        } else {
+            self.content_dir.clone()
+        }
+    }
+
+    /// Returns the content directory that contains the given file path.
+    fn content_root(&self, file: &Path) -> &Path {
+        self.vhosts
+            .values()
+            .filter_map(|v| v.content_dir.as_deref())
+            .chain(self.aliases.iter().map(|alias| alias.dir.as_path()))
+            .chain(self.content_layers.iter().map(PathBuf::as_path))
+            .find(|dir| file.starts_with(dir))
+            .unwrap_or(&self.content_dir)
+    }
+
+    /// Returns the directory the URL path is resolved in for the given
+    /// hostname and the number of URL path segments that lead to it.
+    fn root_dir(&self, host: &str, url_path: &str) -> (PathBuf, usize) {
+        let user_dir = || {
+            let segment = url_path.strip_prefix('/')?.split('/').next()?;
+            Some((self.user_dirs.as_ref()?.dir(segment)?, 1))
+        };
+        self.aliases
+            .iter()
+            .find_map(|alias| Some((alias.dir.clone(), alias.segments(url_path)?)))
+            .or_else(user_dir)
+            .unwrap_or_else(|| (self.content_dir(host), 0))
+    }
+
+    /// Returns the directory for a valid URL path of a directory.
+    fn dir_path(&self, host: &str, url_path: &str) -> PathBuf {
+        let (root, skip) = self.root_dir(host, url_path);
+        sanitize::segments(url_path)
+            .unwrap_or_default()
+            .iter()
+            .skip(skip)
+            .fold(root, |path, segment| path.join(segment))
+    }
+
+    /// Returns the first content layer that contains the file and the path
+    /// in it if the file does not exist in the content directory `root`.
+    fn layered(&self, root: PathBuf, path: PathBuf) -> (PathBuf, PathBuf) {
+        if self.content_layers.is_empty() || path.exists() {
+            return (root, path);
+        }
+        let Ok(relative) = path.strip_prefix(&root) else {
+            return (root, path);
+        };
+        self.content_layers
+            .iter()
+            .map(|layer| (layer.clone(), layer.join(relative)))
+            .find(|(_, path)| path.exists())
+            .unwrap_or((root, path))
+    }
 }
 
 fn args() -> Result<Args> {
     let args: Vec<String> = std::env::args().collect();
     let mut opts = getopts::Options::new();
+    opts.optopt(
+        "",
+        "config",
+        "Configuration file to read further options from, options given on the command line take precedence",
+        "FILE",
+    );
     opts.optopt(
         "",
         "content",
         "Root of the content directory (default ./content/)",
         "DIR",
     );
+    opts.optmulti(
+        "",
+        "content-layer",
+        "Directory to search for files that do not exist in the content directory, shared by all hosts (multiple occurences are searched in order)",
+        "DIR",
+    );
     opts.optopt(
         "",
         "certs",
         "Root of the certificate directory (default ./.certificates/)",
         "DIR",
     );
+    opts.optopt(
+        "",
+        "cert-expiry-warning",
+        "Log a warning when a certificate expires within the given number of days, checked once a day (default 30)",
+        "DAYS",
+    );
+    opts.optopt(
+        "",
+        "cert-reload-interval",
+        "Seconds between checks of the certificate directory for changed certificates, which are then loaded without a restart (default 30, 0 disables reloading)",
+        "SECONDS",
+    );
     opts.optmulti(
         "",
         "addr",
@@ -203,72 +842,713 @@ fn args() -> Result<Args> {
         "Unix socket to listen on (multiple occurences means listening on multiple sockets)",
         "PATH",
     );
+    #[cfg(unix)]
+    opts.optmulti(
+        "",
+        "plain-socket",
+        "Unix socket to listen on for Gemini requests without TLS, for a reverse proxy that terminates TLS (multiple occurences means listening on multiple sockets)",
+        "PATH",
+    );
+    #[cfg(unix)]
+    opts.optopt(
+        "",
+        "user",
+        "User to switch to after listening, when started as root (default: keep running as root)",
+        "NAME",
+    );
+    #[cfg(unix)]
+    opts.optopt(
+        "",
+        "group",
+        "Group to switch to after listening, when started as root (default: the primary group of the user)",
+        "NAME",
+    );
+    #[cfg(unix)]
+    opts.optflagopt(
+        "",
+        "chroot",
+        "Change the root directory to the content directory after listening, so no files outside of it can be served. Requires root.",
+        "BOOL",
+    );
+    #[cfg(target_os = "linux")]
+    opts.optflagopt(
+        "",
+        "sandbox",
+        "Restrict file access with Landlock and system calls with seccomp after listening",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "cache-size",
+        "Keep recently requested small files in memory, up to this many bytes in total (default: disabled)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "cache-max-file-size",
+        "Maximum size of a file kept in memory with --cache-size in bytes (default 65536)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "worker-threads",
+        "Number of threads handling connections (default: one per CPU core)",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "blocking-threads",
+        "Maximum number of threads for file access and other blocking operations (default 512)",
+        "NUMBER",
+    );
+    #[cfg(target_os = "linux")]
+    opts.optopt(
+        "",
+        "acceptors",
+        "Number of sockets to accept Gemini connections on for every address, the kernel distributes new connections between them (default 1)",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "file-buffer-size",
+        "Size of the chunks files are read and sent in, in bytes (default 65536)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "max-response-size",
+        "Maximum size of files that are sent in bytes, larger files are answered with status 59 (default: unlimited)",
+        "BYTES",
+    );
+    opts.optflagopt(
+        "",
+        "truncate-large-responses",
+        "Send only the beginning of files larger than --max-response-size instead of status 59",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "stat-cache-ttl",
+        "Seconds to remember which file a URL resolves to and its media type, to skip looking it up again (default: disabled)",
+        "SECONDS",
+    );
     opts.optmulti(
         "",
         "hostname",
         "Domain name of this Gemini server, enables checking hostname and port in requests. (multiple occurences means basic vhosts)",
         "NAME",
     );
+    opts.optmulti(
+        "",
+        "index",
+        "File name to serve when a directory is requested (default index.gmi; multiple occurences means trying them in the given order)",
+        "NAME",
+    );
     opts.optopt(
         "",
-        "lang",
-        "RFC 4646 Language code for text/gemini documents",
-        "LANG",
+        "mime-types",
+        "File in the format of mime.types with additional MIME media types for file extensions",
+        "FILE",
     );
-    opts.optflag("h", "help", "Print this help text and exit.");
-    opts.optflag("V", "version", "Print version information and exit.");
-    opts.optflag(
-        "3",
-        "only-tls13",
-        "Only use TLSv1.3 (default also allows TLSv1.2)",
+    opts.optmulti(
+        "",
+        "rewrite",
+        "Rule to rewrite URL paths matching the regular expression, the replacement can refer to groups with $1 to $9; the flag last skips the remaining rules, redirect and permanent send a redirect with status 30 or 31 instead (multiple occurences means multiple rules, applied in order)",
+        "\"PATTERN REPLACEMENT [FLAG]\"",
     );
-    opts.optflag(
+    opts.optopt(
         "",
-        "serve-secret",
-        "Enable serving secret files (files/directories starting with a dot)",
+        "redirects",
+        "File with rules to redirect requests for URL paths to other URLs, one per line as SOURCE TARGET [30|31]",
+        "FILE",
     );
-    opts.optflag("", "log-ip", "Output the remote IP address when logging.");
-    opts.optflag(
-        "C",
-        "central-conf",
-        "Use a central .meta file in the content root directory. Decentral config files will be ignored.",
+    opts.optmulti(
+        "",
+        "gone",
+        "URL path prefix of content that was removed, which is answered with status 52 and the message (multiple occurences means multiple prefixes)",
+        "PREFIX[=MESSAGE]",
     );
-    opts.optflag(
-        "e",
-        "ed25519",
-        "Generate keys using the Ed25519 signature algorithm instead of the default ECDSA.",
+    opts.optmulti(
+        "",
+        "alias",
+        "Serve the files for a URL path prefix from a directory outside of the content directory (multiple occurences means multiple aliases)",
+        "PREFIX=DIR",
     );
-    opts.optflag(
+    opts.optmulti(
         "",
-        "skip-port-check",
-        "Skip URL port check even when a hostname is specified.",
+        "archive",
+        "Serve the files for a URL path prefix from a .tar, .tar.gz or .zip file (multiple occurences means multiple archives)",
+        "PREFIX=FILE",
     );
-
-    let matches = opts.parse(&args[1..]).map_err(|f| f.to_string())?;
-
-    if matches.opt_present("h") {
-        eprintln!("{}", opts.usage(&format!("Usage: {} [options]", &args[0])));
-        std::process::exit(0);
-    }
-
-    if matches.opt_present("V") {
-        eprintln!("agate {}", env!("CARGO_PKG_VERSION"));
-        std::process::exit(0);
-    }
-
-    // try to open the certificate directory
-    let certs_path = matches.opt_get_default("certs", ".certificates".to_string())?;
-    let (certs, certs_path) = match check_path(certs_path.clone()) {
-        // the directory exists, try to load certificates
-        Ok(certs_path) => match certificates::CertStore::load_from(&certs_path) {
-            // all is good
-            Ok(certs) => (Some(certs), certs_path),
-            // the certificate directory did not contain certificates, but we can generate some
-            // because the hostname option was given
-            Err(certificates::CertLoadError::Empty) if matches.opt_present("hostname") => {
-                (None, certs_path)
-            }
-            // failed loading certificates or missing hostname to generate them
+    opts.optmulti(
+        "",
+        "feed",
+        "Serve an Atom feed, an RSS feed if the file name contains rss, or a gemtext subscription page if it ends in .gmi, of the gemtext posts in the directory with the URL path DIR at the URL path PATH, e.g. /feed.xml=/gemlog/ (multiple occurences means multiple feeds)",
+        "PATH=DIR",
+    );
+    opts.optmulti(
+        "",
+        "blog",
+        "Generate an index, tag and archive pages and an Atom feed for the gemtext posts with front matter in the directory with the URL path, e.g. /gemlog/ (multiple occurences means multiple blogs)",
+        "DIR",
+    );
+    opts.optmulti(
+        "",
+        "sitemap",
+        "Serve a site map of all gemtext documents at the URL path, which lists their URLs if it ends in .txt (multiple occurences means multiple site maps)",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "search",
+        "Serve a full-text search of the gemtext and plain text files at the URL path, e.g. /search",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "user-dirs",
+        "Serve URL paths starting with ~USER from the directory given by the pattern, where * is replaced by the user name, e.g. /home/*/public_gemini",
+        "PATTERN",
+    );
+    opts.optopt(
+        "",
+        "maintenance-file",
+        "While this file exists, all requests are answered with status 41 and the first line of the file as the message",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "lang",
+        "RFC 4646 Language code for text/gemini documents",
+        "LANG",
+    );
+    opts.optmulti(
+        "",
+        "lang-variant",
+        "Language of variants like page.de.gmi or de/page.gmi that are served for files that do not exist (multiple occurences means multiple languages in order of preference)",
+        "LANG",
+    );
+    opts.optmulti(
+        "",
+        "robots",
+        "Serve a generated /robots.txt that asks crawlers with the user agent, e.g. archiver or indexer, or all crawlers not to request URL paths with the prefix (multiple occurences means multiple rules)",
+        "[AGENT:]PREFIX",
+    );
+    opts.optopt("", "favicon", "Serve the emoji as /favicon.txt", "EMOJI");
+    opts.optopt(
+        "",
+        "charset",
+        "Charset to add to the MIME type of text files, e.g. iso-8859-1 (default none, meaning UTF-8)",
+        "CHARSET",
+    );
+    opts.optopt(
+        "",
+        "log-level",
+        "Verbosity of logging, one of off, error, warn, info, debug or trace (default info, overridden by RUST_LOG)",
+        "LEVEL",
+    );
+    opts.optmulti(
+        "",
+        "cgi-path",
+        "URL path prefix in which executable files are run as CGI scripts (multiple occurences means multiple prefixes)",
+        "PREFIX",
+    );
+    opts.optflagopt(
+        "",
+        "cgi",
+        "Run executable files ending in .cgi as CGI scripts anywhere in the content directory.",
+        "BOOL",
+    );
+    opts.optmulti(
+        "",
+        "proxy",
+        "Forward requests for a host name and/or URL path prefix to an upstream Gemini server at host:port, e.g. example.org=127.0.0.1:1966 or /app/=127.0.0.1:1966 (multiple occurences means multiple routes)",
+        "[HOST][PREFIX]=UPSTREAM",
+    );
+    opts.optmulti(
+        "",
+        "scgi",
+        "Pass requests for a URL path prefix on to an SCGI application server at host:port or unix:path (multiple occurences means multiple routes)",
+        "PREFIX=ADDRESS",
+    );
+    opts.optmulti(
+        "",
+        "fastcgi",
+        "Pass requests for a URL path prefix on to a FastCGI application server at host:port or unix:path (multiple occurences means multiple routes)",
+        "PREFIX=ADDRESS",
+    );
+    opts.optopt(
+        "",
+        "backend-timeout",
+        "Seconds to wait for a CGI script, SCGI application server, proxied server or HTTP backend to connect or send more of its response (default 30)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "fastcgi-timeout",
+        "Seconds to wait for a FastCGI application server to accept a connection or send more of its response (default 30)",
+        "SECONDS",
+    );
+    opts.optmulti(
+        "",
+        "http-gateway",
+        "Pass requests for a URL path prefix on to an HTTP backend at the given http:// URL and translate its responses (multiple occurences means multiple routes)",
+        "PREFIX=URL",
+    );
+    opts.optmulti(
+        "",
+        "client-cert-area",
+        "URL path prefix that requires a client certificate; certificates are trusted on first use (multiple occurences means multiple areas)",
+        "PREFIX",
+    );
+    opts.optopt(
+        "",
+        "tofu-store",
+        "File to store known client certificates in (default is to only keep them in memory)",
+        "FILE",
+    );
+    opts.optflag(
+        "",
+        "tofu-list",
+        "List the known client certificates from the --tofu-store file and exit.",
+    );
+    opts.optopt(
+        "",
+        "tofu-revoke",
+        "Revoke the client certificate with the given fingerprint in the --tofu-store file and exit.",
+        "FINGERPRINT",
+    );
+    opts.optmulti(
+        "",
+        "spartan",
+        "Additionally serve the content with the Spartan protocol on the given address, the default port is 300 (multiple occurences means multiple listeners)",
+        "IP[:PORT]",
+    );
+    opts.optmulti(
+        "",
+        "gopher",
+        "Additionally serve the content with the Gopher protocol on the given address, the default port is 70 (multiple occurences means multiple listeners)",
+        "IP[:PORT]",
+    );
+    opts.optmulti(
+        "",
+        "titan-area",
+        "URL path prefix that files may be uploaded to with the Titan protocol by clients with a trusted certificate, only by the certificates with the given fingerprints unless --titan-token is given (multiple occurences means multiple areas)",
+        "PREFIX[=FINGERPRINT,...]",
+    );
+    opts.optopt(
+        "",
+        "titan-token",
+        "Token that Titan uploads have to include",
+        "TOKEN",
+    );
+    opts.optopt(
+        "",
+        "titan-max-size",
+        "Maximum size of Titan uploads in bytes (default 10485760)",
+        "BYTES",
+    );
+    opts.optmulti(
+        "",
+        "titan-mime",
+        "Media type that may be uploaded with Titan, type/* allows all subtypes (multiple occurences means multiple types, default is to allow all)",
+        "TYPE",
+    );
+    opts.optopt(
+        "",
+        "access-log",
+        "Append a line for every request to the given file, in a format based on the Common Log Format",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "handshake-timeout",
+        "Seconds to wait for a client to establish the TLS session (default 10)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "request-timeout",
+        "Seconds to wait for a client to send the request once connected, or more of an upload (default 10)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "write-timeout",
+        "Seconds to wait for a client to accept more of the response before closing the connection (default 60)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "bandwidth",
+        "Maximum number of bytes per second sent to each connection (default: unlimited)",
+        "BYTES",
+    );
+    opts.optmulti(
+        "",
+        "bandwidth-limit",
+        "Maximum number of bytes per second sent to each connection for URL paths starting with the prefix, instead of --bandwidth (multiple occurences means multiple limits, the first matching one is used)",
+        "PREFIX=BYTES",
+    );
+    opts.optflagopt(
+        "",
+        "tcp-nodelay",
+        "Send responses right away instead of waiting for more data to fill a packet",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "tcp-keepalive",
+        "Seconds a TCP connection has to be idle before keepalive probes are sent (default: no keepalive)",
+        "SECONDS",
+    );
+    opts.optopt(
+        "",
+        "send-buffer-size",
+        "Size of the send buffer of TCP connections in bytes (default: chosen by the operating system)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "recv-buffer-size",
+        "Size of the receive buffer of TCP connections in bytes (default: chosen by the operating system)",
+        "BYTES",
+    );
+    opts.optopt(
+        "",
+        "listen-backlog",
+        "Maximum number of TCP connections waiting to be accepted (default 1024)",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "max-connections",
+        "Maximum number of simultaneous connections, further connections are only accepted when others are closed",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "max-connections-per-ip",
+        "Maximum number of simultaneous connections from one IP address, further connections are closed right away",
+        "NUMBER",
+    );
+    opts.optopt(
+        "",
+        "rate-limit",
+        "Limit the requests from each IP address to the given average number per second, further requests get status 44",
+        "REQUESTS",
+    );
+    opts.optopt(
+        "",
+        "rate-limit-burst",
+        "Number of requests an IP address can make at once before the rate limit applies (default 10)",
+        "REQUESTS",
+    );
+    opts.optmulti(
+        "",
+        "rate-limit-exempt",
+        "IP address range in CIDR notation that is not rate limited (multiple occurences means multiple ranges)",
+        "CIDR",
+    );
+    opts.optopt(
+        "",
+        "metrics",
+        "Serve metrics for Prometheus at /metrics with HTTP on the given address, e.g. 127.0.0.1:9090",
+        "IP:PORT",
+    );
+    opts.optopt(
+        "",
+        "log-format",
+        "Format of log messages and access log lines, text or json (default text)",
+        "FORMAT",
+    );
+    opts.optopt(
+        "",
+        "log-target",
+        "Where to send log messages, stderr, syslog, journald or eventlog on Windows (default stderr)",
+        "TARGET",
+    );
+    opts.optopt(
+        "",
+        "log-file",
+        "Append log messages to the given file instead of standard error",
+        "FILE",
+    );
+    #[cfg(unix)]
+    opts.optflagopt(
+        "",
+        "daemon",
+        "Fork into the background after listening and detach from the terminal",
+        "BOOL",
+    );
+    #[cfg(unix)]
+    opts.optopt(
+        "",
+        "pidfile",
+        "Write the process ID to the given file, which is removed when stopped with SIGTERM or SIGINT",
+        "FILE",
+    );
+    #[cfg(windows)]
+    opts.optflag(
+        "",
+        "service",
+        "Run as a Windows service, when started by the service control manager",
+    );
+    #[cfg(windows)]
+    opts.optflag(
+        "",
+        "install-service",
+        "Install a Windows service that runs Agate with the other options given, and exit",
+    );
+    #[cfg(windows)]
+    opts.optflag(
+        "",
+        "uninstall-service",
+        "Uninstall the Windows service and exit",
+    );
+    opts.optflag("h", "help", "Print this help text and exit.");
+    opts.optflag("V", "version", "Print version information and exit.");
+    opts.optflagopt(
+        "3",
+        "only-tls13",
+        "Only use TLSv1.3 (default also allows TLSv1.2)",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "key-log-file",
+        "Append the TLS secrets of all connections to this file in the NSS key log format to decrypt the traffic for debugging, also enabled by the SSLKEYLOGFILE environment variable (default: disabled)",
+        "FILE",
+    );
+    opts.optflagopt(
+        "",
+        "no-session-resumption",
+        "Disable TLS session resumption, so every connection needs a full handshake",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "",
+        "session-tickets",
+        "Use stateless session tickets for TLS session resumption instead of a session cache in memory",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "session-ticket-lifetime",
+        "Seconds a session ticket can be used for resumption, the ticket key is changed after half this time (default 43200)",
+        "SECONDS",
+    );
+    opts.optmulti(
+        "",
+        "cipher-suite",
+        "Allow a TLS cipher suite like TLS13_AES_256_GCM_SHA384 (default: all cipher suites supported by rustls; multiple occurrences allow multiple cipher suites)",
+        "NAME",
+    );
+    opts.optmulti(
+        "",
+        "curve",
+        "Allow a key exchange group like X25519, secp256r1 or secp384r1, multiple occurrences give them in the order of preference (default: all groups supported by rustls)",
+        "NAME",
+    );
+    opts.optflagopt(
+        "",
+        "serve-secret",
+        "Enable serving secret files (files/directories starting with a dot), same as --hidden-files unlisted",
+        "BOOL",
+    );
+    opts.optopt(
+        "",
+        "hidden-files",
+        "What happens to files and directories starting with a dot or matching --hide: serve, unlisted (served but not listed), hide (answered with status 52 unless configured in .meta, the default) or deny (always answered with status 51)",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "symlinks",
+        "Which symbolic links to follow: never, inside (only if the target is inside the content directory) or always (default)",
+        "MODE",
+    );
+    opts.optflagopt(
+        "",
+        "includes",
+        "Replace lines like <!--#include file=\"nav.gmi\"--> in gemtext files with the named file",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "",
+        "templates",
+        "Render files ending in .gmi.tera as gemtext with variables like {{ path }} and {{ query }}",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "",
+        "markdown",
+        "Convert files ending in .md or .markdown to gemtext",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "",
+        "case-insensitive",
+        "Match URL paths to files regardless of their case and redirect to the actual spelling",
+        "BOOL",
+    );
+    opts.optmulti(
+        "",
+        "hide",
+        "Also hide files and directories with a name matching the glob pattern, e.g. *.bak (multiple occurences means multiple patterns)",
+        "PATTERN",
+    );
+    opts.optflagopt(
+        "",
+        "serve-dir-listings",
+        "List the contents of all directories without an index file, not only those containing a .directory-listing-ok file.",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "",
+        "dir-listing-details",
+        "Show file sizes and modification dates in directory listings.",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "",
+        "log-ip",
+        "Output the remote IP address when logging.",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "C",
+        "central-conf",
+        "Use a central .meta file in the content root directory. Decentral config files will be ignored.",
+    "BOOL",
+    );
+    opts.optflagopt(
+        "e",
+        "ed25519",
+        "Generate keys using the Ed25519 signature algorithm instead of the default ECDSA.",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "",
+        "skip-port-check",
+        "Skip URL port check even when a hostname is specified.",
+        "BOOL",
+    );
+    opts.optflagopt(
+        "",
+        "proxy-protocol",
+        "Expect a PROXY protocol header (version 1 or 2) at the start of every TCP connection, as sent by load balancers like HAProxy, and use the client address from it.",
+        "BOOL",
+    );
+
+    let matches = config::Matches::parse(&opts, &args[1..])?;
+
+    if matches.opt_present("h") {
+        eprintln!(
+            "{}",
+            opts.usage(&format!(
+                "Usage: {} [options]\n\n\
+                Options can also be given in the configuration file, using the long\n\
+                option names as keys. Options given on the command line take\n\
+                precedence. Flags accept an optional value like --log-ip=false to\n\
+                override the configuration file.",
+                &args[0]
+            ))
+        );
+        std::process::exit(0);
+    }
+
+    if matches.opt_present("V") {
+        eprintln!("agate {}", env!("CARGO_PKG_VERSION"));
+        std::process::exit(0);
+    }
+
+    let log_level = matches.opt_get_default("log-level", log::LevelFilter::Info)?;
+    let log_format = matches.opt_get_default("log-format", logging::LogFormat::Text)?;
+    #[cfg(windows)]
+    let service = if matches.opt_present("service") {
+        Some(service::Command::Run)
+    } else if matches.opt_present("install-service") {
+        let args = args[1..]
+            .iter()
+            .filter(|arg| *arg != "--install-service")
+            .cloned()
+            .chain(["--service".to_string()])
+            .collect();
+        Some(service::Command::Install(args))
+    } else if matches.opt_present("uninstall-service") {
+        Some(service::Command::Uninstall)
+    } else {
+        None
+    };
+    // a service has no console to log to
+    #[cfg(windows)]
+    let default_log_target = match service {
+        Some(service::Command::Run) => logging::LogTarget::EventLog,
+        _ => logging::LogTarget::Stderr,
+    };
+    #[cfg(not(windows))]
+    let default_log_target = logging::LogTarget::Stderr;
+    let log_target = matches.opt_get_default("log-target", default_log_target)?;
+    let log_file = matches.opt_str("log-file").map(PathBuf::from);
+    if log_file.is_some() && log_target != logging::LogTarget::Stderr {
+        return Err("--log-file can only be used with --log-target stderr".into());
+    }
+    logging::init(log_level, log_format, log_target, log_file.as_deref())?;
+
+    let tofu = match matches.opt_str("tofu-store") {
+        Some(path) => tofu::TofuStore::load(path.into())?,
+        None => tofu::TofuStore::new(),
+    };
+    if matches.opt_present("tofu-list") {
+        for (fingerprint, identity) in tofu.identities() {
+            let first_seen = std::time::UNIX_EPOCH + Duration::from_secs(identity.first_seen);
+            println!(
+                "{fingerprint} {}{}",
+                humantime::format_rfc3339_seconds(first_seen),
+                if identity.revoked { " revoked" } else { "" }
+            );
+        }
+        std::process::exit(0);
+    }
+    if let Some(fingerprint) = matches.opt_str("tofu-revoke") {
+        tofu.revoke(&fingerprint)?;
+        eprintln!("Revoked client certificate {fingerprint}");
+        std::process::exit(0);
+    }
+
+    let titan_areas: Vec<titan::Area> = matches
+        .opt_strs("titan-area")
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<_, _>>()?;
+    let titan_token = matches.opt_str("titan-token");
+    // any certificate is trusted on first use, so without fingerprints or a
+    // token anybody could change the files
+    if let Some(area) = titan_areas
+        .iter()
+        .find(|area| area.fingerprints.is_empty() && titan_token.is_none())
+    {
+        return Err(format!(
+            "--titan-area {} needs certificate fingerprints or --titan-token",
+            area.prefix
+        )
+        .into());
+    }
+
+    // try to open the certificate directory
+    let certs_path = matches.opt_get_default("certs", ".certificates".to_string())?;
+    let (certs, certs_path) = match check_path(certs_path.clone()) {
+        // the directory exists, try to load certificates
+        Ok(certs_path) => match certificates::CertStore::load_from(&certs_path) {
+            // all is good
+            Ok(certs) => (Some(certs), certs_path),
+            // the certificate directory did not contain certificates, but we can generate some
+            // because the hostname option was given
+            Err(certificates::CertLoadError::Empty) if matches.opt_present("hostname") => {
+                (None, certs_path)
+            }
+            // failed loading certificates or missing hostname to generate them
             Err(e) => return Err(e.into()),
         },
         // the directory does not exist
@@ -282,450 +1562,2720 @@ fn args() -> Result<Args> {
             // we just created the directory, skip loading from it
             (None, PathBuf::from(certs_path))
         }
-    };
+    };
+
+    // If we have not loaded any certificates yet, we have to try to reload them later.
+    // This ensures we get the right error message.
+    let mut reload_certs = certs.is_none();
+
+    let mut hostnames = vec![];
+    for s in matches.opt_strs("hostname") {
+        // normalize hostname, add punycoding if necessary
+        let hostname = Host::parse(&s)?;
+
+        // check if we have a certificate for that domain
+        if let Host::Domain(ref domain) = hostname {
+            if !matches!(certs, Some(ref certs) if certs.has_domain(domain)) {
+                log::info!("No certificate or key found for {:?}, generating them.", s);
+
+                let mut cert_params = CertificateParams::new(vec![domain.clone()])?;
+                cert_params
+                    .distinguished_name
+                    .push(DnType::CommonName, domain);
+
+                // <CertificateParams as Default>::default() already implements a
+                // date in the far future from the time of writing: 4096-01-01
+
+                let key_pair = if matches.opt_flag("e")? {
+                    KeyPair::generate_for(&rcgen::PKCS_ED25519)
+                } else {
+                    KeyPair::generate()
+                }?;
+
+                // generate the certificate with the configuration
+                let cert = cert_params.self_signed(&key_pair)?;
+
+                // make sure the certificate directory exists
+                fs::create_dir(certs_path.join(domain))?;
+                // write certificate data to disk
+                let mut cert_file = File::create(certs_path.join(format!(
+                    "{}/{}",
+                    domain,
+                    certificates::CERT_FILE_NAME
+                )))?;
+                cert_file.write_all(cert.der())?;
+                // write key data to disk
+                let key_file_path =
+                    certs_path.join(format!("{}/{}", domain, certificates::KEY_FILE_NAME));
+                let mut key_file = File::create(&key_file_path)?;
+                #[cfg(unix)]
+                {
+                    // set permissions so only owner can read
+                    match key_file.set_permissions(std::fs::Permissions::from_mode(0o400)) {
+                        Ok(_) => (),
+                        Err(_) => log::warn!(
+                            "could not set permissions for new key file {}",
+                            key_file_path.display()
+                        ),
+                    }
+                }
+                key_file.write_all(key_pair.serialized_der())?;
+
+                reload_certs = true;
+            }
+        }
+
+        hostnames.push(hostname);
+    }
+
+    // if new certificates were generated, reload the certificate store
+    let certs = if reload_certs {
+        certificates::CertStore::load_from(&certs_path)?
+    } else {
+        // there must already have been certificates loaded
+        certs.unwrap()
+    };
+
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut content_dir = check_path(matches.opt_get_default("content", "content".into())?)?;
+    let mut vhosts = BTreeMap::new();
+    for (hostname, vhost_matches) in matches.vhosts() {
+        // use the same normalization as for the hostnames above
+        let hostname = Host::parse(hostname)?.to_string();
+        let content_dir = vhost_matches
+            .opt_str("content")
+            .map(check_path)
+            .transpose()?;
+        let rewrite_rules = vhost_matches
+            .opt_strs("rewrite")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?;
+        vhosts.insert(
+            hostname,
+            VHost {
+                content_dir,
+                rewrite_rules,
+                case_insensitive: vhost_matches.opt_present("case-insensitive"),
+                charset: vhost_matches
+                    .opt_str("charset")
+                    .map(check_charset)
+                    .transpose()?,
+                language: vhost_matches.opt_str("lang"),
+                lang_variants: vhost_matches
+                    .opt_strs("lang-variant")
+                    .into_iter()
+                    .map(language::check)
+                    .collect::<Result<_, _>>()?,
+                robots_rules: vhost_matches
+                    .opt_strs("robots")
+                    .iter()
+                    .map(|s| s.parse())
+                    .collect::<Result<_, _>>()?,
+                favicon: vhost_matches
+                    .opt_str("favicon")
+                    .map(check_favicon)
+                    .transpose()?,
+            },
+        );
+    }
+
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut aliases = matches
+        .opt_strs("alias")
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<alias::Alias>, _>>()?;
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut archives = matches
+        .opt_strs("archive")
+        .iter()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<archive::Archive>, _>>()?;
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut content_layers = matches
+        .opt_strs("content-layer")
+        .into_iter()
+        .map(PathBuf::from)
+        .collect::<Vec<_>>();
+    if let Some(layer) = content_layers.iter().find(|layer| !layer.is_dir()) {
+        return Err(format!("content layer {layer:?} is not a directory").into());
+    }
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut user_dirs = matches
+        .opt_str("user-dirs")
+        .map(|s| s.parse::<userdir::UserDirs>())
+        .transpose()?;
+
+    // after changing the root directory, all content directories have to be
+    // given relative to the new root
+    #[cfg(unix)]
+    let chroot = if matches.opt_flag("chroot")? {
+        let root = content_dir.canonicalize()?;
+        for (hostname, vhost) in vhosts.iter_mut() {
+            if let Some(dir) = &mut vhost.content_dir {
+                let relative = dir.canonicalize()?;
+                let relative = relative.strip_prefix(&root).map_err(|_| {
+                    format!(
+                        "The content directory of {hostname} is outside of the chroot directory"
+                    )
+                })?;
+                *dir = Path::new("/").join(relative);
+            }
+        }
+        for alias in aliases.iter_mut() {
+            let relative = alias.dir.canonicalize()?;
+            let relative = relative.strip_prefix(&root).map_err(|_| {
+                format!(
+                    "The directory of the alias {} is outside of the chroot directory",
+                    alias.prefix
+                )
+            })?;
+            alias.dir = Path::new("/").join(relative);
+        }
+        for archive in archives.iter_mut() {
+            let relative = archive.file.canonicalize()?;
+            let relative = relative.strip_prefix(&root).map_err(|_| {
+                format!(
+                    "The archive of {} is outside of the chroot directory",
+                    archive.prefix
+                )
+            })?;
+            archive.file = Path::new("/").join(relative);
+        }
+        for layer in content_layers.iter_mut() {
+            let relative = layer.canonicalize()?;
+            let relative = relative.strip_prefix(&root).map_err(|_| {
+                format!("The content layer {layer:?} is outside of the chroot directory")
+            })?;
+            *layer = Path::new("/").join(relative);
+        }
+        if let Some(user_dirs) = &mut user_dirs {
+            let relative = user_dirs.base().canonicalize()?;
+            let relative = relative.strip_prefix(&root).map_err(|_| {
+                "The user directories are outside of the chroot directory".to_string()
+            })?;
+            user_dirs.set_base(&Path::new("/").join(relative));
+        }
+        content_dir = "/".into();
+        Some(root)
+    } else {
+        None
+    };
+
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut cert_reload_interval =
+        Duration::from_secs(matches.opt_get_default("cert-reload-interval", 30)?);
+    // the certificate directory can not be read anymore
+    #[cfg(unix)]
+    if chroot.is_some() && !cert_reload_interval.is_zero() {
+        log::info!("Certificates are not reloaded because of --chroot");
+        cert_reload_interval = Duration::ZERO;
+    }
+
+    let mut index_files = matches.opt_strs("index");
+    if index_files.is_empty() {
+        index_files.push("index.gmi".into());
+    }
+    if let Some(name) = index_files
+        .iter()
+        .find(|name| name.contains(path::is_separator))
+    {
+        return Err(format!("Index file name must not contain a path separator: {name:?}").into());
+    }
+
+    // parse listening addresses
+    let mut addrs = vec![];
+    for i in matches.opt_strs("addr") {
+        addrs.push(i.parse()?);
+    }
+
+    let mut spartan_addrs = vec![];
+    for i in matches.opt_strs("spartan") {
+        spartan_addrs.push(match i.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, spartan::DEFAULT_PORT),
+            Err(_) => i.parse()?,
+        });
+    }
+    let mut gopher_addrs = vec![];
+    for i in matches.opt_strs("gopher") {
+        gopher_addrs.push(match i.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, gopher::DEFAULT_PORT),
+            Err(_) => i.parse()?,
+        });
+    }
+
+    #[cfg_attr(not(unix), allow(unused_mut))]
+    let mut empty = addrs.is_empty();
+
+    #[cfg(unix)]
+    let mut sockets = vec![];
+    #[cfg(unix)]
+    let mut plain_sockets = vec![];
+    #[cfg(unix)]
+    {
+        for i in matches.opt_strs("socket") {
+            sockets.push(i.parse()?);
+        }
+        for i in matches.opt_strs("plain-socket") {
+            plain_sockets.push(i.parse()?);
+        }
+
+        empty &= sockets.is_empty() && plain_sockets.is_empty();
+    }
+
+    #[cfg(unix)]
+    let user = matches.opt_str("user");
+    #[cfg(unix)]
+    let group = matches.opt_str("group");
+    #[cfg(unix)]
+    let account = if user.is_some() || group.is_some() {
+        Some(privileges::Account::lookup(
+            user.as_deref(),
+            group.as_deref(),
+        )?)
+    } else {
+        None
+    };
+
+    if empty {
+        addrs = vec![
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEFAULT_PORT),
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DEFAULT_PORT),
+        ];
+    }
+
+    let key_log = match matches.opt_str("key-log-file").or_else(|| {
+        std::env::var("SSLKEYLOGFILE")
+            .ok()
+            .filter(|path| !path.is_empty())
+    }) {
+        Some(path) => {
+            let file = keylog::KeyLogFile::open(Path::new(&path))
+                .map_err(|e| format!("could not open key log file {path:?}: {e}"))?;
+            log::warn!("Writing TLS secrets to {path:?}, they allow decrypting all connections");
+            Some(Arc::new(file))
+        }
+        None => None,
+    };
+
+    let only_tls13 = matches.opt_flag("only-tls13")?;
+    let tls_provider = Arc::new(crypto_provider(
+        &matches.opt_strs("cipher-suite"),
+        &matches.opt_strs("curve"),
+    )?);
+    // fails if none of the cipher suites can be used with the TLS versions
+    ServerConfig::builder_with_provider(tls_provider.clone())
+        .with_protocol_versions(tls_versions(only_tls13))
+        .map_err(|e| format!("Invalid TLS configuration: {e}"))?;
+
+    Ok(Args {
+        addrs,
+        spartan_addrs,
+        gopher_addrs,
+        #[cfg(unix)]
+        sockets,
+        #[cfg(unix)]
+        plain_sockets,
+        #[cfg(unix)]
+        account,
+        #[cfg(unix)]
+        chroot,
+        #[cfg(target_os = "linux")]
+        sandbox: matches.opt_flag("sandbox")?,
+        #[cfg(unix)]
+        daemon: matches.opt_flag("daemon")?,
+        #[cfg(unix)]
+        pid_file: matches.opt_str("pidfile").map(PathBuf::from),
+        #[cfg(windows)]
+        service,
+        content_dir,
+        certs: Arc::new(certs),
+        certs_dir: certs_path,
+        cert_expiry_warning: Duration::from_secs(
+            matches.opt_get_default::<u64>("cert-expiry-warning", 30)? * 24 * 60 * 60,
+        ),
+        cert_reload_interval,
+        hostnames,
+        vhosts,
+        language: matches.opt_str("lang"),
+        includes: matches.opt_flag("includes")?,
+        templates: matches.opt_flag("templates")?,
+        markdown: matches.opt_flag("markdown")?,
+        lang_variants: matches
+            .opt_strs("lang-variant")
+            .into_iter()
+            .map(language::check)
+            .collect::<Result<_, _>>()?,
+        charset: matches.opt_str("charset").map(check_charset).transpose()?,
+        index_files,
+        cgi_paths: matches.opt_strs("cgi-path"),
+        cgi: matches.opt_flag("cgi")?,
+        proxy_routes: matches
+            .opt_strs("proxy")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        scgi_routes: matches
+            .opt_strs("scgi")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        fastcgi_routes: matches
+            .opt_strs("fastcgi")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        backend_timeout: Duration::from_secs(matches.opt_get_default("backend-timeout", 30)?),
+        fastcgi_timeout: Duration::from_secs(matches.opt_get_default("fastcgi-timeout", 30)?),
+        handshake_timeout: Duration::from_secs(matches.opt_get_default("handshake-timeout", 10)?),
+        request_timeout: Duration::from_secs(matches.opt_get_default("request-timeout", 10)?),
+        write_timeout: Duration::from_secs(matches.opt_get_default("write-timeout", 60)?),
+        bandwidth: match matches.opt_get_default("bandwidth", 0)? {
+            0 => None,
+            rate => Some(rate),
+        },
+        bandwidth_limits: matches
+            .opt_strs("bandwidth-limit")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        socket_options: sockopts::SocketOptions {
+            nodelay: matches.opt_flag("tcp-nodelay")?,
+            keepalive: matches
+                .opt_str("tcp-keepalive")
+                .map(|s| s.parse().map(Duration::from_secs))
+                .transpose()?,
+            send_buffer_size: matches
+                .opt_str("send-buffer-size")
+                .map(|s| s.parse())
+                .transpose()?,
+            recv_buffer_size: matches
+                .opt_str("recv-buffer-size")
+                .map(|s| s.parse())
+                .transpose()?,
+        },
+        listen_backlog: matches.opt_get_default("listen-backlog", 1024)?,
+        http_routes: matches
+            .opt_strs("http-gateway")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        client_cert_areas: matches.opt_strs("client-cert-area"),
+        tofu,
+        titan_areas,
+        titan_token,
+        titan_max_size: matches.opt_get_default("titan-max-size", 10 * 1024 * 1024)?,
+        file_cache: match matches.opt_get_default("cache-size", 0)? {
+            0 => None,
+            size => Some(cache::FileCache::new(
+                size,
+                matches.opt_get_default("cache-max-file-size", 64 * 1024)?,
+            )),
+        },
+        worker_threads: threads(&matches, "worker-threads")?,
+        blocking_threads: threads(&matches, "blocking-threads")?,
+        #[cfg(target_os = "linux")]
+        acceptors: match matches.opt_get_default("acceptors", 1)? {
+            0 => return Err("--acceptors must be larger than 0".into()),
+            acceptors => acceptors,
+        },
+        file_buffer_size: match matches.opt_get_default("file-buffer-size", 64 * 1024)? {
+            0 => return Err("--file-buffer-size must be larger than 0".into()),
+            size => size,
+        },
+        max_response_size: matches
+            .opt_str("max-response-size")
+            .map(|s| s.parse())
+            .transpose()?,
+        truncate_large_responses: matches.opt_flag("truncate-large-responses")?,
+        stat_cache: match matches.opt_get_default("stat-cache-ttl", 0)? {
+            0 => None,
+            ttl => Some(cache::StatCache::new(Duration::from_secs(ttl))),
+        },
+        titan_mime_types: matches.opt_strs("titan-mime"),
+        mime_types: match matches.opt_str("mime-types") {
+            Some(path) => mimetypes::MimeTypes::load(Path::new(&path))?,
+            None => mimetypes::MimeTypes::new(),
+        },
+        rewrite_rules: matches
+            .opt_strs("rewrite")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        redirects: match matches.opt_str("redirects") {
+            Some(path) => redirects::Redirects::load(Path::new(&path))?,
+            None => redirects::Redirects::default(),
+        },
+        robots_rules: matches
+            .opt_strs("robots")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        favicon: matches.opt_str("favicon").map(check_favicon).transpose()?,
+        gone: matches
+            .opt_strs("gone")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        aliases,
+        archives,
+        feeds: matches
+            .opt_strs("feed")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        blogs: matches
+            .opt_strs("blog")
+            .iter()
+            .map(|s| s.parse())
+            .collect::<Result<_, _>>()?,
+        sitemaps: match matches.opt_strs("sitemap") {
+            paths if paths.is_empty() => None,
+            paths => Some(sitemap::Sitemaps::new(
+                paths.iter().map(|s| s.parse()).collect::<Result<_, _>>()?,
+            )),
+        },
+        search: matches
+            .opt_str("search")
+            .map(search::Search::new)
+            .transpose()?,
+        content_layers,
+        user_dirs,
+        maintenance: matches
+            .opt_str("maintenance-file")
+            .map(|path| maintenance::Maintenance::new(PathBuf::from(path))),
+        hidden_files: hidden::HiddenFiles::new(
+            match matches.opt_str("hidden-files") {
+                Some(mode) => mode.parse()?,
+                None if matches.opt_flag("serve-secret")? => hidden::Mode::Unlisted,
+                None => hidden::Mode::Hide,
+            },
+            &matches.opt_strs("hide"),
+        )?,
+        case_insensitive: matches.opt_flag("case-insensitive")?,
+        symlinks: matches
+            .opt_str("symlinks")
+            .map_or(Ok(symlinks::Mode::Always), |mode| mode.parse())?,
+        serve_dir_listings: matches.opt_flag("serve-dir-listings")?,
+        dir_listing_details: matches.opt_flag("dir-listing-details")?,
+        log_ips: matches.opt_flag("log-ip")?,
+        only_tls13,
+        key_log,
+        session_resumption: !matches.opt_flag("no-session-resumption")?,
+        session_tickets: if matches.opt_flag("session-tickets")? {
+            Some(Duration::from_secs(
+                matches.opt_get_default("session-ticket-lifetime", 12 * 60 * 60)?,
+            ))
+        } else {
+            None
+        },
+        tls_provider,
+        central_config: matches.opt_flag("central-conf")?,
+        access_log: matches
+            .opt_str("access-log")
+            .map(|path| accesslog::AccessLog::open(Path::new(&path), log_format))
+            .transpose()?,
+        skip_port_check: matches.opt_flag("skip-port-check")?,
+        proxy_protocol: matches.opt_flag("proxy-protocol")?,
+        connection_limits: limits::ConnectionLimits::new(
+            matches
+                .opt_str("max-connections")
+                .map(|s| s.parse())
+                .transpose()?,
+            matches
+                .opt_str("max-connections-per-ip")
+                .map(|s| s.parse())
+                .transpose()?,
+        ),
+        rate_limit: matches
+            .opt_str("rate-limit")
+            .map(|rate| -> Result<_> {
+                let rate = rate.parse()?;
+                let burst = matches.opt_get_default("rate-limit-burst", 10.0)?;
+                let exempt = matches
+                    .opt_strs("rate-limit-exempt")
+                    .iter()
+                    .map(|s| s.parse())
+                    .collect::<Result<_, _>>()?;
+                Ok(ratelimit::RateLimiter::new(rate, burst, exempt)?)
+            })
+            .transpose()?,
+        metrics_addr: matches
+            .opt_str("metrics")
+            .map(|addr| addr.parse())
+            .transpose()?,
+    })
+}
+
+/// Formats a file size in bytes for humans, using binary prefixes.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+fn check_path(s: String) -> Result<PathBuf, String> {
+    let p = PathBuf::from(s);
+    if p.as_path().exists() {
+        Ok(p)
+    } else {
+        Err(format!("No such file: {p:?}"))
+    }
+}
+
+/// Checks that the charset can be used as a MIME parameter value.
+fn check_charset(s: String) -> Result<String, String> {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c))
+    {
+        Ok(s)
+    } else {
+        Err(format!("invalid charset {s:?}"))
+    }
+}
+
+/// Checks that the favicon is not empty and has no whitespace or control
+/// characters, so it is a single line.
+fn check_favicon(s: String) -> Result<String, String> {
+    if !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        Ok(s)
+    } else {
+        Err(format!("invalid favicon {s:?}"))
+    }
+}
+
+/// Parses a number of threads, which has to be at least one.
+fn threads(matches: &config::Matches, name: &str) -> Result<Option<usize>> {
+    match matches.opt_str(name).map(|s| s.parse()) {
+        None => Ok(None),
+        Some(Ok(0) | Err(_)) => Err(format!("--{name} must be a number larger than 0").into()),
+        Some(Ok(threads)) => Ok(Some(threads)),
+    }
+}
+
+/// Accepts Gemini connections on the TCP listener and handles them.
+async fn serve(listener: TcpListener, metadata: Arc<Mutex<FileOptions>>) {
+    let addr = listener.local_addr().unwrap();
+    log::info!("Started listener on {addr}");
+
+    loop {
+        let slot = ARGS.connection_limits.slot().await;
+        let (mut stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                accept_failed(&addr.to_string(), e).await;
+                continue;
+            }
+        };
+        let metadata = metadata.clone();
+        spawn_connection(format!("{peer_addr} on {addr}"), async move {
+            let idle = &ARGS.connection_limits.idle();
+            unless_shed(idle, async move {
+                let Some((connection, local_addr, peer_addr)) =
+                    admit(&mut stream, peer_addr, slot).await
+                else {
+                    return;
+                };
+                let _connection = (connection, metrics::ActiveConnection::new());
+                match RequestHandle::new(stream, local_addr, peer_addr, metadata).await {
+                    Ok(handle) => handle.handle(idle).await,
+                    Err(log_line) => {
+                        log::warn!("{}", log_line);
+                    }
+                }
+            })
+            .await;
+        });
+    }
+}
+
+/// Accepts connections for a protocol without TLS, like Spartan or Gopher,
+/// on the TCP listener and handles them.
+async fn serve_plain(listener: TcpListener, protocol: Protocol, metadata: Arc<Mutex<FileOptions>>) {
+    let addr = listener.local_addr().unwrap();
+    log::info!("Started {protocol:?} listener on {addr}");
+
+    loop {
+        let slot = ARGS.connection_limits.slot().await;
+        let (mut stream, peer_addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                accept_failed(&addr.to_string(), e).await;
+                continue;
+            }
+        };
+        let metadata = metadata.clone();
+        spawn_connection(format!("{peer_addr} on {addr}"), async move {
+            let idle = &ARGS.connection_limits.idle();
+            unless_shed(idle, async move {
+                let Some((connection, local_addr, peer_addr)) =
+                    admit(&mut stream, peer_addr, slot).await
+                else {
+                    return;
+                };
+                let _connection = (connection, metrics::ActiveConnection::new());
+                RequestHandle::new_plain(stream, local_addr, peer_addr, protocol, metadata)
+                    .handle(idle)
+                    .await;
+            })
+            .await;
+        });
+    }
+}
+
+/// Accepts Gemini connections on the Unix socket listener and handles them,
+/// with or without TLS.
+#[cfg(unix)]
+async fn serve_unix(listener: UnixListener, tls: bool, metadata: Arc<Mutex<FileOptions>>) {
+    let path = listener
+        .local_addr()
+        .ok()
+        .and_then(|addr| Some(addr.as_pathname()?.display().to_string()))
+        .unwrap_or_default();
+    if tls {
+        log::info!("Started listener on {path}");
+    } else {
+        log::info!("Started listener without TLS on {path}");
+    }
+
+    loop {
+        let slot = ARGS.connection_limits.slot().await;
+        let (stream, _) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                accept_failed(&path, e).await;
+                continue;
+            }
+        };
+        // IP addresses are not known for Unix sockets
+        let connection = ARGS.connection_limits.admit(slot, None);
+        let metadata = metadata.clone();
+        spawn_connection(path.clone(), async move {
+            let _connection = (connection, metrics::ActiveConnection::new());
+            let idle = &ARGS.connection_limits.idle();
+            unless_shed(idle, async move {
+                if !tls {
+                    RequestHandle::new_unix_plain(stream, metadata)
+                        .handle(idle)
+                        .await;
+                    return;
+                }
+                match RequestHandle::new_unix(stream, metadata).await {
+                    Ok(handle) => handle.handle(idle).await,
+                    Err(log_line) => {
+                        log::warn!("{}", log_line);
+                    }
+                }
+            })
+            .await;
+        });
+    }
+}
+
+/// Spawns a task that handles a connection. A panic while handling it is
+/// logged and counted, and only closes this connection.
+fn spawn_connection(
+    context: String,
+    connection: impl std::future::Future<Output = ()> + Send + 'static,
+) {
+    tokio::spawn(async move {
+        if let Err(panic) = std::panic::AssertUnwindSafe(connection)
+            .catch_unwind()
+            .await
+        {
+            let message = panic
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown error");
+            log::error!("panic while handling the connection from {context}: {message}");
+            metrics::METRICS.panic();
+        }
+    });
+}
+
+/// The number of idle connections that are closed when a new connection can
+/// not be accepted because file descriptors or memory ran out.
+const SHED_CONNECTIONS: usize = 16;
+
+/// Handles an error accepting a new connection, so the listener keeps
+/// serving. When there are not enough file descriptors or memory, the
+/// oldest idle connections are closed to make room for new ones.
+async fn accept_failed(listener: &str, e: std::io::Error) {
+    if resources_exhausted(&e) {
+        let shed = ARGS.connection_limits.shed_idle(SHED_CONNECTIONS);
+        log::error!(
+            "could not accept new connection on {listener}: {e}, closed {shed} idle connections"
+        );
+        // give the closed connections time to release their resources
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    } else if matches!(
+        e.kind(),
+        ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted
+    ) {
+        // the client went away before the connection was accepted
+        log::debug!("could not accept new connection on {listener}: {e}");
+    } else {
+        log::error!("could not accept new connection on {listener}: {e}");
+        // do not fill the log if the error persists
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+}
+
+/// Checks if the error means that there are too many open files or not
+/// enough memory.
+fn resources_exhausted(e: &std::io::Error) -> bool {
+    if e.kind() == ErrorKind::OutOfMemory {
+        return true;
+    }
+    #[cfg(unix)]
+    return matches!(
+        e.raw_os_error(),
+        Some(libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM)
+    );
+    // WSAEMFILE and WSAENOBUFS
+    #[cfg(windows)]
+    return matches!(e.raw_os_error(), Some(10024 | 10055));
+    #[cfg(not(any(unix, windows)))]
+    false
+}
+
+/// Handles a connection until it is done, or until it is closed by
+/// [`limits::ConnectionLimits::shed_idle`] while it is still idle.
+async fn unless_shed(idle: &limits::Idle, connection: impl std::future::Future<Output = ()>) {
+    futures_util::future::select(std::pin::pin!(connection), std::pin::pin!(idle.shed())).await;
+}
+
+/// Binds a TCP listener. An IPv6 socket only accepts IPv6 connections if
+/// there is also an IPv4 address with the same port among the given
+/// addresses, so e.g. `[::]:1965` and `0.0.0.0:1965` can be used together.
+/// Otherwise, the default of the system applies, which on many systems means
+/// that the IPv6 unspecified address also accepts IPv4 connections.
+fn bind(
+    addr: SocketAddr,
+    addrs: &[SocketAddr],
+    #[cfg_attr(not(unix), allow(unused_variables))] reuse_port: bool,
+) -> std::io::Result<std::net::TcpListener> {
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        Type::STREAM,
+        Some(SockProtocol::TCP),
+    )?;
+    if addr.is_ipv6() && addrs.iter().any(|a| a.is_ipv4() && a.port() == addr.port()) {
+        socket.set_only_v6(true)?;
+    }
+    // like the standard library, allow restarting while old connections are
+    // still in the TIME_WAIT state
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    // allow several sockets on the same address
+    #[cfg(unix)]
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(ARGS.listen_backlog)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket.into())
+}
+
+/// TLS configuration.
+static TLS: Lazy<TlsAcceptor> = Lazy::new(acceptor);
+
+/// Returns the default crypto provider restricted to the given cipher suites
+/// and key exchange groups, in the given order. If no names are given, all
+/// of them are kept.
+fn crypto_provider(suites: &[String], groups: &[String]) -> Result<CryptoProvider> {
+    let mut provider = tokio_rustls::rustls::crypto::ring::default_provider();
+    if !suites.is_empty() {
+        provider.cipher_suites = suites
+            .iter()
+            .map(|name| {
+                provider
+                    .cipher_suites
+                    .iter()
+                    .find(|suite| format!("{:?}", suite.suite()).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| format!("Unsupported cipher suite: {name}"))
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    if !groups.is_empty() {
+        provider.kx_groups = groups
+            .iter()
+            .map(|name| {
+                provider
+                    .kx_groups
+                    .iter()
+                    .find(|group| format!("{:?}", group.name()).eq_ignore_ascii_case(name))
+                    .copied()
+                    .ok_or_else(|| format!("Unsupported curve: {name}"))
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(provider)
+}
+
+/// Returns the enabled TLS versions.
+fn tls_versions(only_tls13: bool) -> &'static [&'static SupportedProtocolVersion] {
+    static ONLY_TLS13: &[&SupportedProtocolVersion] = &[&TLS13];
+    if only_tls13 {
+        ONLY_TLS13
+    } else {
+        DEFAULT_VERSIONS
+    }
+}
+
+fn acceptor() -> TlsAcceptor {
+    // the combination of versions and cipher suites was already checked when
+    // parsing the arguments
+    let config = ServerConfig::builder_with_provider(ARGS.tls_provider.clone())
+        .with_protocol_versions(tls_versions(ARGS.only_tls13))
+        .unwrap();
+    // clients are always asked for an optional certificate, because a
+    // directory configuration can require one for any path
+    let mut config = config
+        .with_client_cert_verifier(tofu::AnyClientCert::new())
+        .with_cert_resolver(ARGS.certs.clone());
+    if let Some(key_log) = &ARGS.key_log {
+        config.key_log = key_log.clone();
+    }
+    if !ARGS.session_resumption {
+        config.session_storage = Arc::new(NoServerSessionStorage {});
+        config.send_tls13_tickets = 0;
+    } else if let Some(lifetime) = ARGS.session_tickets {
+        config.ticketer =
+            tickets::ticketer(lifetime / 2).expect("could not create the session ticket key");
+        // sessions are only stored in the tickets
+        config.session_storage = Arc::new(NoServerSessionStorage {});
+    }
+    TlsAcceptor::from(Arc::new(config))
+}
+
+/// The protocol a request is made with.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Protocol {
+    Gemini,
+    Spartan,
+    Gopher,
+}
+
+struct RequestHandle<T> {
+    stream: accesslog::Counter<throttle::Throttle<timeout::IdleTimeout<T>>>,
+    protocol: Protocol,
+    /// When the connection was accepted.
+    start: Instant,
+    /// The host name the client sent with SNI.
+    sni: Option<String>,
+    /// The negotiated TLS version, if the connection uses TLS.
+    tls_version: Option<&'static str>,
+    /// The requested URL, once the request was parsed successfully.
+    url: Option<Url>,
+    /// The literal request, for the access log.
+    request: String,
+    /// The status code of the response, once it was sent.
+    status: Option<u8>,
+    local_addr: Option<SocketAddr>,
+    peer_addr: Option<SocketAddr>,
+    /// Fingerprint of the client certificate, if one was sent.
+    client_cert: Option<String>,
+    /// Common name of the client certificate, if one was sent with one.
+    client_name: Option<String>,
+    local_port_check: Option<u16>,
+    log_line: String,
+    metadata: Arc<Mutex<FileOptions>>,
+}
+
+/// Establishes the TLS session, which has to be done within the time limit.
+async fn handshake<IO>(stream: IO) -> std::io::Result<TlsStream<IO>>
+where
+    IO: AsyncRead + AsyncWrite + Unpin,
+{
+    tokio::time::timeout(ARGS.handshake_timeout, TLS.accept(stream))
+        .await
+        .unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "TLS handshake timed out",
+            ))
+        })
+}
+
+/// Returns the host name the client sent with SNI, if any.
+fn sni<T>(stream: &TlsStream<T>) -> Option<String> {
+    stream.get_ref().1.server_name().map(str::to_string)
+}
+
+/// Returns the negotiated TLS version in the usual notation.
+fn tls_version<T>(stream: &TlsStream<T>) -> Option<&'static str> {
+    match stream.get_ref().1.protocol_version()? {
+        ProtocolVersion::TLSv1_2 => Some("TLSv1.2"),
+        ProtocolVersion::TLSv1_3 => Some("TLSv1.3"),
+        _ => None,
+    }
+}
+
+/// Returns the fingerprint of the client certificate, if one was sent.
+fn client_cert<T>(stream: &TlsStream<T>) -> Option<String> {
+    stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .map(tofu::fingerprint)
+}
+
+/// Reads a gemtext file and expands its includes if they are enabled.
+async fn read_gemtext(path: &Path) -> std::io::Result<Vec<u8>> {
+    if ARGS.includes {
+        include::expand(ARGS.content_root(path), path).await
+    } else {
+        tokio::fs::read(path).await
+    }
+}
+
+/// Returns the common name of the client certificate, if one was sent.
+fn client_name<T>(stream: &TlsStream<T>) -> Option<String> {
+    stream
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(tofu::common_name)
+}
+
+/// Returns the name of the entry in the directory that only differs from the
+/// given name in case, if there is one. If several entries match, the first
+/// one in sorted order is used, so the result does not change randomly.
+async fn find_case_insensitive(dir: &Path, name: &str) -> Option<String> {
+    let mut entries = tokio::fs::read_dir(dir).await.ok()?;
+    let name = name.to_lowercase();
+    let mut found = None::<String>;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let Ok(entry) = entry.file_name().into_string() else {
+            continue;
+        };
+        if entry.to_lowercase() == name && found.as_ref().is_none_or(|found| entry < *found) {
+            found = Some(entry);
+        }
+    }
+    found
+}
+
+/// Admits an accepted TCP connection within the connection limits. With
+/// `--proxy-protocol`, the PROXY protocol header is read first and the
+/// addresses of the original connection are used from then on. Returns the
+/// local and remote address, or `None` if the connection has to be closed,
+/// which is already logged.
+async fn admit(
+    stream: &mut TcpStream,
+    peer_addr: SocketAddr,
+    slot: limits::Slot,
+) -> Option<(limits::Connection, SocketAddr, SocketAddr)> {
+    let Ok(mut local_addr) = stream.local_addr() else {
+        // use nonexistent status code 01 if the addresses are unknown
+        log::warn!("- - \"\" 01 \"IP error\" error:could not get local address");
+        return None;
+    };
+    let mut peer_addr = peer_addr;
+
+    if let Err(e) = ARGS.socket_options.apply(stream) {
+        log::warn!("could not set socket options: {e}");
+    }
+
+    if ARGS.proxy_protocol {
+        let header = tokio::time::timeout(
+            ARGS.handshake_timeout,
+            proxyprotocol::read_header(&mut *stream),
+        )
+        .await
+        .unwrap_or_else(|_| {
+            Err(std::io::Error::new(
+                ErrorKind::TimedOut,
+                "PROXY protocol header timed out",
+            ))
+        });
+        match header {
+            Ok(Some((source, destination))) => {
+                peer_addr = source;
+                local_addr = destination;
+            }
+            // the connection is from the proxy itself
+            Ok(None) => (),
+            Err(e) => {
+                // use nonexistent status code 03 if the PROXY protocol failed
+                log::warn!(
+                    "{} \"\" 03 \"PROXY protocol error\" error:{e}",
+                    tcp_log_line(local_addr, peer_addr)
+                );
+                return None;
+            }
+        }
+    }
+
+    let connection = ARGS.connection_limits.admit(slot, Some(peer_addr.ip()));
+    if connection.is_none() {
+        // use nonexistent status code 02 if the connection was rejected
+        log::warn!(
+            "{} \"\" 02 \"Too many connections\"",
+            tcp_log_line(local_addr, peer_addr)
+        );
+    }
+    Some((connection?, local_addr, peer_addr))
+}
+
+/// Returns the start of the log line for a connection, which contains the
+/// local address and, if desired, the remote IP address.
+fn tcp_log_line(local_addr: SocketAddr, peer_addr: SocketAddr) -> String {
+    if ARGS.log_ips {
+        format!("{local_addr} {}", peer_addr.ip())
+    } else {
+        // Do not log IP address, but something else so columns still line up.
+        format!("{local_addr} -")
+    }
+}
+
+impl RequestHandle<TlsStream<TcpStream>> {
+    /// Creates a new request handle for the given stream. If establishing the TLS
+    /// session fails, returns a corresponding log line.
+    async fn new(
+        stream: TcpStream,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        metadata: Arc<Mutex<FileOptions>>,
+    ) -> Result<Self, String> {
+        let start = Instant::now();
+        let log_line = tcp_log_line(local_addr, peer_addr);
+
+        let local_port_check = if ARGS.skip_port_check {
+            None
+        } else {
+            Some(local_addr.port())
+        };
+
+        match handshake(stream).await {
+            Ok(stream) => Ok(Self {
+                client_cert: client_cert(&stream),
+                client_name: client_name(&stream),
+                local_addr: Some(local_addr),
+                peer_addr: Some(peer_addr),
+                sni: sni(&stream),
+                tls_version: tls_version(&stream),
+                url: None,
+                stream: accesslog::Counter::new(throttle::Throttle::new(
+                    timeout::IdleTimeout::new(stream, ARGS.request_timeout, ARGS.write_timeout),
+                )),
+                protocol: Protocol::Gemini,
+                start,
+                request: String::new(),
+                status: None,
+                local_port_check,
+                log_line,
+                metadata,
+            }),
+            // use nonexistent status code 00 if connection was not established
+            Err(e) => {
+                metrics::METRICS.tls_failure();
+                Err(format!("{log_line} \"\" 00 \"TLS error\" error:{e}"))
+            }
+        }
+    }
+}
+
+impl RequestHandle<TcpStream> {
+    /// Creates a new request handle for a connection with a protocol that
+    /// does not use TLS, like Spartan or Gopher.
+    fn new_plain(
+        stream: TcpStream,
+        local_addr: SocketAddr,
+        peer_addr: SocketAddr,
+        protocol: Protocol,
+        metadata: Arc<Mutex<FileOptions>>,
+    ) -> Self {
+        Self {
+            log_line: tcp_log_line(local_addr, peer_addr),
+            local_addr: Some(local_addr),
+            peer_addr: Some(peer_addr),
+            client_cert: None,
+            client_name: None,
+            sni: None,
+            tls_version: None,
+            url: None,
+            stream: accesslog::Counter::new(throttle::Throttle::new(timeout::IdleTimeout::new(
+                stream,
+                ARGS.request_timeout,
+                ARGS.write_timeout,
+            ))),
+            protocol,
+            start: Instant::now(),
+            request: String::new(),
+            status: None,
+            // Spartan and Gopher requests do not contain a port
+            local_port_check: None,
+            metadata,
+        }
+    }
+}
+
+/// Returns the start of the log line for a connection via a Unix socket.
+#[cfg(unix)]
+fn unix_log_line(stream: &UnixStream) -> String {
+    format!(
+        "unix:{} -",
+        stream
+            .local_addr()
+            .ok()
+            .and_then(|addr| Some(addr.as_pathname()?.to_string_lossy().into_owned()))
+            .unwrap_or_default()
+    )
+}
+
+#[cfg(unix)]
+impl RequestHandle<TlsStream<UnixStream>> {
+    async fn new_unix(
+        stream: UnixStream,
+        metadata: Arc<Mutex<FileOptions>>,
+    ) -> Result<Self, String> {
+        let start = Instant::now();
+        let log_line = unix_log_line(&stream);
+
+        match handshake(stream).await {
+            Ok(stream) => Ok(Self {
+                client_cert: client_cert(&stream),
+                client_name: client_name(&stream),
+                local_addr: None,
+                peer_addr: None,
+                sni: sni(&stream),
+                tls_version: tls_version(&stream),
+                url: None,
+                stream: accesslog::Counter::new(throttle::Throttle::new(
+                    timeout::IdleTimeout::new(stream, ARGS.request_timeout, ARGS.write_timeout),
+                )),
+                protocol: Protocol::Gemini,
+                start,
+                request: String::new(),
+                status: None,
+                // TODO add port check for unix sockets, requires extra arg for port
+                local_port_check: None,
+                log_line,
+                metadata,
+            }),
+            // use nonexistent status code 00 if connection was not established
+            Err(e) => {
+                metrics::METRICS.tls_failure();
+                Err(format!("{} \"\" 00 \"TLS error\" error:{}", log_line, e))
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+impl RequestHandle<UnixStream> {
+    /// Creates a new request handle for a Gemini connection via a Unix socket
+    /// without TLS, which was already terminated by a reverse proxy. Client
+    /// certificates and the SNI name are not known.
+    fn new_unix_plain(stream: UnixStream, metadata: Arc<Mutex<FileOptions>>) -> Self {
+        Self {
+            log_line: unix_log_line(&stream),
+            local_addr: None,
+            peer_addr: None,
+            client_cert: None,
+            client_name: None,
+            sni: None,
+            tls_version: None,
+            url: None,
+            stream: accesslog::Counter::new(throttle::Throttle::new(timeout::IdleTimeout::new(
+                stream,
+                ARGS.request_timeout,
+                ARGS.write_timeout,
+            ))),
+            protocol: Protocol::Gemini,
+            start: Instant::now(),
+            request: String::new(),
+            status: None,
+            local_port_check: None,
+            metadata,
+        }
+    }
+}
+
+/// The error message for Gopher requests of paths that need a client
+/// certificate, input or a CGI script.
+const GEMINI_ONLY: &str = "Only available with Gemini.";
+
+impl<T> RequestHandle<T>
+where
+    T: AsyncWrite + AsyncRead + Unpin,
+{
+    /// Do the necessary actions to handle this request and log the result,
+    /// with a warning if the request could not be finished without errors.
+    async fn handle(mut self, idle: &limits::Idle) {
+        let request = self.parse_request().await;
+        idle.busy();
+        if let Ok((url, _)) = &request {
+            self.url = Some(url.clone());
+            // the limit can not be avoided by encoding characters
+            let url_path = sanitize::normalized(url.path()).unwrap_or_default();
+            let rate = ARGS
+                .bandwidth_limits
+                .iter()
+                .find(|limit| url_path.starts_with(&limit.prefix))
+                .map_or(ARGS.bandwidth, |limit| Some(limit.rate));
+            self.stream.get_mut().set_rate(rate);
+        }
+        let maintenance = ARGS
+            .maintenance
+            .as_ref()
+            .and_then(maintenance::Maintenance::message);
+        // not already in error condition
+        let result = if let (Some(message), Ok(_)) = (&maintenance, &request) {
+            self.send_header(SERVER_UNAVAILABLE, message).await
+        } else if let Some(wait) = self.rate_limit_wait() {
+            // always round up so the client does not retry too early
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            self.send_header(SLOW_DOWN, &secs.to_string()).await
+        } else {
+            match request {
+                Ok((url, received)) if url.scheme() == "titan" => {
+                    self.receive_upload(url, &received).await
+                }
+                Ok((url, _)) if self.protocol == Protocol::Gopher => {
+                    self.send_gopher_response(url).await
+                }
+                Ok((url, _)) => self.send_response(url).await,
+                Err((status, msg)) => self.send_header(status, msg).await,
+            }
+        };
+
+        let duration = self.start.elapsed();
+        let ip = self
+            .peer_addr
+            .filter(|_| ARGS.log_ips)
+            .map(|addr| addr.ip());
+        let host = self.url.as_ref().and_then(Url::host_str);
+        let path = self.url.as_ref().map(Url::path);
+        if let Some(access_log) = &ARGS.access_log {
+            access_log.write(&accesslog::Entry {
+                time: SystemTime::now() - duration,
+                ip,
+                sni: self.sni.as_deref(),
+                host,
+                path,
+                request: &self.request,
+                status: self.status,
+                bytes: self.stream.written(),
+                duration,
+                tls_version: self.tls_version,
+            });
+        }
+
+        metrics::METRICS.request(host, self.status, self.stream.written(), duration);
+
+        let close_result = self.stream.shutdown().await;
+
+        let error = match (result, close_result) {
+            (Err(e), _) => Some(e.to_string()),
+            (Ok(_), Err(e)) => Some(e.to_string()),
+            (Ok(_), Ok(_)) => None,
+        };
+        // the fields are only output with the JSON log format
+        let level = if error.is_some() {
+            log::Level::Warn
+        } else {
+            log::Level::Info
+        };
+        log::log!(
+            level,
+            ip = ip.map(|ip| ip.to_string()).as_deref(),
+            host = host,
+            path = path,
+            status = self.status,
+            size = self.stream.written(),
+            duration_ms = duration.as_millis() as u64,
+            tls_version = self.tls_version,
+            error = error.as_deref();
+            "{}{}",
+            self.log_line,
+            error.as_ref().map_or(String::new(), |e| format!(" error:{e}"))
+        );
+    }
+
+    /// Checks if the client exceeded the rate limit and returns how long it
+    /// has to wait until the next request is allowed.
+    fn rate_limit_wait(&self) -> Option<Duration> {
+        let limiter = ARGS.rate_limit.as_ref()?;
+        // connections via Unix sockets are not limited
+        limiter.check(self.peer_addr?.ip()).err()
+    }
+
+    /// Return the URL requested by the client and the bytes that were received
+    /// after it, which can only be the start of a Titan upload. Spartan and
+    /// Gopher requests are returned as URLs with the `spartan` and `gopher`
+    /// scheme.
+    async fn parse_request(&mut self) -> std::result::Result<(Url, Vec<u8>), (u8, &'static str)> {
+        // Because requests are limited to 1024 bytes (plus 2 bytes for CRLF), we
+        // can use a fixed-sized buffer on the stack, avoiding allocations and
+        // copying, and stopping bad clients from making us use too much memory.
+        let mut request = [0; 1026];
+        let mut len = 0;
+
+        // Read until CRLF, end-of-stream, or there's no buffer space left.
+        //
+        // Since neither CR nor LF can be part of a URI according to
+        // ISOC-RFC 3986, we could use BufRead::read_line here, but that does
+        // not allow us to cap the number of read bytes at 1024+2.
+        //
+        // The whole request has to be received within the time limit, so
+        // clients can not keep connections open by sending it very slowly.
+        let deadline = tokio::time::Instant::now() + ARGS.request_timeout;
+        let protocol = self.protocol;
+        let read_line = async {
+            loop {
+                let bytes_read = match self.stream.read(&mut request[len..]).await {
+                    Ok(bytes_read) => bytes_read,
+                    // the stream may time out just before the deadline
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {
+                        break Err((BAD_REQUEST, "Request timed out"))
+                    }
+                    Err(_) => break Err((BAD_REQUEST, "Request ended unexpectedly")),
+                };
+                len += bytes_read;
+                if let Some(end) = request[..len].windows(2).position(|w| w == b"\r\n") {
+                    break Ok(end);
+                } else if len == request.len() {
+                    break Err((BAD_REQUEST, "Request too long"));
+                } else if protocol == Protocol::Gemini && request[..len].contains(&b'\n') {
+                    // do not wait for a CRLF that will never come
+                    break Err((BAD_REQUEST, "Request does not end with CRLF"));
+                } else if bytes_read == 0 {
+                    break Err((BAD_REQUEST, "Request ended unexpectedly"));
+                }
+            }
+        };
+        let result = tokio::time::timeout_at(deadline, read_line)
+            .await
+            .unwrap_or(Err((BAD_REQUEST, "Request timed out")))
+            .and_then(|end| {
+                std::str::from_utf8(&request[..end])
+                    .map(|url| (url, request[end + 2..len].to_vec()))
+                    .or(Err((BAD_REQUEST, "Non-UTF-8 request")))
+            });
+
+        let (request, received) = result.inspect_err(|_| {
+            // write empty request to log line for uniformity
+            write!(self.log_line, " \"\"").unwrap();
+        })?;
+
+        // log literal request (might be different from or not an actual URL)
+        write!(self.log_line, " \"{request}\"").unwrap();
+        self.request = request.to_string();
+
+        let (mut url, received) = match self.protocol {
+            Protocol::Gemini => {
+                // the URL parser would silently remove surrounding spaces,
+                // tabs and line breaks or encode spaces, but none of them
+                // may be part of a URI, neither may a byte order mark
+                if request.starts_with('\u{feff}')
+                    || request.contains(|c: char| c.is_ascii_control() || c == ' ')
+                {
+                    return Err((BAD_REQUEST, "Invalid URL"));
+                }
+                (
+                    Url::parse(request).or(Err((BAD_REQUEST, "Invalid URL")))?,
+                    received,
+                )
+            }
+            Protocol::Spartan => {
+                let (mut url, len) =
+                    spartan::parse_request(request).ok_or((BAD_REQUEST, "Invalid request"))?;
+                if len > spartan::MAX_DATA {
+                    return Err((BAD_REQUEST, "Request data too large"));
+                }
+                let mut data = received;
+                if data.len() < len {
+                    let start = data.len();
+                    data.resize(len, 0);
+                    tokio::time::timeout_at(deadline, self.stream.read_exact(&mut data[start..]))
+                        .await
+                        .or(Err((BAD_REQUEST, "Request timed out")))?
+                        .map_err(|e| match e.kind() {
+                            ErrorKind::TimedOut => (BAD_REQUEST, "Request timed out"),
+                            _ => (BAD_REQUEST, "Request ended unexpectedly"),
+                        })?;
+                }
+                data.truncate(len);
+                spartan::set_data(&mut url, &data);
+                (url, vec![])
+            }
+            Protocol::Gopher => {
+                // Gopher requests only contain the selector and maybe a
+                // search query, so use the server's own host and port
+                let local_addr = self.local_addr.expect("no local address");
+                let host = match ARGS.hostnames.first() {
+                    Some(hostname) => format!("{hostname}:{}", local_addr.port()),
+                    None => local_addr.to_string(),
+                };
+                let mut url = Url::parse(&format!("gopher://{host}/"))
+                    .or(Err((BAD_REQUEST, "Invalid URL")))?;
+                let (selector, query) = match request.split_once('\t') {
+                    Some((selector, query)) => (selector, Some(query)),
+                    None => (request, None),
+                };
+                url.set_path(selector);
+                url.set_query(query);
+                return Ok((url, received));
+            }
+        };
+
+        // Validate the URL:
+        // correct scheme
+        match (self.protocol, url.scheme()) {
+            (Protocol::Gemini, "gemini") if received.is_empty() => (),
+            (Protocol::Gemini, "gemini") => {
+                return Err((BAD_REQUEST, "Request contains data after the URL"))
+            }
+            (Protocol::Gemini, "titan") if !ARGS.titan_areas.is_empty() => (),
+            (Protocol::Spartan, _) => (),
+            _ => return Err((PROXY_REQUEST_REFUSED, "Unsupported URL scheme")),
+        }
+
+        // no userinfo and no fragment, also no empty userinfo like in
+        // gemini://@example.com/ that the URL parser drops
+        let raw_authority = request
+            .split_once("://")
+            .and_then(|(_, rest)| rest.split(['/', '?', '#']).next())
+            .unwrap_or_default();
+        if url.password().is_some()
+            || !url.username().is_empty()
+            || url.fragment().is_some()
+            || (self.protocol == Protocol::Gemini && raw_authority.contains('@'))
+        {
+            return Err((BAD_REQUEST, "URL contains fragment or userinfo"));
+        }
+
+        // correct host
+        let Some(domain) = url.domain() else {
+            return Err((BAD_REQUEST, "URL does not contain a domain"));
+        };
+        // because the gemini scheme is not special enough for WHATWG, normalize
+        // it ourselves
+        let host = Host::parse(
+            &percent_decode_str(domain)
+                .decode_utf8()
+                .or(Err((BAD_REQUEST, "Invalid URL")))?,
+        )
+        .or(Err((BAD_REQUEST, "Invalid URL")))?;
+        // TODO: simplify when <https://github.com/servo/rust-url/issues/586> resolved
+        url.set_host(Some(&host.to_string()))
+            .expect("invalid domain?");
+        // do not use "contains" here since it requires the same type and does
+        // not allow to check for Host<&str> if the vec contains Hostname<String>
+        if !ARGS.hostnames.is_empty()
+            && !ARGS.hostnames.iter().any(|h| h == &host)
+            && !ARGS
+                .proxy_routes
+                .iter()
+                .any(|route| route.host == Some(host.to_string()))
+        {
+            return Err((PROXY_REQUEST_REFUSED, "Proxy request refused"));
+        }
+        // the certificate the client got for the server name it sent with SNI
+        // has to be the one for the host of the request, otherwise the host
+        // belongs to another capsule on this server
+        if let Some(sni) = &self.sni {
+            if !ARGS.certs.same_certificate(sni, &host.to_string()) {
+                return Err((PROXY_REQUEST_REFUSED, "Proxy request refused"));
+            }
+        }
+
+        // correct port
+        if let Some(expected_port) = self.local_port_check {
+            if let Some(port) = url.port() {
+                // Validate that the port in the URL is the same as for the stream this request
+                // came in on.
+                if port != expected_port {
+                    return Err((PROXY_REQUEST_REFUSED, "Proxy request refused"));
+                }
+            }
+        }
+        Ok((url, received))
+    }
 
-    // If we have not loaded any certificates yet, we have to try to reload them later.
-    // This ensures we get the right error message.
-    let mut reload_certs = certs.is_none();
+    /// Send the client the file located at the requested URL.
+    async fn send_response(&mut self, url: Url) -> Result {
+        // existence of host_str was checked by parse_request already
+        let host = url.host_str().expect("no hostname");
+        let url = match rewrite::apply(ARGS.rewrite_rules(host), &url) {
+            Some(rewrite::Rewritten::Internal(new)) => new,
+            Some(rewrite::Rewritten::Redirect(status, target)) => {
+                return self.send_header(status, target.as_str()).await;
+            }
+            None => url,
+        };
 
-    let mut hostnames = vec![];
-    for s in matches.opt_strs("hostname") {
-        // normalize hostname, add punycoding if necessary
-        let hostname = Host::parse(&s)?;
+        if let Some((status, target)) = ARGS.redirects.find(&url) {
+            return self.send_header(status, target.as_str()).await;
+        }
+        let normalized = sanitize::normalized(url.path());
+        if let Some(gone) = normalized.as_ref().ok().and_then(|url_path| {
+            ARGS.gone
+                .iter()
+                .find(|gone| sanitize::has_prefix(url_path, &gone.prefix))
+        }) {
+            return self.send_header(GONE, &gone.message).await;
+        }
 
-        // check if we have a certificate for that domain
-        if let Host::Domain(ref domain) = hostname {
-            if !matches!(certs, Some(ref certs) if certs.has_domain(domain)) {
-                log::info!("No certificate or key found for {:?}, generating them.", s);
+        if let Some(route) = ARGS
+            .proxy_routes
+            .iter()
+            .find(|route| route.matches(&url, normalized.as_deref().ok()))
+        {
+            return self.run_proxy(&url, route).await;
+        }
 
-                let mut cert_params = CertificateParams::new(vec![domain.clone()])?;
-                cert_params
-                    .distinguished_name
-                    .push(DnType::CommonName, domain);
+        let url_path = match normalized {
+            Ok(url_path) => url_path,
+            Err((status, meta)) => return self.send_header(status, meta).await,
+        };
+        if ARGS
+            .client_cert_areas
+            .iter()
+            .any(|prefix| sanitize::has_prefix(&url_path, prefix))
+            && !self.check_client_cert().await?
+        {
+            return Ok(());
+        }
 
-                // <CertificateParams as Default>::default() already implements a
-                // date in the far future from the time of writing: 4096-01-01
+        if let Some(route) = ARGS
+            .scgi_routes
+            .iter()
+            .find(|route| sanitize::has_prefix(&url_path, &route.prefix))
+        {
+            return self.run_scgi(&url, &url_path, route).await;
+        }
+        if let Some(route) = ARGS
+            .fastcgi_routes
+            .iter()
+            .find(|route| sanitize::has_prefix(&url_path, &route.prefix))
+        {
+            return self.run_fastcgi(&url, &url_path, route).await;
+        }
+        if let Some(route) = ARGS
+            .http_routes
+            .iter()
+            .find(|route| sanitize::has_prefix(&url_path, &route.prefix))
+        {
+            return self.run_http(&url, &url_path, route).await;
+        }
+        // a generated robots.txt takes precedence over a file
+        let robots_rules = ARGS.robots_rules(url.host_str().expect("no hostname"));
+        if url.path() == "/robots.txt" && !robots_rules.is_empty() {
+            let robots = robots::generate(robots_rules);
+            self.send_header(SUCCESS, "text/plain").await?;
+            self.stream.write_all(robots.as_bytes()).await?;
+            return Ok(());
+        }
+        // a favicon from the configuration takes precedence over a file
+        if let Some(favicon) = ARGS
+            .favicon(url.host_str().expect("no hostname"))
+            .filter(|_| url.path() == "/favicon.txt")
+        {
+            self.send_header(SUCCESS, "text/plain").await?;
+            self.stream
+                .write_all(format!("{favicon}\n").as_bytes())
+                .await?;
+            return Ok(());
+        }
+        if let Some(feed) = ARGS.feeds.iter().find(|feed| feed.path == url.path()) {
+            return self.send_feed(&url, feed).await;
+        }
+        if let Some((blog, page)) = ARGS
+            .blogs
+            .iter()
+            .find_map(|blog| Some((blog, blog.page(url.path())?)))
+        {
+            return self.send_blog_page(&url, blog, page).await;
+        }
+        if let Some((sitemaps, route)) = ARGS.sitemaps.as_ref().and_then(|sitemaps| {
+            let route = sitemaps
+                .routes
+                .iter()
+                .find(|route| route.path == url.path())?;
+            Some((sitemaps, route))
+        }) {
+            let host = url.host_str().expect("no hostname");
+            let documents = sitemaps.documents(&ARGS.content_dir(host)).await;
+            let page = sitemap::generate(route.format, &url, &documents);
+            self.send_header(SUCCESS, route.format.mime()).await?;
+            self.stream.write_all(page.as_bytes()).await?;
+            return Ok(());
+        }
+        if let Some(search) = ARGS
+            .search
+            .as_ref()
+            .filter(|search| search.path == url.path())
+        {
+            return self.send_search_results(&url, search).await;
+        }
+        if let Some((archive, skip)) = ARGS
+            .archives
+            .iter()
+            .find_map(|archive| Some((archive, archive.segments(url.path())?)))
+        {
+            return self.send_archive_member(&url, archive, skip).await;
+        }
 
-                let key_pair = if matches.opt_present("e") {
-                    KeyPair::generate_for(&rcgen::PKCS_ED25519)
-                } else {
-                    KeyPair::generate()
-                }?;
+        // existence of host_str was checked by parse_request already
+        let host = url.host_str().expect("no hostname");
+        let key = format!("{host}{}", url.path());
+        let cached = ARGS.stat_cache.as_ref().and_then(|cache| cache.get(&key));
+        let (path, mime, uncached, snippets) = match cached {
+            Some(resolved) => {
+                if !self.check_resolved(&resolved).await? {
+                    return Ok(());
+                }
+                (
+                    resolved.path,
+                    resolved.mime,
+                    None,
+                    snippets::Snippets::default(),
+                )
+            }
+            None => {
+                let (mut root, skip) = ARGS.root_dir(host, url.path());
+                let mut path = root.clone();
+                // the settings of all directories leading to the file
+                let mut dir_config = dirconfig::DirConfig::default();
+                // the directories whose settings were applied
+                let mut dirs = vec![];
+                // the header and footer for gemtext files
+                let mut snippets = snippets::Snippets::default();
 
-                // generate the certificate with the configuration
-                let cert = cert_params.self_signed(&key_pair)?;
+                // the script to run and the number of URL path segments leading to it
+                let mut cgi_script = None;
+                let cgi_possible = ARGS.cgi || !ARGS.cgi_paths.is_empty();
 
-                // make sure the certificate directory exists
-                fs::create_dir(certs_path.join(domain))?;
-                // write certificate data to disk
-                let mut cert_file = File::create(certs_path.join(format!(
-                    "{}/{}",
-                    domain,
-                    certificates::CERT_FILE_NAME
-                )))?;
-                cert_file.write_all(cert.der())?;
-                // write key data to disk
-                let key_file_path =
-                    certs_path.join(format!("{}/{}", domain, certificates::KEY_FILE_NAME));
-                let mut key_file = File::create(&key_file_path)?;
-                #[cfg(unix)]
-                {
-                    // set permissions so only owner can read
-                    match key_file.set_permissions(std::fs::Permissions::from_mode(0o400)) {
-                        Ok(_) => (),
-                        Err(_) => log::warn!(
-                            "could not set permissions for new key file {}",
-                            key_file_path.display()
-                        ),
+                // the percent-decoded path segments, checked to be file names
+                let mut segments = match sanitize::segments(url.path()) {
+                    Ok(segments) => segments,
+                    Err((status, meta)) => return self.send_header(status, meta).await,
+                };
+                // serve a variant in another language if the file does not exist
+                let mut variant_lang = None;
+                let languages = ARGS.lang_variants(host);
+                if !languages.is_empty() {
+                    let exists = |segments: &[String]| {
+                        let path = segments.iter().fold(root.clone(), |path, s| path.join(s));
+                        path.exists() || (skip == 0 && ARGS.layered(root.clone(), path).1.exists())
+                    };
+                    let requested = segments.get(skip..).unwrap_or_default();
+                    if let Some((variant, lang)) = language::variant(requested, languages, exists) {
+                        segments.truncate(skip);
+                        segments.extend(variant);
+                        variant_lang = Some(lang);
                     }
                 }
-                key_file.write_all(key_pair.serialized_der())?;
+                let case_insensitive = ARGS.case_insensitive(host);
+                // whether a segment is spelled differently than the file
+                let mut recased = false;
+                for (i, segment) in segments.iter_mut().enumerate().skip(skip) {
+                    dirs.push(path.clone());
+                    // a status file applies to everything in its directory
+                    if let Some((status, meta)) = metadata::status_override(&path).await {
+                        return self.send_header(status, &meta).await;
+                    }
+                    match dirconfig::load(&path).await {
+                        Ok(Some(config)) => dir_config.merge(config),
+                        Ok(None) => (),
+                        Err(e) => {
+                            // the settings may restrict access, so do not guess
+                            log::error!("{e}");
+                            return self
+                                .send_header(TEMPORARY_FAILURE, "Temporary failure.")
+                                .await;
+                        }
+                    }
+                    snippets.update(&path).await;
+                    if case_insensitive && !segment.is_empty() && !path.join(&segment).exists() {
+                        if let Some(name) = find_case_insensitive(&path, segment).await {
+                            *segment = name;
+                            recased = true;
+                        }
+                    }
+                    if !segment.is_empty() {
+                        path.push(&segment);
+                    }
+                    // the remaining segments are passed on to a CGI script
+                    if cgi_possible && ARGS.is_cgi_script(&url_path, &path) {
+                        cgi_script = Some(i + 1);
+                        break;
+                    }
+                }
+                // the language of the variant overrides the directory's
+                if let Some(lang) = variant_lang {
+                    dir_config.lang = Some(lang.to_string());
+                }
+                // aliased directories have no layers
+                if cgi_script.is_none() && skip == 0 {
+                    (root, path) = ARGS.layered(root, path);
+                }
+                if !self.check_hidden(&segments, &path).await? {
+                    return Ok(());
+                }
 
-                reload_certs = true;
-            }
-        }
+                match dir_config.access {
+                    Some(dirconfig::Access::Deny) => {
+                        return self.send_header(NOT_FOUND, "Not found, sorry.").await
+                    }
+                    Some(dirconfig::Access::Certificate) if !self.check_client_cert().await? => {
+                        return Ok(())
+                    }
+                    _ => (),
+                }
 
-        hostnames.push(hostname);
-    }
+                if !self.check_symlinks(&root, &path).await? {
+                    return Ok(());
+                }
 
-    // if new certificates were generated, reload the certificate store
-    let certs = if reload_certs {
-        certificates::CertStore::load_from(&certs_path)?
-    } else {
-        // there must already have been certificates loaded
-        certs.unwrap()
-    };
+                // there should only be one URL for each file
+                if recased {
+                    let mut url = url;
+                    url.path_segments_mut()
+                        .expect("URL without path")
+                        .clear()
+                        .extend(&segments);
+                    return self.send_header(REDIRECT_PERMANENT, url.as_str()).await;
+                }
 
-    // parse listening addresses
-    let mut addrs = vec![];
-    for i in matches.opt_strs("addr") {
-        addrs.push(i.parse()?);
-    }
+                if let Some(depth) = cgi_script {
+                    // the script only runs once the input was sent
+                    let data = self.metadata.lock().await.get(&path);
+                    if let (PresetMeta::Input(status, meta), None) = (data, url.query()) {
+                        return self.send_header(status, &meta).await;
+                    }
+                    return self.run_cgi(&url, &path, depth).await;
+                }
 
-    #[cfg_attr(not(unix), allow(unused_mut))]
-    let mut empty = addrs.is_empty();
+                if let Ok(metadata) = tokio::fs::metadata(&path).await {
+                    if metadata.is_dir() {
+                        if url.path().ends_with('/') || url.path().is_empty() {
+                            // if the path ends with a slash or the path is empty, the links will work the same
+                            // without a redirect
+                            // use the first index file that exists
+                            // use `push` instead of `join` because the changed path is used later
+                            let Some(index) = ARGS
+                                .index_files
+                                .iter()
+                                .find(|name| path.join(name).exists())
+                            else {
+                                // try listing directory
+                                return self.list_directory(&path, dir_config.dir_listing).await;
+                            };
+                            path.push(index);
+                            if !self.check_symlinks(&root, &path).await? {
+                                return Ok(());
+                            }
+                        } else {
+                            // if client is not redirected, links may not work as expected without trailing slash
+                            let mut url = url;
+                            url.set_path(&format!("{}/", url.path()));
+                            return self.send_header(REDIRECT_PERMANENT, url.as_str()).await;
+                        }
+                    }
+                }
 
-    #[cfg(unix)]
-    let mut sockets = vec![];
-    #[cfg(unix)]
-    {
-        for i in matches.opt_strs("socket") {
-            sockets.push(i.parse()?);
+                // whether the file is only served after an input prompt
+                let mut prompted = false;
+                let params = dir_config.parameters(ARGS.language(host));
+                let data = {
+                    let mut metadata = self.metadata.lock().await;
+                    // an entry for the file takes precedence
+                    if !metadata.exists(&path) {
+                        PresetMeta::Parameters(params)
+                    } else {
+                        match metadata.get(&path) {
+                            // the input was sent, so serve the file
+                            PresetMeta::Input(..) if url.query().is_some() => {
+                                prompted = true;
+                                PresetMeta::Parameters(params)
+                            }
+                            data => data,
+                        }
+                    }
+                };
+
+                if let PresetMeta::FullHeader(status, meta) | PresetMeta::Input(status, meta) = data
+                {
+                    self.send_header(status, &meta).await?;
+                    // do not try to access the file
+                    return Ok(());
+                }
+
+                let mime = match data {
+                    // this was already handled before
+                    PresetMeta::FullHeader(..) | PresetMeta::Input(..) => unreachable!(),
+                    // treat this as the full MIME type
+                    PresetMeta::FullMime(mime) => mime.clone(),
+                    // guess the MIME type and add the parameters
+                    PresetMeta::Parameters(params) => {
+                        let mime = match dir_config.mime_type(&path) {
+                            _ if ARGS.templates && template::is_template(&path) => {
+                                "text/gemini".to_string()
+                            }
+                            _ if ARGS.markdown && markdown::is_markdown(&path) => {
+                                "text/gemini".to_string()
+                            }
+                            Some(mime) => mime.to_string(),
+                            None => ARGS.mime_types.guess_or_sniff(&path).await,
+                        };
+                        match ARGS.charset(host) {
+                            // a charset from the directory takes precedence
+                            Some(charset)
+                                if mime.starts_with("text/") && !params.contains("charset=") =>
+                            {
+                                format!("{mime};charset={charset}{params}")
+                            }
+                            _ => format!("{mime}{params}"),
+                        }
+                    }
+                };
+                // the header and footer only apply to gemtext
+                if !mime.starts_with("text/gemini") {
+                    snippets = snippets::Snippets::default();
+                }
+                // a cached file would be served without checking the
+                // certificate, sending the input prompt or adding the header
+                // and footer
+                let cacheable = dir_config.access != Some(dirconfig::Access::Certificate)
+                    && !prompted
+                    && snippets.is_empty();
+                let uncached = cacheable.then_some((key, root, segments, dirs));
+                (path, mime, uncached, snippets)
+            }
+        };
+        // only URLs of files that exist are cached
+        let remember = |path: &Path, mime: &str| {
+            if let (Some(cache), Some((key, root, segments, dirs))) = (&ARGS.stat_cache, uncached) {
+                let resolved = cache::Resolved {
+                    path: path.to_owned(),
+                    mime: mime.to_owned(),
+                    root,
+                    segments,
+                    dirs,
+                };
+                cache.insert(key, resolved);
+            }
+        };
+
+        // templates are rendered and gemtext with includes is expanded for
+        // every request, Markdown is converted once per modification, other
+        // files may be cached; an error is reported when the file is opened
+        // below
+        let contents = if ARGS.templates && template::is_template(&path) {
+            read_gemtext(&path).await.ok().map(|source| {
+                let source = String::from_utf8_lossy(&source);
+                Arc::from(template::render(&source, &self.template_variables(&url)).into_bytes())
+            })
+        } else if ARGS.markdown && markdown::is_markdown(&path) && mime.starts_with("text/gemini") {
+            markdown::convert(&path).await.ok().map(Arc::from)
+        } else if mime.starts_with("text/gemini")
+            && ARGS.blogs.iter().any(|blog| blog.contains(url.path()))
+        {
+            // posts are served without their front matter and drafts not at all
+            match read_gemtext(&path).await {
+                Ok(content) => {
+                    let content = String::from_utf8_lossy(&content);
+                    match blog::FrontMatter::split(&content) {
+                        (Some(front_matter), _) if front_matter.draft => {
+                            return self.send_header(NOT_FOUND, "Not found, sorry.").await;
+                        }
+                        (_, post) => Some(Arc::from(post.as_bytes())),
+                    }
+                }
+                Err(_) => None,
+            }
+        } else if ARGS.includes && mime.starts_with("text/gemini") {
+            read_gemtext(&path).await.ok().map(Arc::from)
+        } else if let Some(cache) = &ARGS.file_cache {
+            cache.get(&path).await
+        } else {
+            None
+        };
+        if let Some(contents) = contents {
+            remember(&path, &mime);
+            if !self.check_size(&path, contents.len() as u64).await? {
+                return Ok(());
+            }
+            let (header, footer) = snippets.read().await;
+            self.send_header(SUCCESS, &mime).await?;
+            let len = ARGS
+                .max_response_size
+                .map_or(contents.len(), |max| contents.len().min(max as usize));
+            self.stream.write_all(&header).await?;
+            self.stream.write_all(&contents[..len]).await?;
+            self.stream.write_all(&footer).await?;
+            return Ok(());
+        }
+
+        // Make sure the file opens successfully before sending a success header.
+        let file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                match e.kind() {
+                    ErrorKind::NotFound | ErrorKind::PermissionDenied => {
+                        self.send_header(NOT_FOUND, "Not found, sorry.").await?
+                    }
+                    // something went wrong on our side, the client may retry
+                    _ => {
+                        self.send_header(TEMPORARY_FAILURE, "Temporary failure.")
+                            .await?
+                    }
+                }
+                return Err(e.into());
+            }
+        };
+        remember(&path, &mime);
+        if !self.check_size(&path, file.metadata().await?.len()).await? {
+            return Ok(());
         }
 
-        empty &= sockets.is_empty();
+        let (header, footer) = snippets.read().await;
+
+        // Send header.
+        self.send_header(SUCCESS, &mime).await?;
+
+        // Send body.
+        self.stream.write_all(&header).await?;
+        self.send_file(file).await?;
+        self.stream.write_all(&footer).await?;
+        Ok(())
     }
 
-    if empty {
-        addrs = vec![
-            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), DEFAULT_PORT),
-            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), DEFAULT_PORT),
-        ];
+    /// Returns the variables that templates can use for this request.
+    fn template_variables(&self, url: &Url) -> Vec<(&'static str, String)> {
+        vec![
+            ("host", url.host_str().unwrap_or_default().to_string()),
+            ("path", url.path().to_string()),
+            (
+                "query",
+                url.query()
+                    .map(|query| percent_decode_str(query).decode_utf8_lossy().into_owned())
+                    .unwrap_or_default(),
+            ),
+            ("cert_hash", self.client_cert.clone().unwrap_or_default()),
+            ("cert_name", self.client_name.clone().unwrap_or_default()),
+            (
+                "now",
+                humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            ),
+        ]
     }
 
-    Ok(Args {
-        addrs,
-        #[cfg(unix)]
-        sockets,
-        content_dir: check_path(matches.opt_get_default("content", "content".into())?)?,
-        certs: Arc::new(certs),
-        hostnames,
-        language: matches.opt_str("lang"),
-        serve_secret: matches.opt_present("serve-secret"),
-        log_ips: matches.opt_present("log-ip"),
-        only_tls13: matches.opt_present("only-tls13"),
-        central_config: matches.opt_present("central-conf"),
-        skip_port_check: matches.opt_present("skip-port-check"),
-    })
-}
+    /// Sends the file or directory at the requested selector to a Gopher
+    /// client. Directories and gemtext files are sent as Gopher menus.
+    ///
+    /// The same rewrites, redirects and access settings as for Gemini apply.
+    /// Gopher clients can not send a client certificate or answer an input
+    /// prompt, and CGI scripts and other backends expect Gemini requests, so
+    /// such paths are refused.
+    async fn send_gopher_response(&mut self, url: Url) -> Result {
+        // existence of host_str was checked by parse_request already
+        let rules = ARGS.rewrite_rules(url.host_str().expect("no hostname"));
+        let url = match rewrite::apply(rules, &url) {
+            Some(rewrite::Rewritten::Internal(new)) => new,
+            Some(rewrite::Rewritten::Redirect(status, target)) => {
+                return self.gopher_redirect(&url, status, &target).await;
+            }
+            None => url,
+        };
+        if let Some((status, target)) = ARGS.redirects.find(&url) {
+            return self.gopher_redirect(&url, status, &target).await;
+        }
+        let url_path = match sanitize::normalized(url.path()) {
+            Ok(url_path) => url_path,
+            Err((status, meta)) => return self.send_header(status, meta).await,
+        };
+        if let Some(gone) = ARGS
+            .gone
+            .iter()
+            .find(|gone| sanitize::has_prefix(&url_path, &gone.prefix))
+        {
+            return self.send_header(GONE, &gone.message).await;
+        }
 
-fn check_path(s: String) -> Result<PathBuf, String> {
-    let p = PathBuf::from(s);
-    if p.as_path().exists() {
-        Ok(p)
-    } else {
-        Err(format!("No such file: {p:?}"))
-    }
-}
+        if ARGS
+            .client_cert_areas
+            .iter()
+            .any(|prefix| sanitize::has_prefix(&url_path, prefix))
+        {
+            return self
+                .send_header(CLIENT_CERTIFICATE_REQUIRED, GEMINI_ONLY)
+                .await;
+        }
+        let backend = ARGS
+            .proxy_routes
+            .iter()
+            .any(|route| route.matches(&url, Some(&url_path)))
+            || ARGS
+                .scgi_routes
+                .iter()
+                .chain(&ARGS.fastcgi_routes)
+                .any(|route| url.path().starts_with(&route.prefix))
+            || ARGS
+                .http_routes
+                .iter()
+                .any(|route| url.path().starts_with(&route.prefix));
+        if backend {
+            return self.send_header(PERMANENT_FAILURE, GEMINI_ONLY).await;
+        }
 
-/// TLS configuration.
-static TLS: Lazy<TlsAcceptor> = Lazy::new(acceptor);
+        let host = url.host_str().expect("no hostname");
+        let (mut root, skip) = ARGS.root_dir(host, url.path());
+        let mut path = root.clone();
+        let mut dir_config = dirconfig::DirConfig::default();
+        let cgi_possible = ARGS.cgi || !ARGS.cgi_paths.is_empty();
 
-fn acceptor() -> TlsAcceptor {
-    let config = if ARGS.only_tls13 {
-        ServerConfig::builder_with_protocol_versions(&[&TLS13])
-    } else {
-        ServerConfig::builder()
-    }
-    .with_no_client_auth()
-    .with_cert_resolver(ARGS.certs.clone());
-    TlsAcceptor::from(Arc::new(config))
-}
+        let segments = match sanitize::segments(url.path()) {
+            Ok(segments) => segments,
+            Err((status, meta)) => return self.send_header(status, meta).await,
+        };
+        for segment in segments.iter().skip(skip) {
+            // a status file applies to everything in its directory
+            if let Some((status, meta)) = metadata::status_override(&path).await {
+                return self.send_header(status, &meta).await;
+            }
+            match dirconfig::load(&path).await {
+                Ok(Some(config)) => dir_config.merge(config),
+                Ok(None) => (),
+                Err(e) => {
+                    // the settings may restrict access, so do not guess
+                    log::error!("{e}");
+                    return self
+                        .send_header(TEMPORARY_FAILURE, "Temporary failure.")
+                        .await;
+                }
+            }
+            // empty segments would add a trailing separator
+            if !segment.is_empty() {
+                path.push(segment);
+            }
+            if cgi_possible && ARGS.is_cgi_script(&url_path, &path) {
+                return self.send_header(PERMANENT_FAILURE, GEMINI_ONLY).await;
+            }
+        }
+        // aliased directories have no layers
+        if skip == 0 {
+            (root, path) = ARGS.layered(root, path);
+        }
+        if !self.check_hidden(&segments, &path).await? {
+            return Ok(());
+        }
+        match dir_config.access {
+            Some(dirconfig::Access::Deny) => {
+                return self.send_header(NOT_FOUND, "Not found, sorry.").await
+            }
+            Some(dirconfig::Access::Certificate) => {
+                return self
+                    .send_header(CLIENT_CERTIFICATE_REQUIRED, GEMINI_ONLY)
+                    .await
+            }
+            Some(dirconfig::Access::Allow) | None => (),
+        }
+        if !self.check_symlinks(&root, &path).await? {
+            return Ok(());
+        }
 
-struct RequestHandle<T> {
-    stream: TlsStream<T>,
-    local_port_check: Option<u16>,
-    log_line: String,
-    metadata: Arc<Mutex<FileOptions>>,
-}
+        // a gophermap file is already a menu
+        let mut is_menu = false;
+        if path.is_dir() {
+            if path.join("gophermap").is_file() {
+                path.push("gophermap");
+                is_menu = true;
+            } else if let Some(index) = ARGS
+                .index_files
+                .iter()
+                .find(|name| path.join(name).exists())
+            {
+                path.push(index);
+            } else {
+                return self.gopher_directory(&path, &url).await;
+            }
+            if !self.check_symlinks(&root, &path).await? {
+                return Ok(());
+            }
+        }
 
-impl RequestHandle<TcpStream> {
-    /// Creates a new request handle for the given stream. If establishing the TLS
-    /// session fails, returns a corresponding log line.
-    async fn new(stream: TcpStream, metadata: Arc<Mutex<FileOptions>>) -> Result<Self, String> {
-        let local_addr = stream.local_addr().unwrap().to_string();
-
-        // try to get the remote IP address if desired
-        let peer_addr = if ARGS.log_ips {
-            stream
-                .peer_addr()
-                .map_err(|_| {
-                    format!(
-                        // use nonexistent status code 01 if peer IP is unknown
-                        "{local_addr} - \"\" 01 \"IP error\" error:could not get peer address",
-                    )
-                })?
-                .ip()
-                .to_string()
-        } else {
-            // Do not log IP address, but something else so columns still line up.
-            "-".into()
+        let data = self.metadata.lock().await.get(&path);
+        match data {
+            // a prompt can not be answered, but a search query can be sent
+            PresetMeta::Input(..) if url.query().is_none() => {
+                return self.send_header(INPUT, GEMINI_ONLY).await;
+            }
+            PresetMeta::FullHeader(status, meta) if status / 10 != 2 => {
+                return self.send_header(status, &meta).await;
+            }
+            _ => (),
+        }
+
+        let mut file = match tokio::fs::File::open(&path).await {
+            Ok(file) => file,
+            Err(e) => {
+                match e.kind() {
+                    ErrorKind::NotFound | ErrorKind::PermissionDenied => {
+                        self.send_header(NOT_FOUND, "Not found, sorry.").await?
+                    }
+                    _ => {
+                        self.send_header(TEMPORARY_FAILURE, "Temporary failure.")
+                            .await?
+                    }
+                }
+                return Err(e.into());
+            }
         };
 
-        let log_line = format!("{local_addr} {peer_addr}",);
+        if !self.check_size(&path, file.metadata().await?.len()).await? {
+            return Ok(());
+        }
 
-        let local_port_check = if ARGS.skip_port_check {
-            None
+        let mime = ARGS.mime_types.guess_or_sniff(&path).await;
+        if is_menu {
+            self.send_header(SUCCESS, gopher::MENU_MIME).await?;
+            self.send_file(file).await?;
+        } else if mime == "text/gemini" {
+            let mut gemtext = vec![];
+            file.read_to_end(&mut gemtext).await?;
+            let menu = gopher::convert_gemtext(&String::from_utf8_lossy(&gemtext), &url, |path| {
+                ARGS.mime_types.guess(Path::new(path))
+            });
+            self.send_header(SUCCESS, gopher::MENU_MIME).await?;
+            self.stream.write_all(menu.as_bytes()).await?;
         } else {
-            Some(stream.local_addr().unwrap().port())
+            self.send_header(SUCCESS, &mime).await?;
+            self.send_file(file).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a Gopher menu with a link to the target of a redirect, because
+    /// Gopher has no redirects.
+    async fn gopher_redirect(&mut self, url: &Url, status: u8, target: &Url) -> Result {
+        write!(self.log_line, " {status} \"{target}\"")?;
+        self.status = Some(status);
+        let menu =
+            gopher::convert_gemtext(&format!("=> {target} Moved to {target}"), url, |path| {
+                ARGS.mime_types.guess(Path::new(path))
+            });
+        self.stream.write_all(menu.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Sends a Gopher menu with the entries of the given directory.
+    async fn gopher_directory(&mut self, path: &Path, url: &Url) -> Result {
+        let mut entries = match tokio::fs::read_dir(path).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.send_header(TEMPORARY_FAILURE, "Temporary failure.")
+                    .await?;
+                return Err(e.into());
+            }
         };
 
-        match TLS.accept(stream).await {
-            Ok(stream) => Ok(Self {
-                stream,
-                local_port_check,
-                log_line,
-                metadata,
-            }),
-            // use nonexistent status code 00 if connection was not established
-            Err(e) => Err(format!("{log_line} \"\" 00 \"TLS error\" error:{e}")),
+        let host = url.host_str().unwrap_or_default();
+        let port = url.port().unwrap_or(gopher::DEFAULT_PORT);
+        let mut base = percent_decode_str(url.path())
+            .decode_utf8_lossy()
+            .into_owned();
+        if !base.ends_with('/') {
+            base.push('/');
         }
-    }
-}
 
-#[cfg(unix)]
-impl RequestHandle<UnixStream> {
-    async fn new_unix(
-        stream: UnixStream,
-        metadata: Arc<Mutex<FileOptions>>,
-    ) -> Result<Self, String> {
-        let log_line = format!(
-            "unix:{} -",
-            stream
-                .local_addr()
-                .ok()
-                .and_then(|addr| Some(addr.as_pathname()?.to_string_lossy().into_owned()))
-                .unwrap_or_default()
-        );
+        let mut items = vec![];
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry
+                .file_name()
+                .into_string()
+                .or(Err("Non-Unicode filename"))?;
+            if !ARGS.hidden_files.listed(&name) {
+                continue;
+            }
+            let (item_type, selector) = if entry.file_type().await?.is_dir() {
+                ('1', format!("{base}{name}/"))
+            } else {
+                (
+                    gopher::item_type(&ARGS.mime_types.guess(&entry.path())),
+                    format!("{base}{name}"),
+                )
+            };
+            items.push((name, item_type, selector));
+        }
+        items.sort();
 
-        match TLS.accept(stream).await {
-            Ok(stream) => Ok(Self {
-                stream,
-                // TODO add port check for unix sockets, requires extra arg for port
-                local_port_check: None,
-                log_line,
-                metadata,
-            }),
-            // use nonexistent status code 00 if connection was not established
-            Err(e) => Err(format!("{} \"\" 00 \"TLS error\" error:{}", log_line, e)),
+        let mut menu = String::new();
+        for (name, item_type, selector) in items {
+            gopher::menu_line(&mut menu, item_type, &name, &selector, host, port);
         }
+        menu.push_str(".\r\n");
+
+        self.send_header(SUCCESS, gopher::MENU_MIME).await?;
+        self.stream.write_all(menu.as_bytes()).await?;
+        Ok(())
     }
-}
 
-impl<T> RequestHandle<T>
-where
-    T: AsyncWriteExt + AsyncReadExt + Unpin,
-{
-    /// Do the necessary actions to handle this request. Returns a corresponding
-    /// log line as Err or Ok, depending on if the request finished with or
-    /// without errors.
-    async fn handle(mut self) -> Result<String, String> {
-        // not already in error condition
-        let result = match self.parse_request().await {
-            Ok(url) => self.send_response(url).await,
-            Err((status, msg)) => self.send_header(status, msg).await,
+    /// Stores a file uploaded with the Titan protocol and redirects the client
+    /// to its Gemini URL. An upload with size 0 deletes the file.
+    async fn receive_upload(&mut self, url: Url, received: &[u8]) -> Result {
+        let Some((url_path, params)) = titan::parse_path(url.path()) else {
+            return self
+                .send_header(BAD_REQUEST, "Invalid Titan parameters")
+                .await;
         };
+        let Some(area) = ARGS.titan_areas.iter().find(|area| area.contains(url_path)) else {
+            return self
+                .send_header(PERMANENT_FAILURE, "Uploads are not allowed here.")
+                .await;
+        };
+        match &self.client_cert {
+            None => {
+                return self
+                    .send_header(CLIENT_CERTIFICATE_REQUIRED, "Client certificate required.")
+                    .await
+            }
+            Some(fingerprint) if !ARGS.tofu.check(fingerprint) || !area.allows(fingerprint) => {
+                return self
+                    .send_header(CERTIFICATE_NOT_AUTHORISED, "Certificate not authorised.")
+                    .await
+            }
+            Some(_) => (),
+        }
+        if ARGS.titan_token.is_some() && params.token != ARGS.titan_token {
+            return self.send_header(PERMANENT_FAILURE, "Invalid token.").await;
+        }
+        if params.size > ARGS.titan_max_size {
+            return self.send_header(PERMANENT_FAILURE, "File too large.").await;
+        }
 
-        let close_result = self.stream.shutdown().await;
+        // the Gemini URL of the uploaded file
+        let mut target = url.clone();
+        target.set_path(url_path);
+        target
+            .set_scheme("gemini")
+            .expect("could not change URL scheme");
 
-        match (result, close_result) {
-            (Err(e), _) => Err(format!("{} error:{}", self.log_line, e)),
-            (Ok(_), Err(e)) => Err(format!("{} error:{}", self.log_line, e)),
-            (Ok(_), Ok(_)) => Ok(self.log_line),
+        let root = ARGS.content_dir(url.host_str().expect("no hostname"));
+        let mut path = root.clone();
+        let segments = match sanitize::segments(target.path()) {
+            Ok(segments) => segments,
+            Err((status, meta)) => return self.send_header(status, meta).await,
+        };
+        if !ARGS.hidden_files.served() && ARGS.hidden_files.in_path(&segments) {
+            return self
+                .send_header(PERMANENT_FAILURE, "Uploads are not allowed here.")
+                .await;
+        }
+        path.extend(segments.iter().filter(|segment| !segment.is_empty()));
+        if !ARGS.symlinks.allows(&root, &path) {
+            log::warn!("not following a symbolic link in {path:?}");
+            return self
+                .send_header(PERMANENT_FAILURE, "Uploads are not allowed here.")
+                .await;
+        }
+        // the same rules apply as for requests of the directories
+        let mut dirs = path
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(&root))
+            .map(Path::to_path_buf)
+            .collect::<Vec<_>>();
+        dirs.reverse();
+        if !self.check_dirs(&dirs).await? {
+            return Ok(());
+        }
+        if target.path().ends_with('/') || path.is_dir() {
+            return self
+                .send_header(BAD_REQUEST, "Can not upload to a directory.")
+                .await;
+        }
+        if !titan::mime_allowed(&ARGS.titan_mime_types, &params.mime)
+            || !titan::mime_allowed(&ARGS.titan_mime_types, &ARGS.mime_types.guess(&path))
+        {
+            return self
+                .send_header(PERMANENT_FAILURE, "Media type not allowed.")
+                .await;
         }
-    }
-
-    /// Return the URL requested by the client.
-    async fn parse_request(&mut self) -> std::result::Result<Url, (u8, &'static str)> {
-        // Because requests are limited to 1024 bytes (plus 2 bytes for CRLF), we
-        // can use a fixed-sized buffer on the stack, avoiding allocations and
-        // copying, and stopping bad clients from making us use too much memory.
-        let mut request = [0; 1026];
-        let mut buf = &mut request[..];
-        let mut len = 0;
 
-        // Read until CRLF, end-of-stream, or there's no buffer space left.
-        //
-        // Since neither CR nor LF can be part of a URI according to
-        // ISOC-RFC 3986, we could use BufRead::read_line here, but that does
-        // not allow us to cap the number of read bytes at 1024+2.
-        let result = loop {
-            let Ok(bytes_read) = self.stream.read(buf).await else {
-                break Err((BAD_REQUEST, "Request ended unexpectedly"));
-            };
-            len += bytes_read;
-            if request[..len].ends_with(b"\r\n") {
-                break Ok(());
-            } else if bytes_read == 0 {
-                break Err((BAD_REQUEST, "Request ended unexpectedly"));
+        let result = if params.size == 0 {
+            tokio::fs::remove_file(&path).await
+        } else {
+            titan::write(&path, received, &mut self.stream, params.size).await
+        };
+        match result {
+            Ok(()) => self.send_header(REDIRECT_TEMPORARY, target.as_str()).await,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                self.send_header(NOT_FOUND, "Not found, sorry.").await
+            }
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                self.send_header(BAD_REQUEST, "Upload ended unexpectedly.")
+                    .await?;
+                Err(e.into())
+            }
+            Err(e) if e.kind() == ErrorKind::TimedOut => {
+                self.send_header(BAD_REQUEST, "Upload timed out.").await?;
+                Err(e.into())
+            }
+            Err(e) => {
+                self.send_header(TEMPORARY_FAILURE, "Could not store file.")
+                    .await?;
+                Err(e.into())
             }
-            buf = &mut request[len..];
         }
-        .and_then(|()| {
-            std::str::from_utf8(&request[..len - 2]).or(Err((BAD_REQUEST, "Non-UTF-8 request")))
-        });
-
-        let request = result.map_err(|e| {
-            // write empty request to log line for uniformity
-            write!(self.log_line, " \"\"").unwrap();
-            e
-        })?;
+    }
 
-        // log literal request (might be different from or not an actual URL)
-        write!(self.log_line, " \"{request}\"").unwrap();
+    /// Runs the CGI script at the given path and sends its output, which must
+    /// start with a Gemini response header, to the client. `depth` is the
+    /// number of URL path segments that lead to the script.
+    async fn run_cgi(&mut self, url: &Url, script: &Path, depth: usize) -> Result {
+        // the segments were already checked when the script was found
+        let segments = sanitize::segments(url.path()).unwrap_or_default();
+        let (script_segments, rest) = segments.split_at(depth.min(segments.len()));
+        let script_name = script_segments
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(|segment| format!("/{segment}"))
+            .collect::<String>();
+        let path_info = rest
+            .iter()
+            .map(|segment| format!("/{segment}"))
+            .collect::<String>();
+        let request = cgi::Request {
+            url,
+            script_name,
+            path_info,
+            local_addr: self.local_addr,
+            peer_addr: self.peer_addr,
+            client_cert: self.client_cert.as_deref(),
+        };
 
-        let mut url = Url::parse(request).or(Err((BAD_REQUEST, "Invalid URL")))?;
+        let output = match cgi::spawn(script, &request, ARGS.backend_timeout) {
+            Ok(output) => output,
+            Err(e) => {
+                self.send_header(CGI_ERROR, "CGI error").await?;
+                return Err(e.into());
+            }
+        };
+        self.relay_response(output).await
+    }
 
-        // Validate the URL:
-        // correct scheme
-        if url.scheme() != "gemini" {
-            return Err((PROXY_REQUEST_REFUSED, "Unsupported URL scheme"));
+    /// Returns the information about the request for an application server
+    /// that handles the given route. `url_path` is the normalized path the
+    /// route was matched against.
+    fn backend_request<'a>(
+        &'a self,
+        url: &'a Url,
+        url_path: &str,
+        route: &cgi::Route,
+    ) -> cgi::Request<'a> {
+        let path_info = url_path
+            .strip_prefix(route.prefix.as_str())
+            .unwrap_or_default()
+            .to_string();
+        cgi::Request {
+            url,
+            script_name: route.prefix.trim_end_matches('/').to_string(),
+            // the path info has to start with a slash, if it is not empty
+            path_info: if path_info.is_empty() || path_info.starts_with('/') {
+                path_info
+            } else {
+                format!("/{path_info}")
+            },
+            local_addr: self.local_addr,
+            peer_addr: self.peer_addr,
+            client_cert: self.client_cert.as_deref(),
         }
+    }
 
-        // no userinfo and no fragment
-        if url.password().is_some() || !url.username().is_empty() || url.fragment().is_some() {
-            return Err((BAD_REQUEST, "URL contains fragment or userinfo"));
-        }
+    /// Forwards the request to the upstream server of the given route and
+    /// sends its response to the client.
+    async fn run_proxy(&mut self, url: &Url, route: &proxy::Route) -> Result {
+        let output = match proxy::request(route, url, ARGS.backend_timeout).await {
+            Ok(output) => output,
+            Err(e) => {
+                self.send_header(PROXY_ERROR, "Proxy error").await?;
+                return Err(e.into());
+            }
+        };
+        self.relay_response(output).await
+    }
 
-        // correct host
-        let Some(domain) = url.domain() else {
-            return Err((BAD_REQUEST, "URL does not contain a domain"));
+    /// Passes the request on to the SCGI application server of the given
+    /// route and sends its response to the client.
+    async fn run_scgi(&mut self, url: &Url, url_path: &str, route: &cgi::Route) -> Result {
+        let request = self.backend_request(url, url_path, route);
+        let output = match scgi::request(&route.address, &request, ARGS.backend_timeout).await {
+            Ok(output) => output,
+            Err(e) => {
+                self.send_header(CGI_ERROR, "SCGI error").await?;
+                return Err(e.into());
+            }
         };
-        // because the gemini scheme is not special enough for WHATWG, normalize
-        // it ourselves
-        let host = Host::parse(
-            &percent_decode_str(domain)
-                .decode_utf8()
-                .or(Err((BAD_REQUEST, "Invalid URL")))?,
-        )
-        .or(Err((BAD_REQUEST, "Invalid URL")))?;
-        // TODO: simplify when <https://github.com/servo/rust-url/issues/586> resolved
-        url.set_host(Some(&host.to_string()))
-            .expect("invalid domain?");
-        // do not use "contains" here since it requires the same type and does
-        // not allow to check for Host<&str> if the vec contains Hostname<String>
-        if !ARGS.hostnames.is_empty() && !ARGS.hostnames.iter().any(|h| h == &host) {
-            return Err((PROXY_REQUEST_REFUSED, "Proxy request refused"));
-        }
+        self.relay_response(output).await
+    }
 
-        // correct port
-        if let Some(expected_port) = self.local_port_check {
-            if let Some(port) = url.port() {
-                // Validate that the port in the URL is the same as for the stream this request
-                // came in on.
-                if port != expected_port {
-                    return Err((PROXY_REQUEST_REFUSED, "Proxy request refused"));
-                }
+    /// Passes the request on to the FastCGI application server of the given
+    /// route and sends its response to the client.
+    async fn run_fastcgi(&mut self, url: &Url, url_path: &str, route: &cgi::Route) -> Result {
+        let request = self.backend_request(url, url_path, route);
+        let output = match fastcgi::request(&route.address, &request, ARGS.fastcgi_timeout).await {
+            Ok(output) => output,
+            Err(e) => {
+                self.send_header(CGI_ERROR, "FastCGI error").await?;
+                return Err(e.into());
             }
-        }
-        Ok(url)
+        };
+        self.relay_response(output).await
     }
 
-    /// Send the client the file located at the requested URL.
-    async fn send_response(&mut self, url: Url) -> Result {
-        let mut path = std::path::PathBuf::from(&ARGS.content_dir);
-
-        if ARGS.hostnames.len() > 1 {
-            // basic vhosts, existence of host_str was checked by parse_request already
-            path.push(url.host_str().expect("no hostname"));
-        }
-
-        if let Some(mut segments) = url.path_segments() {
-            // append percent-decoded path segments
-            for segment in segments.clone() {
-                // To prevent directory traversal attacks, we need to
-                // check that each filesystem path component in the URL
-                // path segment is a normal component (not the root
-                // directory, the parent directory, a drive label, or
-                // another special component). Furthermore, since path
-                // separators (e.g. the escaped forward slash %2F) in a
-                // single URL path segment are non-structural, the URL
-                // path segment should not contain multiple filesystem
-                // path components.
-                let decoded = percent_decode_str(segment).decode_utf8()?;
-                let mut components = Path::new(decoded.as_ref()).components();
-                // the first component must be a normal component; if
-                // so, push it onto the PathBuf
-                match components.next() {
-                    None => (),
-                    Some(Component::Normal(c)) => path.push(c),
-                    Some(_) => return self.send_header(NOT_FOUND, "Not found, sorry.").await,
-                }
-                // there must not be more than one component
-                if components.next().is_some() {
-                    return self.send_header(NOT_FOUND, "Not found, sorry.").await;
-                }
-                // even if it's one component, there may be trailing path
-                // separators at the end
-                if decoded.ends_with(path::is_separator) {
-                    return self.send_header(NOT_FOUND, "Not found, sorry.").await;
-                }
-            }
-            // check if hiding files is disabled
-            if !ARGS.serve_secret
-                // there is a configuration for this file, assume it should be served
-                && !self.metadata.lock().await.exists(&path)
-                // check if file or directory is hidden
-                && segments.any(|segment| segment.starts_with('.'))
-            {
-                return self
-                    .send_header(GONE, "If I told you, it would not be a secret.")
-                    .await;
+    /// Passes the request on to the HTTP backend of the given route and sends
+    /// its translated response to the client.
+    async fn run_http(&mut self, url: &Url, url_path: &str, route: &http::Route) -> Result {
+        let output = match http::request(
+            route,
+            url,
+            url_path,
+            self.client_cert.as_deref(),
+            ARGS.backend_timeout,
+        )
+        .await
+        {
+            Ok(output) => output,
+            Err(e) => {
+                self.send_header(PROXY_ERROR, "Gateway error").await?;
+                return Err(e.into());
             }
-        }
+        };
+        self.relay_response(output).await
+    }
 
-        if let Ok(metadata) = tokio::fs::metadata(&path).await {
-            if metadata.is_dir() {
-                if url.path().ends_with('/') || url.path().is_empty() {
-                    // if the path ends with a slash or the path is empty, the links will work the same
-                    // without a redirect
-                    // use `push` instead of `join` because the changed path is used later
-                    path.push("index.gmi");
-                    if !path.exists() {
-                        path.pop();
-                        // try listing directory
-                        return self.list_directory(&path).await;
-                    }
-                } else {
-                    // if client is not redirected, links may not work as expected without trailing slash
-                    let mut url = url;
-                    url.set_path(&format!("{}/", url.path()));
-                    return self.send_header(REDIRECT_PERMANENT, url.as_str()).await;
+    /// Sends the output of a CGI script or similar backend to the client. The
+    /// output must start with a Gemini response header.
+    async fn relay_response(&mut self, mut output: cgi::Output) -> Result {
+        // read the response header so it can be checked and logged
+        let mut buf = vec![];
+        let header_end = loop {
+            if let Some(end) = buf.iter().position(|&b| b == b'\n') {
+                break Some(end);
+            } else if buf.len() > 1029 {
+                break None;
+            }
+            match output.recv().await {
+                Some(Ok(chunk)) => buf.extend(chunk),
+                Some(Err(e)) => {
+                    self.send_header(CGI_ERROR, "CGI error").await?;
+                    return Err(e.into());
                 }
+                None => break None,
             }
-        }
-
-        let data = self.metadata.lock().await.get(&path);
+        };
+        let header = header_end
+            .and_then(|end| std::str::from_utf8(&buf[..end]).ok())
+            .map(|header| header.trim_end_matches('\r'))
+            .filter(|header| {
+                header.len() >= 2
+                    && header.as_bytes()[..2].iter().all(u8::is_ascii_digit)
+                    && matches!(header.as_bytes().get(2), None | Some(b' '))
+            });
+        let Some(header) = header else {
+            self.send_header(CGI_ERROR, "CGI error").await?;
+            return Err("invalid response header from backend".into());
+        };
+        let (status, meta) = header.split_at(2);
+        let status = status.parse().expect("status is not a number");
+        let meta = meta.trim_start().to_string();
+        self.send_header(status, &meta).await?;
 
-        if let PresetMeta::FullHeader(status, meta) = data {
-            self.send_header(status, &meta).await?;
-            // do not try to access the file
-            return Ok(());
+        // header_end is Some because the header is valid
+        self.stream
+            .write_all(&buf[header_end.unwrap() + 1..])
+            .await?;
+        while let Some(chunk) = output.recv().await {
+            self.stream.write_all(&chunk?).await?;
         }
+        Ok(())
+    }
 
-        // Make sure the file opens successfully before sending a success header.
-        let mut file = match tokio::fs::File::open(&path).await {
-            Ok(file) => file,
+    /// Generates and sends the feed of a directory with posts.
+    async fn send_feed(&mut self, url: &Url, feed: &feed::Feed) -> Result {
+        // existence of host_str was checked by parse_request already
+        let host = url.host_str().expect("no hostname").to_string();
+        // the directory was checked when the arguments were parsed
+        let dir = ARGS.dir_path(&host, &feed.dir);
+        let posts = tokio::task::spawn_blocking(move || {
+            let title = feed::dir_title(&dir, &ARGS.index_files);
+            feed::posts(&dir, &ARGS.index_files).map(|posts| (title, posts))
+        })
+        .await?;
+        let (title, posts) = match posts {
+            Ok(posts) => posts,
             Err(e) => {
                 self.send_header(NOT_FOUND, "Not found, sorry.").await?;
                 return Err(e.into());
             }
         };
+        let title = title.unwrap_or(host);
+        let dir_url = url.join(&feed.dir)?;
+        let xml = feed::generate(feed.format, &dir_url, url, &title, &posts);
+        self.send_header(SUCCESS, feed.format.mime()).await?;
+        self.stream.write_all(xml.as_bytes()).await?;
+        Ok(())
+    }
 
-        // Send header.
-        let mime = match data {
-            // this was already handled before opening the file
-            PresetMeta::FullHeader(..) => unreachable!(),
-            // treat this as the full MIME type
-            PresetMeta::FullMime(mime) => mime.clone(),
-            // guess the MIME type and add the parameters
-            PresetMeta::Parameters(params) => {
-                if path.extension() == Some(OsStr::new("gmi")) {
-                    format!("text/gemini{params}")
+    /// Generates and sends a page of a blog.
+    async fn send_blog_page(&mut self, url: &Url, blog: &blog::Blog, page: blog::Page) -> Result {
+        // existence of host_str was checked by parse_request already
+        let host = url.host_str().expect("no hostname");
+        // the directory was checked when the arguments were parsed
+        let dir = ARGS.dir_path(host, &blog.dir);
+        let dir_url = url.join(&blog.dir)?;
+        let blog = blog.clone();
+        let rendered = tokio::task::spawn_blocking(move || {
+            blog.render(&page, &dir, &dir_url, &ARGS.index_files)
+        })
+        .await?;
+        match rendered {
+            Ok(Some((mime, page))) => {
+                self.send_header(SUCCESS, mime).await?;
+                self.stream.write_all(page.as_bytes()).await?;
+                Ok(())
+            }
+            Ok(None) => self.send_header(NOT_FOUND, "Not found, sorry.").await,
+            Err(e) => {
+                self.send_header(NOT_FOUND, "Not found, sorry.").await?;
+                Err(e.into())
+            }
+        }
+    }
+
+    /// Sends a file from an archive that is mounted at a URL path prefix.
+    /// Directories are served like in the content directory, but without
+    /// directory listings.
+    async fn send_archive_member(
+        &mut self,
+        url: &Url,
+        archive: &archive::Archive,
+        skip: usize,
+    ) -> Result {
+        // existence of host_str was checked by parse_request already
+        let host = url.host_str().expect("no hostname");
+        let segments = match sanitize::segments(url.path()) {
+            Ok(segments) => segments,
+            Err((status, meta)) => return self.send_header(status, meta).await,
+        };
+        let mut name = segments[skip.min(segments.len())..]
+            .iter()
+            .filter(|segment| !segment.is_empty())
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join("/");
+        if !self
+            .check_hidden(&segments, &archive.file.join(&name))
+            .await?
+        {
+            return Ok(());
+        }
+        let mut member = archive.get(&name).await;
+        if let Ok(Some(archive::Member::Dir)) = member {
+            if !url.path().ends_with('/') {
+                let mut url = url.clone();
+                url.set_path(&format!("{}/", url.path()));
+                return self.send_header(REDIRECT_PERMANENT, url.as_str()).await;
+            }
+            member = Ok(None);
+            for index in &ARGS.index_files {
+                let index = if name.is_empty() {
+                    index.clone()
                 } else {
-                    let mime = mime_guess::from_path(&path).first_or_octet_stream();
-                    format!("{}{}", mime.essence_str(), params)
+                    format!("{name}/{index}")
+                };
+                if let Ok(Some(archive::Member::File(content))) = archive.get(&index).await {
+                    name = index;
+                    member = Ok(Some(archive::Member::File(content)));
+                    break;
                 }
             }
+        }
+        let contents = match member {
+            Ok(Some(archive::Member::File(contents))) => contents,
+            Ok(_) => return self.send_header(NOT_FOUND, "Not found, sorry.").await,
+            Err(e) => {
+                self.send_header(TEMPORARY_FAILURE, "Temporary failure.")
+                    .await?;
+                return Err(e.into());
+            }
+        };
+        let path = archive.file.join(&name);
+        let mime = ARGS.mime_types.guess(&path);
+        let params = dirconfig::DirConfig::default().parameters(ARGS.language(host));
+        let mime = match ARGS.charset(host) {
+            Some(charset) if mime.starts_with("text/") => {
+                format!("{mime};charset={charset}{params}")
+            }
+            _ => format!("{mime}{params}"),
         };
+        if !self.check_size(&path, contents.len() as u64).await? {
+            return Ok(());
+        }
         self.send_header(SUCCESS, &mime).await?;
+        let len = ARGS
+            .max_response_size
+            .map_or(contents.len(), |max| contents.len().min(max as usize));
+        self.stream.write_all(&contents[..len]).await?;
+        Ok(())
+    }
 
-        // Send body.
-        tokio::io::copy(&mut file, &mut self.stream).await?;
+    /// Asks for a search query or sends the results of the search.
+    async fn send_search_results(&mut self, url: &Url, search: &search::Search) -> Result {
+        let query = url
+            .query()
+            .map(|query| percent_decode_str(query).decode_utf8_lossy().into_owned())
+            .unwrap_or_default();
+        if query.trim().is_empty() {
+            return self.send_header(INPUT, "Search").await;
+        }
+        // existence of host_str was checked by parse_request already
+        let host = url.host_str().expect("no hostname");
+        let results = search.search(&ARGS.content_dir(host), &query).await;
+
+        // line breaks in the query would end the heading
+        let mut page = format!(
+            "# Search results for \"{}\"\n\n",
+            query.replace(char::is_control, " ")
+        );
+        if results.is_empty() {
+            page += "No results.\n";
+        }
+        for (path, title) in results {
+            page += &format!("=> {path} {title}\n");
+        }
+        page += &format!("\n=> {} New search\n", search.path);
+        self.send_header(SUCCESS, "text/gemini").await?;
+        self.stream.write_all(page.as_bytes()).await?;
         Ok(())
     }
 
-    async fn list_directory(&mut self, path: &Path) -> Result {
+    /// Lists the directory if listings are enabled for it. `enabled` is the
+    /// setting of the directory configuration, if any.
+    async fn list_directory(&mut self, path: &Path, enabled: Option<bool>) -> Result {
         // https://url.spec.whatwg.org/#path-percent-encode-set
         const ENCODE_SET: AsciiSet = CONTROLS
             .add(b' ')
@@ -739,33 +4289,69 @@ where
             .add(b'}');
 
         // check if directory listing is enabled by getting preamble
-        let Ok(preamble) = std::fs::read_to_string(path.join(".directory-listing-ok")) else {
-            self.send_header(NOT_FOUND, "Directory index disabled.")
-                .await?;
-            return Ok(());
+        let preamble = match std::fs::read_to_string(path.join(".directory-listing-ok")) {
+            _ if enabled == Some(false) => {
+                self.send_header(NOT_FOUND, "Directory index disabled.")
+                    .await?;
+                return Ok(());
+            }
+            Ok(preamble) => preamble,
+            // directory listings are enabled for all directories
+            Err(_) if ARGS.serve_dir_listings || enabled == Some(true) => String::new(),
+            Err(_) => {
+                self.send_header(NOT_FOUND, "Directory index disabled.")
+                    .await?;
+                return Ok(());
+            }
         };
 
         log::info!("Listing directory {:?}", path);
 
+        // open the directory before sending a success header
+        let mut entries = match tokio::fs::read_dir(path).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                self.send_header(TEMPORARY_FAILURE, "Temporary failure.")
+                    .await?;
+                return Err(e.into());
+            }
+        };
+
         self.send_header(SUCCESS, "text/gemini").await?;
         self.stream.write_all(preamble.as_bytes()).await?;
 
-        let mut entries = tokio::fs::read_dir(path).await?;
         let mut lines = vec![];
         while let Some(entry) = entries.next_entry().await? {
             let mut name = entry
                 .file_name()
                 .into_string()
                 .or(Err("Non-Unicode filename"))?;
-            if name.starts_with('.') {
+            if !ARGS.hidden_files.listed(&name) {
                 continue;
             }
             if entry.file_type().await?.is_dir() {
                 name += "/";
             }
-            let line = match percent_encode(name.as_bytes(), &ENCODE_SET).into() {
-                Cow::Owned(url) => format!("=> {url} {name}\n"),
-                Cow::Borrowed(url) => format!("=> {url}\n"), // url and name are identical
+            let url = percent_encode(name.as_bytes(), &ENCODE_SET).to_string();
+            let line = if ARGS.dir_listing_details {
+                // follow symlinks to show the details of the target
+                let metadata = tokio::fs::metadata(entry.path()).await?;
+                let mut details = vec![];
+                if !metadata.is_dir() {
+                    details.push(format_size(metadata.len()));
+                }
+                if let Ok(modified) = metadata.modified() {
+                    // only keep the date part of the timestamp
+                    let mut date = humantime::format_rfc3339_seconds(modified).to_string();
+                    date.truncate(10);
+                    details.push(date);
+                }
+                format!("=> {url} {name} ({})\n", details.join(", "))
+            } else if url == name {
+                // url and name are identical
+                format!("=> {url}\n")
+            } else {
+                format!("=> {url} {name}\n")
             };
             lines.push(line);
         }
@@ -776,13 +4362,146 @@ where
         Ok(())
     }
 
+    /// Checks that no segment of the URL path is hidden, or that hidden files
+    /// are served. Otherwise, the error is sent and false is returned.
+    async fn check_hidden(&mut self, segments: &[String], path: &Path) -> Result<bool> {
+        if !ARGS.hidden_files.in_path(segments) {
+            return Ok(true);
+        }
+        match ARGS.hidden_files.mode {
+            hidden::Mode::Serve | hidden::Mode::Unlisted => Ok(true),
+            // there is a configuration for this file, assume it should be served
+            hidden::Mode::Hide if self.metadata.lock().await.exists(path) => Ok(true),
+            hidden::Mode::Hide => {
+                self.send_header(GONE, "If I told you, it would not be a secret.")
+                    .await?;
+                Ok(false)
+            }
+            hidden::Mode::Deny => {
+                self.send_header(NOT_FOUND, "Not found, sorry.").await?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Checks again that a file from the stat cache may be served, since its
+    /// status files, directory settings or symbolic links may have changed
+    /// after it was cached. Otherwise, the error is sent and false is
+    /// returned.
+    async fn check_resolved(&mut self, resolved: &cache::Resolved) -> Result<bool> {
+        Ok(self.check_dirs(&resolved.dirs).await?
+            && self
+                .check_hidden(&resolved.segments, &resolved.path)
+                .await?
+            && self.check_symlinks(&resolved.root, &resolved.path).await?)
+    }
+
+    /// Checks the status files and the access settings of the directory
+    /// configuration in the given directories, from the outermost one in.
+    /// If a file in them must not be served, the error is sent and false is
+    /// returned.
+    async fn check_dirs(&mut self, dirs: &[PathBuf]) -> Result<bool> {
+        let mut dir_config = dirconfig::DirConfig::default();
+        for dir in dirs {
+            if let Some((status, meta)) = metadata::status_override(dir).await {
+                self.send_header(status, &meta).await?;
+                return Ok(false);
+            }
+            match dirconfig::load(dir).await {
+                Ok(Some(config)) => dir_config.merge(config),
+                Ok(None) => (),
+                Err(e) => {
+                    log::error!("{e}");
+                    self.send_header(TEMPORARY_FAILURE, "Temporary failure.")
+                        .await?;
+                    return Ok(false);
+                }
+            }
+        }
+        match dir_config.access {
+            Some(dirconfig::Access::Deny) => {
+                self.send_header(NOT_FOUND, "Not found, sorry.").await?;
+                Ok(false)
+            }
+            Some(dirconfig::Access::Certificate) => self.check_client_cert().await,
+            _ => Ok(true),
+        }
+    }
+
+    /// Checks that the symbolic links in the path below `root` may be
+    /// followed. Otherwise, the error is sent and false is returned.
+    async fn check_symlinks(&mut self, root: &Path, path: &Path) -> Result<bool> {
+        if ARGS.symlinks.allows(root, path) {
+            return Ok(true);
+        }
+        log::warn!("not following a symbolic link in {path:?}");
+        self.send_header(NOT_FOUND, "Not found, sorry.").await?;
+        Ok(false)
+    }
+
+    /// Checks that the client sent a trusted certificate. Otherwise, the
+    /// error is sent and false is returned.
+    async fn check_client_cert(&mut self) -> Result<bool> {
+        match &self.client_cert {
+            None => {
+                self.send_header(CLIENT_CERTIFICATE_REQUIRED, "Client certificate required.")
+                    .await?;
+                Ok(false)
+            }
+            Some(fingerprint) if !ARGS.tofu.check(fingerprint) => {
+                self.send_header(CERTIFICATE_NOT_AUTHORISED, "Certificate not authorised.")
+                    .await?;
+                Ok(false)
+            }
+            Some(_) => Ok(true),
+        }
+    }
+
     async fn send_header(&mut self, status: u8, meta: &str) -> Result {
         // add response status and response meta
         write!(self.log_line, " {status} \"{meta}\"")?;
+        self.status = Some(status);
 
-        self.stream
-            .write_all(format!("{status} {meta}\r\n").as_bytes())
-            .await?;
+        let header = match self.protocol {
+            Protocol::Gemini => format!("{status} {meta}\r\n"),
+            Protocol::Spartan => spartan::header(status, meta),
+            // Gopher responses do not have a header, but errors are sent as a
+            // menu with an error item
+            Protocol::Gopher if status / 10 == 2 => String::new(),
+            Protocol::Gopher => gopher::error(meta),
+        };
+        self.stream.write_all(header.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Checks the size of a file against `--max-response-size`. Sends an
+    /// error and returns `false` if the file must not be sent at all.
+    async fn check_size(&mut self, path: &Path, len: u64) -> Result<bool> {
+        match ARGS.max_response_size {
+            Some(max) if len > max => {
+                if ARGS.truncate_large_responses {
+                    log::warn!("Truncating {} from {len} to {max} bytes", path.display());
+                    Ok(true)
+                } else {
+                    log::warn!(
+                        "Not sending {}, it is larger than {max} bytes",
+                        path.display()
+                    );
+                    self.send_header(BAD_REQUEST, "Response too large").await?;
+                    Ok(false)
+                }
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Sends the rest of the file in chunks of the configured buffer size,
+    /// so large files are never read into memory at once. Only sends up to
+    /// `--max-response-size` bytes.
+    async fn send_file(&mut self, file: tokio::fs::File) -> Result {
+        let file = file.take(ARGS.max_response_size.unwrap_or(u64::MAX));
+        let mut reader = tokio::io::BufReader::with_capacity(ARGS.file_buffer_size, file);
+        tokio::io::copy_buf(&mut reader, &mut self.stream).await?;
         Ok(())
     }
 }
@@ -0,0 +1,77 @@
+use std::{
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    sync::RwLock,
+    time::Duration,
+};
+
+/// How often the maintenance file is checked.
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// The message that is sent if the maintenance file is empty.
+const DEFAULT_MESSAGE: &str = "Down for maintenance, please try again later.";
+
+/// Maintenance mode, which is on while a file exists, so it can be turned on
+/// and off without a restart. While it is on, all requests are answered with
+/// status 41 and the first line of the file as the message.
+pub(crate) struct Maintenance {
+    file: PathBuf,
+    message: RwLock<Option<String>>,
+}
+
+impl Maintenance {
+    /// Checks the file once, so maintenance mode is on right from the start
+    /// if it exists.
+    pub fn new(file: PathBuf) -> Self {
+        let message = read(&file);
+        Self {
+            file,
+            message: RwLock::new(message),
+        }
+    }
+
+    /// Returns the file that turns on maintenance mode.
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// Returns the message to send if maintenance mode is on.
+    pub fn message(&self) -> Option<String> {
+        self.message.read().unwrap().clone()
+    }
+
+    /// Checks the file periodically and turns maintenance mode on or off.
+    pub async fn watch(&self) {
+        loop {
+            tokio::time::sleep(INTERVAL).await;
+            let message = read(&self.file);
+            let mut current = self.message.write().unwrap();
+            match (current.is_some(), message.is_some()) {
+                (false, true) => log::info!("Maintenance mode on"),
+                (true, false) => log::info!("Maintenance mode off"),
+                _ => (),
+            }
+            *current = message;
+        }
+    }
+}
+
+/// Reads the message from the file, or returns `None` if it does not exist.
+fn read(file: &Path) -> Option<String> {
+    match std::fs::read_to_string(file) {
+        Ok(content) => {
+            let message = content.lines().next().unwrap_or_default().trim();
+            Some(if message.is_empty() {
+                DEFAULT_MESSAGE.to_string()
+            } else {
+                message.to_string()
+            })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            // turn maintenance mode on, since the file is there
+            log::warn!("could not read maintenance file {}: {e}", file.display());
+            Some(DEFAULT_MESSAGE.to_string())
+        }
+    }
+}
@@ -0,0 +1,495 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+/// Extensions of files that are converted from Markdown to gemtext.
+static EXTENSIONS: &[&str] = &["md", "markdown"];
+
+/// The converted content of a Markdown file and the modification time of the
+/// file.
+type Cached = (SystemTime, Vec<u8>);
+
+/// The Markdown files that were converted.
+static CACHE: Lazy<Mutex<HashMap<PathBuf, Cached>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns whether the file is a Markdown file.
+pub(crate) fn is_markdown(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| EXTENSIONS.iter().any(|md| md.eq_ignore_ascii_case(ext)))
+}
+
+/// Reads the Markdown file and converts it to gemtext. A file is only
+/// converted again once it was modified.
+pub(crate) async fn convert(file: &Path) -> std::io::Result<Vec<u8>> {
+    let file = file.to_path_buf();
+    tokio::task::spawn_blocking(move || {
+        let modified = std::fs::metadata(&file)?.modified().ok();
+        if let Some(modified) = modified {
+            if let Some((read, content)) = CACHE.lock().unwrap().get(&file) {
+                if *read == modified {
+                    return Ok(content.clone());
+                }
+            }
+        }
+        let content = to_gemtext(&String::from_utf8_lossy(&std::fs::read(&file)?)).into_bytes();
+        if let Some(modified) = modified {
+            CACHE
+                .lock()
+                .unwrap()
+                .insert(file, (modified, content.clone()));
+        }
+        Ok(content)
+    })
+    .await?
+}
+
+/// Converts Markdown to gemtext. Headings, lists, block quotes and fenced
+/// code blocks are turned into their gemtext equivalents and the lines of a
+/// paragraph are joined. Since gemtext has no inline links, the text of links
+/// and images is kept in place and a link line for each of them is added
+/// after the block. Other inline formatting is kept as it is.
+pub(crate) fn to_gemtext(markdown: &str) -> String {
+    let references = references(markdown);
+    let mut converter = Converter {
+        references: &references,
+        output: String::with_capacity(markdown.len()),
+        paragraph: vec![],
+        links: vec![],
+        in_list: false,
+    };
+    let mut fence: Option<String> = None;
+    for line in markdown.lines() {
+        if let Some(marker) = &fence {
+            if is_fence_end(line, marker) {
+                converter.output.push_str("```\n");
+                fence = None;
+            } else {
+                converter.output.push_str(line);
+                converter.output.push('\n');
+            }
+            continue;
+        }
+        fence = converter.line(line);
+    }
+    if fence.is_some() {
+        converter.output.push_str("```\n");
+    }
+    converter.end_block();
+    // blank lines at the end, e.g. before link references, are removed
+    while converter.output.ends_with("\n\n") {
+        converter.output.pop();
+    }
+    converter.output
+}
+
+/// The state of a conversion outside of code blocks.
+struct Converter<'a> {
+    /// Destinations of reference links by their lowercase label.
+    references: &'a HashMap<String, String>,
+    output: String,
+    /// Lines of the current paragraph.
+    paragraph: Vec<String>,
+    /// Destinations and texts of the links in the current block.
+    links: Vec<(String, String)>,
+    /// Whether the last line was a list item or its continuation.
+    in_list: bool,
+}
+
+impl Converter<'_> {
+    /// Converts a line outside of a code block. Returns the marker of a code
+    /// block if the line starts one.
+    fn line(&mut self, line: &str) -> Option<String> {
+        let trimmed = line.trim();
+        let indent = line.len() - line.trim_start().len();
+        if trimmed.is_empty() {
+            self.end_block();
+            if !self.output.is_empty() && !self.output.ends_with("\n\n") {
+                self.output.push('\n');
+            }
+            return None;
+        }
+        if indent < 4 {
+            if let Some((marker, info)) = fence_start(trimmed) {
+                self.end_block();
+                self.output.push_str("```");
+                self.output.push_str(info);
+                self.output.push('\n');
+                return Some(marker);
+            }
+            if self.paragraph.is_empty() && reference(trimmed).is_some() {
+                return None;
+            }
+        }
+        if let Some((level, text)) = heading(trimmed).filter(|_| indent < 4) {
+            self.end_block();
+            self.heading(level, text);
+        } else if let Some(level) = underline(trimmed).filter(|_| !self.paragraph.is_empty()) {
+            let text = std::mem::take(&mut self.paragraph).join(" ");
+            self.end_block();
+            self.heading(level, &text);
+        } else if is_break(trimmed) && self.paragraph.is_empty() {
+            self.end_block();
+            self.output.push_str(trimmed);
+            self.output.push('\n');
+        } else if let Some(item) = list_item(trimmed) {
+            self.end_paragraph();
+            let text = self.inline(item);
+            self.output.push_str("* ");
+            self.output.push_str(&text);
+            self.output.push('\n');
+            self.in_list = true;
+        } else if ordered_item(trimmed) {
+            self.end_paragraph();
+            let text = self.inline(trimmed);
+            self.output.push_str(&text);
+            self.output.push('\n');
+            self.in_list = true;
+        } else if let Some(quote) = trimmed.strip_prefix('>') {
+            self.end_paragraph();
+            self.in_list = false;
+            let text = self.inline(quote.trim_start());
+            self.output.push_str("> ");
+            self.output.push_str(&text);
+            self.output.push('\n');
+        } else if self.in_list && indent > 0 {
+            // continuation of a list item
+            let text = self.inline(trimmed);
+            self.output.pop();
+            self.output.push(' ');
+            self.output.push_str(&text);
+            self.output.push('\n');
+        } else {
+            self.in_list = false;
+            // a hard line break ends the line of the paragraph
+            let hard_break = line.ends_with("  ") || trimmed.ends_with('\\');
+            let text = trimmed.strip_suffix('\\').unwrap_or(trimmed).trim_end();
+            match self.paragraph.last_mut() {
+                Some(last) if !last.ends_with('\n') => {
+                    last.push(' ');
+                    last.push_str(text);
+                }
+                _ => self.paragraph.push(text.to_string()),
+            }
+            if hard_break {
+                self.paragraph.last_mut().unwrap().push('\n');
+            }
+        }
+        None
+    }
+
+    fn heading(&mut self, level: usize, text: &str) {
+        let text = self.inline(text);
+        self.output.push_str(&"#".repeat(level.min(3)));
+        self.output.push(' ');
+        self.output.push_str(&text);
+        self.output.push('\n');
+        self.end_block();
+    }
+
+    /// Writes the current paragraph. A paragraph that only consists of a
+    /// link is replaced by the link line.
+    fn end_paragraph(&mut self) {
+        if self.paragraph.is_empty() {
+            return;
+        }
+        for line in std::mem::take(&mut self.paragraph) {
+            let links = self.links.len();
+            let text = self.inline(line.trim_end());
+            let only_link = self.links.len() == links + 1 && self.links[links].1 == text;
+            if !only_link {
+                self.output.push_str(&text);
+                self.output.push('\n');
+            }
+        }
+    }
+
+    /// Writes the current paragraph and the link lines of the block.
+    fn end_block(&mut self) {
+        self.end_paragraph();
+        self.in_list = false;
+        for (url, text) in std::mem::take(&mut self.links) {
+            self.output.push_str("=> ");
+            self.output.push_str(&url);
+            if !text.is_empty() {
+                self.output.push(' ');
+                self.output.push_str(&text);
+            }
+            self.output.push('\n');
+        }
+    }
+
+    /// Converts the inline elements of some text. The text of links and
+    /// images is kept and they are remembered for the link lines.
+    fn inline(&mut self, text: &str) -> String {
+        let mut output = String::with_capacity(text.len());
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '\\' if rest[1..].starts_with(|c: char| c.is_ascii_punctuation()) => {
+                    output.push_str(&rest[1..2]);
+                    rest = &rest[2..];
+                    continue;
+                }
+                '`' => {
+                    // code spans are kept as they are
+                    let ticks = rest.len() - rest.trim_start_matches('`').len();
+                    let delimiter = &rest[..ticks];
+                    if let Some(end) = rest[ticks..].find(delimiter) {
+                        let len = ticks + end + ticks;
+                        output.push_str(&rest[..len]);
+                        rest = &rest[len..];
+                    } else {
+                        output.push_str(delimiter);
+                        rest = &rest[ticks..];
+                    }
+                    continue;
+                }
+                '[' | '!' if rest.starts_with('[') || rest.starts_with("![") => {
+                    let start = if c == '!' { 2 } else { 1 };
+                    if let Some((label, url, len)) = self.link(&rest[start..]) {
+                        let label = self.inline(label);
+                        output.push_str(&label);
+                        self.links.push((url, collapse(&label)));
+                        rest = &rest[start + len..];
+                        continue;
+                    }
+                }
+                '<' => {
+                    if let Some(end) = rest.find('>') {
+                        let url = &rest[1..end];
+                        if is_autolink(url) {
+                            output.push_str(url);
+                            self.links.push((url.to_string(), String::new()));
+                            rest = &rest[end + 1..];
+                            continue;
+                        }
+                    }
+                }
+                _ => (),
+            }
+            output.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+        output
+    }
+
+    /// Parses a link after its opening bracket. Returns the text and the
+    /// destination of the link and the length of the rest of the link.
+    fn link<'t>(&self, text: &'t str) -> Option<(&'t str, String, usize)> {
+        let end = closing_bracket(text)?;
+        let label = &text[..end];
+        let after = &text[end + 1..];
+        if let Some(destination) = after.strip_prefix('(') {
+            let (url, len) = destination_and_title(destination)?;
+            return Some((label, url.to_string(), end + 2 + len));
+        }
+        // reference links, which may omit the reference if it is the text
+        let (reference, len) = match after.strip_prefix('[') {
+            Some(reference) => {
+                let close = reference.find(']')?;
+                match &reference[..close] {
+                    "" => (label, close + 2),
+                    reference => (reference, close + 2),
+                }
+            }
+            None => (label, 0),
+        };
+        let url = self.references.get(&normalize(reference))?;
+        Some((label, url.clone(), end + 1 + len))
+    }
+}
+
+/// Returns the marker and the info string if the line starts a fenced code
+/// block.
+fn fence_start(line: &str) -> Option<(String, &str)> {
+    let c = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = line.len() - line.trim_start_matches(c).len();
+    let info = line[len..].trim();
+    (len >= 3 && !(c == '`' && info.contains('`'))).then(|| (line[..len].to_string(), info))
+}
+
+/// Returns whether the line ends the fenced code block with the marker.
+fn is_fence_end(line: &str, marker: &str) -> bool {
+    let indent = line.len() - line.trim_start().len();
+    let line = line.trim();
+    let c = marker.chars().next().unwrap_or('`');
+    indent < 4 && line.len() >= marker.len() && line.chars().all(|x| x == c)
+}
+
+/// Returns the level and the text if the line is an ATX heading like
+/// `## Heading`.
+fn heading(line: &str) -> Option<(usize, &str)> {
+    let level = line.len() - line.trim_start_matches('#').len();
+    let rest = &line[level..];
+    if !(1..=6).contains(&level) || !(rest.is_empty() || rest.starts_with([' ', '\t'])) {
+        return None;
+    }
+    // closing hashes are removed
+    let text = rest.trim();
+    let text = match text.trim_end_matches('#') {
+        stripped if stripped.is_empty() || stripped.ends_with([' ', '\t']) => stripped.trim_end(),
+        _ => text,
+    };
+    Some((level, text))
+}
+
+/// Returns the level if the line underlines a setext heading.
+fn underline(line: &str) -> Option<usize> {
+    if line.chars().all(|c| c == '=') {
+        Some(1)
+    } else if line.chars().all(|c| c == '-') {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+/// Returns whether the line is a thematic break like `***` or `- - -`.
+fn is_break(line: &str) -> bool {
+    let Some(c) = line.chars().next().filter(|c| matches!(c, '-' | '*' | '_')) else {
+        return false;
+    };
+    line.chars().all(|x| x == c || x == ' ' || x == '\t') && line.matches(c).count() >= 3
+}
+
+/// Returns the text of a bullet list item.
+fn list_item(line: &str) -> Option<&str> {
+    line.strip_prefix(['-', '*', '+'])
+        .filter(|rest| rest.starts_with([' ', '\t']))
+        .map(str::trim_start)
+}
+
+/// Returns whether the line is an item of an ordered list like `1. Item`.
+fn ordered_item(line: &str) -> bool {
+    let digits = line.len() - line.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+    (1..=9).contains(&digits)
+        && line[digits..]
+            .strip_prefix(['.', ')'])
+            .is_some_and(|rest| rest.starts_with([' ', '\t']))
+}
+
+/// Returns the label and the destination if the line defines a link
+/// reference like `[label]: https://example.com "Title"`.
+fn reference(line: &str) -> Option<(String, &str)> {
+    let rest = line.strip_prefix('[')?;
+    let end = rest.find(']')?;
+    let label = normalize(&rest[..end]);
+    let destination = rest[end + 1..].strip_prefix(':')?.trim_start();
+    let url = match destination.strip_prefix('<') {
+        Some(url) => &url[..url.find('>')?],
+        None => destination.split_whitespace().next()?,
+    };
+    (!label.is_empty()).then_some((label, url))
+}
+
+/// Collects the link references outside of code blocks.
+fn references(markdown: &str) -> HashMap<String, String> {
+    let mut references = HashMap::new();
+    let mut fence: Option<String> = None;
+    for line in markdown.lines() {
+        match &fence {
+            Some(marker) if is_fence_end(line, marker) => fence = None,
+            Some(_) => (),
+            None => {
+                if let Some((marker, _)) = fence_start(line.trim()) {
+                    fence = Some(marker);
+                } else if let Some((label, url)) = reference(line.trim()) {
+                    // the first definition of a label is used
+                    references.entry(label).or_insert_with(|| url.to_string());
+                }
+            }
+        }
+    }
+    references
+}
+
+/// Normalizes a reference label, which is matched case-insensitively and
+/// regardless of whitespace.
+fn normalize(label: &str) -> String {
+    collapse(&label.to_lowercase())
+}
+
+/// Replaces each sequence of whitespace with a single space.
+fn collapse(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Returns the position of the bracket that closes an opening bracket before
+/// the text.
+fn closing_bracket(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '[' => depth += 1,
+            ']' if depth == 0 => return Some(i),
+            ']' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Parses the destination and the optional title of an inline link after
+/// the opening parenthesis. Returns the destination and the length up to and
+/// including the closing parenthesis.
+fn destination_and_title(text: &str) -> Option<(&str, usize)> {
+    let start = text.len() - text.trim_start().len();
+    let (url, mut pos) = if let Some(rest) = text[start..].strip_prefix('<') {
+        let end = rest.find(['>', '\n'])?;
+        (&rest[..end], start + 1 + end + 1)
+    } else {
+        // parentheses in the destination have to be balanced
+        let mut depth = 0;
+        let mut end = text.len();
+        for (i, c) in text[start..].char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 0 => {
+                    end = start + i;
+                    break;
+                }
+                ')' => depth -= 1,
+                c if c.is_whitespace() => {
+                    end = start + i;
+                    break;
+                }
+                _ => (),
+            }
+        }
+        (&text[start..end], end)
+    };
+    pos += text[pos..].len() - text[pos..].trim_start().len();
+    if let Some(quote) = text[pos..]
+        .chars()
+        .next()
+        .filter(|c| matches!(c, '"' | '\'' | '('))
+    {
+        let close = if quote == '(' { ')' } else { quote };
+        pos += 1 + text[pos + 1..].find(close)? + 1;
+        pos += text[pos..].len() - text[pos..].trim_start().len();
+    }
+    text[pos..].starts_with(')').then_some((url, pos + 1))
+}
+
+/// Returns whether the text between angle brackets is an absolute URL.
+fn is_autolink(text: &str) -> bool {
+    if text.is_empty() || text.contains(|c: char| c.is_whitespace() || c == '<') {
+        return false;
+    }
+    match text.split_once(':') {
+        Some((scheme, _)) => {
+            scheme.len() >= 2
+                && scheme.starts_with(|c: char| c.is_ascii_alphabetic())
+                && scheme
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '.' | '-'))
+        }
+        None => false,
+    }
+}
@@ -1,11 +1,16 @@
 use configparser::ini::Ini;
 use glob::{glob_with, MatchOptions};
 use std::collections::BTreeMap;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
 static SIDECAR_FILENAME: &str = ".meta";
 
+/// Name of the file that sets the response header for its directory and
+/// everything in it.
+static STATUS_FILENAME: &str = ".status";
+
 /// A struct to store a string of metadata for each file retrieved from
 /// sidecar files with the name given by `SIDECAR_FILENAME`.
 ///
@@ -57,6 +62,14 @@ pub(crate) enum PresetMeta {
     /// Agate will send this header line, CR, LF, and nothing else. Agate will
     /// not try to access the requested file.
     FullHeader(u8, String),
+    /// A `FullHeader` line with an input status code between 10 and 19.
+    /// ```text
+    /// search.cgi: 10 Search for
+    /// ```
+    /// Agate will send this header line for requests without a query. The
+    /// client then requests the same URL with the input as the query, which
+    /// is answered with the file or the output of the CGI script as usual.
+    Input(u8, String),
 }
 
 impl FileOptions {
@@ -72,9 +85,10 @@ impl FileOptions {
     /// still up to date and re-reads it if outdated or not yet read.
     fn update(&mut self, file: &Path) {
         let mut db = if super::ARGS.central_config {
-            super::ARGS.content_dir.clone()
+            super::ARGS.content_root(file).to_path_buf()
         } else {
-            file.parent().expect("no parent directory").to_path_buf()
+            // the root directory has no parent, e.g. with --chroot
+            file.parent().unwrap_or(file).to_path_buf()
         };
         db.push(SIDECAR_FILENAME);
 
@@ -166,7 +180,11 @@ impl FileOptions {
                 // not taking a slice here because the separator
                 // might be a whitespace wider than a byte
                 let meta = header.chars().skip(3).collect::<String>();
-                PresetMeta::FullHeader(status, meta)
+                if status < 20 {
+                    PresetMeta::Input(status, meta)
+                } else {
+                    PresetMeta::FullHeader(status, meta)
+                }
             } else {
                 // must be a MIME type, but without status code
                 PresetMeta::FullMime(header.to_string())
@@ -178,7 +196,7 @@ impl FileOptions {
                 require_literal_separator: true,
                 // security measure because entries for .hidden files
                 // would result in them being exposed.
-                require_literal_leading_dot: !crate::ARGS.serve_secret,
+                require_literal_leading_dot: !crate::ARGS.hidden_files.served(),
             };
 
             // process filename as glob
@@ -234,3 +252,39 @@ impl FileOptions {
         self.file_meta.contains_key(file)
     }
 }
+
+/// Reads the response header from the status file in the given directory,
+/// if there is one. The file contains a single line in the form
+/// ```text
+/// <status> <meta>
+/// ```
+/// for example `52 This directory was removed.`, which is sent for all
+/// requests for the directory and everything in it. The file is read on
+/// every request, so changes apply right away.
+pub(crate) async fn status_override(dir: &Path) -> Option<(u8, String)> {
+    let file = dir.join(STATUS_FILENAME);
+    let content = match tokio::fs::read_to_string(&file).await {
+        Ok(content) => content,
+        // the path may also lead to a file instead of a directory
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::NotADirectory) => {
+            return None
+        }
+        Err(e) => {
+            log::error!("could not read status file {:?}: {}", file, e);
+            return None;
+        }
+    };
+    let line = content.lines().next().unwrap_or_default();
+    match line.split_once(' ') {
+        Some((status, meta)) if status.len() == 2 && matches!(status.parse(), Ok(10..=69)) => {
+            Some((status.parse().unwrap(), meta.trim().to_string()))
+        }
+        _ => {
+            log::error!(
+                "status file {:?} does not start with a status code and a space, ignoring it",
+                file
+            );
+            None
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use {
+    once_cell::sync::Lazy,
+    std::{
+        collections::BTreeMap,
+        fmt::Write,
+        sync::{
+            atomic::{AtomicI64, AtomicU64, Ordering},
+            Mutex,
+        },
+        time::{Duration, UNIX_EPOCH},
+    },
+    tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{TcpListener, TcpStream},
+    },
+};
+
+/// Upper bounds of the buckets of the request duration histogram in seconds.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// The maximum size of the request header of a metrics request.
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// The metrics of the whole server.
+pub(crate) static METRICS: Lazy<Metrics> = Lazy::new(Metrics::default);
+
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Number of requests by host name and status code.
+    requests: Mutex<BTreeMap<(String, String), u64>>,
+    /// Number of bytes sent by host name.
+    bytes: Mutex<BTreeMap<String, u64>>,
+    active_connections: AtomicI64,
+    tls_failures: AtomicU64,
+    /// Counts of the request duration histogram for each bucket, the last
+    /// one is for durations that are larger than all bucket bounds.
+    durations: [AtomicU64; BUCKETS.len() + 1],
+    /// Sum of all request durations in microseconds.
+    duration_sum: AtomicU64,
+    stat_cache_hits: AtomicU64,
+    stat_cache_misses: AtomicU64,
+    panics: AtomicU64,
+}
+
+impl Metrics {
+    /// Records a finished request. Unknown host names and status codes are
+    /// recorded as empty labels.
+    pub fn request(&self, host: Option<&str>, status: Option<u8>, bytes: u64, duration: Duration) {
+        let host = host.unwrap_or_default().to_string();
+        let status = status.map_or(String::new(), |status| status.to_string());
+        *self
+            .requests
+            .lock()
+            .unwrap()
+            .entry((host.clone(), status))
+            .or_default() += 1;
+        *self.bytes.lock().unwrap().entry(host).or_default() += bytes;
+
+        let seconds = duration.as_secs_f64();
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| seconds <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.durations[bucket].fetch_add(1, Ordering::Relaxed);
+        self.duration_sum
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Records that a TLS handshake failed.
+    pub fn tls_failure(&self) {
+        self.tls_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records whether a URL was found in the stat cache.
+    pub fn stat_cache_lookup(&self, hit: bool) {
+        let counter = if hit {
+            &self.stat_cache_hits
+        } else {
+            &self.stat_cache_misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records that handling a connection panicked.
+    pub fn panic(&self) {
+        self.panics.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the metrics in the Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str(
+            "# HELP agate_requests_total Number of requests by host name and status code.\n",
+        );
+        out.push_str("# TYPE agate_requests_total counter\n");
+        for ((host, status), count) in self.requests.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "agate_requests_total{{host=\"{}\",status=\"{status}\"}} {count}",
+                escape(host)
+            )
+            .unwrap();
+        }
+
+        out.push_str("# HELP agate_sent_bytes_total Number of bytes sent by host name.\n");
+        out.push_str("# TYPE agate_sent_bytes_total counter\n");
+        for (host, bytes) in self.bytes.lock().unwrap().iter() {
+            writeln!(
+                out,
+                "agate_sent_bytes_total{{host=\"{}\"}} {bytes}",
+                escape(host)
+            )
+            .unwrap();
+        }
+
+        out.push_str("# HELP agate_active_connections Number of currently open connections.\n");
+        out.push_str("# TYPE agate_active_connections gauge\n");
+        writeln!(
+            out,
+            "agate_active_connections {}",
+            self.active_connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out.push_str(
+            "# HELP agate_tls_handshake_failures_total Number of failed TLS handshakes.\n",
+        );
+        out.push_str("# TYPE agate_tls_handshake_failures_total counter\n");
+        writeln!(
+            out,
+            "agate_tls_handshake_failures_total {}",
+            self.tls_failures.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out.push_str(
+            "# HELP agate_panics_total Number of connections that were closed because handling them panicked.\n",
+        );
+        out.push_str("# TYPE agate_panics_total counter\n");
+        writeln!(
+            out,
+            "agate_panics_total {}",
+            self.panics.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        out.push_str("# HELP agate_certificate_expiry_timestamp_seconds Time when a certificate expires, by domain name, which is empty for the fallback certificate.\n");
+        out.push_str("# TYPE agate_certificate_expiry_timestamp_seconds gauge\n");
+        for (domain, not_after) in crate::ARGS.certs.expiry() {
+            if let Some(not_after) = not_after {
+                writeln!(
+                    out,
+                    "agate_certificate_expiry_timestamp_seconds{{domain=\"{}\"}} {}",
+                    escape(&domain),
+                    not_after
+                        .duration_since(UNIX_EPOCH)
+                        .map_or(0, |time| time.as_secs())
+                )
+                .unwrap();
+            }
+        }
+
+        out.push_str(
+            "# HELP agate_request_duration_seconds Time from accepting a connection until the response was sent.\n",
+        );
+        out.push_str("# TYPE agate_request_duration_seconds histogram\n");
+        // the buckets of the exposition format are cumulative
+        let mut count = 0;
+        for (i, bound) in BUCKETS.iter().enumerate() {
+            count += self.durations[i].load(Ordering::Relaxed);
+            writeln!(
+                out,
+                "agate_request_duration_seconds_bucket{{le=\"{bound}\"}} {count}"
+            )
+            .unwrap();
+        }
+        count += self.durations[BUCKETS.len()].load(Ordering::Relaxed);
+        writeln!(
+            out,
+            "agate_request_duration_seconds_bucket{{le=\"+Inf\"}} {count}"
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "agate_request_duration_seconds_sum {}",
+            self.duration_sum.load(Ordering::Relaxed) as f64 / 1e6
+        )
+        .unwrap();
+        writeln!(out, "agate_request_duration_seconds_count {count}").unwrap();
+
+        if crate::ARGS.stat_cache.is_some() {
+            out.push_str(
+                "# HELP agate_stat_cache_lookups_total Number of URLs looked up in the stat cache by result.\n",
+            );
+            out.push_str("# TYPE agate_stat_cache_lookups_total counter\n");
+            for (result, counter) in [
+                ("hit", &self.stat_cache_hits),
+                ("miss", &self.stat_cache_misses),
+            ] {
+                writeln!(
+                    out,
+                    "agate_stat_cache_lookups_total{{result=\"{result}\"}} {}",
+                    counter.load(Ordering::Relaxed)
+                )
+                .unwrap();
+            }
+        }
+
+        out
+    }
+}
+
+/// Escapes a label value for the Prometheus text exposition format.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Counts a connection as active for as long as it exists.
+pub(crate) struct ActiveConnection(());
+
+impl ActiveConnection {
+    pub fn new() -> Self {
+        METRICS.active_connections.fetch_add(1, Ordering::Relaxed);
+        Self(())
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        METRICS.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serves the metrics at `/metrics` with HTTP on the given listener.
+pub(crate) async fn serve(listener: TcpListener) {
+    loop {
+        let (stream, _) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                log::warn!("could not accept metrics connection: {e}");
+                continue;
+            }
+        };
+        tokio::spawn(async {
+            if let Err(e) = respond(stream).await {
+                log::debug!("error in metrics connection: {e}");
+            }
+        });
+    }
+}
+
+/// Answers a single HTTP request for the metrics.
+async fn respond(stream: TcpStream) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream);
+    let mut head = String::new();
+    loop {
+        let len = (&mut reader)
+            .take(MAX_REQUEST_SIZE - head.len() as u64)
+            .read_line(&mut head)
+            .await?;
+        if len == 0 || head.ends_with("\r\n\r\n") || head.ends_with("\n\n") {
+            break;
+        }
+    }
+
+    let mut request_line = head.lines().next().unwrap_or_default().split(' ');
+    let response = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let body = METRICS.render();
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        }
+        (Some("GET"), _) => {
+            "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into()
+        }
+        _ => "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".into(),
+    };
+    let mut stream = reader.into_inner();
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
@@ -0,0 +1,166 @@
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::io::AsyncReadExt;
+
+/// Media types for file extensions that are not known to `mime_guess` or
+/// that Agate should treat differently.
+static BUILTIN: &[(&str, &str)] = &[("gmi", "text/gemini"), ("gemini", "text/gemini")];
+
+/// Media types of files that start with the given bytes at the given
+/// offset, for files whose extension is not known.
+static MAGIC: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (8, b"WEBP", "image/webp"),
+    (8, b"WAVE", "audio/wav"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"fLaC", "audio/flac"),
+    (0, b"ID3", "audio/mpeg"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"BZh", "application/x-bzip2"),
+    (0, b"\xfd7zXZ\x00", "application/x-xz"),
+    (0, b"7z\xbc\xaf\x27\x1c", "application/x-7z-compressed"),
+    (257, b"ustar", "application/x-tar"),
+];
+
+/// Number of bytes at the start of a file that are used to sniff its media
+/// type.
+const SNIFF_LEN: usize = 512;
+
+/// A struct to look up the MIME media type of a file from its extension.
+///
+/// Media types are looked up in this order:
+/// 1. the `mime.types` file given with `--mime-types`, if any
+/// 2. the built-in table `BUILTIN`
+/// 3. the database of the `mime_guess` crate
+///
+/// If none of these know the extension, `application/octet-stream` is used,
+/// or with `guess_or_sniff`, a media type guessed from the first bytes of the
+/// file.
+pub(crate) struct MimeTypes {
+    /// Media types by lower case file extension.
+    types: HashMap<String, String>,
+}
+
+impl MimeTypes {
+    /// Creates a lookup table that only uses the built-in types.
+    pub fn new() -> Self {
+        Self {
+            types: HashMap::new(),
+        }
+    }
+
+    /// Reads a file in the format of `mime.types` as used by e.g. Apache
+    /// or nginx, where each line contains a media type followed by the
+    /// extensions it applies to, separated by whitespace:
+    /// ```text
+    /// # comment
+    /// text/gemini gmi gemini
+    /// text/markdown md
+    /// ```
+    /// Lines that start with `#` and lines without extensions are ignored.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read MIME types file {path:?}: {e}"))?;
+
+        let mut types = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words = line.split_whitespace();
+            // there must be at least one word because the line is not empty
+            let mime = words.next().unwrap();
+            if !mime.contains('/') {
+                return Err(format!(
+                    "{}:{}: {mime:?} is not a MIME media type",
+                    path.display(),
+                    number + 1
+                ));
+            }
+            for ext in words {
+                types.insert(ext.to_lowercase(), mime.to_string());
+            }
+        }
+
+        Ok(Self { types })
+    }
+
+    /// Guesses the MIME media type of the given file by its extension.
+    pub fn guess(&self, path: &Path) -> String {
+        self.known(path)
+            .unwrap_or_else(|| "application/octet-stream".to_string())
+    }
+
+    /// Returns the MIME media type for the extension of the given file, if
+    /// it is known.
+    fn known(&self, path: &Path) -> Option<String> {
+        let ext = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default()
+            .to_lowercase();
+
+        if let Some(mime) = self.types.get(&ext) {
+            Some(mime.clone())
+        } else if let Some((_, mime)) = BUILTIN.iter().find(|(e, _)| *e == ext) {
+            Some(mime.to_string())
+        } else {
+            mime_guess::from_path(path)
+                .first()
+                .map(|mime| mime.essence_str().to_string())
+        }
+    }
+
+    /// Guesses the MIME media type of the given file by its extension, or
+    /// by its first bytes if the extension is not known.
+    pub async fn guess_or_sniff(&self, path: &Path) -> String {
+        if let Some(mime) = self.known(path) {
+            return mime;
+        }
+        let mut head = Vec::with_capacity(SNIFF_LEN);
+        let read = match tokio::fs::File::open(path).await {
+            Ok(file) => file.take(SNIFF_LEN as u64).read_to_end(&mut head).await,
+            Err(e) => Err(e),
+        };
+        match read {
+            Ok(_) => sniff(&head).to_string(),
+            // the error is reported when the file is opened to be sent
+            Err(_) => self.guess(path),
+        }
+    }
+}
+
+/// Guesses the MIME media type from the first bytes of a file. Files of an
+/// unknown type are `text/plain` if they look like UTF-8 text, otherwise
+/// `application/octet-stream`.
+fn sniff(head: &[u8]) -> &'static str {
+    if let Some((_, _, mime)) = MAGIC
+        .iter()
+        .find(|(offset, magic, _)| head.get(*offset..).is_some_and(|h| h.starts_with(magic)))
+    {
+        return mime;
+    }
+    let text = match std::str::from_utf8(head) {
+        Ok(text) => text,
+        // the last character may be cut off
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&head[..e.valid_up_to()]).expect("checked before")
+        }
+        Err(_) => return "application/octet-stream",
+    };
+    if text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c' | '\x1b'))
+    {
+        "application/octet-stream"
+    } else {
+        "text/plain"
+    }
+}
@@ -0,0 +1,85 @@
+//! Dropping root privileges after the listeners are bound.
+
+use {
+    agate::sys,
+    std::{ffi::CString, fmt, io},
+};
+
+/// The user and group Agate switches to.
+#[derive(Debug)]
+pub(crate) struct Account {
+    /// The user name, to also switch to its supplementary groups.
+    user: Option<CString>,
+    uid: Option<libc::uid_t>,
+    gid: libc::gid_t,
+}
+
+impl Account {
+    /// Looks up the user and group, given by name or number. If only the
+    /// user is given, its primary group is used.
+    pub fn lookup(user: Option<&str>, group: Option<&str>) -> Result<Self, String> {
+        let (user, uid, primary_gid) = match user {
+            Some(user) => {
+                let (uid, gid) = lookup_user(user)?;
+                // supplementary groups can only be looked up by user name
+                let name = user
+                    .parse::<libc::uid_t>()
+                    .err()
+                    .and_then(|_| CString::new(user).ok());
+                (name, Some(uid), Some(gid))
+            }
+            None => (None, None, None),
+        };
+        let gid = match group {
+            Some(group) => lookup_group(group)?,
+            None => primary_gid.ok_or("A user or group is required")?,
+        };
+        Ok(Self { user, uid, gid })
+    }
+
+    /// Switches the whole process to the user and group, including all
+    /// threads. This fails unless Agate runs as root.
+    pub fn switch(&self) -> io::Result<()> {
+        // the supplementary groups have to be set while still being root
+        match &self.user {
+            Some(user) => sys::initgroups(user, self.gid)?,
+            None => sys::setgroups(self.gid)?,
+        }
+        sys::setgid(self.gid)?;
+        if let Some(uid) = self.uid {
+            sys::setuid(uid)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(uid) = self.uid {
+            write!(f, "user {uid} and ")?;
+        }
+        write!(f, "group {}", self.gid)
+    }
+}
+
+/// Returns the user ID and primary group ID of a user name or number.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, libc::gid_t), String> {
+    let unknown = || format!("Unknown user: {user}");
+    match user.parse::<libc::uid_t>() {
+        Ok(uid) => Ok((uid, sys::user_by_id(uid).ok_or_else(unknown)?.1)),
+        Err(_) => {
+            let name = CString::new(user).map_err(|_| unknown())?;
+            sys::user_by_name(&name).ok_or_else(unknown)
+        }
+    }
+}
+
+/// Returns the group ID of a group name or number.
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+    let unknown = || format!("Unknown group: {group}");
+    let name = CString::new(group).map_err(|_| unknown())?;
+    sys::group_by_name(&name).ok_or_else(unknown)
+}
@@ -0,0 +1,148 @@
+use {
+    crate::{
+        cgi, sanitize,
+        timeout::{self, IdleTimeout},
+    },
+    once_cell::sync::Lazy,
+    std::{io, str::FromStr, sync::Arc, time::Duration},
+    tokio::{io::AsyncWriteExt, net::TcpStream},
+    tokio_rustls::{
+        rustls::{
+            self,
+            client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+            crypto::{ring::default_provider, WebPkiSupportedAlgorithms},
+            pki_types::{CertificateDer, ServerName, UnixTime},
+            ClientConfig, DigitallySignedStruct, SignatureScheme,
+        },
+        TlsConnector,
+    },
+    url::Url,
+};
+
+/// A rule to forward requests to an upstream Gemini server.
+#[derive(Debug, Clone)]
+pub(crate) struct Route {
+    /// The host name the rule applies to, or `None` for all host names.
+    pub host: Option<String>,
+    /// The URL path prefix the rule applies to.
+    pub prefix: String,
+    /// The address of the upstream server in the form `host:port`.
+    pub upstream: String,
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Parses a route in the form `[HOST][PREFIX]=UPSTREAM`, where the prefix
+    /// has to start with a slash.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((route, upstream)) = s.split_once('=') else {
+            return Err(format!(
+                "invalid proxy route {s:?}, expected [HOST][PREFIX]=UPSTREAM"
+            ));
+        };
+        if !upstream.contains(':') {
+            return Err(format!(
+                "invalid upstream address {upstream:?}, expected host:port"
+            ));
+        }
+        let (host, prefix) = match route.find('/') {
+            Some(0) => (None, route),
+            Some(i) => (Some(&route[..i]), &route[i..]),
+            None if route.is_empty() => (None, "/"),
+            None => (Some(route), "/"),
+        };
+        Ok(Self {
+            host: host.map(str::to_lowercase),
+            prefix: prefix.to_string(),
+            upstream: upstream.to_string(),
+        })
+    }
+}
+
+impl Route {
+    /// Checks if the request for the given URL should be forwarded.
+    /// `url_path` is its normalized path, or `None` if the path is not
+    /// valid, which only routes for all paths forward.
+    pub fn matches(&self, url: &Url, url_path: Option<&str>) -> bool {
+        self.host
+            .as_deref()
+            .is_none_or(|host| url.host_str() == Some(host))
+            && (self.prefix == "/"
+                || url_path.is_some_and(|url_path| sanitize::has_prefix(url_path, &self.prefix)))
+    }
+}
+
+/// TLS configuration for connections to upstream servers.
+static CONNECTOR: Lazy<TlsConnector> = Lazy::new(|| {
+    let config = ClientConfig::builder()
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(AnyServerCert {
+            algorithms: default_provider().signature_verification_algorithms,
+        }))
+        .with_no_client_auth();
+    TlsConnector::from(Arc::new(config))
+});
+
+/// Forwards the request for the given URL to the upstream server of the
+/// route and returns a channel that will receive its response in chunks.
+///
+/// The host name of the URL is used for SNI, so the upstream server can use
+/// virtual hosts. The request fails if the upstream server stalls for
+/// `time_limit`.
+pub(crate) async fn request(
+    route: &Route,
+    url: &Url,
+    time_limit: Duration,
+) -> io::Result<cgi::Output> {
+    let server_name = ServerName::try_from(url.host_str().unwrap_or_default().to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let stream = timeout::limit(time_limit, TcpStream::connect(&route.upstream)).await?;
+    let stream = IdleTimeout::new(stream, time_limit, time_limit);
+    let mut stream = CONNECTOR.connect(server_name, stream).await?;
+    stream.write_all(format!("{url}\r\n").as_bytes()).await?;
+    Ok(cgi::forward(stream))
+}
+
+/// Accepts any server certificate, as long as the server can prove that it
+/// holds the respective private key. Gemini servers usually use self signed
+/// certificates and the upstream servers are configured by the operator.
+#[derive(Debug)]
+struct AnyServerCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,142 @@
+//! The PROXY protocol in version 1 and 2, which load balancers like HAProxy
+//! use to pass on the addresses of the original connection, see
+//! <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use {
+    std::{
+        io::{self, ErrorKind},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    },
+    tokio::io::{AsyncRead, AsyncReadExt},
+};
+
+/// The signature at the start of a version 2 header.
+const SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// The maximum length of a version 1 header including CRLF.
+const MAX_V1_LEN: usize = 107;
+
+/// Reads a PROXY protocol header from the start of the stream. Returns the
+/// source and destination address of the original connection, or `None` if
+/// the proxy does not pass on addresses, like for its own health checks.
+///
+/// No more than the header is read, so the stream can be used for the
+/// proxied connection afterwards.
+pub(crate) async fn read_header<S>(stream: &mut S) -> io::Result<Option<(SocketAddr, SocketAddr)>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut start = [0; 5];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY" {
+        read_v1(stream).await
+    } else if start == SIGNATURE[..5] {
+        read_v2(stream).await
+    } else {
+        Err(invalid(
+            "the connection does not start with a PROXY protocol header",
+        ))
+    }
+}
+
+/// Reads the rest of a human-readable version 1 header like
+/// `PROXY TCP4 192.0.2.1 192.0.2.2 56324 1965\r\n`.
+async fn read_v1<S>(stream: &mut S) -> io::Result<Option<(SocketAddr, SocketAddr)>>
+where
+    S: AsyncRead + Unpin,
+{
+    // read byte by byte, to not read any data after the header
+    let mut line = b"PROXY".to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == MAX_V1_LEN {
+            return Err(invalid("the PROXY protocol header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("the PROXY protocol header is not valid ASCII"))?;
+
+    let fields = line.split(' ').collect::<Vec<_>>();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, destination, source_port, destination_port] =>
+        {
+            let parse = |ip: &str, port: &str| -> Option<SocketAddr> {
+                let ip = ip.parse::<IpAddr>().ok()?;
+                if ip.is_ipv4() != (family == "TCP4") {
+                    return None;
+                }
+                Some(SocketAddr::new(ip, port.parse().ok()?))
+            };
+            match (
+                parse(source, source_port),
+                parse(destination, destination_port),
+            ) {
+                (Some(source), Some(destination)) => Ok(Some((source, destination))),
+                _ => Err(invalid("the PROXY protocol header has invalid addresses")),
+            }
+        }
+        _ => Err(invalid("the PROXY protocol header is invalid")),
+    }
+}
+
+/// Reads the rest of a binary version 2 header.
+async fn read_v2<S>(stream: &mut S) -> io::Result<Option<(SocketAddr, SocketAddr)>>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut header = [0; 11];
+    stream.read_exact(&mut header).await?;
+    if header[..7] != SIGNATURE[5..] {
+        return Err(invalid("the PROXY protocol header is invalid"));
+    }
+    let [.., version_command, family, len_high, len_low] = header;
+    // the addresses may be followed by extensions, which are ignored
+    let mut data = vec![0; usize::from(u16::from_be_bytes([len_high, len_low]))];
+    stream.read_exact(&mut data).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("the PROXY protocol version is not supported"));
+    }
+    match version_command & 0xf {
+        // the proxy made the connection on its own behalf
+        0 => return Ok(None),
+        1 => (),
+        _ => return Err(invalid("the PROXY protocol command is not supported")),
+    }
+
+    // the lower four bits are the transport protocol, which does not matter
+    let addrs = match family >> 4 {
+        // the proxy does not know the addresses, or they are Unix sockets
+        0 | 3 => None,
+        1 if data.len() >= 12 => {
+            let ip = |i: usize| {
+                IpAddr::from(Ipv4Addr::from(
+                    <[u8; 4]>::try_from(&data[i..i + 4]).unwrap(),
+                ))
+            };
+            let port = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+            Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            ))
+        }
+        2 if data.len() >= 36 => {
+            let ip = |i: usize| {
+                IpAddr::from(Ipv6Addr::from(
+                    <[u8; 16]>::try_from(&data[i..i + 16]).unwrap(),
+                ))
+            };
+            let port = |i: usize| u16::from_be_bytes([data[i], data[i + 1]]);
+            Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            ))
+        }
+        _ => return Err(invalid("the PROXY protocol header has invalid addresses")),
+    };
+    Ok(addrs)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
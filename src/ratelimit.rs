@@ -0,0 +1,127 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The number of buckets after which buckets that are full again are removed.
+const PRUNE_THRESHOLD: usize = 1024;
+
+/// A range of IP addresses in CIDR notation, e.g. `192.0.2.0/24`.
+#[derive(Debug, Clone)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    /// Parses an address range like `192.0.2.0/24` or `2001:db8::/32`. A
+    /// single address without a prefix length only matches itself.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s.split_once('/').unwrap_or((s, ""));
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|_| format!("invalid IP address range {s:?}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = if prefix_len.is_empty() {
+            max
+        } else {
+            prefix_len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max)
+                .ok_or_else(|| format!("invalid prefix length in IP address range {s:?}"))?
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+impl Cidr {
+    /// Checks if the address is in this range. IPv4 addresses that are mapped
+    /// to IPv6 addresses are treated like IPv4 addresses.
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Limits the requests per client IP address with a token bucket for each
+/// address.
+pub(crate) struct RateLimiter {
+    /// The number of tokens added to a bucket per second.
+    rate: f64,
+    /// The maximum number of tokens in a bucket.
+    burst: f64,
+    /// IP address ranges that are not limited.
+    exempt: Vec<Cidr>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64, exempt: Vec<Cidr>) -> Result<Self, String> {
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err("the rate limit has to be a positive number".into());
+        }
+        if burst.is_nan() || burst < 1.0 {
+            return Err("the rate limit burst has to be at least 1".into());
+        }
+        Ok(Self {
+            rate,
+            burst,
+            exempt,
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes a token for a request from the given address. If there is none
+    /// left, returns how long the client has to wait until the next request
+    /// is allowed.
+    pub fn check(&self, addr: IpAddr) -> Result<(), Duration> {
+        let addr = addr.to_canonical();
+        if self.exempt.iter().any(|range| range.contains(addr)) {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD {
+            buckets.retain(|_, bucket| self.tokens(bucket, now) < self.burst);
+        }
+        let bucket = buckets.entry(addr).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        bucket.tokens = self.tokens(bucket, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    /// Returns the number of tokens in the bucket at the given time.
+    fn tokens(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst)
+    }
+}
@@ -0,0 +1,124 @@
+use {
+    crate::codes::{REDIRECT_PERMANENT, REDIRECT_TEMPORARY},
+    std::{path::Path, str::FromStr},
+    url::Url,
+};
+
+/// A rule that redirects requests for a URL path to another URL.
+struct Rule {
+    /// The URL path the rule applies to, or the prefix of the paths if
+    /// `prefix` is set.
+    source: String,
+    prefix: bool,
+    /// The URL or path to redirect to. If it ends with `*`, the `*` is
+    /// replaced with the rest of the requested path after the prefix.
+    target: String,
+    status: u8,
+}
+
+/// Redirect rules, so links keep working when the content is reorganized.
+#[derive(Default)]
+pub(crate) struct Redirects {
+    rules: Vec<Rule>,
+}
+
+impl Redirects {
+    /// Reads a file where each line contains the source path, the target and
+    /// optionally the status code, separated by whitespace:
+    /// ```text
+    /// # comment
+    /// /old.gmi /new.gmi
+    /// /blog/* gemini://blog.example.com/* 31
+    /// ```
+    /// A source ending in `*` matches all paths starting with the rest of
+    /// it. Relative targets are resolved against the requested URL. The
+    /// status code is either 30 for a temporary redirect, which is the
+    /// default, or 31 for a permanent one. Lines that start with `#` are
+    /// ignored.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("could not read redirects file {path:?}: {e}"))?;
+
+        let mut rules = vec![];
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |msg: &str| format!("{}:{}: {msg}", path.display(), number + 1);
+            let words = line.split_whitespace().collect::<Vec<_>>();
+            let (source, target, status) = match words[..] {
+                [source, target] => (source, target, REDIRECT_TEMPORARY),
+                [source, target, "30"] => (source, target, REDIRECT_TEMPORARY),
+                [source, target, "31"] => (source, target, REDIRECT_PERMANENT),
+                [_, _, status] => {
+                    return Err(error(&format!("status {status:?} is neither 30 nor 31")))
+                }
+                _ => {
+                    return Err(error(
+                        "expected a source path, a target and optionally a status",
+                    ))
+                }
+            };
+            if !source.starts_with('/') {
+                return Err(error(&format!("source {source:?} does not start with /")));
+            }
+            let (source, prefix) = match source.strip_suffix('*') {
+                Some(prefix) => (prefix, true),
+                None => (source, false),
+            };
+            rules.push(Rule {
+                source: source.to_string(),
+                prefix,
+                target: target.to_string(),
+                status,
+            });
+        }
+
+        Ok(Self { rules })
+    }
+
+    /// Returns the status code and target of the first rule that applies
+    /// to the URL, if any.
+    pub fn find(&self, url: &Url) -> Option<(u8, Url)> {
+        let path = url.path();
+        self.rules.iter().find_map(|rule| {
+            let rest = if rule.prefix {
+                path.strip_prefix(&rule.source)?
+            } else if path == rule.source {
+                ""
+            } else {
+                return None;
+            };
+            let target = match rule.target.strip_suffix('*') {
+                Some(target) => format!("{target}{rest}"),
+                None => rule.target.clone(),
+            };
+            Some((rule.status, url.join(&target).ok()?))
+        })
+    }
+}
+
+/// A URL path prefix of content that was removed on purpose, which is
+/// answered with status 52.
+#[derive(Debug, Clone)]
+pub(crate) struct Gone {
+    pub prefix: String,
+    pub message: String,
+}
+
+impl FromStr for Gone {
+    type Err = String;
+
+    /// Parses a prefix in the form `PREFIX[=MESSAGE]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, message) = s.split_once('=').unwrap_or((s, "Gone."));
+        if !prefix.starts_with('/') {
+            return Err(format!("gone prefix {prefix:?} does not start with /"));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+            message: message.trim().to_string(),
+        })
+    }
+}
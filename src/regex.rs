@@ -0,0 +1,539 @@
+//! A small regular expression engine for rewrite rules.
+//!
+//! It supports the common syntax of Perl-like regular expressions: literal
+//! characters, `.`, character classes like `[a-z]` and `[^/]`, the escapes
+//! `\d`, `\w`, `\s` and their negations, the anchors `^` and `$`, capturing
+//! groups `(...)`, non-capturing groups `(?:...)`, alternation with `|` and
+//! the quantifiers `*`, `+`, `?`, `{n}`, `{n,}` and `{n,m}`, which can be made
+//! lazy with a trailing `?`.
+//!
+//! Expressions are compiled to a program for a Pike VM, which simulates all
+//! possible matches at the same time, so matching takes linear time in the
+//! length of the text, whatever the expression. Of several possible matches,
+//! the one Perl would find is used.
+
+use std::{iter::Peekable, str::Chars, str::FromStr};
+
+/// The maximum number of instructions of a compiled expression, which limits
+/// the memory used by large counted repetitions.
+const MAX_PROGRAM_SIZE: usize = 10_000;
+
+/// A compiled regular expression.
+#[derive(Debug)]
+pub(crate) struct Regex {
+    program: Vec<Inst>,
+    /// The number of capturing groups, including the whole match.
+    groups: usize,
+}
+
+/// A part of a parsed expression.
+#[derive(Debug)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    /// A group, which captures the match with the given index, if any.
+    Group(Box<Node>, Option<usize>),
+    Concat(Vec<Node>),
+    Alternation(Vec<Node>),
+    Repeat {
+        node: Box<Node>,
+        min: u32,
+        max: Option<u32>,
+        greedy: bool,
+    },
+}
+
+/// A set of characters given as inclusive ranges.
+#[derive(Debug, Clone)]
+struct Class {
+    ranges: Vec<(char, char)>,
+    negated: bool,
+}
+
+impl Class {
+    fn new(ranges: &[(char, char)], negated: bool) -> Self {
+        Self {
+            ranges: ranges.to_vec(),
+            negated,
+        }
+    }
+
+    fn contains(&self, c: char) -> bool {
+        self.ranges.iter().any(|&(a, b)| a <= c && c <= b) != self.negated
+    }
+}
+
+const DIGIT: &[(char, char)] = &[('0', '9')];
+const WORD: &[(char, char)] = &[('0', '9'), ('A', 'Z'), ('_', '_'), ('a', 'z')];
+const SPACE: &[(char, char)] = &[('\t', '\r'), (' ', ' ')];
+
+/// An instruction of the compiled program.
+#[derive(Debug, Clone)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Start,
+    End,
+    /// Stores the current position in a capture slot.
+    Save(usize),
+    /// Continues at both instructions, preferring the first one.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    groups: usize,
+}
+
+impl Parser<'_> {
+    fn alternation(&mut self) -> Result<Node, String> {
+        let mut alternatives = vec![self.concat()?];
+        while self.chars.next_if_eq(&'|').is_some() {
+            alternatives.push(self.concat()?);
+        }
+        Ok(if alternatives.len() == 1 {
+            alternatives.pop().unwrap()
+        } else {
+            Node::Alternation(alternatives)
+        })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = vec![];
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' {
+                break;
+            }
+            let atom = self.atom()?;
+            nodes.push(self.quantifier(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        Ok(match self.chars.next().unwrap() {
+            '(' => {
+                let index = if self.chars.next_if_eq(&'?').is_some() {
+                    if self.chars.next_if_eq(&':').is_none() {
+                        return Err("only non-capturing groups (?:...) are supported".into());
+                    }
+                    None
+                } else {
+                    self.groups += 1;
+                    Some(self.groups)
+                };
+                let node = self.alternation()?;
+                if self.chars.next_if_eq(&')').is_none() {
+                    return Err("missing )".into());
+                }
+                Node::Group(Box::new(node), index)
+            }
+            '[' => Node::Class(self.class()?),
+            '.' => Node::Any,
+            '^' => Node::Start,
+            '$' => Node::End,
+            '\\' => match self.escape()? {
+                Ok(c) => Node::Char(c),
+                Err(class) => Node::Class(class),
+            },
+            c @ ('*' | '+' | '?' | '{') => return Err(format!("nothing to repeat before {c}")),
+            c => Node::Char(c),
+        })
+    }
+
+    /// Parses an escape after the backslash, which is either a character or
+    /// a class of characters.
+    fn escape(&mut self) -> Result<Result<char, Class>, String> {
+        Ok(match self.chars.next() {
+            None => return Err("trailing backslash".into()),
+            Some('d') => Err(Class::new(DIGIT, false)),
+            Some('D') => Err(Class::new(DIGIT, true)),
+            Some('w') => Err(Class::new(WORD, false)),
+            Some('W') => Err(Class::new(WORD, true)),
+            Some('s') => Err(Class::new(SPACE, false)),
+            Some('S') => Err(Class::new(SPACE, true)),
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some(c) if c.is_ascii_alphanumeric() => return Err(format!("unknown escape \\{c}")),
+            Some(c) => Ok(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Class, String> {
+        let negated = self.chars.next_if_eq(&'^').is_some();
+        let mut ranges = vec![];
+        let mut first = true;
+        loop {
+            let start = match self.chars.next() {
+                None => return Err("missing ]".into()),
+                Some(']') if !first => break,
+                Some('\\') => match self.escape()? {
+                    Ok(c) => c,
+                    Err(class) if !class.negated => {
+                        ranges.extend(class.ranges);
+                        first = false;
+                        continue;
+                    }
+                    Err(_) => return Err("negated escapes are not supported in classes".into()),
+                },
+                Some(c) => c,
+            };
+            first = false;
+            // a - at the end is a literal character
+            let end = if self.chars.peek() == Some(&'-') {
+                self.chars.next();
+                match self.chars.next() {
+                    None => return Err("missing ]".into()),
+                    Some(']') => {
+                        ranges.extend([(start, start), ('-', '-')]);
+                        break;
+                    }
+                    Some('\\') => match self.escape()? {
+                        Ok(c) => c,
+                        Err(_) => return Err("invalid range in class".into()),
+                    },
+                    Some(c) => c,
+                }
+            } else {
+                start
+            };
+            if end < start {
+                return Err(format!("invalid range {start}-{end} in class"));
+            }
+            ranges.push((start, end));
+        }
+        Ok(Class { ranges, negated })
+    }
+
+    fn quantifier(&mut self, node: Node) -> Result<Node, String> {
+        let (min, max) = match self.chars.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.chars.next();
+                let min = self.number()?;
+                let max = if self.chars.next_if_eq(&',').is_some() {
+                    if self.chars.peek() == Some(&'}') {
+                        None
+                    } else {
+                        Some(self.number()?)
+                    }
+                } else {
+                    Some(min)
+                };
+                if self.chars.next() != Some('}') {
+                    return Err("missing } in repetition".into());
+                }
+                if max.is_some_and(|max| max < min) {
+                    return Err(format!("invalid repetition {{{min},{}}}", max.unwrap()));
+                }
+                let greedy = self.chars.next_if_eq(&'?').is_none();
+                return self.quantifier(Node::Repeat {
+                    node: Box::new(node),
+                    min,
+                    max,
+                    greedy,
+                });
+            }
+            _ => return Ok(node),
+        };
+        self.chars.next();
+        let greedy = self.chars.next_if_eq(&'?').is_none();
+        self.quantifier(Node::Repeat {
+            node: Box::new(node),
+            min,
+            max,
+            greedy,
+        })
+    }
+
+    fn number(&mut self) -> Result<u32, String> {
+        let mut digits = String::new();
+        while let Some(c) = self.chars.next_if(char::is_ascii_digit) {
+            digits.push(c);
+        }
+        digits
+            .parse()
+            .map_err(|_| "invalid number in repetition".to_string())
+    }
+}
+
+/// Compiles parsed expressions into a program.
+#[derive(Default)]
+struct Compiler {
+    program: Vec<Inst>,
+}
+
+impl Compiler {
+    fn push(&mut self, inst: Inst) -> Result<usize, String> {
+        if self.program.len() >= MAX_PROGRAM_SIZE {
+            return Err("expression is too large".into());
+        }
+        self.program.push(inst);
+        Ok(self.program.len() - 1)
+    }
+
+    /// Emits a split whose targets are filled in later.
+    fn split(&mut self) -> Result<usize, String> {
+        self.push(Inst::Split(0, 0))
+    }
+
+    fn set_split(&mut self, pc: usize, preferred: usize, other: usize, greedy: bool) {
+        self.program[pc] = if greedy {
+            Inst::Split(preferred, other)
+        } else {
+            Inst::Split(other, preferred)
+        };
+    }
+
+    fn compile(&mut self, node: &Node) -> Result<(), String> {
+        match node {
+            Node::Empty => (),
+            Node::Char(c) => {
+                self.push(Inst::Char(*c))?;
+            }
+            Node::Any => {
+                self.push(Inst::Any)?;
+            }
+            Node::Class(class) => {
+                self.push(Inst::Class(class.clone()))?;
+            }
+            Node::Start => {
+                self.push(Inst::Start)?;
+            }
+            Node::End => {
+                self.push(Inst::End)?;
+            }
+            Node::Group(node, index) => {
+                if let Some(index) = index {
+                    self.push(Inst::Save(2 * index))?;
+                }
+                self.compile(node)?;
+                if let Some(index) = index {
+                    self.push(Inst::Save(2 * index + 1))?;
+                }
+            }
+            Node::Concat(nodes) => {
+                for node in nodes {
+                    self.compile(node)?;
+                }
+            }
+            Node::Alternation(alternatives) => {
+                let mut jumps = vec![];
+                for (i, node) in alternatives.iter().enumerate() {
+                    if i + 1 == alternatives.len() {
+                        self.compile(node)?;
+                    } else {
+                        let split = self.split()?;
+                        self.compile(node)?;
+                        jumps.push(self.push(Inst::Jump(0))?);
+                        let next = self.program.len();
+                        self.set_split(split, split + 1, next, true);
+                    }
+                }
+                let end = self.program.len();
+                for jump in jumps {
+                    self.program[jump] = Inst::Jump(end);
+                }
+            }
+            Node::Repeat {
+                node,
+                min,
+                max,
+                greedy,
+            } => {
+                for _ in 0..*min {
+                    self.compile(node)?;
+                }
+                match max {
+                    None => {
+                        let split = self.split()?;
+                        self.compile(node)?;
+                        self.push(Inst::Jump(split))?;
+                        let end = self.program.len();
+                        self.set_split(split, split + 1, end, *greedy);
+                    }
+                    Some(max) => {
+                        let mut splits = vec![];
+                        for _ in *min..*max {
+                            splits.push(self.split()?);
+                            self.compile(node)?;
+                        }
+                        let end = self.program.len();
+                        for split in splits {
+                            self.set_split(split, split + 1, end, *greedy);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Regex {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parser = Parser {
+            chars: s.chars().peekable(),
+            groups: 0,
+        };
+        let node = parser.alternation()?;
+        if parser.chars.next().is_some() {
+            return Err("unmatched )".into());
+        }
+
+        let mut compiler = Compiler::default();
+        compiler.push(Inst::Save(0))?;
+        compiler.compile(&node)?;
+        compiler.push(Inst::Save(1))?;
+        compiler.push(Inst::Match)?;
+        Ok(Self {
+            program: compiler.program,
+            groups: parser.groups + 1,
+        })
+    }
+}
+
+/// A possible match that is being followed, with its capture slots.
+struct Thread {
+    pc: usize,
+    slots: Vec<Option<usize>>,
+}
+
+impl Regex {
+    /// Returns the start and end of the first match and of every capturing
+    /// group, or `None` if the expression does not match.
+    pub fn captures(&self, text: &str) -> Option<Vec<Option<(usize, usize)>>> {
+        let mut current = vec![];
+        let mut next = vec![];
+        // the generation each instruction was last added in, so every
+        // instruction is only followed once per position
+        let mut added = vec![usize::MAX; self.program.len()];
+        let mut matched = None;
+
+        let positions = text.char_indices().map(Some).chain([None]);
+        let mut generation = 0;
+        for step in positions {
+            let pos = step.map_or(text.len(), |(pos, _)| pos);
+            // start a new match at this position, unless one was found
+            if matched.is_none() {
+                let slots = vec![None; 2 * self.groups];
+                self.add(&mut current, &mut added, generation, 0, pos, text, slots);
+            }
+            if current.is_empty() {
+                break;
+            }
+
+            generation += 1;
+            for thread in current.drain(..) {
+                let advance = match (&self.program[thread.pc], step) {
+                    (Inst::Match, _) => {
+                        matched = Some(thread.slots);
+                        // threads after this one have a lower priority
+                        break;
+                    }
+                    (Inst::Char(expected), Some((_, c))) => *expected == c,
+                    (Inst::Any, Some(_)) => true,
+                    (Inst::Class(class), Some((_, c))) => class.contains(c),
+                    _ => false,
+                };
+                if advance {
+                    let (_, c) = step.unwrap();
+                    self.add(
+                        &mut next,
+                        &mut added,
+                        generation,
+                        thread.pc + 1,
+                        pos + c.len_utf8(),
+                        text,
+                        thread.slots,
+                    );
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+        }
+
+        let slots = matched?;
+        Some(
+            slots
+                .chunks(2)
+                .map(|slot| Some((slot[0]?, slot[1]?)))
+                .collect(),
+        )
+    }
+
+    /// Adds a thread to the list, following all instructions that do not
+    /// consume a character.
+    #[allow(clippy::too_many_arguments)]
+    fn add(
+        &self,
+        list: &mut Vec<Thread>,
+        added: &mut [usize],
+        generation: usize,
+        pc: usize,
+        pos: usize,
+        text: &str,
+        mut slots: Vec<Option<usize>>,
+    ) {
+        if added[pc] == generation {
+            return;
+        }
+        added[pc] = generation;
+        match self.program[pc] {
+            Inst::Jump(target) => self.add(list, added, generation, target, pos, text, slots),
+            Inst::Split(first, second) => {
+                self.add(list, added, generation, first, pos, text, slots.clone());
+                self.add(list, added, generation, second, pos, text, slots);
+            }
+            Inst::Save(slot) => {
+                slots[slot] = Some(pos);
+                self.add(list, added, generation, pc + 1, pos, text, slots);
+            }
+            Inst::Start if pos == 0 => self.add(list, added, generation, pc + 1, pos, text, slots),
+            Inst::End if pos == text.len() => {
+                self.add(list, added, generation, pc + 1, pos, text, slots)
+            }
+            Inst::Start | Inst::End => (),
+            _ => list.push(Thread { pc, slots }),
+        }
+    }
+
+    /// Replaces the text with the replacement if the expression matches it.
+    /// `$0` in the replacement stands for the whole match, `$1` to `$9` for
+    /// the capturing groups and `$$` for a dollar sign. Groups that did not
+    /// participate in the match are replaced with nothing.
+    pub fn replace(&self, text: &str, replacement: &str) -> Option<String> {
+        let captures = self.captures(text)?;
+        let mut result = String::new();
+        let mut chars = replacement.chars().peekable();
+        while let Some(c) = chars.next() {
+            if c != '$' {
+                result.push(c);
+            } else if chars.next_if_eq(&'$').is_some() {
+                result.push('$');
+            } else if let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                let index = digit.to_digit(10).unwrap() as usize;
+                if let Some(Some((start, end))) = captures.get(index) {
+                    result.push_str(&text[*start..*end]);
+                }
+            } else {
+                result.push('$');
+            }
+        }
+        Some(result)
+    }
+}
@@ -0,0 +1,109 @@
+use {
+    crate::{
+        codes::{REDIRECT_PERMANENT, REDIRECT_TEMPORARY},
+        regex::Regex,
+    },
+    std::str::FromStr,
+    url::Url,
+};
+
+/// What to do when a rewrite rule matches.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Action {
+    /// Use the new path internally and continue with the next rule.
+    Rewrite,
+    /// Use the new path internally and skip the remaining rules.
+    Last,
+    /// Redirect the client to the new URL with the given status code.
+    Redirect(u8),
+}
+
+/// A rule that rewrites URL paths matching a regular expression.
+#[derive(Debug)]
+pub(crate) struct Rule {
+    pattern: Regex,
+    /// The new path or URL, which may refer to the groups of the pattern.
+    replacement: String,
+    action: Action,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// Parses a rule in the form `PATTERN REPLACEMENT [FLAG]`, where the
+    /// flag is `last`, `redirect` or `permanent`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        let (pattern, replacement, action) = match words[..] {
+            [pattern, replacement] => (pattern, replacement, Action::Rewrite),
+            [pattern, replacement, flag] => (
+                pattern,
+                replacement,
+                match flag {
+                    "last" => Action::Last,
+                    "redirect" => Action::Redirect(REDIRECT_TEMPORARY),
+                    "permanent" => Action::Redirect(REDIRECT_PERMANENT),
+                    _ => {
+                        return Err(format!(
+                            "unknown flag {flag:?} in rewrite rule {s:?}, expected last, redirect or permanent"
+                        ))
+                    }
+                },
+            ),
+            _ => return Err(format!(
+                "rewrite rule {s:?} is not in the form PATTERN REPLACEMENT [FLAG]"
+            )),
+        };
+        // requests must not be rewritten to other hosts or protocols
+        if !matches!(action, Action::Redirect(_)) && !replacement.starts_with('/') {
+            return Err(format!(
+                "the replacement of rewrite rule {s:?} has to be a path starting with /"
+            ));
+        }
+        Ok(Self {
+            pattern: pattern
+                .parse()
+                .map_err(|e| format!("invalid pattern in rewrite rule {s:?}: {e}"))?,
+            replacement: replacement.to_string(),
+            action,
+        })
+    }
+}
+
+/// The result of rewriting a URL.
+pub(crate) enum Rewritten {
+    /// The URL to handle instead of the requested one.
+    Internal(Url),
+    /// The URL to redirect the client to with the given status code.
+    Redirect(u8, Url),
+}
+
+/// Applies the rules in order to the path of the URL. Returns `None` if no
+/// rule matches.
+pub(crate) fn apply(rules: &[Rule], url: &Url) -> Option<Rewritten> {
+    let mut current = None::<Url>;
+    for rule in rules {
+        let url = current.as_ref().unwrap_or(url);
+        let Some(target) = rule.pattern.replace(url.path(), &rule.replacement) else {
+            continue;
+        };
+        let mut new = match url.join(&target) {
+            Ok(new) => new,
+            Err(e) => {
+                log::warn!("could not rewrite {url} to {target:?}: {e}");
+                continue;
+            }
+        };
+        // keep the query unless the rule sets a new one
+        if !target.contains('?') {
+            new.set_query(url.query());
+        }
+        log::debug!("rewrote {url} to {new}");
+        match rule.action {
+            Action::Rewrite => current = Some(new),
+            Action::Last => return Some(Rewritten::Internal(new)),
+            Action::Redirect(status) => return Some(Rewritten::Redirect(status, new)),
+        }
+    }
+    current.map(Rewritten::Internal)
+}
@@ -0,0 +1,61 @@
+use std::str::FromStr;
+
+/// A URL path prefix that a crawler should not request.
+#[derive(Debug, Clone)]
+pub(crate) struct Rule {
+    /// The user agent the rule applies to, like `archiver` or `*` for all
+    /// crawlers.
+    pub agent: String,
+    pub prefix: String,
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    /// Parses a rule in the form `[AGENT:]PREFIX`. Without an agent, the rule
+    /// applies to all crawlers.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (agent, prefix) = match s.split_once(':') {
+            Some((agent, prefix)) if !s.starts_with('/') => (agent.trim(), prefix.trim()),
+            _ => ("*", s.trim()),
+        };
+        if agent.is_empty()
+            || !agent
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '*'))
+        {
+            return Err(format!("invalid user agent {agent:?} in robots rule {s:?}"));
+        }
+        if !prefix.starts_with('/') || prefix.contains(char::is_whitespace) {
+            return Err(format!(
+                "robots prefix {prefix:?} does not start with / or contains whitespace"
+            ));
+        }
+        Ok(Self {
+            agent: agent.to_string(),
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+/// Generates a robots.txt file with a group for each user agent, in the
+/// order they first appear in the rules.
+pub(crate) fn generate(rules: &[Rule]) -> String {
+    let mut agents: Vec<&str> = vec![];
+    for rule in rules {
+        if !agents.contains(&rule.agent.as_str()) {
+            agents.push(&rule.agent);
+        }
+    }
+    let groups = agents
+        .into_iter()
+        .map(|agent| {
+            let mut group = format!("User-agent: {agent}\n");
+            for rule in rules.iter().filter(|rule| rule.agent == agent) {
+                group += &format!("Disallow: {}\n", rule.prefix);
+            }
+            group
+        })
+        .collect::<Vec<_>>();
+    groups.join("\n")
+}
@@ -0,0 +1,351 @@
+//! Sandboxing with `pledge(2)` and `unveil(2)` on OpenBSD, and with Landlock
+//! and a seccomp filter on Linux.
+
+use {
+    crate::{cgi, ARGS},
+    agate::sys,
+    std::{io, path::Path},
+};
+
+/// Returns whether CGI scripts may be run. They can use any interpreter and
+/// libraries, so the files are only restricted without them.
+fn cgi_enabled() -> bool {
+    ARGS.cgi || !ARGS.cgi_paths.is_empty()
+}
+
+/// Returns the content directories of all hosts.
+fn content_dirs() -> impl Iterator<Item = &'static Path> {
+    ARGS.vhosts
+        .values()
+        .filter_map(|vhost| vhost.content_dir.as_deref())
+        .chain([ARGS.content_dir.as_path()])
+}
+
+/// Returns whether requests can be passed on to servers with a host name
+/// that has to be resolved.
+fn upstream_enabled() -> bool {
+    !ARGS.proxy_routes.is_empty()
+        || !ARGS.http_routes.is_empty()
+        || ARGS
+            .scgi_routes
+            .iter()
+            .chain(&ARGS.fastcgi_routes)
+            .any(|route| !matches!(route.address, cgi::Address::Unix(_)))
+}
+
+#[cfg(target_os = "openbsd")]
+pub(crate) use openbsd::restrict;
+
+#[cfg(target_os = "openbsd")]
+mod openbsd {
+    use {
+        super::*,
+        std::{ffi::CString, os::unix::ffi::OsStrExt},
+    };
+
+    /// Restricts the files Agate can access to the directories it needs, and
+    /// the system calls to the ones it uses with the current configuration.
+    pub(crate) fn restrict() -> io::Result<()> {
+        let cgi = cgi_enabled();
+        let titan = !ARGS.titan_areas.is_empty();
+
+        let mut promises = vec!["stdio", "rpath", "inet", "unix", "dns"];
+        if titan || ARGS.tofu.path().is_some() {
+            promises.extend(["wpath", "cpath"]);
+        }
+        if cgi {
+            promises.extend(["proc", "exec"]);
+        }
+
+        if !cgi {
+            let content = if titan { "rwc" } else { "r" };
+            for dir in content_dirs() {
+                unveil(dir, content)?;
+            }
+            for alias in &ARGS.aliases {
+                unveil(&alias.dir, "r")?;
+            }
+            for archive in &ARGS.archives {
+                unveil(&archive.file, "r")?;
+            }
+            for layer in &ARGS.content_layers {
+                unveil(layer, "r")?;
+            }
+            if let Some(user_dirs) = &ARGS.user_dirs {
+                unveil(user_dirs.base(), "r")?;
+            }
+            // certificates can also be reloaded with SIGHUP
+            if ARGS.chroot.is_none() {
+                unveil(&ARGS.certs_dir, "r")?;
+            }
+            if let Some(path) = ARGS.tofu.path() {
+                unveil(path, "rwc")?;
+            }
+            if let Some(maintenance) = &ARGS.maintenance {
+                unveil(maintenance.file(), "r")?;
+            }
+            for route in ARGS.scgi_routes.iter().chain(&ARGS.fastcgi_routes) {
+                if let cgi::Address::Unix(path) = &route.address {
+                    unveil(path, "rw")?;
+                }
+            }
+            if upstream_enabled() {
+                unveil(Path::new("/etc/resolv.conf"), "r")?;
+                unveil(Path::new("/etc/hosts"), "r")?;
+            }
+
+            sys::unveil_lock()?;
+        }
+
+        // the promises of executed CGI scripts are not restricted
+        sys::pledge(&CString::new(promises.join(" ")).unwrap())
+    }
+
+    /// Allows accessing the file or directory with the given permissions.
+    fn unveil(path: &Path, permissions: &str) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())?;
+        sys::unveil(&path, &CString::new(permissions)?)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) use linux::restrict;
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use {
+        super::*,
+        std::{
+            fs::OpenOptions,
+            os::{
+                fd::{AsFd, OwnedFd},
+                unix::fs::OpenOptionsExt,
+            },
+        },
+    };
+
+    // access rights of the first Landlock ABI version, see landlock(7)
+    const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    const ACCESS_FS_REMOVE_DIR: u64 = 1 << 4;
+    const ACCESS_FS_REMOVE_FILE: u64 = 1 << 5;
+    const ACCESS_FS_MAKE_DIR: u64 = 1 << 7;
+    const ACCESS_FS_MAKE_REG: u64 = 1 << 8;
+    /// All access rights of the first ABI version.
+    const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+
+    const READ: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    const WRITE: u64 = ACCESS_FS_WRITE_FILE
+        | ACCESS_FS_REMOVE_DIR
+        | ACCESS_FS_REMOVE_FILE
+        | ACCESS_FS_MAKE_DIR
+        | ACCESS_FS_MAKE_REG;
+
+    /// Restricts the files Agate can access with Landlock to the directories
+    /// it needs, and forbids system calls it never uses with a seccomp
+    /// filter. This applies to the calling thread and all threads it starts
+    /// afterwards.
+    pub(crate) fn restrict() -> io::Result<()> {
+        // required to apply the restrictions without being root
+        sys::set_no_new_privs()?;
+
+        if !cgi_enabled() {
+            landlock()?;
+        }
+        seccomp()
+    }
+
+    fn landlock() -> io::Result<()> {
+        if !matches!(sys::landlock_abi_version(), Ok(1..)) {
+            log::warn!("Landlock is not supported by the kernel, file access is not restricted");
+            return Ok(());
+        }
+        let ruleset = sys::landlock_create_ruleset(ACCESS_FS_ALL)?;
+
+        let titan = !ARGS.titan_areas.is_empty();
+        for dir in content_dirs() {
+            allow(&ruleset, dir, if titan { READ | WRITE } else { READ })?;
+        }
+        for alias in &ARGS.aliases {
+            allow(&ruleset, &alias.dir, READ)?;
+        }
+        // directory rights can not be granted for files
+        for archive in &ARGS.archives {
+            allow(&ruleset, &archive.file, ACCESS_FS_READ_FILE)?;
+        }
+        for layer in &ARGS.content_layers {
+            allow(&ruleset, layer, READ)?;
+        }
+        if let Some(user_dirs) = &ARGS.user_dirs {
+            allow(&ruleset, user_dirs.base(), READ)?;
+        }
+        // certificates can also be reloaded with SIGHUP
+        if ARGS.chroot.is_none() {
+            allow(&ruleset, &ARGS.certs_dir, READ)?;
+        }
+        if let Some(path) = ARGS.tofu.path() {
+            allow(
+                &ruleset,
+                parent(path),
+                ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE | ACCESS_FS_MAKE_REG,
+            )?;
+        }
+        if let Some(maintenance) = &ARGS.maintenance {
+            // the file may not exist yet
+            allow(&ruleset, parent(maintenance.file()), ACCESS_FS_READ_FILE)?;
+        }
+        if upstream_enabled() {
+            // the resolver configuration and the libraries it might load
+            for dir in ["/etc", "/lib", "/lib64", "/usr/lib", "/usr/lib64"] {
+                allow(&ruleset, Path::new(dir), READ)?;
+            }
+        }
+
+        sys::landlock_restrict_self(ruleset.as_fd())
+    }
+
+    /// Returns the directory containing the file.
+    fn parent(path: &Path) -> &Path {
+        path.parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(Path::new("."))
+    }
+
+    /// Adds a rule that allows the given access to everything in the
+    /// directory. Directories that do not exist are skipped.
+    fn allow(ruleset: &OwnedFd, dir: &Path, access: u64) -> io::Result<()> {
+        let dir = match OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_PATH | libc::O_CLOEXEC)
+            .open(dir)
+        {
+            Ok(dir) => dir,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        sys::landlock_add_path_rule(ruleset.as_fd(), access, dir.as_fd())
+    }
+
+    /// The architecture the system call numbers below are valid for, as
+    /// reported to seccomp filters.
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// System calls that are never needed to serve requests. They fail with
+    /// `EPERM`, so if something does use them, it is not killed.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const DENIED: &[libc::c_long] = &[
+        libc::SYS_acct,
+        libc::SYS_add_key,
+        libc::SYS_adjtimex,
+        libc::SYS_bpf,
+        libc::SYS_chroot,
+        libc::SYS_clock_adjtime,
+        libc::SYS_clock_settime,
+        libc::SYS_delete_module,
+        libc::SYS_fanotify_init,
+        libc::SYS_finit_module,
+        libc::SYS_fsconfig,
+        libc::SYS_fsmount,
+        libc::SYS_fsopen,
+        libc::SYS_fspick,
+        libc::SYS_init_module,
+        libc::SYS_kexec_file_load,
+        libc::SYS_kexec_load,
+        libc::SYS_keyctl,
+        libc::SYS_mount,
+        libc::SYS_mount_setattr,
+        libc::SYS_move_mount,
+        libc::SYS_name_to_handle_at,
+        libc::SYS_open_by_handle_at,
+        libc::SYS_open_tree,
+        libc::SYS_perf_event_open,
+        libc::SYS_pivot_root,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_ptrace,
+        libc::SYS_quotactl,
+        libc::SYS_reboot,
+        libc::SYS_request_key,
+        libc::SYS_setdomainname,
+        libc::SYS_sethostname,
+        libc::SYS_setns,
+        libc::SYS_settimeofday,
+        libc::SYS_swapoff,
+        libc::SYS_swapon,
+        libc::SYS_umount2,
+        libc::SYS_unshare,
+        libc::SYS_userfaultfd,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_ioperm,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_iopl,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_modify_ldt,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_uselib,
+    ];
+
+    /// Installs a seccomp filter that denies the system calls above, and
+    /// also starting other programs unless CGI scripts are enabled.
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    fn seccomp() -> io::Result<()> {
+        use libc::{
+            sock_filter, BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W,
+        };
+
+        fn stmt(code: u32, k: u32) -> sock_filter {
+            sock_filter {
+                code: code as u16,
+                jt: 0,
+                jf: 0,
+                k,
+            }
+        }
+        fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+            sock_filter {
+                code: code as u16,
+                jt,
+                jf,
+                k,
+            }
+        }
+
+        let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+        let mut denied = DENIED.to_vec();
+        if !cgi_enabled() {
+            denied.extend([libc::SYS_execve, libc::SYS_execveat]);
+        }
+
+        // the offsets of the fields of struct seccomp_data
+        let mut filter = vec![
+            // system call numbers differ between architectures
+            stmt(BPF_LD | BPF_W | BPF_ABS, 4),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+            stmt(BPF_LD | BPF_W | BPF_ABS, 0),
+        ];
+        // the x32 ABI uses the same architecture with a flag in the number
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([
+            jump(BPF_JMP | BPF_JGE | BPF_K, 0x4000_0000, 0, 1),
+            stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS),
+        ]);
+        for nr in denied {
+            filter.push(jump(BPF_JMP | BPF_JEQ | BPF_K, nr as u32, 0, 1));
+            filter.push(stmt(BPF_RET | BPF_K, deny));
+        }
+        filter.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+
+        sys::seccomp_filter(&filter)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    fn seccomp() -> io::Result<()> {
+        log::warn!("system calls are not restricted on this architecture");
+        Ok(())
+    }
+}
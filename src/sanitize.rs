@@ -0,0 +1,139 @@
+use crate::codes::{BAD_REQUEST, NOT_FOUND};
+use percent_encoding::percent_decode_str;
+use std::path::{self, Component, Path};
+
+/// Decodes the segments of a URL path, so each of them can be used as a file
+/// name below the content directory. Empty segments, e.g. for the trailing
+/// slash of a directory, are kept.
+///
+/// The segments are percent-decoded first, so the checks can not be avoided
+/// by encoding characters. Segments that are not valid UTF-8 or contain
+/// control characters like NUL or line breaks are answered with status 59.
+///
+/// `.` segments are removed and `..` segments remove the segment before
+/// them. URLs are already normalized like this when they are parsed, but
+/// percent-decoding could produce new ones. A `..` that would leave the
+/// content directory is answered with status 51.
+///
+/// To prevent directory traversal attacks, each remaining segment has to be
+/// a single normal file system path component, not the root directory, a
+/// drive label or another special component. Since path separators like the
+/// escaped forward slash %2F in a URL path segment are not structural, a
+/// segment must not contain a separator either, which is answered with
+/// status 51. On Windows, names that Windows would not open as they are, like
+/// alternate data streams or device names, are answered with status 51 too.
+pub(crate) fn segments(url_path: &str) -> Result<Vec<String>, (u8, &'static str)> {
+    if url_path.is_empty() {
+        return Ok(vec![]);
+    }
+
+    let raw = url_path
+        .strip_prefix('/')
+        .unwrap_or(url_path)
+        .split('/')
+        .collect::<Vec<_>>();
+    let mut segments = vec![];
+    for (i, segment) in raw.iter().enumerate() {
+        let Ok(decoded) = percent_decode_str(segment).decode_utf8() else {
+            return Err((BAD_REQUEST, "Invalid URL"));
+        };
+        if decoded.chars().any(char::is_control) {
+            return Err((BAD_REQUEST, "Invalid URL"));
+        }
+        let last = i == raw.len() - 1;
+        match decoded.as_ref() {
+            "." => (),
+            ".." => {
+                if segments.pop().is_none() {
+                    return Err((NOT_FOUND, "Not found, sorry."));
+                }
+            }
+            _ => {
+                segments.push(checked(&decoded)?.to_string());
+                continue;
+            }
+        }
+        // a path ending in a dot segment refers to a directory
+        if last {
+            segments.push(String::new());
+        }
+    }
+    Ok(segments)
+}
+
+/// Returns the URL path the way the file for it is found: percent-decoded,
+/// without empty, `.` and `..` segments, and with a trailing slash if the URL
+/// path has one. Settings for URL path prefixes are matched against this
+/// path, so they can not be avoided by encoding characters or adding slashes.
+pub(crate) fn normalized(url_path: &str) -> Result<String, (u8, &'static str)> {
+    let segments = segments(url_path)?;
+    let mut path = String::new();
+    for segment in segments.iter().filter(|segment| !segment.is_empty()) {
+        path.push('/');
+        path.push_str(segment);
+    }
+    if segments.last().is_none_or(String::is_empty) {
+        path.push('/');
+    }
+    Ok(path)
+}
+
+/// Checks whether the normalized URL path is below the prefix. A prefix
+/// without a trailing slash has to end at a segment boundary, so `/cgi`
+/// matches `/cgi` and `/cgi/script` but not `/cgi-public/`.
+pub(crate) fn has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Checks that the decoded segment is a single normal path component or
+/// empty.
+fn checked(decoded: &str) -> Result<&str, (u8, &'static str)> {
+    if decoded.contains(path::is_separator) {
+        return Err((NOT_FOUND, "Not found, sorry."));
+    }
+    #[cfg(windows)]
+    if !windows_name_allowed(decoded) {
+        return Err((NOT_FOUND, "Not found, sorry."));
+    }
+    match Path::new(decoded).components().next() {
+        None | Some(Component::Normal(_)) => Ok(decoded),
+        Some(_) => Err((NOT_FOUND, "Not found, sorry.")),
+    }
+}
+
+/// Names of devices that Windows opens instead of a file with that name, in
+/// any directory and with any extension.
+#[cfg(windows)]
+static RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$", "COM0", "COM1", "COM2", "COM3", "COM4",
+    "COM5", "COM6", "COM7", "COM8", "COM9", "COM¹", "COM²", "COM³", "LPT0", "LPT1", "LPT2", "LPT3",
+    "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9", "LPT¹", "LPT²", "LPT³",
+];
+
+/// Checks that Windows opens the file with exactly this name. Otherwise, a
+/// name like `secret.gmi::$DATA` for an alternate data stream, `NUL.gmi` for
+/// a device or `secret.gmi.` with a trailing dot that Windows removes would
+/// open another file than the name suggests, which could avoid rules that
+/// depend on the name or the extension.
+#[cfg(windows)]
+fn windows_name_allowed(name: &str) -> bool {
+    if name.is_empty() {
+        return true;
+    }
+    if name.contains(['<', '>', ':', '"', '|', '?', '*']) || name.ends_with(['.', ' ']) {
+        return false;
+    }
+    // the device names are also reserved with an extension and with spaces
+    // before it
+    let stem = name
+        .split('.')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(' ');
+    !RESERVED_NAMES
+        .iter()
+        .any(|reserved| reserved.eq_ignore_ascii_case(stem))
+}
@@ -0,0 +1,39 @@
+use {
+    crate::{
+        cgi::{self, Address, Request},
+        timeout::{self, IdleTimeout},
+    },
+    std::{fmt::Write as _, io, time::Duration},
+    tokio::io::AsyncWriteExt,
+};
+
+/// Encodes the request headers as a netstring, as required by the SCGI
+/// protocol. `CONTENT_LENGTH` has to be the first header.
+fn encode_headers(request: &Request) -> Vec<u8> {
+    let mut headers = String::new();
+    let mut vars = vec![
+        ("CONTENT_LENGTH", "0".to_string()),
+        ("SCGI", "1".to_string()),
+    ];
+    vars.extend(request.variables());
+    for (name, value) in vars {
+        // NUL bytes would break the format, they can not occur in valid
+        // URLs but they might occur in decoded path segments
+        write!(headers, "{name}\0{}\0", value.replace('\0', "")).unwrap();
+    }
+    format!("{}:{headers},", headers.len()).into_bytes()
+}
+
+/// Sends the request to the SCGI application server at the given address
+/// and returns a channel that will receive its response in chunks. The
+/// request fails if the application server stalls for `time_limit`.
+pub(crate) async fn request(
+    address: &Address,
+    request: &Request<'_>,
+    time_limit: Duration,
+) -> io::Result<cgi::Output> {
+    let stream = timeout::limit(time_limit, address.connect()).await?;
+    let mut stream = IdleTimeout::new(stream, time_limit, time_limit);
+    stream.write_all(&encode_headers(request)).await?;
+    Ok(cgi::forward(stream))
+}
@@ -0,0 +1,245 @@
+use crate::dirconfig::DirConfig;
+use crate::ARGS;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// How often the content directories are checked for changed files.
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// The most results that are listed.
+const MAX_RESULTS: usize = 50;
+
+/// How much more a word in the title counts than a word in the text.
+const TITLE_WEIGHT: u32 = 5;
+
+/// A text file in the search index.
+#[derive(Debug)]
+struct Document {
+    modified: Option<SystemTime>,
+    url_path: String,
+    title: String,
+    /// How often each word occurs.
+    words: HashMap<String, u32>,
+}
+
+/// The search index of a content directory.
+#[derive(Debug, Default)]
+struct Index {
+    documents: Vec<Arc<Document>>,
+    /// The positions of the documents in which each word occurs and how
+    /// often it occurs in them.
+    postings: HashMap<String, Vec<(usize, u32)>>,
+}
+
+impl Index {
+    fn new(documents: Vec<Arc<Document>>) -> Self {
+        let mut postings: HashMap<String, Vec<(usize, u32)>> = HashMap::new();
+        for (i, document) in documents.iter().enumerate() {
+            for (word, count) in &document.words {
+                postings.entry(word.clone()).or_default().push((i, *count));
+            }
+        }
+        Self {
+            documents,
+            postings,
+        }
+    }
+
+    /// Returns the documents that contain all words of the query, the most
+    /// relevant first. The relevance is the sum of the term frequency times
+    /// the inverse document frequency of the words.
+    fn search(&self, query: &str) -> Vec<&Document> {
+        let mut words = words(query);
+        words.sort();
+        words.dedup();
+        if words.is_empty() {
+            return vec![];
+        }
+        let mut scores: HashMap<usize, (usize, f64)> = HashMap::new();
+        for word in &words {
+            let Some(postings) = self.postings.get(word) else {
+                return vec![];
+            };
+            let idf = (1.0 + self.documents.len() as f64 / postings.len() as f64).ln();
+            for (i, count) in postings {
+                let score = scores.entry(*i).or_default();
+                score.0 += 1;
+                score.1 += f64::from(*count) * idf;
+            }
+        }
+        let mut results = scores
+            .into_iter()
+            .filter(|(_, (matched, _))| *matched == words.len())
+            .map(|(i, (_, score))| (score, &*self.documents[i]))
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| {
+            b.0.total_cmp(&a.0)
+                .then_with(|| a.1.url_path.cmp(&b.1.url_path))
+        });
+        results
+            .into_iter()
+            .take(MAX_RESULTS)
+            .map(|(_, document)| document)
+            .collect()
+    }
+}
+
+/// Full-text search of the gemtext and plain text files of the content
+/// directories. The index is kept up to date by a background task.
+pub(crate) struct Search {
+    /// The URL path at which the search is served.
+    pub path: String,
+    indexes: RwLock<HashMap<PathBuf, Arc<Index>>>,
+}
+
+impl Search {
+    pub fn new(path: String) -> Result<Self, String> {
+        if !path.starts_with('/') {
+            return Err(format!("search path {path:?} does not start with /"));
+        }
+        Ok(Self {
+            path,
+            indexes: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// Searches the files of the content directory, which is indexed now if
+    /// it was not indexed before. Returns the URL paths and titles of the
+    /// results.
+    pub async fn search(&self, root: &Path, query: &str) -> Vec<(String, String)> {
+        let index = self.indexes.read().unwrap().get(root).cloned();
+        let index = match index {
+            Some(index) => index,
+            None => {
+                let index = Arc::new(build(root, None).await);
+                self.indexes
+                    .write()
+                    .unwrap()
+                    .insert(root.to_path_buf(), index.clone());
+                index
+            }
+        };
+        index
+            .search(query)
+            .into_iter()
+            .map(|document| (document.url_path.clone(), document.title.clone()))
+            .collect()
+    }
+
+    /// Updates the indexes of the content directories that were searched
+    /// periodically. Only files that were modified are read again.
+    pub async fn watch(&self) {
+        loop {
+            tokio::time::sleep(INTERVAL).await;
+            let indexes = self
+                .indexes
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(root, index)| (root.clone(), index.clone()))
+                .collect::<Vec<_>>();
+            for (root, old) in indexes {
+                let index = Arc::new(build(&root, Some(&old)).await);
+                self.indexes.write().unwrap().insert(root, index);
+            }
+        }
+    }
+}
+
+/// Indexes the text files in the content directory. Files that were not
+/// modified since the old index was made are not read again.
+async fn build(root: &Path, old: Option<&Index>) -> Index {
+    let old = old
+        .map(|old| {
+            old.documents
+                .iter()
+                .map(|document| (document.url_path.as_str(), document))
+                .collect::<HashMap<_, _>>()
+        })
+        .unwrap_or_default();
+    let mut documents = vec![];
+    for file in crate::sitemap::files(root, is_text).await {
+        let modified = tokio::fs::metadata(&file.path)
+            .await
+            .ok()
+            .and_then(|metadata| metadata.modified().ok());
+        match old.get(file.url_path.as_str()) {
+            Some(document) if modified.is_some() && document.modified == modified => {
+                documents.push(Arc::clone(document));
+            }
+            _ => {
+                let Ok(content) = tokio::fs::read(&file.path).await else {
+                    continue;
+                };
+                let content = String::from_utf8_lossy(&content);
+                let (front_matter, content) = crate::blog::split_post(&file.url_path, &content);
+                let front_matter = front_matter.unwrap_or_default();
+                if front_matter.draft {
+                    continue;
+                }
+                let gemtext = is_gemtext(&file.path);
+                let title = front_matter.title.unwrap_or_else(|| {
+                    crate::feed::title(content)
+                        .filter(|_| gemtext)
+                        .unwrap_or(&file.url_path)
+                        .to_string()
+                });
+                let mut words = HashMap::new();
+                for word in self::words(&text(content, gemtext)) {
+                    *words.entry(word).or_default() += 1;
+                }
+                for word in self::words(&title) {
+                    *words.entry(word).or_default() += TITLE_WEIGHT;
+                }
+                documents.push(Arc::new(Document {
+                    modified,
+                    url_path: file.url_path,
+                    title,
+                    words,
+                }));
+            }
+        }
+    }
+    Index::new(documents)
+}
+
+/// Returns whether the file is served as gemtext or plain text.
+fn is_text(config: &DirConfig, path: &Path) -> bool {
+    let mime = match config.mime_type(path) {
+        Some(mime) => mime.to_string(),
+        None => ARGS.mime_types.guess(path),
+    };
+    mime.starts_with("text/gemini") || mime.starts_with("text/plain")
+}
+
+fn is_gemtext(path: &Path) -> bool {
+    ARGS.mime_types.guess(path).starts_with("text/gemini")
+}
+
+/// Returns the text of a file without the URLs of gemtext link lines.
+fn text(content: &str, gemtext: bool) -> String {
+    if !gemtext {
+        return content.to_string();
+    }
+    content
+        .lines()
+        .map(|line| match line.strip_prefix("=>") {
+            Some(link) => link
+                .trim_start()
+                .split_once(char::is_whitespace)
+                .map_or("", |(_, text)| text),
+            None => line,
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Splits the text into lower case words.
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
@@ -0,0 +1,178 @@
+//! Running as a Windows service, started and stopped by the service control
+//! manager.
+
+use {
+    agate::sys,
+    std::{
+        ffi::OsStr,
+        io,
+        sync::{
+            atomic::{AtomicIsize, Ordering},
+            Mutex, OnceLock,
+        },
+    },
+    tokio::sync::oneshot,
+    windows_sys::Win32::System::Services::{
+        SC_MANAGER_CONNECT, SC_MANAGER_CREATE_SERVICE, SERVICE_ACCEPT_SHUTDOWN,
+        SERVICE_ACCEPT_STOP, SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN,
+        SERVICE_CONTROL_STOP, SERVICE_RUNNING, SERVICE_START_PENDING, SERVICE_STATUS,
+        SERVICE_STOPPED, SERVICE_STOP_PENDING, SERVICE_WIN32_OWN_PROCESS,
+    },
+};
+
+/// The name the service is installed with.
+pub(crate) const NAME: &str = "agate";
+
+/// What to do with the service, given on the command line.
+#[derive(Debug)]
+pub(crate) enum Command {
+    /// Run as the service, which only works when started by the service
+    /// control manager.
+    Run,
+    /// Install the service to run with the given arguments.
+    Install(Vec<String>),
+    Uninstall,
+}
+
+/// The function that serves requests until the receiver is notified.
+static SERVE: OnceLock<fn(oneshot::Receiver<()>)> = OnceLock::new();
+/// Notifies the server to stop.
+static STOP: Mutex<Option<oneshot::Sender<()>>> = Mutex::new(None);
+/// The handle to report the status of the service with.
+static STATUS_HANDLE: AtomicIsize = AtomicIsize::new(0);
+
+/// Connects to the service control manager, which then calls `serve` on
+/// another thread. Returns once the service is stopped.
+pub(crate) fn run(serve: fn(oneshot::Receiver<()>)) -> io::Result<()> {
+    // there is no console to print the messages of panics to
+    std::panic::set_hook(Box::new(|info| log::error!("{info}")));
+    let _ = SERVE.set(serve);
+    sys::start_service_dispatcher(OsStr::new(NAME), service_main)
+}
+
+/// Called by the service control manager on a new thread to start the
+/// service.
+extern "system" fn service_main(_argc: u32, _argv: *mut *mut u16) {
+    let handle = match sys::register_service_handler(OsStr::new(NAME), handler) {
+        Ok(handle) => handle,
+        Err(e) => {
+            log::error!("Failed to register the service control handler: {e}");
+            return;
+        }
+    };
+    STATUS_HANDLE.store(handle, Ordering::SeqCst);
+    set_status(SERVICE_START_PENDING);
+
+    let (stop, stopped) = oneshot::channel();
+    *STOP.lock().unwrap() = Some(stop);
+    set_status(SERVICE_RUNNING);
+    if let Some(serve) = SERVE.get() {
+        serve(stopped);
+    }
+    log::info!("Stopped the service");
+    set_status(SERVICE_STOPPED);
+}
+
+/// Handles requests of the service control manager.
+extern "system" fn handler(
+    control: u32,
+    _event_type: u32,
+    _event_data: *mut std::ffi::c_void,
+    _context: *mut std::ffi::c_void,
+) -> u32 {
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            log::info!("Stopping the service");
+            set_status(SERVICE_STOP_PENDING);
+            if let Some(stop) = STOP.lock().unwrap().take() {
+                let _ = stop.send(());
+            }
+            0
+        }
+        // the current status has to be reported again
+        SERVICE_CONTROL_INTERROGATE => 0,
+        // ERROR_CALL_NOT_IMPLEMENTED
+        _ => 120,
+    }
+}
+
+/// Reports the state of the service to the service control manager.
+fn set_status(state: u32) {
+    let status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        dwWin32ExitCode: 0,
+        dwServiceSpecificExitCode: 0,
+        dwCheckPoint: 0,
+        // loading the certificates and binding the listeners is quick
+        dwWaitHint: if state == SERVICE_RUNNING || state == SERVICE_STOPPED {
+            0
+        } else {
+            10_000
+        },
+    };
+    let _ = sys::set_service_status(STATUS_HANDLE.load(Ordering::SeqCst), &status);
+}
+
+/// Installs the service, which is then started automatically at boot with
+/// the given arguments.
+pub(crate) fn install(args: &[String]) -> io::Result<()> {
+    let exe = std::env::current_exe()?;
+    let mut command = quote(&exe.to_string_lossy());
+    for arg in args {
+        command.push(' ');
+        command.push_str(&quote(arg));
+    }
+
+    let manager = sys::open_service_manager(SC_MANAGER_CREATE_SERVICE)?;
+    let result = sys::create_service(
+        manager,
+        OsStr::new(NAME),
+        OsStr::new("Agate Gemini server"),
+        OsStr::new(&command),
+    )
+    .map(sys::close_service_handle);
+    sys::close_service_handle(manager);
+    result
+}
+
+/// Removes the service. A running service is removed once it is stopped.
+pub(crate) fn uninstall() -> io::Result<()> {
+    let manager = sys::open_service_manager(SC_MANAGER_CONNECT)?;
+    let result = sys::open_service(manager, OsStr::new(NAME)).and_then(|service| {
+        let result = sys::delete_service(service);
+        sys::close_service_handle(service);
+        result
+    });
+    sys::close_service_handle(manager);
+    result
+}
+
+/// Quotes an argument for a Windows command line, if necessary.
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_owned();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            // backslashes are only special before a quote
+            '"' => {
+                quoted.extend(std::iter::repeat('\\').take(backslashes + 1));
+                backslashes = 0;
+            }
+            _ => backslashes = 0,
+        }
+        quoted.push(c);
+    }
+    quoted.extend(std::iter::repeat('\\').take(backslashes));
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,270 @@
+use crate::dirconfig::{self, Access, DirConfig};
+use crate::{metadata, ARGS};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, CONTROLS};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use url::Url;
+
+/// How often the content directories are walked again.
+const INTERVAL: Duration = Duration::from_secs(60);
+
+/// Characters that are percent-encoded in the URL paths of documents.
+pub(crate) const ENCODE_SET: &AsciiSet = &CONTROLS
+    .add(b' ')
+    .add(b'"')
+    .add(b'#')
+    .add(b'%')
+    .add(b'<')
+    .add(b'>')
+    .add(b'?')
+    .add(b'`')
+    .add(b'{')
+    .add(b'}');
+
+/// A URL path at which a site map is served.
+#[derive(Debug, Clone)]
+pub(crate) struct Route {
+    pub path: String,
+    pub format: Format,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// A gemtext page with a link line for each document.
+    Gemini,
+    /// The URL of each document on a line, for crawlers.
+    Text,
+}
+
+impl Format {
+    pub fn mime(self) -> &'static str {
+        match self {
+            Self::Gemini => "text/gemini",
+            Self::Text => "text/plain",
+        }
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    /// Parses the URL path of a site map, which is a list of URLs if it ends
+    /// in `.txt` and a gemtext page otherwise.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !s.starts_with('/') || s.ends_with('/') {
+            return Err(format!(
+                "site map path {s:?} has to start with / and must not end with /"
+            ));
+        }
+        Ok(Self {
+            path: s.to_string(),
+            format: if s.ends_with(".txt") {
+                Format::Text
+            } else {
+                Format::Gemini
+            },
+        })
+    }
+}
+
+/// A gemtext document in a content directory.
+#[derive(Debug)]
+pub(crate) struct Document {
+    /// The percent-encoded URL path.
+    pub path: String,
+    /// The first heading of the document, or its URL path.
+    pub title: String,
+}
+
+/// The site maps of the content directories, which are kept up to date by a
+/// background task.
+pub(crate) struct Sitemaps {
+    pub routes: Vec<Route>,
+    documents: RwLock<HashMap<PathBuf, Arc<Vec<Document>>>>,
+}
+
+impl Sitemaps {
+    pub fn new(routes: Vec<Route>) -> Self {
+        Self {
+            routes,
+            documents: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the documents in the content directory, which is walked now
+    /// if it was not walked before.
+    pub async fn documents(&self, root: &Path) -> Arc<Vec<Document>> {
+        if let Some(documents) = self.documents.read().unwrap().get(root) {
+            return documents.clone();
+        }
+        let documents = Arc::new(walk(root).await);
+        self.documents
+            .write()
+            .unwrap()
+            .insert(root.to_path_buf(), documents.clone());
+        documents
+    }
+
+    /// Walks the content directories that were requested periodically, so
+    /// the site maps include new and changed documents.
+    pub async fn watch(&self) {
+        loop {
+            tokio::time::sleep(INTERVAL).await;
+            let roots = self
+                .documents
+                .read()
+                .unwrap()
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            for root in roots {
+                let documents = Arc::new(walk(&root).await);
+                self.documents.write().unwrap().insert(root, documents);
+            }
+        }
+    }
+}
+
+/// A file that is served from a content directory.
+pub(crate) struct File {
+    pub path: PathBuf,
+    /// The percent-encoded URL path, which is the path of the directory for
+    /// index files.
+    pub url_path: String,
+}
+
+/// Finds the files in the content directory for which `wanted` returns true,
+/// given the directory configuration and the path. Only files that clients
+/// without a certificate get are included, so hidden files, gone paths,
+/// client certificate areas, paths handled by backends, CGI scripts, files
+/// below a status file and directories that are not publicly accessible are
+/// left out. Symbolic links to directories are not followed, so the walk can
+/// not loop.
+pub(crate) async fn files(root: &Path, wanted: impl Fn(&DirConfig, &Path) -> bool) -> Vec<File> {
+    // whether the file at the percent-encoded URL path is served publicly
+    let public = |url_path: &str, path: &Path| {
+        let decoded = percent_decode_str(url_path).decode_utf8_lossy();
+        ARGS.is_public(&decoded)
+            && !ARGS.is_cgi_script(&decoded, path)
+            && ARGS.symlinks.allows(root, path)
+    };
+    let mut files = vec![];
+    let mut dirs = vec![(root.to_path_buf(), String::from("/"), DirConfig::default())];
+    while let Some((dir, url_path, mut config)) = dirs.pop() {
+        match dirconfig::load(&dir).await {
+            Ok(Some(dir_config)) => config.merge(dir_config),
+            Ok(None) => (),
+            Err(e) => {
+                log::warn!("not walking {}: {e}", dir.display());
+                continue;
+            }
+        }
+        if matches!(config.access, Some(Access::Deny | Access::Certificate))
+            || metadata::status_override(&dir).await.is_some()
+            || !public(&url_path, &dir)
+        {
+            continue;
+        }
+        let mut entries = match tokio::fs::read_dir(&dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::warn!("could not read {}: {e}", dir.display());
+                continue;
+            }
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if !ARGS.hidden_files.listed(&name) {
+                continue;
+            }
+            let encoded = utf8_percent_encode(&name, ENCODE_SET).to_string();
+            match entry.file_type().await {
+                Ok(file_type) if file_type.is_dir() => {
+                    dirs.push((
+                        entry.path(),
+                        format!("{url_path}{encoded}/"),
+                        config.clone(),
+                    ));
+                    continue;
+                }
+                Ok(_) => (),
+                Err(_) => continue,
+            }
+            let path = entry.path();
+            let file_url_path = format!("{url_path}{encoded}");
+            if !wanted(&config, &path) || !public(&file_url_path, &path) {
+                continue;
+            }
+            let url_path = if ARGS.index_files.contains(&name) {
+                url_path.clone()
+            } else {
+                file_url_path
+            };
+            files.push(File { path, url_path });
+        }
+    }
+    files.sort_by(|a, b| a.url_path.cmp(&b.url_path));
+    files.dedup_by(|a, b| a.url_path == b.url_path);
+    files
+}
+
+/// Finds the gemtext documents in the content directory, sorted by their URL
+/// path.
+async fn walk(root: &Path) -> Vec<Document> {
+    let mut documents = vec![];
+    for file in files(root, is_document).await {
+        let content = tokio::fs::read_to_string(&file.path)
+            .await
+            .unwrap_or_default();
+        let (front_matter, content) = crate::blog::split_post(&file.url_path, &content);
+        let front_matter = front_matter.unwrap_or_default();
+        if front_matter.draft {
+            continue;
+        }
+        let title = front_matter.title.unwrap_or_else(|| {
+            crate::feed::title(content)
+                .unwrap_or(&file.url_path)
+                .to_string()
+        });
+        documents.push(Document {
+            path: file.url_path,
+            title,
+        });
+    }
+    documents
+}
+
+/// Returns whether the file is served as gemtext.
+fn is_document(config: &DirConfig, path: &Path) -> bool {
+    let mime = match config.mime_type(path) {
+        Some(mime) => mime.to_string(),
+        None => ARGS.mime_types.guess(path),
+    };
+    mime.starts_with("text/gemini")
+        || (ARGS.markdown && crate::markdown::is_markdown(path))
+        || (ARGS.templates && crate::template::is_template(path))
+}
+
+/// Generates the site map of the documents on the server at `base`.
+pub(crate) fn generate(format: Format, base: &Url, documents: &[Document]) -> String {
+    let mut output = String::new();
+    if format == Format::Gemini {
+        output += &format!("# {}\n\n", base.host_str().unwrap_or_default());
+    }
+    for document in documents {
+        match format {
+            Format::Gemini => output += &format!("=> {} {}\n", document.path, document.title),
+            Format::Text => {
+                if let Ok(url) = base.join(&document.path) {
+                    output += &format!("{url}\n");
+                }
+            }
+        }
+    }
+    output
+}
@@ -0,0 +1,60 @@
+use std::path::{Path, PathBuf};
+
+/// Name of the file whose content is added before gemtext files in its
+/// directory and everything in it.
+static HEADER_FILENAME: &str = ".header.gmi";
+
+/// Name of the file whose content is added after gemtext files in its
+/// directory and everything in it.
+static FOOTER_FILENAME: &str = ".footer.gmi";
+
+/// The header and footer files for a gemtext file. A header or footer in a
+/// subdirectory replaces the one of its parent directories.
+#[derive(Debug, Default)]
+pub(crate) struct Snippets {
+    header: Option<PathBuf>,
+    footer: Option<PathBuf>,
+}
+
+impl Snippets {
+    /// Uses the header and footer files in the given directory, if there
+    /// are any.
+    pub async fn update(&mut self, dir: &Path) {
+        for (name, snippet) in [
+            (HEADER_FILENAME, &mut self.header),
+            (FOOTER_FILENAME, &mut self.footer),
+        ] {
+            let file = dir.join(name);
+            if tokio::fs::metadata(&file)
+                .await
+                .is_ok_and(|metadata| metadata.is_file())
+            {
+                *snippet = Some(file);
+            }
+        }
+    }
+
+    /// Returns whether there is neither a header nor a footer.
+    pub fn is_empty(&self) -> bool {
+        self.header.is_none() && self.footer.is_none()
+    }
+
+    /// Reads the header and the footer. A file that can not be read is
+    /// logged and left out, so the page itself is still served.
+    pub async fn read(&self) -> (Vec<u8>, Vec<u8>) {
+        (
+            read(self.header.as_deref()).await,
+            read(self.footer.as_deref()).await,
+        )
+    }
+}
+
+async fn read(file: Option<&Path>) -> Vec<u8> {
+    let Some(file) = file else {
+        return vec![];
+    };
+    tokio::fs::read(file).await.unwrap_or_else(|e| {
+        log::error!("could not read {:?}: {}", file, e);
+        vec![]
+    })
+}
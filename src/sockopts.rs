@@ -0,0 +1,41 @@
+use {
+    socket2::{SockRef, TcpKeepalive},
+    std::{io, time::Duration},
+    tokio::net::TcpStream,
+};
+
+/// Options for accepted TCP connections. Everything that is not set keeps
+/// the default of the operating system.
+#[derive(Debug, Default)]
+pub(crate) struct SocketOptions {
+    /// Whether to send data right away instead of waiting for more, which
+    /// Nagle's algorithm does by default.
+    pub nodelay: bool,
+    /// How long a connection has to be idle before keepalive probes are
+    /// sent.
+    pub keepalive: Option<Duration>,
+    /// The size of the send buffer in bytes.
+    pub send_buffer_size: Option<usize>,
+    /// The size of the receive buffer in bytes.
+    pub recv_buffer_size: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Sets the options on an accepted connection.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            socket.set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
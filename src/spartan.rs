@@ -0,0 +1,63 @@
+use {
+    percent_encoding::{percent_encode, NON_ALPHANUMERIC},
+    url::Url,
+};
+
+/// The default port of Spartan servers.
+pub(crate) const DEFAULT_PORT: u16 = 300;
+
+/// The maximum size of the data block of a request. The data is passed on
+/// as the query string, so it is limited like a Gemini URL.
+pub(crate) const MAX_DATA: usize = 1024;
+
+/// Parses a Spartan request line, which has the form
+/// ```text
+/// <host> <path> <content length>
+/// ```
+/// Returns the URL of the request, with the scheme `spartan`, and the length
+/// of the data block that follows the request line.
+pub(crate) fn parse_request(request: &str) -> Option<(Url, usize)> {
+    let mut parts = request.split(' ');
+    let (Some(host), Some(path), Some(len), None) =
+        (parts.next(), parts.next(), parts.next(), parts.next())
+    else {
+        return None;
+    };
+    if host.is_empty() || !path.starts_with('/') {
+        return None;
+    }
+    let url = Url::parse(&format!("spartan://{host}{path}")).ok()?;
+    Some((url, len.parse().ok()?))
+}
+
+/// Sets the data block of a request as the query string of the URL, so it
+/// is available to CGI scripts and similar backends like Gemini input.
+pub(crate) fn set_data(url: &mut Url, data: &[u8]) {
+    if !data.is_empty() {
+        url.set_query(Some(&percent_encode(data, NON_ALPHANUMERIC).to_string()));
+    }
+}
+
+/// Translates a Gemini response header to a Spartan response header.
+///
+/// Spartan only has one status code for each class of response. Input can
+/// not be requested, so Gemini status 1x is treated as a client error.
+/// Redirects in Spartan only contain a path.
+pub(crate) fn header(status: u8, meta: &str) -> String {
+    match status / 10 {
+        2 => format!("2 {meta}\r\n"),
+        3 => match Url::parse(meta) {
+            Ok(url) if url.scheme() == "spartan" => {
+                let mut path = url.path().to_string();
+                if let Some(query) = url.query() {
+                    path.push('?');
+                    path.push_str(query);
+                }
+                format!("3 {path}\r\n")
+            }
+            _ => format!("3 {meta}\r\n"),
+        },
+        4 => format!("5 {meta}\r\n"),
+        _ => format!("4 {meta}\r\n"),
+    }
+}
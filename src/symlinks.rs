@@ -0,0 +1,71 @@
+use std::{path::Path, str::FromStr};
+
+/// Which symbolic links are followed when serving files.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Mode {
+    /// Symbolic links are never followed.
+    Never,
+    /// Symbolic links are only followed if their target is inside the
+    /// content directory.
+    Inside,
+    /// All symbolic links are followed.
+    Always,
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "never" => Ok(Self::Never),
+            "inside" => Ok(Self::Inside),
+            "always" => Ok(Self::Always),
+            _ => Err(format!(
+                "unknown mode {s:?} for symbolic links, expected never, inside or always"
+            )),
+        }
+    }
+}
+
+impl Mode {
+    /// Returns whether the path, which has to be inside the directory
+    /// `root`, may be accessed. The path does not have to exist, so it can
+    /// also be checked before creating a file. The root itself may be a
+    /// symbolic link.
+    pub fn allows(self, root: &Path, path: &Path) -> bool {
+        match self {
+            Self::Always => true,
+            Self::Never => {
+                let Ok(relative) = path.strip_prefix(root) else {
+                    return false;
+                };
+                let mut current = root.to_path_buf();
+                for component in relative.components() {
+                    current.push(component);
+                    match current.symlink_metadata() {
+                        Ok(metadata) if metadata.is_symlink() => return false,
+                        Ok(_) => (),
+                        // nothing below a path that does not exist can be a link
+                        Err(_) => break,
+                    }
+                }
+                true
+            }
+            Self::Inside => {
+                let Ok(root) = root.canonicalize() else {
+                    return false;
+                };
+                // the path or its parent directories may not exist yet
+                for ancestor in path.ancestors() {
+                    match ancestor.canonicalize() {
+                        Ok(real) => return real.starts_with(&root),
+                        // a dangling symbolic link could point anywhere
+                        Err(_) if ancestor.symlink_metadata().is_ok() => return false,
+                        Err(_) => (),
+                    }
+                }
+                false
+            }
+        }
+    }
+}
@@ -0,0 +1,540 @@
+//! Safe wrappers around the system calls Agate makes directly. This is the
+//! only module with unsafe code, every unsafe block explains why it is sound.
+
+#[cfg(unix)]
+pub use unix::*;
+
+#[cfg(target_os = "linux")]
+pub use linux::*;
+
+#[cfg(target_os = "openbsd")]
+pub use openbsd::*;
+
+#[cfg(windows)]
+pub use windows::*;
+
+#[cfg(unix)]
+mod unix {
+    use {
+        socket2::Socket,
+        std::{
+            env,
+            ffi::CStr,
+            fs::File,
+            io, mem,
+            os::{
+                fd::{AsRawFd, FromRawFd, IntoRawFd},
+                unix::net::UnixStream,
+            },
+            ptr,
+            sync::atomic::{AtomicI32, Ordering},
+        },
+    };
+
+    /// The first file descriptor passed by systemd.
+    const LISTEN_FDS_START: i32 = 3;
+
+    /// The largest buffer for the strings of a user or group entry.
+    const MAX_ENTRY_BUFFER: usize = 1 << 20;
+
+    /// The socket the signal handler writes to.
+    static SIGNAL_SOCKET: AtomicI32 = AtomicI32::new(-1);
+
+    pub(crate) fn check(result: libc::c_long) -> io::Result<libc::c_long> {
+        if result >= 0 {
+            Ok(result)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Takes the sockets passed by systemd socket activation, see
+    /// `sd_listen_fds(3)`, which is empty if Agate was not started by socket
+    /// activation. The environment variables are removed, so the sockets are
+    /// only taken once and the variables are not passed on to CGI scripts.
+    pub fn listen_fds() -> Vec<Socket> {
+        let pid = env::var("LISTEN_PID").ok();
+        let fds = env::var("LISTEN_FDS").ok();
+        env::remove_var("LISTEN_PID");
+        env::remove_var("LISTEN_FDS");
+        env::remove_var("LISTEN_FDNAMES");
+
+        // the variables might have been meant for a parent process
+        if pid.and_then(|pid| pid.parse().ok()) != Some(std::process::id()) {
+            return vec![];
+        }
+        let Some(fds) = fds.and_then(|fds| fds.parse::<i32>().ok()) else {
+            return vec![];
+        };
+        (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(fds.max(0)))
+            // SAFETY: systemd passes the file descriptors starting at 3,
+            // which are not used otherwise, and each is only taken once
+            // because the environment variables were removed
+            .map(|fd| unsafe { Socket::from_raw_fd(fd) })
+            .collect()
+    }
+
+    /// Forks into the background and starts a new session, so the process
+    /// is detached from the terminal. The parent process exits right away.
+    ///
+    /// This has to be called before any threads are started.
+    pub fn detach() -> io::Result<()> {
+        // SAFETY: no other threads are running, so the child can use
+        // anything
+        match unsafe { libc::fork() } {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => (),
+            // SAFETY: the parent exits without running any destructors,
+            // which are run by the child
+            _ => unsafe { libc::_exit(0) },
+        }
+        // SAFETY: the function does not have any preconditions
+        check(unsafe { libc::setsid() }.into())?;
+        Ok(())
+    }
+
+    /// Replaces standard input, output and error with the file.
+    pub fn redirect_stdio(file: &File) -> io::Result<()> {
+        for fd in 0..=2 {
+            // SAFETY: both are valid file descriptors, and the standard
+            // streams stay open, they only refer to the file afterwards
+            check(unsafe { libc::dup2(file.as_raw_fd(), fd) }.into())?;
+        }
+        Ok(())
+    }
+
+    /// Installs a handler for each of the signals that writes the signal
+    /// number as a single byte to the socket, which is kept open until the
+    /// process exits.
+    pub fn forward_signals(signals: &[libc::c_int], socket: UnixStream) -> io::Result<()> {
+        SIGNAL_SOCKET.store(socket.into_raw_fd(), Ordering::SeqCst);
+
+        extern "C" fn handle(signal: libc::c_int) {
+            // SAFETY: writing to a socket is async-signal-safe, and errors
+            // can be ignored because there is nothing else the handler could
+            // do
+            unsafe {
+                libc::write(
+                    SIGNAL_SOCKET.load(Ordering::SeqCst),
+                    [signal as u8].as_ptr().cast(),
+                    1,
+                )
+            };
+        }
+        let handler = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        for &signal in signals {
+            // SAFETY: the handler is async-signal-safe, and handlers are
+            // reset when CGI scripts are started, unlike blocked signals
+            if unsafe { libc::signal(signal, handler) } == libc::SIG_ERR {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets the supplementary groups to the groups of the user and the given
+    /// group, see `initgroups(3)`.
+    pub fn initgroups(user: &CStr, gid: libc::gid_t) -> io::Result<()> {
+        // SAFETY: the user name is a valid C string
+        check(unsafe { libc::initgroups(user.as_ptr(), gid as _) }.into())?;
+        Ok(())
+    }
+
+    /// Sets the supplementary groups to only the given group.
+    pub fn setgroups(gid: libc::gid_t) -> io::Result<()> {
+        // SAFETY: the pointer points to one group ID
+        check(unsafe { libc::setgroups(1, &gid) }.into())?;
+        Ok(())
+    }
+
+    pub fn setgid(gid: libc::gid_t) -> io::Result<()> {
+        // SAFETY: the function does not have any preconditions
+        check(unsafe { libc::setgid(gid) }.into())?;
+        Ok(())
+    }
+
+    pub fn setuid(uid: libc::uid_t) -> io::Result<()> {
+        // SAFETY: the function does not have any preconditions
+        check(unsafe { libc::setuid(uid) }.into())?;
+        Ok(())
+    }
+
+    /// Returns the user ID and primary group ID of the user with the name.
+    pub fn user_by_name(name: &CStr) -> Option<(libc::uid_t, libc::gid_t)> {
+        entry(|entry: &mut libc::passwd, buf, result| {
+            // SAFETY: the name is a valid C string, and the buffer has the
+            // given length
+            unsafe { libc::getpwnam_r(name.as_ptr(), entry, buf.as_mut_ptr(), buf.len(), result) }
+        })
+        .map(|entry| (entry.pw_uid, entry.pw_gid))
+    }
+
+    /// Returns the user ID and primary group ID of the user with the ID.
+    pub fn user_by_id(uid: libc::uid_t) -> Option<(libc::uid_t, libc::gid_t)> {
+        entry(|entry: &mut libc::passwd, buf, result| {
+            // SAFETY: the buffer has the given length
+            unsafe { libc::getpwuid_r(uid, entry, buf.as_mut_ptr(), buf.len(), result) }
+        })
+        .map(|entry| (entry.pw_uid, entry.pw_gid))
+    }
+
+    /// Returns the ID of the group with the name.
+    pub fn group_by_name(name: &CStr) -> Option<libc::gid_t> {
+        entry(|entry: &mut libc::group, buf, result| {
+            // SAFETY: the name is a valid C string, and the buffer has the
+            // given length
+            unsafe { libc::getgrnam_r(name.as_ptr(), entry, buf.as_mut_ptr(), buf.len(), result) }
+        })
+        .map(|entry| entry.gr_gid)
+    }
+
+    /// Looks up a user or group entry with one of the reentrant functions
+    /// like `getpwnam_r(3)`, with a larger buffer for the strings of the
+    /// entry as long as it is too small. Only the IDs of the entry may be
+    /// used, because the strings point into the buffer.
+    fn entry<T>(
+        get: impl Fn(&mut T, &mut [libc::c_char], &mut *mut T) -> libc::c_int,
+    ) -> Option<T> {
+        let mut buf = vec![0; 1024];
+        loop {
+            // SAFETY: the entries only consist of integers and pointers,
+            // for which zero is a valid value
+            let mut entry = unsafe { mem::zeroed() };
+            let mut result = ptr::null_mut();
+            match get(&mut entry, &mut buf, &mut result) {
+                0 if result.is_null() => return None,
+                0 => return Some(entry),
+                libc::ERANGE if buf.len() < MAX_ENTRY_BUFFER => buf.resize(buf.len() * 2, 0),
+                _ => return None,
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use {
+        super::unix::check,
+        std::{
+            io,
+            mem::size_of,
+            os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd},
+            ptr,
+        },
+    };
+
+    const CREATE_RULESET_VERSION: libc::c_uint = 1;
+    const RULE_PATH_BENEATH: libc::c_int = 1;
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    /// Makes sure the process and the programs it executes can not gain
+    /// privileges, which is required to restrict it without being root, see
+    /// `PR_SET_NO_NEW_PRIVS` in `prctl(2)`.
+    pub fn set_no_new_privs() -> io::Result<()> {
+        // SAFETY: the arguments are plain integers
+        check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) }.into())?;
+        Ok(())
+    }
+
+    /// Returns the highest Landlock ABI version the kernel supports, or an
+    /// error if it does not support Landlock, see `landlock(7)`.
+    pub fn landlock_abi_version() -> io::Result<libc::c_long> {
+        // SAFETY: the version is queried without an attribute
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                ptr::null::<RulesetAttr>(),
+                0,
+                CREATE_RULESET_VERSION,
+            )
+        })
+    }
+
+    /// Creates a Landlock ruleset that restricts the given file system
+    /// access rights of the first ABI version.
+    pub fn landlock_create_ruleset(handled_access_fs: u64) -> io::Result<OwnedFd> {
+        let attr = RulesetAttr { handled_access_fs };
+        // SAFETY: the attribute has the layout of the first ABI version
+        let fd = check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr,
+                size_of::<RulesetAttr>(),
+                0,
+            )
+        })?;
+        // SAFETY: the file descriptor was just created and is owned by
+        // nothing else
+        Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+    }
+
+    /// Adds a rule to the ruleset that allows the given access to
+    /// everything beneath the directory.
+    pub fn landlock_add_path_rule(
+        ruleset: BorrowedFd,
+        allowed_access: u64,
+        dir: BorrowedFd,
+    ) -> io::Result<()> {
+        let attr = PathBeneathAttr {
+            allowed_access,
+            parent_fd: dir.as_raw_fd(),
+        };
+        // SAFETY: the attribute has the layout the kernel expects for rules
+        // of this type, and both file descriptors are valid
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_landlock_add_rule,
+                ruleset.as_raw_fd(),
+                RULE_PATH_BENEATH,
+                &attr,
+                0,
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Restricts the calling thread and the threads it starts afterwards to
+    /// the rules of the ruleset.
+    pub fn landlock_restrict_self(ruleset: BorrowedFd) -> io::Result<()> {
+        // SAFETY: the file descriptor is valid
+        check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset.as_raw_fd(), 0) })?;
+        Ok(())
+    }
+
+    /// Installs the seccomp filter program for all threads of the process.
+    pub fn seccomp_filter(filter: &[libc::sock_filter]) -> io::Result<()> {
+        let program = libc::sock_fprog {
+            len: filter
+                .len()
+                .try_into()
+                .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?,
+            // the kernel only reads the filter
+            filter: filter.as_ptr().cast_mut(),
+        };
+        // SAFETY: the program points to the filter, which lives until the
+        // kernel copied it
+        check(unsafe {
+            libc::syscall(
+                libc::SYS_seccomp,
+                libc::SECCOMP_SET_MODE_FILTER,
+                libc::SECCOMP_FILTER_FLAG_TSYNC,
+                &program,
+            )
+        })?;
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "openbsd")]
+mod openbsd {
+    use {
+        super::unix::check,
+        std::{ffi::CStr, io, ptr},
+    };
+
+    /// Restricts the system calls of the process to the promises, see
+    /// `pledge(2)`. The promises of programs it executes are not restricted.
+    pub fn pledge(promises: &CStr) -> io::Result<()> {
+        // SAFETY: the promises are a valid C string, and a null pointer
+        // keeps the promises of executed programs unrestricted
+        check(unsafe { libc::pledge(promises.as_ptr(), ptr::null()) }.into())?;
+        Ok(())
+    }
+
+    /// Allows accessing the file or directory with the given permissions,
+    /// see `unveil(2)`.
+    pub fn unveil(path: &CStr, permissions: &CStr) -> io::Result<()> {
+        // SAFETY: both arguments are valid C strings
+        check(unsafe { libc::unveil(path.as_ptr(), permissions.as_ptr()) }.into())?;
+        Ok(())
+    }
+
+    /// Prevents unveiling more paths.
+    pub fn unveil_lock() -> io::Result<()> {
+        // SAFETY: null pointers prevent unveiling more paths
+        check(unsafe { libc::unveil(ptr::null(), ptr::null()) }.into())?;
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod windows {
+    use {
+        std::{ffi::OsStr, io, os::windows::ffi::OsStrExt, ptr},
+        windows_sys::Win32::System::{
+            EventLog::{RegisterEventSourceW, ReportEventW, REPORT_EVENT_TYPE},
+            Services::{
+                CloseServiceHandle, CreateServiceW, DeleteService, OpenSCManagerW, OpenServiceW,
+                RegisterServiceCtrlHandlerExW, SetServiceStatus, StartServiceCtrlDispatcherW,
+                SERVICE_ALL_ACCESS, SERVICE_AUTO_START, SERVICE_ERROR_NORMAL, SERVICE_STATUS,
+                SERVICE_TABLE_ENTRYW, SERVICE_WIN32_OWN_PROCESS,
+            },
+        },
+    };
+
+    /// Converts the string to a null-terminated wide string.
+    fn wide(s: &OsStr) -> Vec<u16> {
+        s.encode_wide().chain([0]).collect()
+    }
+
+    fn check(result: i32) -> io::Result<()> {
+        if result != 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    fn check_handle(handle: isize) -> io::Result<isize> {
+        if handle != 0 {
+            Ok(handle)
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Connects to the service control manager, which then calls `main` on
+    /// another thread to start the service with the given name. Returns once
+    /// the service is stopped.
+    pub fn start_service_dispatcher(
+        name: &OsStr,
+        main: extern "system" fn(u32, *mut *mut u16),
+    ) -> io::Result<()> {
+        let mut name = wide(name);
+        let table = [
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: name.as_mut_ptr(),
+                lpServiceProc: Some(main),
+            },
+            // the table ends with an empty entry
+            SERVICE_TABLE_ENTRYW {
+                lpServiceName: ptr::null_mut(),
+                lpServiceProc: None,
+            },
+        ];
+        // SAFETY: the table is valid until the function returns, which is
+        // when the service stopped
+        check(unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) })
+    }
+
+    /// Registers the function that handles requests of the service control
+    /// manager for the service, and returns the handle to report its status
+    /// with.
+    pub fn register_service_handler(
+        name: &OsStr,
+        handler: extern "system" fn(u32, u32, *mut std::ffi::c_void, *mut std::ffi::c_void) -> u32,
+    ) -> io::Result<isize> {
+        let name = wide(name);
+        // SAFETY: the name is a null-terminated wide string, and the handler
+        // does not use the context
+        check_handle(unsafe {
+            RegisterServiceCtrlHandlerExW(name.as_ptr(), Some(handler), ptr::null_mut())
+        })
+    }
+
+    /// Reports the status of the service with the handle returned by
+    /// `register_service_handler`.
+    pub fn set_service_status(handle: isize, status: &SERVICE_STATUS) -> io::Result<()> {
+        // SAFETY: the status is valid for the duration of the call
+        check(unsafe { SetServiceStatus(handle, status) })
+    }
+
+    /// Opens the service control manager of the local computer.
+    pub fn open_service_manager(access: u32) -> io::Result<isize> {
+        // SAFETY: null pointers select the local computer and default
+        // database
+        check_handle(unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) })
+    }
+
+    /// Creates a service that is started automatically at boot with the
+    /// command line and runs as LocalSystem.
+    pub fn create_service(
+        manager: isize,
+        name: &OsStr,
+        display_name: &OsStr,
+        command: &OsStr,
+    ) -> io::Result<isize> {
+        let name = wide(name);
+        let display_name = wide(display_name);
+        let command = wide(command);
+        // SAFETY: all strings are null-terminated wide strings, and the
+        // service runs as LocalSystem when no account is given
+        check_handle(unsafe {
+            CreateServiceW(
+                manager,
+                name.as_ptr(),
+                display_name.as_ptr(),
+                SERVICE_ALL_ACCESS,
+                SERVICE_WIN32_OWN_PROCESS,
+                SERVICE_AUTO_START,
+                SERVICE_ERROR_NORMAL,
+                command.as_ptr(),
+                ptr::null(),
+                ptr::null_mut(),
+                ptr::null(),
+                ptr::null(),
+                ptr::null(),
+            )
+        })
+    }
+
+    pub fn open_service(manager: isize, name: &OsStr) -> io::Result<isize> {
+        let name = wide(name);
+        // SAFETY: the name is a null-terminated wide string
+        check_handle(unsafe { OpenServiceW(manager, name.as_ptr(), SERVICE_ALL_ACCESS) })
+    }
+
+    /// Marks the service for deletion, which happens once it is stopped.
+    pub fn delete_service(service: isize) -> io::Result<()> {
+        // SAFETY: the function only takes the handle
+        check(unsafe { DeleteService(service) })
+    }
+
+    /// Closes a handle returned by the service control manager.
+    pub fn close_service_handle(handle: isize) {
+        // SAFETY: the function only takes the handle
+        unsafe { CloseServiceHandle(handle) };
+    }
+
+    /// Registers an event source of the Windows event log with the name.
+    pub fn register_event_source(name: &OsStr) -> io::Result<isize> {
+        let name = wide(name);
+        // SAFETY: a null pointer selects the local computer, and the name
+        // is a null-terminated wide string
+        check_handle(unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) })
+    }
+
+    /// Writes an event with the message to the event log.
+    pub fn report_event(
+        source: isize,
+        event_type: REPORT_EVENT_TYPE,
+        message: &OsStr,
+    ) -> io::Result<()> {
+        let message = wide(message);
+        let strings = [message.as_ptr()];
+        // SAFETY: the only string is a null-terminated wide string
+        check(unsafe {
+            ReportEventW(
+                source,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_ptr(),
+                ptr::null(),
+            )
+        })
+    }
+}
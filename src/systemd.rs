@@ -0,0 +1,118 @@
+//! Socket activation and service notifications as done by systemd, see
+//! `sd_listen_fds(3)` and `sd_notify(3)`.
+
+use {
+    socket2::Type,
+    std::{
+        env, io,
+        net::TcpListener,
+        os::{
+            fd::AsRawFd,
+            unix::net::{SocketAddr, UnixDatagram, UnixListener},
+        },
+        time::Duration,
+    },
+};
+
+/// A listening socket passed by systemd.
+pub(crate) enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+/// Returns the listening sockets passed by systemd, which is empty if Agate
+/// was not started by socket activation.
+pub(crate) fn listeners() -> io::Result<Vec<Listener>> {
+    agate::sys::listen_fds()
+        .into_iter()
+        .map(|socket| {
+            if socket.r#type()? != Type::STREAM {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "file descriptor {} is not a stream socket",
+                        socket.as_raw_fd()
+                    ),
+                ));
+            }
+            socket.set_nonblocking(true)?;
+            if socket.local_addr()?.is_unix() {
+                Ok(Listener::Unix(socket.into()))
+            } else {
+                Ok(Listener::Tcp(socket.into()))
+            }
+        })
+        .collect()
+}
+
+/// Sends notifications about the state of the service to systemd.
+pub(crate) struct Notifier {
+    socket: UnixDatagram,
+    addr: SocketAddr,
+    /// The interval in which systemd expects to be pinged, if the watchdog
+    /// is enabled.
+    watchdog: Option<Duration>,
+}
+
+impl Notifier {
+    /// Returns a notifier if systemd expects notifications, which is the
+    /// case for services with `Type=notify`. The environment variables are
+    /// removed so they are not passed on to CGI scripts.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = env::var("NOTIFY_SOCKET").ok();
+        let watchdog_pid = env::var("WATCHDOG_PID").ok();
+        let watchdog_usec = env::var("WATCHDOG_USEC").ok();
+        env::remove_var("NOTIFY_SOCKET");
+        env::remove_var("WATCHDOG_PID");
+        env::remove_var("WATCHDOG_USEC");
+
+        let Some(path) = path else {
+            return Ok(None);
+        };
+        let addr = match path.strip_prefix('@') {
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            Some(name) => {
+                #[cfg(target_os = "android")]
+                use std::os::android::net::SocketAddrExt;
+                #[cfg(target_os = "linux")]
+                use std::os::linux::net::SocketAddrExt;
+                SocketAddr::from_abstract_name(name)?
+            }
+            _ => SocketAddr::from_pathname(&path)?,
+        };
+
+        // the watchdog might be meant for another process
+        let watchdog = watchdog_usec
+            .and_then(|usec| usec.parse().ok())
+            .filter(|_| watchdog_pid.is_none_or(|pid| pid.parse().ok() == Some(std::process::id())))
+            .map(Duration::from_micros);
+
+        Ok(Some(Self {
+            socket: UnixDatagram::unbound()?,
+            addr,
+            watchdog,
+        }))
+    }
+
+    /// Sends a notification like `READY=1`.
+    pub fn notify(&self, state: &str) -> io::Result<()> {
+        self.socket.send_to_addr(state.as_bytes(), &self.addr)?;
+        Ok(())
+    }
+
+    /// Pings the watchdog at half the interval systemd expects, if it is
+    /// enabled. Because this runs on the same runtime as the listeners,
+    /// systemd notices if Agate hangs.
+    pub async fn watchdog(self) {
+        let Some(interval) = self.watchdog else {
+            return;
+        };
+        let mut interval = tokio::time::interval(interval / 2);
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.notify("WATCHDOG=1") {
+                log::warn!("could not notify the systemd watchdog: {e}");
+            }
+        }
+    }
+}
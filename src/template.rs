@@ -0,0 +1,37 @@
+use std::path::Path;
+
+/// Ending of the names of files that are rendered as gemtext templates.
+static TEMPLATE_SUFFIX: &str = ".gmi.tera";
+
+/// Returns whether the file is a gemtext template.
+pub(crate) fn is_template(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.len() > TEMPLATE_SUFFIX.len() && name.ends_with(TEMPLATE_SUFFIX))
+}
+
+/// Replaces each expression like `{{ path }}` in the template with the value
+/// of the variable, which uses the same syntax as Tera for variables. Control
+/// characters in the values are replaced with spaces, so a value like the
+/// query can not start a new gemtext line, e.g. a link. Unknown variables are
+/// logged and replaced with nothing.
+pub(crate) fn render(template: &str, variables: &[(&str, String)]) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let Some(len) = rest[start + 2..].find("}}") else {
+            break;
+        };
+        output.push_str(&rest[..start]);
+        let name = rest[start + 2..start + 2 + len].trim();
+        match variables.iter().find(|(variable, _)| *variable == name) {
+            Some((_, value)) => {
+                output.extend(value.chars().map(|c| if c.is_control() { ' ' } else { c }))
+            }
+            None => log::warn!("unknown template variable {name:?}"),
+        }
+        rest = &rest[start + 2 + len + 2..];
+    }
+    output.push_str(rest);
+    output
+}
@@ -0,0 +1,146 @@
+use {
+    std::{
+        future::Future,
+        io,
+        pin::Pin,
+        str::FromStr,
+        task::{Context, Poll},
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        time::{sleep_until, Instant, Sleep},
+    },
+};
+
+/// A maximum bandwidth for responses to URL paths starting with a prefix.
+#[derive(Debug, Clone)]
+pub(crate) struct Limit {
+    pub prefix: String,
+    /// The maximum number of bytes per second.
+    pub rate: u64,
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    /// Parses a limit in the form `PREFIX=BYTES_PER_SECOND`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, rate) = s
+            .split_once('=')
+            .ok_or_else(|| format!("bandwidth limit {s:?} is not in the form PREFIX=BYTES"))?;
+        match rate.parse() {
+            Ok(0) | Err(_) => Err(format!("invalid bandwidth in {s:?}")),
+            Ok(rate) => Ok(Self {
+                prefix: prefix.to_string(),
+                rate,
+            }),
+        }
+    }
+}
+
+/// A stream where writing waits as long as necessary to not exceed a number
+/// of bytes per second. Up to one second worth of data may be written at
+/// once, so small responses are not slowed down.
+pub(crate) struct Throttle<T> {
+    inner: T,
+    /// The maximum number of bytes per second, or `None` if writing is not
+    /// limited.
+    rate: Option<u64>,
+    /// When the limit was set.
+    start: Instant,
+    /// The number of bytes written since the limit was set.
+    written: u64,
+    /// Started when the limit was reached, until enough time has passed.
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> Throttle<T> {
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            rate: None,
+            start: Instant::now(),
+            written: 0,
+            sleep: None,
+        }
+    }
+
+    /// Limits the bytes written from now on to the given number per second.
+    pub fn set_rate(&mut self, rate: Option<u64>) {
+        self.rate = rate;
+        self.start = Instant::now();
+        self.written = 0;
+        self.sleep = None;
+    }
+
+    /// Returns how many bytes may be written right now, or when that will be
+    /// possible.
+    fn allowed(&self, rate: u64) -> Result<usize, Instant> {
+        let elapsed = self.start.elapsed();
+        let budget = (rate as u128 * (elapsed.as_nanos() + 1_000_000_000) / 1_000_000_000) as u64;
+        match budget.checked_sub(self.written) {
+            Some(allowed) if allowed > 0 => Ok(allowed.try_into().unwrap_or(usize::MAX)),
+            _ => {
+                // the time when one more byte may be written
+                let nanos = ((self.written + 1) as u128 * 1_000_000_000).div_ceil(rate as u128);
+                let wait = Duration::from_nanos(nanos.saturating_sub(1_000_000_000) as u64);
+                Err(self.start + wait)
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Throttle<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Throttle<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let Some(rate) = self.rate else {
+            return Pin::new(&mut self.inner).poll_write(cx, buf);
+        };
+        if let Some(sleep) = &mut self.sleep {
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+            self.sleep = None;
+        }
+        let allowed = loop {
+            match self.allowed(rate) {
+                Ok(allowed) => break allowed,
+                Err(deadline) => {
+                    let mut sleep = Box::pin(sleep_until(deadline));
+                    if sleep.as_mut().poll(cx).is_pending() {
+                        self.sleep = Some(sleep);
+                        return Poll::Pending;
+                    }
+                }
+            }
+        };
+        let len = buf.len().min(allowed);
+        let poll = Pin::new(&mut self.inner).poll_write(cx, &buf[..len]);
+        if let Poll::Ready(Ok(written)) = poll {
+            self.written += written as u64;
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
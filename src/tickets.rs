@@ -0,0 +1,74 @@
+use {
+    ring::{
+        aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, NONCE_LEN},
+        rand::{SecureRandom, SystemRandom},
+    },
+    std::{sync::Arc, time::Duration},
+    tokio_rustls::rustls::{
+        self, crypto::GetRandomFailed, server::ProducesTickets, TicketSwitcher,
+    },
+};
+
+/// Returns a producer of stateless session tickets. A new key is generated
+/// after the given time, tickets encrypted with the previous key are still
+/// accepted for the same time.
+pub(crate) fn ticketer(rotation: Duration) -> Result<Arc<dyn ProducesTickets>, rustls::Error> {
+    let rotation = u32::try_from(rotation.as_secs()).unwrap_or(u32::MAX);
+    Ok(Arc::new(TicketSwitcher::new(rotation, Ticketer::generate)?))
+}
+
+/// Encrypts session tickets with ChaCha20-Poly1305 and a random key.
+#[derive(Debug)]
+struct Ticketer {
+    key: LessSafeKey,
+}
+
+impl Ticketer {
+    fn generate() -> Result<Box<dyn ProducesTickets>, GetRandomFailed> {
+        let mut key = [0; 32];
+        SystemRandom::new()
+            .fill(&mut key)
+            .map_err(|_| GetRandomFailed)?;
+        let key = UnboundKey::new(&aead::CHACHA20_POLY1305, &key).unwrap();
+        Ok(Box::new(Self {
+            key: LessSafeKey::new(key),
+        }))
+    }
+}
+
+impl ProducesTickets for Ticketer {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        // the lifetime is determined by the ticket switcher
+        0
+    }
+
+    fn encrypt(&self, message: &[u8]) -> Option<Vec<u8>> {
+        // the ticket is the random nonce followed by the encrypted message
+        let mut ticket = vec![0; NONCE_LEN];
+        SystemRandom::new().fill(&mut ticket).ok()?;
+        let nonce = Nonce::try_assume_unique_for_key(&ticket).ok()?;
+        let mut encrypted = message.to_vec();
+        self.key
+            .seal_in_place_append_tag(nonce, Aad::empty(), &mut encrypted)
+            .ok()?;
+        ticket.extend(encrypted);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let (nonce, encrypted) = ticket.split_at_checked(NONCE_LEN)?;
+        let nonce = Nonce::try_assume_unique_for_key(nonce).ok()?;
+        let mut message = encrypted.to_vec();
+        let len = self
+            .key
+            .open_in_place(nonce, Aad::empty(), &mut message)
+            .ok()?
+            .len();
+        message.truncate(len);
+        Some(message)
+    }
+}
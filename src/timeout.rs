@@ -0,0 +1,133 @@
+use {
+    std::{
+        future::Future,
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+        time::Duration,
+    },
+    tokio::{
+        io::{AsyncRead, AsyncWrite, ReadBuf},
+        time::{sleep, Sleep},
+    },
+};
+
+/// A stream where reading or writing fails if the other side does not send
+/// or accept any data for some time, so clients and backends that stall can
+/// not keep a connection open indefinitely. Large uploads and responses can
+/// still take longer, as long as they make progress.
+pub(crate) struct IdleTimeout<T> {
+    inner: T,
+    read_timeout: Duration,
+    write_timeout: Duration,
+    /// Started when a read has to wait, reset whenever it makes progress.
+    read_sleep: Option<Pin<Box<Sleep>>>,
+    /// Started when a write has to wait, reset whenever it makes progress.
+    write_sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<T> IdleTimeout<T> {
+    pub fn new(inner: T, read_timeout: Duration, write_timeout: Duration) -> Self {
+        Self {
+            inner,
+            read_timeout,
+            write_timeout,
+            read_sleep: None,
+            write_sleep: None,
+        }
+    }
+}
+
+/// Waits for the given operation, like connecting to a backend, and fails if
+/// it does not finish within `time_limit`.
+pub(crate) async fn limit<R>(
+    time_limit: Duration,
+    operation: impl Future<Output = io::Result<R>>,
+) -> io::Result<R> {
+    tokio::time::timeout(time_limit, operation)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Handles the result of polling an operation of the inner stream, starting
+/// the given sleep if it has to wait and failing once the sleep is over.
+fn check<R>(
+    sleep_slot: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Duration,
+    message: &'static str,
+    cx: &mut Context<'_>,
+    poll: Poll<io::Result<R>>,
+) -> Poll<io::Result<R>> {
+    if poll.is_ready() {
+        *sleep_slot = None;
+        return poll;
+    }
+    let sleep = sleep_slot.get_or_insert_with(|| Box::pin(sleep(timeout)));
+    match sleep.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            *sleep_slot = None;
+            Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, message)))
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for IdleTimeout<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        check(
+            &mut this.read_sleep,
+            this.read_timeout,
+            "timed out reading",
+            cx,
+            poll,
+        )
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for IdleTimeout<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        check(
+            &mut this.write_sleep,
+            this.write_timeout,
+            "timed out writing",
+            cx,
+            poll,
+        )
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_flush(cx);
+        check(
+            &mut this.write_sleep,
+            this.write_timeout,
+            "timed out writing",
+            cx,
+            poll,
+        )
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        let poll = Pin::new(&mut this.inner).poll_shutdown(cx);
+        check(
+            &mut this.write_sleep,
+            this.write_timeout,
+            "timed out writing",
+            cx,
+            poll,
+        )
+    }
+}
@@ -0,0 +1,168 @@
+use {
+    std::{
+        io,
+        path::Path,
+        str::FromStr,
+        sync::atomic::{AtomicUsize, Ordering},
+    },
+    tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+};
+
+/// Counter to make the names of temporary files unique.
+static TEMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+/// A URL path prefix that files may be uploaded to with Titan.
+#[derive(Debug, Clone)]
+pub(crate) struct Area {
+    pub prefix: String,
+    /// Fingerprints of the client certificates that may upload files, or
+    /// empty if any trusted client certificate may upload files.
+    pub fingerprints: Vec<String>,
+}
+
+impl FromStr for Area {
+    type Err = String;
+
+    /// Parses an area in the form `PREFIX[=FINGERPRINT,...]`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.split_once('=') {
+            Some((prefix, fingerprints)) => Self {
+                prefix: prefix.to_string(),
+                fingerprints: fingerprints
+                    .split(',')
+                    .map(|fingerprint| fingerprint.trim().to_lowercase())
+                    .filter(|fingerprint| !fingerprint.is_empty())
+                    .collect(),
+            },
+            None => Self {
+                prefix: s.to_string(),
+                fingerprints: vec![],
+            },
+        })
+    }
+}
+
+impl Area {
+    /// Checks if the URL path is in this area. The prefix has to end at a
+    /// segment boundary, so `/wiki` does not contain `/wiki-private/`.
+    pub fn contains(&self, path: &str) -> bool {
+        match path.strip_prefix(&self.prefix) {
+            Some(rest) => self.prefix.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        }
+    }
+
+    /// Checks if the client certificate with the given fingerprint may upload
+    /// files to this area.
+    pub fn allows(&self, fingerprint: &str) -> bool {
+        self.fingerprints.is_empty() || self.fingerprints.iter().any(|f| f == fingerprint)
+    }
+}
+
+/// The parameters of a Titan request.
+#[derive(Debug)]
+pub(crate) struct Params {
+    pub mime: String,
+    pub size: u64,
+    pub token: Option<String>,
+}
+
+/// Splits the parameters off the path of a Titan URL, which looks like
+/// ```text
+/// /path/to/file.gmi;mime=text/gemini;size=123;token=secret
+/// ```
+/// Returns the path without parameters and the parsed parameters, or `None`
+/// if the size is missing or invalid. The media type defaults to
+/// `text/gemini`.
+pub(crate) fn parse_path(path: &str) -> Option<(&str, Params)> {
+    let (path, params) = path.split_once(';')?;
+    let mut mime = "text/gemini".to_string();
+    let mut size = None;
+    let mut token = None;
+    for param in params.split(';') {
+        let (key, value) = param.split_once('=')?;
+        let value = percent_encoding::percent_decode_str(value)
+            .decode_utf8()
+            .ok()?
+            .into_owned();
+        match key {
+            "mime" => mime = value,
+            "size" => size = Some(value.parse().ok()?),
+            "token" => token = Some(value),
+            // ignore unknown parameters
+            _ => (),
+        }
+    }
+    Some((
+        path,
+        Params {
+            mime,
+            size: size?,
+            token,
+        },
+    ))
+}
+
+/// Checks if the media type matches one of the allowed types, which may end
+/// in `/*` to allow all subtypes. An empty list allows all media types.
+pub(crate) fn mime_allowed(allowed: &[String], mime: &str) -> bool {
+    // ignore media type parameters like the charset
+    let mime = mime.split(';').next().unwrap_or_default().trim();
+    allowed.is_empty()
+        || allowed
+            .iter()
+            .any(|allowed| match allowed.strip_suffix("/*") {
+                Some(kind) => mime
+                    .split_once('/')
+                    .is_some_and(|(prefix, _)| prefix.eq_ignore_ascii_case(kind)),
+                None => allowed.eq_ignore_ascii_case(mime),
+            })
+}
+
+/// Writes the upload to the given path. The content starts with `received`,
+/// which was already read together with the request, and continues with the
+/// remaining bytes read from `reader`, up to `size` bytes in total.
+///
+/// The content is first written to a temporary file in the same directory,
+/// which is then renamed, so the file is replaced atomically and incomplete
+/// uploads never become visible.
+pub(crate) async fn write<R>(
+    path: &Path,
+    received: &[u8],
+    reader: &mut R,
+    size: u64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let dir = path.parent().expect("file has no parent directory");
+    tokio::fs::create_dir_all(dir).await?;
+    let temp = dir.join(format!(
+        ".{}.{}-{}.titan",
+        path.file_name().unwrap_or_default().to_string_lossy(),
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed),
+    ));
+
+    let result = async {
+        let mut file = tokio::fs::File::create(&temp).await?;
+        let received = &received[..received.len().min(size as usize)];
+        file.write_all(received).await?;
+        let remaining = size - received.len() as u64;
+        let copied = tokio::io::copy(&mut reader.take(remaining), &mut file).await?;
+        if copied < remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "upload ended unexpectedly",
+            ));
+        }
+        file.sync_all().await?;
+        tokio::fs::rename(&temp, path).await
+    }
+    .await;
+
+    if result.is_err() {
+        let _ = tokio::fs::remove_file(&temp).await;
+    }
+    result
+}
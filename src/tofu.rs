@@ -0,0 +1,304 @@
+use {
+    std::{
+        collections::BTreeMap,
+        fmt::Write as _,
+        fs::OpenOptions,
+        io::Write as _,
+        path::PathBuf,
+        sync::{Arc, Mutex},
+        time::{SystemTime, UNIX_EPOCH},
+    },
+    tokio_rustls::rustls::{
+        self,
+        client::danger::HandshakeSignatureValid,
+        crypto::{ring::default_provider, WebPkiSupportedAlgorithms},
+        pki_types::{CertificateDer, UnixTime},
+        server::danger::{ClientCertVerified, ClientCertVerifier},
+        DigitallySignedStruct, DistinguishedName, SignatureScheme,
+    },
+};
+
+/// Returns the fingerprint used to identify a client certificate, which is
+/// the lower case hexadecimal SHA-256 hash of the DER encoded certificate.
+pub(crate) fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let hash = ring::digest::digest(&ring::digest::SHA256, cert.as_ref());
+    let mut hex = String::with_capacity(64);
+    for byte in hash.as_ref() {
+        write!(hex, "{byte:02x}").unwrap();
+    }
+    hex
+}
+
+/// Returns the common name in the subject of a client certificate, if it
+/// has one.
+pub(crate) fn common_name(cert: &CertificateDer<'_>) -> Option<String> {
+    let (_, cert, _) = der_next(cert.as_ref())?;
+    let (_, tbs, _) = der_next(cert)?;
+    // skip the optional version, the serial number, the signature algorithm,
+    // the issuer and the validity
+    let (tag, _, mut rest) = der_next(tbs)?;
+    if tag == 0xa0 {
+        rest = der_next(rest)?.2;
+    }
+    for _ in 0..3 {
+        rest = der_next(rest)?.2;
+    }
+    let (_, mut subject, _) = der_next(rest)?;
+
+    let mut name = None;
+    while !subject.is_empty() {
+        let (_, mut set, rest) = der_next(subject)?;
+        subject = rest;
+        while !set.is_empty() {
+            let (_, attribute, rest) = der_next(set)?;
+            set = rest;
+            let (_, oid, value) = der_next(attribute)?;
+            // the object identifier 2.5.4.3 of the common name
+            if oid == [0x55, 0x04, 0x03] {
+                let (_, value, _) = der_next(value)?;
+                name = Some(String::from_utf8_lossy(value).into_owned());
+            }
+        }
+    }
+    name
+}
+
+/// Splits the DER encoded value at the start of the data into its tag, its
+/// content and the data after it.
+fn der_next(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = data.split_first()?;
+    let (&len, mut rest) = rest.split_first()?;
+    let len = if len < 0x80 {
+        len as usize
+    } else {
+        // the length is given in the following bytes
+        let bytes = (len & 0x7f) as usize;
+        if bytes == 0 || bytes > 4 || rest.len() < bytes {
+            return None;
+        }
+        let (len, after) = rest.split_at(bytes);
+        rest = after;
+        len.iter().fold(0, |len, &byte| len << 8 | byte as usize)
+    };
+    if rest.len() < len {
+        return None;
+    }
+    let (content, rest) = rest.split_at(len);
+    Some((tag, content, rest))
+}
+
+/// A client identity that has been seen before.
+#[derive(Debug, Clone)]
+pub(crate) struct Identity {
+    /// When the identity was first seen, in seconds since the UNIX epoch.
+    pub first_seen: u64,
+    /// Whether the identity was revoked by the operator.
+    pub revoked: bool,
+}
+
+/// A trust-on-first-use registry of client certificates.
+///
+/// The registry is optionally stored in a file where each line has the
+/// format
+/// ```text
+/// <fingerprint> <first seen>[ revoked]
+/// ```
+/// with the fingerprint as given by [`fingerprint`] and the time the
+/// identity was first seen in seconds since the UNIX epoch.
+pub(crate) struct TofuStore {
+    /// The file the registry is stored in, or `None` if it is only kept in
+    /// memory.
+    path: Option<PathBuf>,
+    identities: Mutex<BTreeMap<String, Identity>>,
+}
+
+impl TofuStore {
+    /// Creates a registry that is only kept in memory.
+    pub fn new() -> Self {
+        Self {
+            path: None,
+            identities: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the file the registry is stored in, if any.
+    #[cfg(any(
+        all(target_os = "openbsd", feature = "openbsd-sandbox"),
+        target_os = "linux"
+    ))]
+    pub fn path(&self) -> Option<&std::path::Path> {
+        self.path.as_deref()
+    }
+
+    /// Loads the registry from the given file. If the file does not exist,
+    /// it will be created when the first identity is recorded.
+    pub fn load(path: PathBuf) -> Result<Self, String> {
+        let mut identities = BTreeMap::new();
+        match std::fs::read_to_string(&path) {
+            Ok(content) => {
+                for (number, line) in content.lines().enumerate() {
+                    let mut words = line.split_whitespace();
+                    let (Some(fingerprint), Some(Ok(first_seen))) =
+                        (words.next(), words.next().map(str::parse))
+                    else {
+                        return Err(format!(
+                            "{}:{}: invalid client certificate registry entry",
+                            path.display(),
+                            number + 1
+                        ));
+                    };
+                    let revoked = words.next() == Some("revoked");
+                    identities.insert(
+                        fingerprint.to_string(),
+                        Identity {
+                            first_seen,
+                            revoked,
+                        },
+                    );
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => (),
+            Err(e) => {
+                return Err(format!(
+                    "could not read client certificate registry {}: {e}",
+                    path.display()
+                ))
+            }
+        }
+
+        Ok(Self {
+            path: Some(path),
+            identities: Mutex::new(identities),
+        })
+    }
+
+    /// Checks if the identity with the given fingerprint may pass. Identities
+    /// that have not been seen before are recorded and trusted, revoked
+    /// identities are not trusted.
+    pub fn check(&self, fingerprint: &str) -> bool {
+        let mut identities = self.identities.lock().unwrap();
+        if let Some(identity) = identities.get(fingerprint) {
+            return !identity.revoked;
+        }
+
+        let identity = Identity {
+            first_seen: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            revoked: false,
+        };
+        log::info!("New client certificate {fingerprint}");
+        if let Some(path) = &self.path {
+            let result = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{fingerprint} {}", identity.first_seen));
+            if let Err(e) = result {
+                log::error!(
+                    "could not record client certificate in {}: {e}",
+                    path.display()
+                );
+            }
+        }
+        identities.insert(fingerprint.to_string(), identity);
+        true
+    }
+
+    /// Returns all known identities, sorted by fingerprint.
+    pub fn identities(&self) -> Vec<(String, Identity)> {
+        self.identities
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    /// Revokes the identity with the given fingerprint and rewrites the
+    /// registry file. Revoked identities stay in the registry, so they are
+    /// not trusted again when they are used the next time.
+    pub fn revoke(&self, fingerprint: &str) -> Result<(), String> {
+        let mut identities = self.identities.lock().unwrap();
+        let Some(identity) = identities.get_mut(fingerprint) else {
+            return Err(format!("unknown client certificate {fingerprint}"));
+        };
+        identity.revoked = true;
+
+        if let Some(path) = &self.path {
+            let mut content = String::new();
+            for (fingerprint, identity) in identities.iter() {
+                write!(content, "{fingerprint} {}", identity.first_seen).unwrap();
+                if identity.revoked {
+                    content.push_str(" revoked");
+                }
+                content.push('\n');
+            }
+            std::fs::write(path, content).map_err(|e| {
+                format!(
+                    "could not write client certificate registry {}: {e}",
+                    path.display()
+                )
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Accepts any client certificate, as long as the client can prove that it
+/// holds the respective private key. Client certificates in Gemini are
+/// usually self signed, so there is no chain of trust to check. Instead
+/// they are checked against the [`TofuStore`] when a request is handled.
+#[derive(Debug)]
+pub(crate) struct AnyClientCert {
+    algorithms: WebPkiSupportedAlgorithms,
+}
+
+impl AnyClientCert {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            algorithms: default_provider().signature_verification_algorithms,
+        })
+    }
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// A pattern for the directories that URL paths starting with `~USER` are
+/// served from, where `*` stands for the user name.
+#[derive(Debug, Clone)]
+pub(crate) struct UserDirs {
+    pattern: String,
+}
+
+impl UserDirs {
+    /// Returns the directory for the first segment of a URL path if it
+    /// refers to a user.
+    pub fn dir(&self, segment: &str) -> Option<PathBuf> {
+        let name = segment.strip_prefix('~')?;
+        if name.is_empty()
+            || name.starts_with(['.', '-'])
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_'))
+        {
+            return None;
+        }
+        Some(PathBuf::from(self.pattern.replace('*', name)))
+    }
+
+    /// Splits the pattern into the directory that contains all user
+    /// directories and the rest starting with the component with the `*`.
+    fn split(&self) -> (&str, &str) {
+        let star = self.pattern.find('*').unwrap_or_default();
+        match self.pattern[..star].rfind(std::path::is_separator) {
+            Some(i) => (&self.pattern[..i], &self.pattern[i + 1..]),
+            None => ("", &self.pattern),
+        }
+    }
+
+    /// Returns the directory that contains all user directories.
+    pub fn base(&self) -> &Path {
+        match self.split().0 {
+            "" if self.pattern.starts_with(std::path::is_separator) => Path::new("/"),
+            "" => Path::new("."),
+            base => Path::new(base),
+        }
+    }
+
+    /// Replaces the directory that contains all user directories, e.g. to
+    /// make it relative to a new root directory.
+    pub fn set_base(&mut self, base: &Path) {
+        self.pattern = base.join(self.split().1).to_string_lossy().into_owned();
+    }
+}
+
+impl FromStr for UserDirs {
+    type Err = String;
+
+    /// Parses a pattern that contains `*` once, e.g. `/home/*/public_gemini`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.matches('*').count() != 1 {
+            return Err(format!(
+                "the user directory pattern {s:?} has to contain * once for the user name"
+            ));
+        }
+        Ok(Self {
+            pattern: s.to_string(),
+        })
+    }
+}
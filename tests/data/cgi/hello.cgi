@@ -0,0 +1,3 @@
+#!/bin/sh
+printf '20 text/plain\r\n'
+printf '%s\n' "$SCRIPT_NAME" "$PATH_INFO" "$QUERY_STRING"
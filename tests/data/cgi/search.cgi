@@ -0,0 +1,3 @@
+#!/bin/sh
+printf '20 text/plain\r\n'
+printf '%s\n' "$QUERY_STRING" "$GEMINI_INPUT"
@@ -0,0 +1,34 @@
+#!/bin/bash
+
+# The RSA certificate and key are copied from the multicert directory, this
+# creates an ECDSA certificate and key for the same domain.
+domain="example.com"
+mkdir -p $domain
+
+openssl genpkey -outform DER -out $domain/key-ecdsa.der -algorithm EC -pkeyopt ec_paramgen_curve:P-256
+
+# create config file:
+# the generated certificates must not be CA-capable, otherwise rustls complains
+cat >openssl.conf <<EOT
+[req]
+distinguished_name = req_distinguished_name
+req_extensions = req_ext
+prompt = no
+
+[v3_ca]
+basicConstraints = critical, CA:false
+
+[req_distinguished_name]
+commonName = $domain
+
+[req_ext]
+subjectAltName = DNS:$domain
+EOT
+
+openssl req -new -sha256 -out request.csr -key $domain/key-ecdsa.der -keyform DER -config openssl.conf
+
+openssl x509 -req -sha256 -days 3650 -in request.csr -outform DER -out $domain/cert-ecdsa.der \
+	-extensions req_ext -extfile openssl.conf -signkey $domain/key-ecdsa.der -keyform DER
+
+# clean up
+rm openssl.conf request.csr
@@ -16,7 +16,7 @@
 use rustls::{pki_types::CertificateDer, ClientConnection, RootCertStore};
 use std::convert::TryInto;
 use std::io::{BufRead, BufReader, Read, Write};
-use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::net::{SocketAddr, TcpStream};
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 use std::sync::atomic::{AtomicU16, Ordering};
@@ -33,6 +33,17 @@ static DEFAULT_PORT: u16 = 1965;
 /// doing it this way avoids port collisions from manually setting ports
 static PORT: AtomicU16 = AtomicU16::new(DEFAULT_PORT);
 
+/// Returns a unique port so tests do not clash, skipping ports that are
+/// already used by other programs.
+fn free_port() -> u16 {
+    loop {
+        let port = PORT.fetch_add(1, Ordering::SeqCst);
+        if std::net::TcpListener::bind(("127.0.0.1", port)).is_ok() {
+            return port;
+        }
+    }
+}
+
 struct Server {
     addr: SocketAddr,
     server: std::process::Child,
@@ -44,15 +55,7 @@ impl Server {
     pub fn new(args: &[&str]) -> Self {
         use std::net::{IpAddr, Ipv4Addr};
 
-        // generate unique port/address so tests do not clash
-        let addr = (
-            IpAddr::V4(Ipv4Addr::LOCALHOST),
-            PORT.fetch_add(1, Ordering::SeqCst),
-        )
-            .to_socket_addrs()
-            .unwrap()
-            .next()
-            .unwrap();
+        let addr = SocketAddr::from((IpAddr::V4(Ipv4Addr::LOCALHOST), free_port()));
 
         // start the server
         let mut server = Command::new(BINARY_PATH)
@@ -221,272 +224,3428 @@ fn index_page_unix() {
     server.stop().expect("failed to stop server");
 }
 
+#[cfg(unix)]
 #[test]
-/// - symlinked files are followed correctly
-fn symlink_page() {
-    let page = get(&[], "gemini://localhost/symlink.gmi").expect("could not get page");
+/// - requests without TLS are served on a plain Unix socket
+fn index_page_unix_plain() {
+    let sock_path = std::env::temp_dir().join("agate-test-unix-plain-socket");
 
-    assert_eq!(page.status, Status::Success.value());
-    assert_eq!(page.meta, "text/gemini");
-    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
-}
+    let mut server = Server::new(&[
+        "--plain-socket",
+        sock_path
+            .to_str()
+            .expect("could not convert temp dir path to string"),
+    ]);
 
-#[test]
-/// - symlinked directories are followed correctly
-fn symlink_directory() {
-    let page = get(&[], "gemini://localhost/symlinked_dir/file.gmi").expect("could not get page");
+    let mut unix = loop {
+        if let Ok(sock) = std::os::unix::net::UnixStream::connect(&sock_path) {
+            break sock;
+        }
+        sleep(Duration::from_millis(10));
+    };
 
-    assert_eq!(page.status, Status::Success.value());
-    assert_eq!(page.meta, "text/gemini");
-    assert_eq!(page.content, include_bytes!("data/symlinked_dir/file.gmi"));
-}
+    write!(unix, "gemini://localhost/\r\n").unwrap();
 
-#[test]
-/// - the `--addr` configuration works
-/// - MIME media types can be set in the configuration file
-fn meta() {
-    let page = get(&[], "gemini://localhost/test").expect("could not get page");
-    assert_eq!(page.status, Status::Success.value());
-    assert_eq!(page.meta, "text/html");
+    let mut response = vec![];
+    unix.read_to_end(&mut response).unwrap();
+
+    assert!(response.starts_with(b"20 text/gemini\r\n"));
+    assert!(response.ends_with(include_bytes!("data/content/index.gmi")));
+
+    server.stop().expect("failed to stop server");
 }
 
 #[test]
-/// - MIME type is correctly guessed for `.gmi` files
-/// - MIME media type parameters can be set in the configuration file
-fn meta_param() {
-    let page = get(&[], "gemini://localhost/test.gmi").expect("could not get page");
+/// - options are read from the configuration file
+/// - comments at the end of a line are ignored
+fn config_file() {
+    let page =
+        get(&["--config", "config/agate.conf"], "gemini://localhost").expect("could not get page");
+
     assert_eq!(page.status, Status::Success.value());
-    assert_eq!(page.meta, "text/gemini;lang=en ;charset=us-ascii");
+    assert_eq!(page.meta, "text/gemini;lang=de");
 }
 
 #[test]
-/// - globs in the configuration file work correctly
-/// - distributed configuration file is used when `-C` flag not used
-fn glob() {
-    let page = get(&[], "gemini://localhost/testdir/a.nl.gmi").expect("could not get page");
+/// - command line options take precedence over the configuration file
+fn config_file_override() {
+    let page = get(
+        &["--config", "config/agate.conf", "--lang", "en"],
+        "gemini://localhost",
+    )
+    .expect("could not get page");
+
     assert_eq!(page.status, Status::Success.value());
-    assert_eq!(page.meta, "text/plain;lang=nl");
+    assert_eq!(page.meta, "text/gemini;lang=en");
 }
 
 #[test]
-/// - double globs (i.e. `**`) work correctly in the configuration file
-/// - central configuration file is used when `-C` flag is used
-fn doubleglob() {
-    let page = get(&["-C"], "gemini://localhost/testdir/a.nl.gmi").expect("could not get page");
+/// - flags set in the configuration file can be unset on the command line
+fn config_file_flag_override() {
+    let page = get(
+        &["--config", "config/serve-secret.conf"],
+        "gemini://localhost/.meta",
+    )
+    .expect("could not get page");
     assert_eq!(page.status, Status::Success.value());
-    assert_eq!(page.meta, "text/gemini;lang=nl");
-}
 
-#[test]
-/// - full header lines can be set in the configuration file
-fn full_header_preset() {
-    let page = get(&[], "gemini://localhost/gone.txt").expect("could not get page");
+    let page = get(
+        &[
+            "--config",
+            "config/serve-secret.conf",
+            "--serve-secret=false",
+        ],
+        "gemini://localhost/.meta",
+    )
+    .expect("could not get page");
     assert_eq!(page.status, Status::Gone.value());
-    assert_eq!(page.meta, "This file is no longer available.");
 }
 
 #[test]
-/// - URLS with fragments are rejected
-fn fragment() {
+/// - index file names are tried in the given order
+fn index_file_names() {
     let page = get(
-        &["--hostname", "example.com"],
-        "gemini://example.com/#fragment",
+        &["--index", "nonexistent.gmi", "--index", "test.gmi"],
+        "gemini://localhost/",
     )
     .expect("could not get page");
 
-    assert_eq!(page.status, Status::BadRequest.value());
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/content/test.gmi"));
 }
 
 #[test]
-/// - URLS with username are rejected
-fn username() {
-    let page = get(&["--hostname", "example.com"], "gemini://user@example.com/")
-        .expect("could not get page");
+/// - requests are written to the access log
+fn access_log() {
+    let log = std::env::temp_dir().join("agate-test-access-log");
+    let _ = std::fs::remove_file(&log);
 
-    assert_eq!(page.status, Status::BadRequest.value());
+    get(
+        &["--access-log", log.to_str().unwrap()],
+        "gemini://localhost/",
+    )
+    .expect("could not get page");
+
+    let log = std::fs::read_to_string(log).unwrap();
+    let fields = log.trim_end().split(' ').collect::<Vec<_>>();
+    // IP addresses are not logged by default
+    assert_eq!(fields[..3], ["-", "-", "-"]);
+    assert!(fields[3].starts_with('[') && fields[4].ends_with(']'));
+    assert_eq!(fields[5], "\"gemini://localhost/\"");
+    assert_eq!(fields[6], "20");
+    assert_eq!(
+        fields[7],
+        (b"20 text/gemini\r\n".len() + include_bytes!("data/content/index.gmi").len()).to_string()
+    );
+    assert_eq!(fields[8], "\"localhost\"");
+    assert!(fields[9].parse::<u64>().is_ok());
 }
 
 #[test]
-/// - URLS with invalid hostnames are rejected
-fn percent_encode() {
-    // Can't use `get` here because we are testing a URL thats invalid so
-    // the gemini fetching library can not process it.
-    let mut server = Server::new(&["--certs", "multicert"]);
-
-    let mut certs = RootCertStore::empty();
-    certs
-        .add(CertificateDer::from(
-            include_bytes!("data/multicert/example.com/cert.der").as_slice(),
-        ))
-        .unwrap();
-    let config = rustls::ClientConfig::builder()
-        .with_root_certificates(certs)
-        .with_no_client_auth();
+/// - the access log is written as JSON lines with the JSON log format
+fn access_log_json() {
+    let log = std::env::temp_dir().join("agate-test-access-log-json");
+    let _ = std::fs::remove_file(&log);
 
-    let mut session = ClientConnection::new(
-        std::sync::Arc::new(config),
-        "example.com".try_into().unwrap(),
+    get(
+        &[
+            "--access-log",
+            log.to_str().unwrap(),
+            "--log-format",
+            "json",
+            "--log-ip",
+        ],
+        "gemini://localhost/",
     )
-    .unwrap();
-    let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
-    let mut tls = rustls::Stream::new(&mut session, &mut tcp);
-
-    write!(tls, "gemini://%/\r\n").unwrap();
-
-    let mut buf = [0; 10];
-    let _ = tls.read(&mut buf);
-
-    assert_eq!(&buf[0..2], b"59");
+    .expect("could not get page");
 
-    server.stop().unwrap();
+    let log = std::fs::read_to_string(log).unwrap();
+    assert!(log.starts_with("{\"time\":\"") && log.ends_with("}\n"));
+    assert!(log.contains(r#","ip":"127.0.0.1","host":"localhost","path":"/","request":"gemini://localhost/","status":20,"size":"#));
+    assert!(log.contains(r#","sni":"localhost","tls_version":"TLSv1.3"}"#));
 }
 
 #[test]
-/// - URLS with password are rejected
-fn password() {
-    let page = get(
-        &["--hostname", "example.com"],
-        "gemini://:secret@example.com/",
+/// - the TLS secrets are written to the key log file
+fn key_log_file() {
+    let log = std::env::temp_dir().join("agate-test-key-log");
+    let _ = std::fs::remove_file(&log);
+
+    get(
+        &["--key-log-file", log.to_str().unwrap()],
+        "gemini://localhost/",
     )
     .expect("could not get page");
 
-    assert_eq!(page.status, Status::BadRequest.value());
+    let log = std::fs::read_to_string(log).unwrap();
+    let line = log
+        .lines()
+        .find(|line| line.starts_with("CLIENT_TRAFFIC_SECRET_0 "))
+        .unwrap();
+    let fields = line.split(' ').collect::<Vec<_>>();
+    // the client random and the secret
+    assert_eq!(fields.len(), 3);
+    assert_eq!(fields[1].len(), 64);
+    assert!(fields[2].bytes().all(|c| c.is_ascii_hexdigit()));
 }
 
 #[test]
-/// - hostname is checked when provided
-/// - status for wrong host is "proxy request refused"
-fn hostname_check() {
-    let page =
-        get(&["--hostname", "example.org"], "gemini://example.com/").expect("could not get page");
+/// - the IPv4 and IPv6 unspecified addresses can be used with the same port
+fn dual_stack() {
+    let port = free_port();
+    let mut server = Server::new(&[
+        "--addr",
+        &format!("[::]:{port}"),
+        "--addr",
+        &format!("0.0.0.0:{port}"),
+    ]);
 
-    assert_eq!(page.status, Status::ProxyRequestRefused.value());
+    // the server reports that it started after the first listener is bound
+    let connect = |ip: &str| {
+        (0..100).any(|_| {
+            TcpStream::connect((ip, port)).is_ok() || {
+                sleep(Duration::from_millis(10));
+                false
+            }
+        })
+    };
+    assert!(connect("127.0.0.1"), "could not connect with IPv4");
+    assert!(connect("::1"), "could not connect with IPv6");
+
+    server.stop().unwrap();
 }
 
+#[cfg(unix)]
 #[test]
-/// - port is checked when hostname is provided
-/// - status for wrong port is "proxy request refused"
-fn port_check() {
-    let page =
-        get(&["--hostname", "example.org"], "gemini://example.org:1/").expect("could not get page");
+#[should_panic]
+/// - Agate does not start if the user to switch to does not exist
+fn unknown_user() {
+    let mut server = Server::new(&["--user", "agate-test-nonexistent-user"]);
 
-    assert_eq!(page.status, Status::ProxyRequestRefused.value());
+    // wait for the server to stop, it should crash
+    let _ = server.server.wait();
 }
 
+#[cfg(unix)]
 #[test]
-/// - port is not checked if the skip option is passed.
-fn port_check_skipped() {
-    let page = get(
-        &["--hostname", "example.org", "--skip-port-check"],
-        "gemini://example.org:1/",
-    )
-    .expect("could not get page");
+/// - systemd is notified when Agate is ready
+/// - the systemd watchdog is pinged
+fn systemd_notify() {
+    let path = std::env::temp_dir().join("agate-test-notify-socket");
+    let _ = std::fs::remove_file(&path);
+    let socket = std::os::unix::net::UnixDatagram::bind(&path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
 
-    assert_eq!(page.status, Status::Success.value());
-}
+    let mut server = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args(["--addr", &format!("127.0.0.1:{}", free_port())])
+        .env("NOTIFY_SOCKET", &path)
+        .env("WATCHDOG_USEC", "100000")
+        .spawn()
+        .expect("failed to start binary");
 
-#[test]
-/// - status for paths with hidden segments is "gone" if file does not exist
-fn secret_nonexistent() {
-    let page = get(&[], "gemini://localhost/.non-existing-secret").expect("could not get page");
+    let mut buf = [0; 64];
+    let len = socket.recv(&mut buf);
+    let ready = buf[..*len.as_ref().unwrap_or(&0)].to_vec();
+    let len = socket.recv(&mut buf);
+    let watchdog = buf[..*len.as_ref().unwrap_or(&0)].to_vec();
+    server.kill().unwrap();
+    server.wait().unwrap();
 
-    assert_eq!(page.status, Status::Gone.value());
+    assert_eq!(ready, b"READY=1");
+    assert_eq!(watchdog, b"WATCHDOG=1");
 }
 
+#[cfg(unix)]
 #[test]
-/// - status for paths with hidden segments is "gone" if file exists
-fn secret_exists() {
-    let page = get(&[], "gemini://localhost/.meta").expect("could not get page");
+/// - Agate forks into the background and writes a PID file
+/// - log messages are appended to the log file
+/// - the PID file is removed when stopped with SIGTERM
+fn daemon() {
+    let pid_file = std::env::temp_dir().join("agate-test-daemon-pid");
+    let log = std::env::temp_dir().join("agate-test-daemon-log");
+    let _ = std::fs::remove_file(&pid_file);
+    let _ = std::fs::remove_file(&log);
+    let port = free_port();
 
-    assert_eq!(page.status, Status::Gone.value());
-}
+    let status = Command::new(BINARY_PATH)
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .args(["--addr", &format!("127.0.0.1:{port}"), "--daemon"])
+        .args(["--pidfile", pid_file.to_str().unwrap()])
+        .args(["--log-file", log.to_str().unwrap()])
+        .status()
+        .expect("failed to start binary");
+    assert!(status.success());
 
-#[test]
-/// - secret file served if `--serve-secret` is enabled
-fn serve_secret() {
-    let page = get(&["--serve-secret"], "gemini://localhost/.meta").expect("could not get page");
+    // the PID file is written by the process in the background
+    let pid = loop {
+        if let Ok(pid) = std::fs::read_to_string(&pid_file) {
+            if pid.ends_with('\n') {
+                break pid.trim().to_string();
+            }
+        }
+        sleep(Duration::from_millis(10));
+    };
 
+    let url = Url::parse("gemini://localhost/").unwrap();
+    let actor = Actor::default().proxy("localhost".into(), port);
+    let page = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(actor.get(url))
+        .expect("could not get page");
     assert_eq!(page.status, Status::Success.value());
+
+    let status = Command::new("kill").args(["-TERM", &pid]).status().unwrap();
+    assert!(status.success());
+    for _ in 0..500 {
+        if !pid_file.exists() {
+            break;
+        }
+        sleep(Duration::from_millis(10));
+    }
+    assert!(!pid_file.exists());
+    assert!(std::fs::read_to_string(&log)
+        .unwrap()
+        .contains("Started listener on"));
 }
 
 #[test]
-/// - secret file served if path is in sidecar
-fn serve_secret_meta_config() {
-    let page = get(&[], "gemini://localhost/.servable-secret").expect("could not get page");
+#[cfg(unix)]
+/// - the server keeps running when it runs out of file descriptors
+/// - idle connections are closed so new requests can be served
+fn too_many_open_files() {
+    let port = free_port();
+    let mut server = Command::new("sh")
+        .args(["-c", "ulimit -n 64 && exec \"$0\" \"$@\"", BINARY_PATH])
+        .args(["--addr", &format!("127.0.0.1:{port}")])
+        .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start binary");
+    let mut reader = BufReader::new(server.stderr.take().unwrap());
+    let mut line = String::new();
+    while !line.contains("Started") {
+        line.clear();
+        assert!(
+            reader.read_line(&mut line).unwrap() > 0,
+            "server did not start"
+        );
+    }
 
+    // connections that never send a request
+    let idle = (0..70)
+        .map(|_| TcpStream::connect(("127.0.0.1", port)).unwrap())
+        .collect::<Vec<_>>();
+
+    let url = Url::parse("gemini://localhost/").unwrap();
+    let actor = Actor::default().proxy("localhost".into(), port);
+    let page = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(actor.get(url))
+        .expect("could not get page");
     assert_eq!(page.status, Status::Success.value());
+    assert!(matches!(server.try_wait(), Ok(None)));
+
+    drop(idle);
+    server.kill().unwrap();
+    let mut log = String::new();
+    reader.read_to_string(&mut log).unwrap();
+    assert!(log.contains("idle connections"));
 }
 
 #[test]
-/// - secret file served if path with subdir is in sidecar
-fn serve_secret_meta_config_subdir() {
-    let page =
-        get(&["-C"], "gemini://localhost/.well-known/servable-secret").expect("could not get page");
+/// - paths are redirected temporarily by default
+/// - prefixes are redirected with the rest of the path and the given status
+/// - absolute target URLs are used as they are
+fn redirects() {
+    let mut server = Server::new(&["--redirects", "redirects.conf"]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = |url: &str| {
+        runtime
+            .block_on(actor.get(Url::parse(url).unwrap()))
+            .expect("could not get page")
+    };
+
+    let page = get("gemini://localhost/old.gmi");
+    assert_eq!(page.status, Status::RedirectTemporary.value());
+    assert_eq!(page.meta, "gemini://localhost/index.gmi");
+
+    let page = get("gemini://localhost/archive/a/b.gmi");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://localhost/testdir/a/b.gmi");
 
+    let page = get("gemini://localhost/elsewhere");
+    assert_eq!(page.status, Status::RedirectTemporary.value());
+    assert_eq!(page.meta, "gemini://example.org/");
+
+    let page = get("gemini://localhost/");
     assert_eq!(page.status, Status::Success.value());
+    server.stop().unwrap();
 }
 
 #[test]
-/// - directory traversal attacks using percent-encoded path separators
-///   fail (this addresses a previous vulnerability)
-fn directory_traversal_regression() {
-    let base = Url::parse("gemini://localhost/").unwrap();
+/// - paths with a gone prefix are answered with status 52 and the message
+/// - the default message is used without one
+/// - prefixes are matched against the decoded, normalized path
+fn gone() {
+    let args = [
+        "--gone",
+        "/testdir/=This directory was removed.",
+        "--gone",
+        "/old",
+    ];
 
-    let mut absolute = base.clone();
-    absolute
-        .path_segments_mut()
-        .unwrap()
-        .push(env!("CARGO_MANIFEST_DIR")) // separators will be percent-encoded
-        .push("tests")
-        .push("data")
-        .push("directory_traversal.gmi");
+    let page = get(&args, "gemini://localhost/testdir/").expect("could not get page");
+    assert_eq!(page.status, Status::Gone.value());
+    assert_eq!(page.meta, "This directory was removed.");
 
-    let mut relative_escape_path = PathBuf::new();
-    relative_escape_path.push("testdir");
-    relative_escape_path.push("..");
-    relative_escape_path.push("..");
-    let mut relative = base;
-    relative
-        .path_segments_mut()
-        .unwrap()
-        .push(relative_escape_path.to_str().unwrap()) // separators will be percent-encoded
-        .push("directory_traversal.gmi");
+    let page = get(&args, "gemini://localhost/old/page.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Gone.value());
+    assert_eq!(page.meta, "Gone.");
 
-    let urls = [absolute, relative];
-    for url in urls.iter() {
-        let page = get(&[], url.as_str()).expect("could not get page");
-        assert_eq!(page.status, Status::NotFound.value());
+    // the prefix can not be avoided by percent-encoding or adding slashes
+    for url in [
+        "gemini://localhost/%74estdir/a.gmi",
+        "gemini://localhost//testdir/a.gmi",
+    ] {
+        let page = get(&args, url).expect("could not get page");
+        assert_eq!(page.status, Status::Gone.value(), "{url}");
     }
+
+    let page = get(&args, "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
 }
 
 #[test]
-/// - if TLSv1.3 is selected, does not accept TLSv1.2 connections
-///   (lower versions do not have to be tested because rustls does not even
-///   support them, making agate incapable of accepting them)
-fn explicit_tls_version() {
-    let server = Server::new(&["-3"]);
+/// - requests are answered with status 41 while the maintenance file exists
+/// - the first line of the file is the message
+/// - maintenance mode is turned on and off without a restart
+fn maintenance_mode() {
+    let file = std::env::temp_dir().join("agate-test-maintenance");
+    let _ = std::fs::remove_file(&file);
 
-    // try to connect using only TLS 1.2
-    let config = rustls::ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS12])
-        .with_root_certificates(RootCertStore::empty())
-        .with_no_client_auth();
+    let mut server = Server::new(&["--maintenance-file", file.to_str().unwrap()]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = || {
+        runtime
+            .block_on(actor.get(Url::parse("gemini://localhost/").unwrap()))
+            .expect("could not get page")
+    };
 
-    let mut session =
-        ClientConnection::new(std::sync::Arc::new(config), "localhost".try_into().unwrap())
-            .unwrap();
-    let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
-    let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+    assert_eq!(get().status, Status::Success.value());
 
-    let mut buf = [0; 10];
-    assert_eq!(
-        *tls.read(&mut buf)
-            .unwrap_err()
+    std::fs::write(&file, "Moving to a new server\n").unwrap();
+    sleep(Duration::from_millis(1500));
+    let page = get();
+    assert_eq!(page.status, Status::ServerUnavailable.value());
+    assert_eq!(page.meta, "Moving to a new server");
+
+    std::fs::remove_file(&file).unwrap();
+    sleep(Duration::from_millis(1500));
+    assert_eq!(get().status, Status::Success.value());
+    server.stop().unwrap();
+}
+
+#[test]
+/// - a status file applies to its directory and everything in it
+/// - other directories are not affected
+fn status_file() {
+    let content = std::env::temp_dir().join("agate-test-status-file");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir_all(content.join("retired")).unwrap();
+    std::fs::create_dir_all(content.join("moved")).unwrap();
+    std::fs::write(content.join("index.gmi"), "# Hello\n").unwrap();
+    std::fs::write(content.join("retired/page.gmi"), "# Old page\n").unwrap();
+    std::fs::write(content.join("retired/.status"), "52 This was removed.\n").unwrap();
+    std::fs::write(content.join("moved/.status"), "31 gemini://example.org/\n").unwrap();
+    let args = ["--content", content.to_str().unwrap()];
+
+    let page = get(&args, "gemini://localhost/retired/page.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Gone.value());
+    assert_eq!(page.meta, "This was removed.");
+
+    let page = get(&args, "gemini://localhost/retired/does/not/exist").expect("could not get page");
+    assert_eq!(page.status, Status::Gone.value());
+
+    let page = get(&args, "gemini://localhost/moved/").expect("could not get page");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://example.org/");
+
+    let page = get(&args, "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - directory configuration files apply to their directory and everything
+///   in it, settings in subdirectories take precedence
+/// - the language, charset and MIME types can be set
+/// - directory listings can be enabled
+/// - access can be denied or require a client certificate
+/// - invalid configuration files are not ignored
+/// - comments at the end of a line are ignored
+fn directory_configuration() {
+    let content = std::env::temp_dir().join("agate-test-directory-configuration");
+    let _ = std::fs::remove_dir_all(&content);
+    for dir in ["list", "private", "members", "broken"] {
+        std::fs::create_dir_all(content.join(dir)).unwrap();
+    }
+    std::fs::write(content.join("index.gmi"), "# Hallo\n").unwrap();
+    std::fs::write(content.join(".agate.ini"), "lang = de # German\n").unwrap();
+    std::fs::write(content.join("list/notes.txt"), "notes\n").unwrap();
+    std::fs::write(
+        content.join("list/.agate.ini"),
+        "charset = utf-8\ndir-listing = true\n\n[mime]\ntxt = text/markdown # notes are Markdown\n",
+    )
+    .unwrap();
+    std::fs::write(content.join("private/page.gmi"), "# Private\n").unwrap();
+    std::fs::write(content.join("private/.agate.ini"), "access = deny\n").unwrap();
+    std::fs::write(content.join("members/page.gmi"), "# Members\n").unwrap();
+    std::fs::write(content.join("members/.agate.ini"), "access = certificate\n").unwrap();
+    std::fs::write(content.join("broken/page.gmi"), "# Broken\n").unwrap();
+    std::fs::write(content.join("broken/.agate.ini"), "access = maybe\n").unwrap();
+    let args = ["--content", content.to_str().unwrap()];
+
+    let page = get(&args, "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.meta, "text/gemini;lang=de");
+
+    let page = get(&args, "gemini://localhost/list/notes.txt").expect("could not get page");
+    assert_eq!(page.meta, "text/markdown;charset=utf-8;lang=de");
+
+    let page = get(&args, "gemini://localhost/list/").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+
+    let page = get(&args, "gemini://localhost/private/page.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/members/page.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::ClientCertificateRequired.value());
+
+    let page = get(&args, "gemini://localhost/broken/page.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::TemporaryFailure.value());
+}
+
+#[test]
+/// - directories that require a certificate are served with one, even if no
+///   client certificate areas are given
+fn directory_configuration_certificate() {
+    let content = std::env::temp_dir().join("agate-test-directory-certificate");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir_all(content.join("members")).unwrap();
+    std::fs::write(content.join("members/page.gmi"), "# Members\n").unwrap();
+    std::fs::write(content.join("members/.agate.ini"), "access = certificate\n").unwrap();
+    let server = Server::new(&[
+        "--certs",
+        "multicert",
+        "--content",
+        content.to_str().unwrap(),
+    ]);
+
+    let mut certs = RootCertStore::empty();
+    certs
+        .add(CertificateDer::from(
+            include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+        ))
+        .unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(certs)
+        .with_client_auth_cert(
+            vec![CertificateDer::from(
+                include_bytes!("data/multicert/example.org/cert.der").as_slice(),
+            )],
+            rustls::pki_types::PrivateKeyDer::Pkcs1(rustls::pki_types::PrivatePkcs1KeyDer::from(
+                include_bytes!("data/multicert/example.org/key.der").as_slice(),
+            )),
+        )
+        .unwrap();
+    let mut session = ClientConnection::new(
+        std::sync::Arc::new(config),
+        "example.com".try_into().unwrap(),
+    )
+    .unwrap();
+    let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+    let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+    tls.write_all(b"gemini://example.com/members/page.gmi\r\n")
+        .unwrap();
+    let mut header = String::new();
+    BufReader::new(tls).read_line(&mut header).unwrap();
+    assert_eq!(header, "20 text/gemini\r\n");
+}
+
+#[test]
+/// - files below an alias prefix are served from the alias directory
+/// - the alias prefix has to match whole path segments
+/// - other paths are still served from the content directory
+fn alias() {
+    let args = ["--alias", "/music/=symlinked_dir"];
+
+    let page = get(&args, "gemini://localhost/music/file.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/symlinked_dir/file.gmi"));
+
+    let page = get(&args, "gemini://localhost/music").expect("could not get page");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://localhost/music/");
+
+    let page = get(&args, "gemini://localhost/musicbox/file.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+}
+
+#[test]
+/// - files are served from tar, compressed tar and zip archives mounted at a
+///   URL path prefix
+/// - directories redirect to a trailing slash and serve their index file
+/// - members that do not exist are not found
+fn archives() {
+    let args = [
+        "--archive",
+        "/tar/=archive/docs.tar",
+        "--archive",
+        "/tgz=archive/docs.tar.gz",
+        "--archive",
+        "/zip=archive/docs.zip",
+    ];
+    let guide = (0..200)
+        .map(|i| format!("Step {i}: read the guide again.\n"))
+        .collect::<String>();
+
+    for prefix in ["/tar/docs", "/tgz/docs", "/zip"] {
+        let page = get(&args, &format!("gemini://localhost{prefix}/")).expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, b"# Documentation\n\n=> guide/ Guide\n");
+
+        let page =
+            get(&args, &format!("gemini://localhost{prefix}/guide")).expect("could not get page");
+        assert_eq!(page.status, Status::RedirectPermanent.value());
+        assert_eq!(page.meta, format!("gemini://localhost{prefix}/guide/"));
+
+        let page =
+            get(&args, &format!("gemini://localhost{prefix}/guide/")).expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, format!("# Guide\n\n{guide}").as_bytes());
+
+        let page = get(&args, &format!("gemini://localhost{prefix}/notes.txt"))
+            .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/plain");
+        assert_eq!(page.content, b"plain notes\n");
+
+        let page = get(&args, &format!("gemini://localhost{prefix}/missing.gmi"))
+            .expect("could not get page");
+        assert_eq!(page.status, Status::NotFound.value());
+    }
+}
+
+#[test]
+/// - files that do not exist in the content directory are served from the
+///   first content layer that contains them
+/// - files in the content directory take precedence
+fn content_layers() {
+    let base = std::env::temp_dir().join("agate-test-content-layers");
+    let _ = std::fs::remove_dir_all(&base);
+    std::fs::create_dir_all(base.join("theme/assets")).unwrap();
+    std::fs::create_dir_all(base.join("shared")).unwrap();
+    std::fs::write(base.join("theme/index.gmi"), "# Theme\n").unwrap();
+    std::fs::write(base.join("theme/assets/style.gmi"), "# Theme style\n").unwrap();
+    std::fs::write(base.join("shared/assets.gmi"), "# Shared\n").unwrap();
+    let theme = base.join("theme");
+    let shared = base.join("shared");
+    let args = [
+        "--content-layer",
+        theme.to_str().unwrap(),
+        "--content-layer",
+        shared.to_str().unwrap(),
+    ];
+
+    let page = get(&args, "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+
+    let page = get(&args, "gemini://localhost/assets/style.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, b"# Theme style\n");
+
+    let page = get(&args, "gemini://localhost/assets.gmi").expect("could not get page");
+    assert_eq!(page.content, b"# Shared\n");
+
+    let page = get(&args, "gemini://localhost/missing.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+}
+
+#[test]
+/// - URL paths starting with ~USER are served from the user's directory
+/// - users without a directory and invalid user names are not found
+fn user_dirs() {
+    let home = std::env::temp_dir().join("agate-test-user-dirs");
+    let _ = std::fs::remove_dir_all(&home);
+    std::fs::create_dir_all(home.join("alice/public_gemini")).unwrap();
+    std::fs::write(home.join("alice/public_gemini/index.gmi"), "# Alice\n").unwrap();
+    let pattern = home.join("*/public_gemini");
+    let args = ["--user-dirs", pattern.to_str().unwrap()];
+
+    let page = get(&args, "gemini://localhost/~alice/").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, b"# Alice\n");
+
+    let page = get(&args, "gemini://localhost/~alice").expect("could not get page");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://localhost/~alice/");
+
+    let page = get(&args, "gemini://localhost/~bob/").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/~.alice/").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+}
+
+#[test]
+/// - matching paths are rewritten internally with the groups of the pattern
+/// - rules are applied in order until a rule with the last flag
+/// - the redirect and permanent flags send redirects
+/// - paths that do not match are not rewritten
+fn rewrite() {
+    let mut server = Server::new(&[
+        "--rewrite",
+        r"^/old/(\w+)\.gmi$ /$1.gmi",
+        "--rewrite",
+        "^/one$ /two",
+        "--rewrite",
+        "^/two$ /test.gmi last",
+        "--rewrite",
+        "^/test.gmi$ /nonexistent.gmi",
+        "--rewrite",
+        r"^/(?:a|b)/x(\d{2,3})$ /numbers/$1 redirect",
+        "--rewrite",
+        "^/go/([^/]*)/?(.*) gemini://$1/$2 permanent",
+    ]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = |url: &str| {
+        runtime
+            .block_on(actor.get(Url::parse(url).unwrap()))
+            .expect("could not get page")
+    };
+
+    let page = get("gemini://localhost/old/index.gmi");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+
+    let page = get("gemini://localhost/one");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/content/test.gmi"));
+
+    let page = get("gemini://localhost/b/x123");
+    assert_eq!(page.status, Status::RedirectTemporary.value());
+    assert_eq!(page.meta, "gemini://localhost/numbers/123");
+
+    assert_eq!(
+        get("gemini://localhost/b/x1").status,
+        Status::NotFound.value()
+    );
+    assert_eq!(
+        get("gemini://localhost/c/x12").status,
+        Status::NotFound.value()
+    );
+
+    let page = get("gemini://localhost/go/example.org/a/b");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://example.org/a/b");
+
+    assert_eq!(get("gemini://localhost/").status, Status::Success.value());
+    server.stop().unwrap();
+}
+
+#[test]
+/// - virtual hosts with their own rewrite rules do not use the global ones
+fn rewrite_vhosts() {
+    let page = get(
+        &["--config", "config/rewrite.conf"],
+        "gemini://localhost/old/index.gmi",
+    )
+    .expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+
+    let page = get(
+        &["--config", "config/rewrite.conf"],
+        "gemini://example.org/old/index.gmi",
+    )
+    .expect("could not get page");
+    assert_eq!(page.status, Status::RedirectTemporary.value());
+    assert_eq!(page.meta, "gemini://example.org/index.gmi");
+}
+
+#[test]
+/// - with `--case-insensitive`, URL paths with another case are redirected
+///   to the actual spelling of the file, keeping the query
+/// - hidden files are not revealed by the redirect
+/// - exact paths are served as usual
+fn case_insensitive() {
+    let args = ["--case-insensitive"];
+
+    let page = get(&args, "gemini://localhost/TestDir/A.GMI?q").expect("could not get page");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://localhost/testdir/a.gmi?q");
+
+    let page = get(&args, "gemini://localhost/.META").expect("could not get page");
+    assert_eq!(page.status, Status::Gone.value());
+
+    let page = get(&args, "gemini://localhost/testdir/a.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+
+    let page = get(&[], "gemini://localhost/TestDir/A.GMI").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+}
+
+#[test]
+/// - case-insensitive matching can be enabled for a virtual host
+fn case_insensitive_vhosts() {
+    let args = ["--config", "config/case-insensitive.conf"];
+
+    let page = get(&args, "gemini://localhost/INDEX.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://localhost/index.gmi");
+
+    let page = get(&args, "gemini://example.org/INDEX.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+}
+
+#[test]
+/// - variants of files that do not exist are served in the preferred language
+/// - a variant with the language before the extension is preferred over a
+///   directory named like the language
+/// - the language is added to the MIME type
+/// - files that exist are served as usual
+fn lang_variants() {
+    let server = Server::new(&[
+        "--content",
+        "variants",
+        "--lang-variant",
+        "en",
+        "--lang-variant",
+        "de",
+    ]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let request = |url| {
+        runtime
+            .block_on(actor.get(url))
+            .expect("could not get page")
+    };
+
+    let page = request("gemini://localhost/page.gmi");
+    assert_eq!(page.meta, "text/gemini;lang=en");
+    assert_eq!(page.content, b"# Page\n");
+
+    let page = request("gemini://localhost/about");
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://localhost/about/");
+    let page = request("gemini://localhost/about/");
+    assert_eq!(page.meta, "text/gemini;lang=en");
+    assert_eq!(page.content, b"# About\n");
+
+    let page = request("gemini://localhost/");
+    assert_eq!(page.meta, "text/gemini");
+    assert_eq!(page.content, b"# Variants\n");
+
+    let page = request("gemini://localhost/missing.gmi");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(
+        &["--content", "variants", "--lang-variant", "de"],
+        "gemini://localhost/page.gmi",
+    )
+    .expect("could not get page");
+    assert_eq!(page.meta, "text/gemini;lang=de");
+    assert_eq!(page.content, b"# Seite\n");
+}
+
+#[test]
+/// - virtual hosts can use another language
+fn lang_vhosts() {
+    let args = ["--config", "config/lang.conf"];
+
+    let page = get(&args, "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.meta, "text/gemini;lang=en");
+
+    let page = get(&args, "gemini://example.org/").expect("could not get page");
+    assert_eq!(page.meta, "text/gemini;lang=de");
+}
+
+#[test]
+/// - robots.txt is generated with a group for each user agent
+/// - virtual hosts can use other rules
+/// - robots.txt is not generated without rules
+fn robots() {
+    let args = ["--config", "config/robots.conf"];
+
+    let page = get(&args, "gemini://localhost/robots.txt").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/plain");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "User-agent: *\n\
+        Disallow: /private/\n\
+        \n\
+        User-agent: archiver\n\
+        Disallow: /\n\
+        \n\
+        User-agent: indexer\n\
+        Disallow: /private/\n\
+        Disallow: /cgi-bin/\n"
+    );
+
+    let page = get(&args, "gemini://example.org/robots.txt").expect("could not get page");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "User-agent: *\nDisallow: /\n"
+    );
+
+    let page = get(&[], "gemini://localhost/robots.txt").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+}
+
+#[test]
+/// - the favicon is served as favicon.txt
+/// - virtual hosts can use another favicon
+fn favicon() {
+    let args = ["--config", "config/favicon.conf"];
+
+    let page = get(&args, "gemini://localhost/favicon.txt").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/plain");
+    assert_eq!(String::from_utf8(page.content).unwrap(), "🌱\n");
+
+    let page = get(&args, "gemini://example.org/favicon.txt").expect("could not get page");
+    assert_eq!(String::from_utf8(page.content).unwrap(), "🦀\n");
+
+    let page = get(&[], "gemini://localhost/favicon.txt").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+}
+
+#[test]
+/// - the charset is added to the MIME type of text files
+/// - a charset from a .meta file takes precedence
+/// - virtual hosts can use another charset
+fn charset() {
+    let args = ["--config", "config/charset.conf"];
+
+    let page = get(&args, "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.meta, "text/gemini;charset=iso-8859-1");
+
+    let page = get(&args, "gemini://localhost/test.gmi").expect("could not get page");
+    assert_eq!(page.meta, "text/gemini;lang=en ;charset=us-ascii");
+
+    let page = get(&args, "gemini://example.org/").expect("could not get page");
+    assert_eq!(page.meta, "text/gemini;charset=koi8-r");
+
+    let page = get(
+        &["--charset", "iso-8859-1", "--lang", "en"],
+        "gemini://localhost/",
+    )
+    .expect("could not get page");
+    assert_eq!(page.meta, "text/gemini;charset=iso-8859-1;lang=en");
+}
+
+#[test]
+/// - TCP options do not prevent serving files
+fn tcp_options() {
+    let page = get(
+        &[
+            "--tcp-nodelay",
+            "--tcp-keepalive",
+            "30",
+            "--send-buffer-size",
+            "100000",
+            "--recv-buffer-size",
+            "50000",
+            "--listen-backlog",
+            "16",
+        ],
+        "gemini://localhost/",
+    )
+    .expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+}
+
+#[test]
+/// - the number of threads can be set
+fn threads() {
+    let page = get(
+        &["--worker-threads", "1", "--blocking-threads", "1"],
+        "gemini://localhost/",
+    )
+    .expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - files larger than the buffer size are sent completely
+fn file_buffer_size() {
+    let content = std::env::temp_dir().join("agate-test-file-buffer-size");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    let data = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(content.join("data.bin"), &data).unwrap();
+
+    let page = get(
+        &[
+            "--content",
+            content.to_str().unwrap(),
+            "--file-buffer-size",
+            "1000",
+        ],
+        "gemini://localhost/data.bin",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, data);
+}
+
+#[test]
+/// - responses are sent no faster than the bandwidth limit of their prefix
+/// - the prefix is matched against the percent-decoded path
+fn bandwidth_limit() {
+    let content = std::env::temp_dir().join("agate-test-bandwidth-limit");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    let data = (0..30_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(content.join("data.bin"), &data).unwrap();
+
+    let mut server = Server::new(&[
+        "--content",
+        content.to_str().unwrap(),
+        "--bandwidth",
+        "1000000",
+        "--bandwidth-limit",
+        "/data=10000",
+    ]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let start = std::time::Instant::now();
+    let page = tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(actor.get(Url::parse("gemini://localhost/%64ata.bin").unwrap()))
+        .expect("could not get page");
+    let elapsed = start.elapsed();
+    server.stop().unwrap();
+
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, data);
+    // one second worth of data is sent right away
+    assert!(elapsed >= std::time::Duration::from_millis(1900));
+    assert!(elapsed < std::time::Duration::from_secs(5));
+}
+
+#[test]
+/// - files larger than the maximum response size are refused
+/// - smaller files are still served
+fn max_response_size() {
+    let content = std::env::temp_dir().join("agate-test-max-response-size");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    std::fs::write(content.join("large.bin"), [0u8; 2000]).unwrap();
+    std::fs::write(content.join("small.bin"), [0u8; 1000]).unwrap();
+    let args = [
+        "--content",
+        content.to_str().unwrap(),
+        "--max-response-size",
+        "1000",
+    ];
+
+    let page = get(&args, "gemini://localhost/large.bin").expect("could not get page");
+    assert_eq!(page.status, Status::BadRequest.value());
+
+    let page = get(&args, "gemini://localhost/small.bin").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content.len(), 1000);
+}
+
+#[test]
+/// - files larger than the maximum response size are truncated
+fn truncate_large_responses() {
+    let content = std::env::temp_dir().join("agate-test-truncate-large-responses");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    let data = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    std::fs::write(content.join("large.bin"), &data).unwrap();
+
+    let page = get(
+        &[
+            "--content",
+            content.to_str().unwrap(),
+            "--max-response-size",
+            "100000",
+            "--truncate-large-responses",
+        ],
+        "gemini://localhost/large.bin",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, data[..100_000]);
+}
+
+#[test]
+/// - files are served from the cache
+/// - modified files are read again
+fn file_cache() {
+    let content = std::env::temp_dir().join("agate-test-file-cache");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir(&content).unwrap();
+    std::fs::write(content.join("index.gmi"), "# Hello\n").unwrap();
+
+    let server = Server::new(&[
+        "--content",
+        content.to_str().unwrap(),
+        "--cache-size",
+        "1000",
+    ]);
+    let get = || {
+        let url = Url::parse("gemini://localhost/").unwrap();
+        let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(actor.get(url))
+            .expect("could not get page")
+    };
+
+    for _ in 0..2 {
+        let page = get();
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, b"# Hello\n");
+    }
+
+    std::fs::write(content.join("index.gmi"), "# Hello again\n").unwrap();
+    assert_eq!(get().content, b"# Hello again\n");
+}
+
+#[test]
+/// - requests are counted in the metrics for Prometheus
+fn metrics() {
+    let port = free_port();
+    let mut server = Server::new(&["--metrics", &format!("127.0.0.1:{port}")]);
+
+    let url = Url::parse("gemini://localhost/").unwrap();
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(actor.get(url))
+        .expect("could not get page");
+
+    // the metrics listener might start after the Gemini listener
+    let mut tcp = loop {
+        if let Ok(tcp) = TcpStream::connect(("127.0.0.1", port)) {
+            break tcp;
+        }
+        sleep(Duration::from_millis(10));
+    };
+    tcp.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    tcp.read_to_string(&mut response).unwrap();
+    server.stop().unwrap();
+
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(response.contains("\nagate_requests_total{host=\"localhost\",status=\"20\"} 1\n"));
+    assert!(response.contains("\nagate_request_duration_seconds_count 1\n"));
+    assert!(response.contains("\nagate_tls_handshake_failures_total 0\n"));
+    // the expiry date of tests/data/.certificates/cert.der
+    assert!(
+        response.contains("\nagate_certificate_expiry_timestamp_seconds{domain=\"\"} 1920205916\n")
+    );
+}
+
+#[test]
+/// - resolved URLs are cached
+/// - cache hits and misses are counted in the metrics
+fn stat_cache() {
+    let port = free_port();
+    let mut server = Server::new(&[
+        "--metrics",
+        &format!("127.0.0.1:{port}"),
+        "--stat-cache-ttl",
+        "60",
+    ]);
+
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for _ in 0..2 {
+        let page = runtime
+            .block_on(actor.get(Url::parse("gemini://localhost/").unwrap()))
+            .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+    }
+
+    let mut tcp = loop {
+        if let Ok(tcp) = TcpStream::connect(("127.0.0.1", port)) {
+            break tcp;
+        }
+        sleep(Duration::from_millis(10));
+    };
+    tcp.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .unwrap();
+    let mut response = String::new();
+    tcp.read_to_string(&mut response).unwrap();
+    server.stop().unwrap();
+
+    assert!(response.contains("\nagate_stat_cache_lookups_total{result=\"hit\"} 1\n"));
+    assert!(response.contains("\nagate_stat_cache_lookups_total{result=\"miss\"} 1\n"));
+}
+
+#[test]
+/// - access settings are checked again for cached URLs
+fn stat_cache_access() {
+    let content = std::env::temp_dir().join("agate-test-stat-cache");
+    let _ = std::fs::remove_dir_all(&content);
+    std::fs::create_dir_all(content.join("private")).unwrap();
+    std::fs::write(content.join("private/index.gmi"), "secret\n").unwrap();
+
+    let mut server = Server::new(&[
+        "--content",
+        content.to_str().unwrap(),
+        "--stat-cache-ttl",
+        "60",
+    ]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = || {
+        runtime
+            .block_on(actor.get(Url::parse("gemini://localhost/private/").unwrap()))
+            .expect("could not get page")
+    };
+
+    assert_eq!(get().status, Status::Success.value());
+    std::fs::write(content.join("private/.agate.ini"), "access = deny\n").unwrap();
+    assert_eq!(get().status, Status::NotFound.value());
+    std::fs::remove_file(content.join("private/.agate.ini")).unwrap();
+    std::fs::write(content.join(".status"), "44 Come back later.\n").unwrap();
+    let page = get();
+    assert_eq!(page.status, Status::SlowDown.value());
+    assert_eq!(page.meta, "Come back later.");
+
+    server.stop().unwrap();
+    let _ = std::fs::remove_dir_all(&content);
+}
+
+#[test]
+/// - clients that exceed the rate limit get status 44
+/// - exempt addresses are not limited
+fn rate_limit() {
+    fn statuses(args: &[&str]) -> Vec<(u8, String)> {
+        let server = Server::new(args);
+        let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        (0..3)
+            .map(|_| {
+                let page = runtime
+                    .block_on(actor.get(Url::parse("gemini://localhost/").unwrap()))
+                    .expect("could not get page");
+                (page.status, page.meta)
+            })
+            .collect()
+    }
+
+    let limited = statuses(&["--rate-limit", "0.5", "--rate-limit-burst", "2"]);
+    assert_eq!(limited[0].0, Status::Success.value());
+    assert_eq!(limited[1].0, Status::Success.value());
+    assert_eq!(limited[2], (Status::SlowDown.value(), "2".to_string()));
+
+    let exempt = statuses(&[
+        "--rate-limit",
+        "0.5",
+        "--rate-limit-burst",
+        "2",
+        "--rate-limit-exempt",
+        "127.0.0.0/8",
+    ]);
+    assert!(exempt
+        .iter()
+        .all(|(status, _)| *status == Status::Success.value()));
+}
+
+#[test]
+/// - connections over the limit per IP address are closed
+fn max_connections_per_ip() {
+    let server = Server::new(&["--max-connections-per-ip", "1"]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let url = Url::parse("gemini://localhost/").unwrap();
+
+    // keep a connection open without sending anything
+    let idle = TcpStream::connect(server.addr).unwrap();
+    sleep(Duration::from_millis(100));
+    assert!(runtime.block_on(actor.get(url.clone())).is_err());
+
+    drop(idle);
+    sleep(Duration::from_millis(100));
+    let page = runtime
+        .block_on(actor.get(url))
+        .expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - connections without a TLS handshake are closed after the time limit
+fn handshake_timeout() {
+    let server = Server::new(&["--handshake-timeout", "1"]);
+
+    let mut tcp = TcpStream::connect(server.addr).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let start = std::time::Instant::now();
+    // the connection is closed without any data
+    assert_eq!(tcp.read(&mut [0; 16]).unwrap(), 0);
+    assert!(start.elapsed() < Duration::from_secs(5));
+}
+
+#[test]
+/// - directories without trailing slash are redirected
+fn directory_redirect() {
+    let page = get(&[], "gemini://localhost/testdir").expect("could not get page");
+
+    assert_eq!(page.status, Status::RedirectPermanent.value());
+    assert_eq!(page.meta, "gemini://localhost/testdir/");
+}
+
+#[test]
+/// - symlinked files are followed correctly
+fn symlink_page() {
+    let page = get(&[], "gemini://localhost/symlink.gmi").expect("could not get page");
+
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini");
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// - several sockets can listen on the same address
+fn acceptors() {
+    let server = Server::new(&["--acceptors", "4"]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    for _ in 0..4 {
+        let page = runtime
+            .block_on(actor.get(Url::parse("gemini://localhost/").unwrap()))
+            .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+    }
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+/// - the sandbox does not prevent serving files
+/// - symlinks outside of the content directory are not followed
+fn sandbox() {
+    let page = get(&["--sandbox"], "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+
+    let page = get(&["--sandbox"], "gemini://localhost/symlinked_dir/file.gmi")
+        .expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+}
+
+#[test]
+/// - symlinked directories are followed correctly
+fn symlink_directory() {
+    let page = get(&[], "gemini://localhost/symlinked_dir/file.gmi").expect("could not get page");
+
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini");
+    assert_eq!(page.content, include_bytes!("data/symlinked_dir/file.gmi"));
+}
+
+#[test]
+/// - with `--symlinks inside`, symlinks are only followed if the target is
+///   inside the content directory
+fn symlinks_inside() {
+    let args = ["--symlinks", "inside"];
+
+    let page = get(&args, "gemini://localhost/symlink.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+
+    let page = get(&args, "gemini://localhost/symlinked_dir/file.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+}
+
+#[test]
+/// - with `--symlinks never`, symlinks are not followed
+fn symlinks_never() {
+    let args = ["--symlinks", "never"];
+
+    let page = get(&args, "gemini://localhost/symlink.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/symlinked_dir/").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/index.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - the `--addr` configuration works
+/// - MIME media types can be set in the configuration file
+fn meta() {
+    let page = get(&[], "gemini://localhost/test").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/html");
+}
+
+#[test]
+/// - MIME type is correctly guessed for `.gmi` files
+/// - MIME media type parameters can be set in the configuration file
+fn meta_param() {
+    let page = get(&[], "gemini://localhost/test.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini;lang=en ;charset=us-ascii");
+}
+
+#[test]
+/// - `.gemini` files are served as text/gemini
+fn mime_builtin() {
+    let page = get(&[], "gemini://localhost/example.gemini").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini");
+}
+
+#[test]
+/// - templates are rendered with the variables of the request
+/// - control characters in values are replaced, so they can not add lines
+/// - templates are only rendered if enabled
+fn templates() {
+    let server = Server::new(&[
+        "--certs",
+        "multicert",
+        "--content",
+        "templates",
+        "--templates",
+        "--client-cert-area",
+        "/private/",
+    ]);
+
+    let mut certs = RootCertStore::empty();
+    certs
+        .add(CertificateDer::from(
+            include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+        ))
+        .unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(certs)
+        .with_client_auth_cert(
+            vec![CertificateDer::from(
+                include_bytes!("data/multicert/example.org/cert.der").as_slice(),
+            )],
+            rustls::pki_types::PrivateKeyDer::Pkcs1(rustls::pki_types::PrivatePkcs1KeyDer::from(
+                include_bytes!("data/multicert/example.org/key.der").as_slice(),
+            )),
+        )
+        .unwrap();
+    let mut session = ClientConnection::new(
+        std::sync::Arc::new(config),
+        "example.com".try_into().unwrap(),
+    )
+    .unwrap();
+    let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+    let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+    tls.write_all(b"gemini://example.com/page.gmi.tera?caf%C3%A9%0A=%3E%20evil\r\n")
+        .unwrap();
+    let mut response = String::new();
+    tls.read_to_string(&mut response).unwrap();
+    assert_eq!(
+        response,
+        "20 text/gemini\r\n\
+        # Hello example.org\n\
+        => gemini://example.com/page.gmi.tera Reload\n\
+        You searched for: café => evil\n\
+        Unknown: \n"
+    );
+
+    let page = get(
+        &["--content", "templates"],
+        "gemini://localhost/page.gmi.tera",
+    )
+    .expect("could not get page");
+    assert_eq!(page.content, include_bytes!("data/templates/page.gmi.tera"));
+}
+
+#[test]
+/// - include directives are replaced with the included files
+/// - included files can include further files relative to their directory
+/// - files outside the content directory and recursive includes are left out
+/// - include directives are only processed if enabled
+fn includes() {
+    let page = get(
+        &["--content", "includes", "--includes"],
+        "gemini://localhost/",
+    )
+    .expect("could not get page");
+    assert_eq!(
+        page.content,
+        b"# Home\n=> / Home\n=> /sub/ More\nText\nLoop\n"
+    );
+
+    let page = get(
+        &["--content", "includes", "--includes"],
+        "gemini://localhost/sub/page.gmi",
+    )
+    .expect("could not get page");
+    assert_eq!(page.content, b"# Page\n=> / Home\n=> /sub/ More\n");
+
+    let page = get(&["--content", "includes"], "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.content, include_bytes!("data/includes/index.gmi"));
+}
+
+#[test]
+/// - Markdown files are converted to gemtext
+/// - Markdown files are only converted if enabled
+fn markdown() {
+    let page = get(
+        &["--content", "markdown", "--markdown"],
+        "gemini://localhost/page.md",
+    )
+    .expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Title\n\
+        \n\
+        Some *text* with a link that continues, and an image.\n\
+        => gemini://example.com/ link\n\
+        => /image.png image\n\
+        \n\
+        ## Lists\n\
+        \n\
+        * first\n\
+        * second with a reference continued\n\
+        1. numbered\n\
+        => /ref.gmi a reference\n\
+        \n\
+        > quoted\n\
+        \n\
+        ```rust\n\
+        # not a heading\n\
+        [not](a link)\n\
+        ```\n\
+        \n\
+        => / Home\n\
+        \n\
+        https://example.org/\n\
+        => https://example.org/\n"
+    );
+
+    let page =
+        get(&["--content", "markdown"], "gemini://localhost/page.md").expect("could not get page");
+    assert_eq!(page.meta, "text/markdown");
+    assert_eq!(page.content, include_bytes!("data/markdown/page.md"));
+}
+
+#[test]
+/// - Atom feeds list the posts in a directory with their first heading
+/// - posts are sorted by the date at the start of their file name
+/// - feeds are RSS feeds if their file name contains rss
+/// - feeds are gemtext subscription pages if their path ends in .gmi
+fn feed() {
+    let args = [
+        "--content",
+        "feed",
+        "--feed",
+        "/feed.xml=/gemlog",
+        "--feed",
+        "/gemlog/rss.xml=/gemlog/",
+        "--feed",
+        "/gemlog/feed.gmi=/gemlog/",
+    ];
+    let page = get(&args, "gemini://localhost/feed.xml").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "application/atom+xml");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <id>gemini://localhost/gemlog/</id>
+  <title>My gemlog</title>
+  <updated>2024-01-31T00:00:00Z</updated>
+  <author><name>localhost</name></author>
+  <link href="gemini://localhost/gemlog/"/>
+  <link rel="self" href="gemini://localhost/feed.xml"/>
+  <entry>
+    <id>gemini://localhost/gemlog/2024-01-31-hello.gmi</id>
+    <title>Hello &amp; welcome</title>
+    <updated>2024-01-31T00:00:00Z</updated>
+    <link href="gemini://localhost/gemlog/2024-01-31-hello.gmi"/>
+  </entry>
+  <entry>
+    <id>gemini://localhost/gemlog/2023-12-24-xmas.gmi</id>
+    <title>Christmas</title>
+    <updated>2023-12-24T00:00:00Z</updated>
+    <link href="gemini://localhost/gemlog/2023-12-24-xmas.gmi"/>
+  </entry>
+</feed>
+"#
+    );
+
+    let page = get(&args, "gemini://localhost/gemlog/rss.xml").expect("could not get page");
+    assert_eq!(page.meta, "application/rss+xml");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        r#"<?xml version="1.0" encoding="utf-8"?>
+<rss version="2.0">
+<channel>
+  <title>My gemlog</title>
+  <link>gemini://localhost/gemlog/</link>
+  <description>My gemlog</description>
+  <lastBuildDate>Wed, 31 Jan 2024 00:00:00 +0000</lastBuildDate>
+  <item>
+    <title>Hello &amp; welcome</title>
+    <link>gemini://localhost/gemlog/2024-01-31-hello.gmi</link>
+    <guid>gemini://localhost/gemlog/2024-01-31-hello.gmi</guid>
+    <pubDate>Wed, 31 Jan 2024 00:00:00 +0000</pubDate>
+  </item>
+  <item>
+    <title>Christmas</title>
+    <link>gemini://localhost/gemlog/2023-12-24-xmas.gmi</link>
+    <guid>gemini://localhost/gemlog/2023-12-24-xmas.gmi</guid>
+    <pubDate>Sun, 24 Dec 2023 00:00:00 +0000</pubDate>
+  </item>
+</channel>
+</rss>
+"#
+    );
+
+    let page = get(&args, "gemini://localhost/gemlog/feed.gmi").expect("could not get page");
+    assert_eq!(page.meta, "text/gemini");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# My gemlog\n\
+        \n\
+        => gemini://localhost/gemlog/2024-01-31-hello.gmi 2024-01-31 - Hello & welcome\n\
+        => gemini://localhost/gemlog/2023-12-24-xmas.gmi 2023-12-24 - Christmas\n"
+    );
+}
+
+#[test]
+/// - the index of a blog lists the posts with the title and date from their
+///   front matter, newest first, after the index file
+/// - tag and archive pages list the posts with a tag or from a year
+/// - the feed of a blog is generated
+/// - posts are served without their front matter and drafts not at all
+/// - drafts are not in the site map
+fn blog() {
+    let args = [
+        "--content",
+        "blog",
+        "--blog",
+        "/gemlog",
+        "--sitemap",
+        "/sitemap.gmi",
+    ];
+    let page = get(&args, "gemini://localhost/gemlog/").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# My gemlog\n\
+        \n\
+        Thoughts.\n\
+        \n\
+        => /gemlog/hello.gmi 2024-01-31 - Hello world\n\
+        => /gemlog/old.gmi 2023-12-24 - Christmas\n\
+        => /gemlog/2022-05-01-plain.gmi 2022-05-01 - Plain post\n\
+        \n\
+        => /gemlog/tags/ Tags\n\
+        => /gemlog/archive/ Archive\n\
+        => /gemlog/atom.xml Atom feed\n"
+    );
+
+    let page = get(&args, "gemini://localhost/gemlog/tags/").expect("could not get page");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Tags of My gemlog\n\
+        \n\
+        => /gemlog/tags/gemini gemini (2)\n\
+        => /gemlog/tags/rust rust (1)\n"
+    );
+
+    let page = get(&args, "gemini://localhost/gemlog/tags/Rust").expect("could not get page");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Posts tagged rust\n\
+        \n\
+        => /gemlog/hello.gmi 2024-01-31 - Hello world\n"
+    );
+
+    let page = get(&args, "gemini://localhost/gemlog/tags/none").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/gemlog/archive/").expect("could not get page");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Archive of My gemlog\n\
+        \n\
+        => /gemlog/archive/2024 2024 (1)\n\
+        => /gemlog/archive/2023 2023 (1)\n\
+        => /gemlog/archive/2022 2022 (1)\n"
+    );
+
+    let page = get(&args, "gemini://localhost/gemlog/archive/2023").expect("could not get page");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Posts from 2023\n\
+        \n\
+        => /gemlog/old.gmi 2023-12-24 - Christmas\n"
+    );
+
+    let page = get(&args, "gemini://localhost/gemlog/atom.xml").expect("could not get page");
+    assert_eq!(page.meta, "application/atom+xml");
+    let feed = String::from_utf8(page.content).unwrap();
+    assert!(feed.contains("<title>Hello world</title>"));
+    assert!(feed.contains("<link rel=\"self\" href=\"gemini://localhost/gemlog/atom.xml\"/>"));
+    assert!(!feed.contains("Unfinished"));
+
+    let page = get(&args, "gemini://localhost/gemlog/hello.gmi").expect("could not get page");
+    assert_eq!(page.content, b"# Hello\n\nFirst post.\n");
+
+    let page = get(&args, "gemini://localhost/gemlog/draft.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/sitemap.gmi").expect("could not get page");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# localhost\n\
+        \n\
+        => /gemlog/ My gemlog\n\
+        => /gemlog/2022-05-01-plain.gmi Plain post\n\
+        => /gemlog/hello.gmi Hello world\n\
+        => /gemlog/old.gmi Christmas\n"
+    );
+}
+
+#[test]
+/// - site maps list the gemtext documents with their first heading
+/// - index files are listed with the URL of their directory
+/// - hidden files and directories that require a certificate are left out
+/// - site maps list the URLs of the documents if their path ends in .txt
+fn sitemap() {
+    let args = [
+        "--content",
+        "sitemap",
+        "--sitemap",
+        "/sitemap.gmi",
+        "--sitemap",
+        "/sitemap.txt",
+    ];
+    let page = get(&args, "gemini://localhost/sitemap.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# localhost\n\
+        \n\
+        => / Home\n\
+        => /about.gmi About me\n\
+        => /sub/ Sub\n\
+        => /with%20space.gmi /with%20space.gmi\n"
+    );
+
+    let page = get(&args, "gemini://localhost/sitemap.txt").expect("could not get page");
+    assert_eq!(page.meta, "text/plain");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "gemini://localhost/\n\
+        gemini://localhost/about.gmi\n\
+        gemini://localhost/sub/\n\
+        gemini://localhost/with%20space.gmi\n"
+    );
+}
+
+#[test]
+/// - files that are not served to clients without a certificate are left out
+///   of site maps, like client certificate areas, gone paths and directories
+///   with a status file
+fn sitemap_access() {
+    let args = [
+        "--content",
+        "sitemap",
+        "--sitemap",
+        "/sitemap.gmi",
+        "--client-cert-area",
+        "/about.gmi",
+        "--gone",
+        "/with space.gmi",
+    ];
+    let page = get(&args, "gemini://localhost/sitemap.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# localhost\n\
+        \n\
+        => / Home\n\
+        => /sub/ Sub\n"
+    );
+}
+
+#[test]
+/// - the search asks for a query
+/// - results contain all words of the query, the most relevant first
+/// - gemtext and plain text files are searched, but not hidden files or the
+///   URLs of links
+fn search() {
+    let args = ["--content", "search", "--search", "/search"];
+    let page = get(&args, "gemini://localhost/search").expect("could not get page");
+    assert_eq!(page.status, Status::Input.value());
+    assert_eq!(page.meta, "Search");
+
+    let page = get(&args, "gemini://localhost/search?Tomatoes").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Search results for \"Tomatoes\"\n\
+        \n\
+        => /garden.gmi Gardening\n\
+        => /recipes.txt /recipes.txt\n\
+        \n\
+        => /search New search\n"
+    );
+
+    let page = get(&args, "gemini://localhost/search?water%20sun").expect("could not get page");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Search results for \"water sun\"\n\
+        \n\
+        => /garden.gmi Gardening\n\
+        \n\
+        => /search New search\n"
+    );
+
+    let page = get(&args, "gemini://localhost/search?example").expect("could not get page");
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Search results for \"example\"\n\
+        \n\
+        No results.\n\
+        \n\
+        => /search New search\n"
+    );
+}
+
+#[test]
+/// - files clients can not get are not found by the search
+fn search_access() {
+    let args = [
+        "--content",
+        "search",
+        "--search",
+        "/search",
+        "--client-cert-area",
+        "/garden.gmi",
+        "--gone",
+        "/recipes.txt",
+    ];
+    let page = get(&args, "gemini://localhost/search?Tomatoes").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(
+        String::from_utf8(page.content).unwrap(),
+        "# Search results for \"Tomatoes\"\n\
+        \n\
+        No results.\n\
+        \n\
+        => /search New search\n"
+    );
+}
+
+#[test]
+/// - header and footer files are added to gemtext files
+/// - header and footer files in subdirectories replace those of the parent
+/// - other files are served as they are
+fn header_footer() {
+    let page = get(&["--content", "snippets"], "gemini://localhost/").expect("could not get page");
+    assert_eq!(page.content, b"=> / Home\n# Index\n-- footer\n");
+
+    let page = get(
+        &["--content", "snippets"],
+        "gemini://localhost/sub/page.gmi",
+    )
+    .expect("could not get page");
+    assert_eq!(page.content, b"=> / Home\n# Page\n-- sub footer\n");
+
+    let page =
+        get(&["--content", "snippets"], "gemini://localhost/file.txt").expect("could not get page");
+    assert_eq!(page.content, b"text\n");
+}
+
+#[test]
+/// - the MIME type of files with an unknown extension is guessed from their
+///   content
+fn mime_sniffing() {
+    let page =
+        get(&["--content", "sniff"], "gemini://localhost/picture").expect("could not get page");
+    assert_eq!(page.meta, "image/png");
+
+    let page =
+        get(&["--content", "sniff"], "gemini://localhost/notes").expect("could not get page");
+    assert_eq!(page.meta, "text/plain");
+
+    let page = get(
+        &["--content", "sniff"],
+        "gemini://localhost/data.unknownext",
+    )
+    .expect("could not get page");
+    assert_eq!(page.meta, "application/octet-stream");
+}
+
+#[test]
+/// - MIME types from a mime.types file take precedence
+fn mime_types_file() {
+    let page = get(
+        &["--mime-types", "mime.types"],
+        "gemini://localhost/example.gemini",
+    )
+    .expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/plain");
+}
+
+#[test]
+/// - globs in the configuration file work correctly
+/// - distributed configuration file is used when `-C` flag not used
+fn glob() {
+    let page = get(&[], "gemini://localhost/testdir/a.nl.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/plain;lang=nl");
+}
+
+#[test]
+/// - double globs (i.e. `**`) work correctly in the configuration file
+/// - central configuration file is used when `-C` flag is used
+fn doubleglob() {
+    let page = get(&["-C"], "gemini://localhost/testdir/a.nl.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.meta, "text/gemini;lang=nl");
+}
+
+#[test]
+/// - full header lines can be set in the configuration file
+fn full_header_preset() {
+    let page = get(&[], "gemini://localhost/gone.txt").expect("could not get page");
+    assert_eq!(page.status, Status::Gone.value());
+    assert_eq!(page.meta, "This file is no longer available.");
+}
+
+#[test]
+/// - URLS with fragments are rejected
+fn fragment() {
+    let page = get(
+        &["--hostname", "example.com"],
+        "gemini://example.com/#fragment",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.status, Status::BadRequest.value());
+}
+
+#[test]
+/// - URLS with username are rejected
+fn username() {
+    let page = get(&["--hostname", "example.com"], "gemini://user@example.com/")
+        .expect("could not get page");
+
+    assert_eq!(page.status, Status::BadRequest.value());
+}
+
+#[test]
+/// - URLS with invalid hostnames are rejected
+fn percent_encode() {
+    // Can't use `get` here because we are testing a URL thats invalid so
+    // the gemini fetching library can not process it.
+    let mut server = Server::new(&["--certs", "multicert"]);
+
+    let mut certs = RootCertStore::empty();
+    certs
+        .add(CertificateDer::from(
+            include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+        ))
+        .unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_root_certificates(certs)
+        .with_no_client_auth();
+
+    let mut session = ClientConnection::new(
+        std::sync::Arc::new(config),
+        "example.com".try_into().unwrap(),
+    )
+    .unwrap();
+    let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+    let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+    write!(tls, "gemini://%/\r\n").unwrap();
+
+    let mut buf = [0; 10];
+    let _ = tls.read(&mut buf);
+
+    assert_eq!(&buf[0..2], b"59");
+
+    server.stop().unwrap();
+}
+
+#[test]
+/// - requests longer than 1024 bytes are rejected
+/// - requests have to end with CRLF, not just LF or the end of the stream
+/// - there must not be data after CRLF
+/// - requests that are not valid UTF-8 are answered with status 59
+/// - URLs with userinfo, even if it is empty, or a fragment are rejected
+/// - the URL has to be absolute and must not contain spaces, control
+///   characters or a byte order mark
+fn request_line() {
+    let server = Server::new(&["--certs", "multicert"]);
+
+    let mut certs = RootCertStore::empty();
+    certs
+        .add(CertificateDer::from(
+            include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+        ))
+        .unwrap();
+    let config = std::sync::Arc::new(
+        rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_no_client_auth(),
+    );
+    let request = |request: &[u8], close: bool| {
+        let mut session =
+            ClientConnection::new(config.clone(), "example.com".try_into().unwrap()).unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        tcp.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        tls.write_all(request).unwrap();
+        if close {
+            tls.conn.send_close_notify();
+            tls.flush().unwrap();
+        }
+        let mut header = String::new();
+        BufReader::new(tls).read_line(&mut header).unwrap();
+        header
+    };
+
+    let url = format!("gemini://example.com/?{}", "a".repeat(1024 - 22));
+    assert_eq!(url.len(), 1024);
+    assert_eq!(
+        request(format!("{url}\r\n").as_bytes(), false),
+        "20 text/gemini\r\n"
+    );
+    assert_eq!(
+        request(format!("{url}a\r\n").as_bytes(), false),
+        "59 Request too long\r\n"
+    );
+    assert_eq!(
+        request(b"gemini://example.com/\n", false),
+        "59 Request does not end with CRLF\r\n"
+    );
+    assert_eq!(
+        request(b"gemini://example.com/", true),
+        "59 Request ended unexpectedly\r\n"
+    );
+    assert_eq!(
+        request(b"gemini://example.com/\r\nmore", false),
+        "59 Request contains data after the URL\r\n"
+    );
+    // an invalid byte, an overlong encoding of "/" and an encoded surrogate
+    for invalid in [&b"\xff"[..], b"\xc0\xaf", b"\xed\xa0\x80"] {
+        assert_eq!(
+            request(
+                &[b"gemini://example.com/", invalid, b"\r\n"].concat(),
+                false
+            ),
+            "59 Non-UTF-8 request\r\n"
+        );
+    }
+    for invalid in [
+        "gemini://@example.com/",
+        "gemini://:@example.com/",
+        "gemini://u%3Ap@example.com/",
+        "gemini://example.com@example.com/",
+        "gemini://example.com/#",
+        "gemini://example.com/?q#f",
+    ] {
+        assert_eq!(
+            request(format!("{invalid}\r\n").as_bytes(), false),
+            "59 URL contains fragment or userinfo\r\n",
+            "{invalid:?}"
+        );
+    }
+    // encoded characters are not structural
+    assert_eq!(
+        request(b"gemini://example.com/%23f@x\r\n", false),
+        "51 Not found, sorry.\r\n"
+    );
+    for invalid in [
+        "gemini://user%40example.com/",
+        "",
+        "/",
+        "//example.com/",
+        " gemini://example.com/",
+        "gemini://example.com/ ",
+        "gemini://example.com/a b",
+        "gemini://example.com/\t",
+        "gemini://example.com/\n",
+        "\u{feff}gemini://example.com/",
+    ] {
+        assert_eq!(
+            request(format!("{invalid}\r\n").as_bytes(), false),
+            "59 Invalid URL\r\n",
+            "{invalid:?}"
+        );
+    }
+}
+
+#[test]
+/// - paths that are not valid UTF-8 after percent-decoding are rejected
+fn non_utf8_path() {
+    let page = get(&[], "gemini://localhost/%FF.gmi").expect("could not get page");
+
+    assert_eq!(page.status, Status::BadRequest.value());
+}
+
+#[test]
+/// - URLS with password are rejected
+fn password() {
+    let page = get(
+        &["--hostname", "example.com"],
+        "gemini://:secret@example.com/",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.status, Status::BadRequest.value());
+}
+
+#[test]
+/// - hostname is checked when provided
+/// - status for wrong host is "proxy request refused"
+fn hostname_check() {
+    let page =
+        get(&["--hostname", "example.org"], "gemini://example.com/").expect("could not get page");
+
+    assert_eq!(page.status, Status::ProxyRequestRefused.value());
+}
+
+#[test]
+/// - port is checked when hostname is provided
+/// - status for wrong port is "proxy request refused"
+fn port_check() {
+    let page =
+        get(&["--hostname", "example.org"], "gemini://example.org:1/").expect("could not get page");
+
+    assert_eq!(page.status, Status::ProxyRequestRefused.value());
+}
+
+#[test]
+/// - port is not checked if the skip option is passed.
+fn port_check_skipped() {
+    let page = get(
+        &["--hostname", "example.org", "--skip-port-check"],
+        "gemini://example.org:1/",
+    )
+    .expect("could not get page");
+
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - status for paths with hidden segments is "gone" if file does not exist
+fn secret_nonexistent() {
+    let page = get(&[], "gemini://localhost/.non-existing-secret").expect("could not get page");
+
+    assert_eq!(page.status, Status::Gone.value());
+}
+
+#[test]
+/// - status for paths with hidden segments is "gone" if file exists
+fn secret_exists() {
+    let page = get(&[], "gemini://localhost/.meta").expect("could not get page");
+
+    assert_eq!(page.status, Status::Gone.value());
+}
+
+#[test]
+/// - secret file served if `--serve-secret` is enabled
+fn serve_secret() {
+    let page = get(&["--serve-secret"], "gemini://localhost/.meta").expect("could not get page");
+
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - secret file served if path is in sidecar
+fn serve_secret_meta_config() {
+    let page = get(&[], "gemini://localhost/.servable-secret").expect("could not get page");
+
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - secret file served if path with subdir is in sidecar
+fn serve_secret_meta_config_subdir() {
+    let page =
+        get(&["-C"], "gemini://localhost/.well-known/servable-secret").expect("could not get page");
+
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - status for paths with percent-encoded hidden segments is "gone"
+fn secret_percent_encoded() {
+    let page = get(&[], "gemini://localhost/%2Emeta").expect("could not get page");
+
+    assert_eq!(page.status, Status::Gone.value());
+}
+
+#[test]
+/// - hidden files are "not found" with `--hidden-files deny`, even if the
+///   path is in the sidecar
+fn hidden_files_deny() {
+    let args = ["--hidden-files", "deny"];
+
+    let page = get(&args, "gemini://localhost/.servable-secret").expect("could not get page");
+    assert_eq!(page.status, Status::NotFound.value());
+
+    let page = get(&args, "gemini://localhost/test.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - files and directories matching a `--hide` pattern are hidden
+fn hide_patterns() {
+    let args = ["--hide", "*.gemini", "--hide", "testdir/"];
+
+    let page = get(&args, "gemini://localhost/example.gemini").expect("could not get page");
+    assert_eq!(page.status, Status::Gone.value());
+
+    let page = get(&args, "gemini://localhost/testdir/a.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Gone.value());
+
+    let page = get(&args, "gemini://localhost/index.gmi").expect("could not get page");
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - directory traversal attacks using percent-encoded path separators
+///   fail (this addresses a previous vulnerability)
+fn directory_traversal_regression() {
+    let base = Url::parse("gemini://localhost/").unwrap();
+
+    let mut absolute = base.clone();
+    absolute
+        .path_segments_mut()
+        .unwrap()
+        .push(env!("CARGO_MANIFEST_DIR")) // separators will be percent-encoded
+        .push("tests")
+        .push("data")
+        .push("directory_traversal.gmi");
+
+    let mut relative_escape_path = PathBuf::new();
+    relative_escape_path.push("testdir");
+    relative_escape_path.push("..");
+    relative_escape_path.push("..");
+    let mut relative = base;
+    relative
+        .path_segments_mut()
+        .unwrap()
+        .push(relative_escape_path.to_str().unwrap()) // separators will be percent-encoded
+        .push("directory_traversal.gmi");
+
+    let urls = [absolute, relative];
+    for url in urls.iter() {
+        let page = get(&[], url.as_str()).expect("could not get page");
+        assert_eq!(page.status, Status::NotFound.value());
+    }
+}
+
+#[test]
+/// - known directory traversal payloads do not leave the content directory
+/// - control characters and invalid UTF-8 in the path are rejected
+/// - dot segments are collapsed before accessing the file system
+fn directory_traversal_payloads() {
+    let server = Server::new(&[]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = |path: &str| {
+        runtime
+            .block_on(actor.get(Url::parse(&format!("gemini://localhost{path}")).unwrap()))
+            .expect("could not get page")
+    };
+
+    let not_found = [
+        "/../../Cargo.toml",
+        "/%2e%2e/%2e%2e/Cargo.toml",
+        "/.%2e/.%2e/Cargo.toml",
+        "/..%2f..%2fCargo.toml",
+        "/%2e%2e%2f%2e%2e%2fCargo.toml",
+        "/testdir/..%2F..%2F..%2Ftests%2Fdata%2Fdirectory_traversal.gmi",
+        "/%2Fetc%2Fpasswd",
+        "/testdir%5c..%5c..%5cCargo.toml",
+        "/symlinked_dir/..%2F..%2Fdirectory_traversal.gmi",
+    ];
+    for path in not_found {
+        let page = get(path);
+        assert_eq!(page.status, Status::NotFound.value(), "{path}");
+    }
+
+    // segments starting with a dot are hidden, but Windows does not even
+    // allow backslashes or trailing dots in file names
+    let gone = ["/..%5c..%5cCargo.toml", "/....//....//Cargo.toml"];
+    for path in gone {
+        let page = get(path);
+        let expected = if cfg!(windows) {
+            Status::NotFound
+        } else {
+            Status::Gone
+        };
+        assert_eq!(page.status, expected.value(), "{path}");
+    }
+
+    let bad_request = [
+        "/index.gmi%00.txt",
+        "/%00",
+        "/index.gmi%0d%0a",
+        "/test%09dir/",
+        "/%7f",
+        "/%c0%ae%c0%ae/Cargo.toml",
+    ];
+    for path in bad_request {
+        let page = get(path);
+        assert_eq!(page.status, Status::BadRequest.value(), "{path}");
+    }
+
+    let page = get("/testdir/../index.gmi");
+    assert_eq!(page.status, Status::Success.value());
+    assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+}
+
+#[test]
+#[cfg(windows)]
+/// - alternate data streams, device names and names that Windows changes
+///   are not found
+fn windows_file_names() {
+    let server = Server::new(&[]);
+    let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let get = |path: &str| {
+        runtime
+            .block_on(actor.get(Url::parse(&format!("gemini://localhost{path}")).unwrap()))
+            .expect("could not get page")
+    };
+
+    for path in [
+        "/index.gmi::$DATA",
+        "/index.gmi:stream",
+        "/index.gmi.",
+        "/index.gmi%20",
+        "/NUL",
+        "/nul.gmi",
+        "/con%20.txt",
+        "/testdir/COM1",
+        "/LPT9.gmi",
+    ] {
+        let page = get(path);
+        assert_eq!(page.status, Status::NotFound.value(), "{path}");
+    }
+
+    let page = get("/index.gmi");
+    assert_eq!(page.status, Status::Success.value());
+}
+
+#[test]
+/// - if TLSv1.3 is selected, does not accept TLSv1.2 connections
+///   (lower versions do not have to be tested because rustls does not even
+///   support them, making agate incapable of accepting them)
+fn explicit_tls_version() {
+    let server = Server::new(&["-3"]);
+
+    // try to connect using only TLS 1.2
+    let config = rustls::ClientConfig::builder_with_protocol_versions(&[&rustls::version::TLS12])
+        .with_root_certificates(RootCertStore::empty())
+        .with_no_client_auth();
+
+    let mut session =
+        ClientConnection::new(std::sync::Arc::new(config), "localhost".try_into().unwrap())
+            .unwrap();
+    let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+    let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+    let mut buf = [0; 10];
+    assert_eq!(
+        *tls.read(&mut buf)
+            .unwrap_err()
             .into_inner()
             .unwrap()
-            .downcast::<rustls::Error>()
-            .unwrap(),
-        rustls::Error::AlertReceived(rustls::AlertDescription::ProtocolVersion)
-    )
+            .downcast::<rustls::Error>()
+            .unwrap(),
+        rustls::Error::AlertReceived(rustls::AlertDescription::ProtocolVersion)
+    )
+}
+
+mod cgi {
+    use super::*;
+
+    #[test]
+    /// - executable .cgi files are run with CGI environment variables
+    #[cfg(unix)]
+    fn script() {
+        let page = get(
+            &["--content", "cgi", "--cgi"],
+            "gemini://localhost/hello.cgi/extra/path?query",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/plain");
+        assert_eq!(page.content, b"/hello.cgi\n/extra/path\nquery\n");
+    }
+
+    #[test]
+    /// - executable files below a CGI path are run as scripts
+    /// - CGI paths match whole path segments
+    /// - CGI paths can not be avoided by percent-encoding the path or adding
+    ///   slashes
+    #[cfg(unix)]
+    fn cgi_path() {
+        for url in [
+            "gemini://localhost/bin/run",
+            "gemini://localhost/%62in/run",
+            "gemini://localhost//bin/run",
+        ] {
+            let page =
+                get(&["--content", "cgi", "--cgi-path", "/bin"], url).expect("could not get page");
+            assert_eq!(page.status, Status::Success.value(), "{url}");
+            assert_eq!(page.content, b"ran\n", "{url}");
+        }
+
+        let page = get(
+            &["--content", "cgi", "--cgi-path", "/bin"],
+            "gemini://localhost/bin-public/run",
+        )
+        .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, include_bytes!("data/cgi/bin-public/run"));
+    }
+
+    #[test]
+    /// - input prompts from .meta files are sent for requests without a query
+    /// - with a query, scripts are run with the decoded input
+    /// - with a query, files are served but not cached
+    #[cfg(unix)]
+    fn input() {
+        let page = get(
+            &["--content", "cgi", "--cgi"],
+            "gemini://localhost/search.cgi",
+        )
+        .expect("could not get page");
+        assert_eq!(page.status, Status::Input.value());
+        assert_eq!(page.meta, "Search for");
+
+        let page = get(
+            &["--content", "cgi", "--cgi"],
+            "gemini://localhost/search.cgi?caf%C3%A9%20au%20lait",
+        )
+        .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(
+            page.content,
+            "caf%C3%A9%20au%20lait\ncafé au lait\n".as_bytes()
+        );
+
+        let server = Server::new(&["--content", "cgi", "--stat-cache-ttl", "60"]);
+        let actor = Actor::default().proxy("localhost".into(), server.addr.port());
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let get = |url| {
+            runtime
+                .block_on(actor.get(url))
+                .expect("could not get page")
+        };
+
+        let page = get("gemini://localhost/secret.gmi?swordfish");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, b"You are in.\n");
+        let page = get("gemini://localhost/secret.gmi");
+        assert_eq!(page.status, Status::SensitiveInput.value());
+        assert_eq!(page.meta, "Password");
+    }
+
+    #[test]
+    /// - scripts that do not send any output for too long are stopped
+    #[cfg(unix)]
+    fn timeout() {
+        let page = get(
+            &["--content", "cgi", "--cgi", "--backend-timeout", "1"],
+            "gemini://localhost/slow.cgi",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::CgiError.value());
+    }
+
+    #[test]
+    /// - non-executable files are served as usual
+    fn not_executable() {
+        let page = get(
+            &["--content", "cgi", "--cgi"],
+            "gemini://localhost/plain.cgi",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, include_bytes!("data/cgi/plain.cgi"));
+    }
+
+    #[test]
+    /// - scripts are not run unless CGI is enabled
+    fn disabled() {
+        let page =
+            get(&["--content", "cgi"], "gemini://localhost/hello.cgi").expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, include_bytes!("data/cgi/hello.cgi"));
+    }
+}
+
+mod scgi {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    /// - requests are passed on to the SCGI application server
+    /// - the response is passed on to the client
+    /// - routes can not be avoided by percent-encoding the path or adding
+    ///   slashes
+    fn request() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let urls = [
+            "gemini://localhost/app/some/path",
+            "gemini://localhost/%61pp/some/path",
+            "gemini://localhost//app//some/path",
+        ];
+        let handle = std::thread::spawn(move || {
+            for _ in urls {
+                let (mut stream, _) = backend.accept().unwrap();
+                // read the netstring length
+                let mut len = String::new();
+                let mut byte = [0];
+                loop {
+                    stream.read_exact(&mut byte).unwrap();
+                    if byte[0] == b':' {
+                        break;
+                    }
+                    len.push(byte[0] as char);
+                }
+                // read the headers and trailing comma
+                let mut headers = vec![0; len.parse::<usize>().unwrap() + 1];
+                stream.read_exact(&mut headers).unwrap();
+                let headers = String::from_utf8(headers).unwrap();
+                let fields = headers.split('\0').collect::<Vec<_>>();
+                assert_eq!(fields[0..2], ["CONTENT_LENGTH", "0"]);
+                let path_info = fields
+                    .chunks(2)
+                    .find(|field| field[0] == "PATH_INFO")
+                    .unwrap()[1];
+
+                write!(stream, "20 text/plain\r\n{path_info}").unwrap();
+            }
+        });
+
+        for url in urls {
+            let page = get(&["--scgi", &format!("/app/={backend_addr}")], url)
+                .expect("could not get page");
+
+            assert_eq!(page.status, Status::Success.value(), "{url}");
+            assert_eq!(page.meta, "text/plain");
+            assert_eq!(page.content, b"/some/path", "{url}");
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    /// - application servers that do not respond for too long fail the
+    ///   request
+    fn timeout() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+            // keep the connection open without responding until it is closed
+            let _ = stream.read_to_end(&mut vec![]);
+        });
+
+        let page = get(
+            &[
+                "--scgi",
+                &format!("/app/={backend_addr}"),
+                "--backend-timeout",
+                "1",
+            ],
+            "gemini://localhost/app/",
+        )
+        .expect("could not get page");
+        handle.join().unwrap();
+
+        assert_eq!(page.status, Status::CgiError.value());
+    }
+}
+
+mod fastcgi {
+    use super::*;
+    use std::net::TcpListener;
+
+    /// Reads a FastCGI record and returns its type, request ID and content.
+    fn read_record(stream: &mut impl Read) -> (u8, u16, Vec<u8>) {
+        let mut header = [0; 8];
+        stream.read_exact(&mut header).unwrap();
+        let id = u16::from_be_bytes([header[2], header[3]]);
+        let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let mut content = vec![0; len + header[6] as usize];
+        stream.read_exact(&mut content).unwrap();
+        content.truncate(len);
+        (header[1], id, content)
+    }
+
+    fn write_record(stream: &mut impl Write, kind: u8, id: u16, content: &[u8]) {
+        let [id_high, id_low] = id.to_be_bytes();
+        stream
+            .write_all(&[1, kind, id_high, id_low, 0, content.len() as u8, 0, 0])
+            .unwrap();
+        stream.write_all(content).unwrap();
+    }
+
+    /// Reads the records of a request and returns its ID and `PATH_INFO`.
+    /// A query whether the application server can handle several requests
+    /// on one connection at once is answered with `multiplexed`.
+    fn read_request(stream: &mut (impl Read + Write), multiplexed: bool) -> (u16, String) {
+        let mut params = vec![];
+        let id = loop {
+            match read_record(stream) {
+                (9, 0, _) => {
+                    let value = if multiplexed { b'1' } else { b'0' };
+                    let mut result = vec![15, 1];
+                    result.extend(b"FCGI_MPXS_CONNS");
+                    result.push(value);
+                    write_record(stream, 10, 0, &result);
+                }
+                // begin request, the connection should be kept
+                (1, _, content) => assert_eq!(content[2] & 1, 1),
+                (4, _, content) => params.extend(content),
+                (5, id, content) if content.is_empty() => break id,
+                (kind, _, _) => panic!("unexpected record type {kind}"),
+            }
+        };
+        // all names and values in this test are shorter than 128 bytes
+        let mut path_info = String::new();
+        let mut rest = &params[..];
+        while !rest.is_empty() {
+            let (name_len, value_len) = (rest[0] as usize, rest[1] as usize);
+            let name = &rest[2..2 + name_len];
+            let value = &rest[2 + name_len..2 + name_len + value_len];
+            if name == b"PATH_INFO" {
+                path_info = String::from_utf8(value.to_vec()).unwrap();
+            }
+            rest = &rest[2 + name_len + value_len..];
+        }
+        (id, path_info)
+    }
+
+    /// Responds to a request with its `PATH_INFO` as the content.
+    fn respond(stream: &mut impl Write, id: u16, path_info: &str) {
+        write_record(
+            stream,
+            6,
+            id,
+            format!("20 text/plain\r\n{path_info}").as_bytes(),
+        );
+        write_record(stream, 6, id, &[]);
+        write_record(stream, 3, id, &[0; 8]);
+    }
+
+    fn fetch(port: u16, path: &str) -> Response {
+        let actor = Actor::default().proxy("localhost".into(), port);
+        let url = Url::parse(&format!("gemini://localhost/app{path}")).unwrap();
+        tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(actor.get(url))
+            .expect("could not get page")
+    }
+
+    #[test]
+    /// - requests are passed on to the FastCGI application server
+    /// - the response is passed on to the client
+    /// - the connection to the application server is reused
+    /// - routes can not be avoided by percent-encoding the path or adding
+    ///   slashes
+    fn request() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            // only accept a single connection
+            let (mut stream, _) = backend.accept().unwrap();
+            for _ in 0..3 {
+                let (id, path_info) = read_request(&mut stream, false);
+                respond(&mut stream, id, &path_info);
+            }
+        });
+
+        let mut server = Server::new(&["--fastcgi", &format!("/app/={backend_addr}")]);
+        for path in ["/one", "/two"] {
+            let page = fetch(server.get_addr().port(), path);
+
+            assert_eq!(page.status, Status::Success.value());
+            assert_eq!(page.meta, "text/plain");
+            assert_eq!(page.content, path.as_bytes());
+        }
+        let actor = Actor::default().proxy("localhost".into(), server.get_addr().port());
+        let url = Url::parse("gemini://localhost/%61pp//three").unwrap();
+        let page = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(actor.get(url))
+            .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.content, b"/three");
+        server.stop().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    /// - several requests are sent on one connection at once if the
+    ///   application server supports it
+    /// - responses are passed on to the right client, even if they arrive
+    ///   in a different order
+    fn multiplexing() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            // only accept a single connection
+            let (mut stream, _) = backend.accept().unwrap();
+            let (id, path_info) = read_request(&mut stream, true);
+            respond(&mut stream, id, &path_info);
+
+            // wait for both requests before answering the second one first
+            let first = read_request(&mut stream, true);
+            let second = read_request(&mut stream, true);
+            assert_ne!(first.0, second.0);
+            respond(&mut stream, second.0, &second.1);
+            respond(&mut stream, first.0, &first.1);
+        });
+
+        let mut server = Server::new(&["--fastcgi", &format!("/app/={backend_addr}")]);
+        let port = server.get_addr().port();
+        assert_eq!(fetch(port, "/one").content, b"/one");
+        let clients =
+            ["/two", "/three"].map(|path| std::thread::spawn(move || (path, fetch(port, path))));
+        for client in clients {
+            let (path, page) = client.join().unwrap();
+            assert_eq!(page.status, Status::Success.value());
+            assert_eq!(page.content, path.as_bytes());
+        }
+        server.stop().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    /// - a request is sent on a new connection if the application server
+    ///   closed the previous one
+    fn reconnect() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            for _ in 0..2 {
+                // close the connection after each request
+                let (mut stream, _) = backend.accept().unwrap();
+                let (id, path_info) = read_request(&mut stream, false);
+                respond(&mut stream, id, &path_info);
+            }
+        });
+
+        let mut server = Server::new(&["--fastcgi", &format!("/app/={backend_addr}")]);
+        for path in ["/one", "/two"] {
+            let page = fetch(server.get_addr().port(), path);
+            assert_eq!(page.status, Status::Success.value());
+            assert_eq!(page.content, path.as_bytes());
+        }
+        server.stop().unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    /// - a request the application server refuses results in a CGI error
+    fn refused() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            let (mut stream, _) = backend.accept().unwrap();
+            let (id, _) = read_request(&mut stream, false);
+            // the application server is overloaded
+            write_record(&mut stream, 3, id, &[0, 0, 0, 0, 2, 0, 0, 0]);
+        });
+
+        let mut server = Server::new(&["--fastcgi", &format!("/app/={backend_addr}")]);
+        let page = fetch(server.get_addr().port(), "/one");
+        assert_eq!(page.status, Status::CgiError.value());
+        server.stop().unwrap();
+        handle.join().unwrap();
+    }
+}
+
+mod proxy {
+    use super::*;
+
+    #[test]
+    /// - requests are forwarded to the upstream server
+    /// - the response of the upstream server is passed on to the client
+    fn forward() {
+        let upstream = Server::new(&[]);
+
+        let page = get(
+            &["--proxy", &format!("/={}", upstream.get_addr())],
+            "gemini://localhost/",
+        )
+        .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, include_bytes!("data/content/index.gmi"));
+    }
+
+    #[test]
+    /// - prefixes can not be avoided by percent-encoding the path or adding
+    ///   slashes
+    fn prefix() {
+        for url in [
+            "gemini://localhost/testdir/a.gmi",
+            "gemini://localhost/%74estdir/a.gmi",
+            "gemini://localhost//testdir/a.gmi",
+        ] {
+            let page = get(&["--proxy", "/testdir/=127.0.0.1:1"], url).expect("could not get page");
+            assert_eq!(page.status, Status::ProxyError.value(), "{url}");
+        }
+    }
+
+    #[test]
+    /// - unreachable upstream servers result in a proxy error
+    fn unreachable() {
+        let page =
+            get(&["--proxy", "/=127.0.0.1:1"], "gemini://localhost/").expect("could not get page");
+
+        assert_eq!(page.status, Status::ProxyError.value());
+    }
+}
+
+mod http_gateway {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    /// - requests are passed on to the HTTP backend with the mapped path
+    /// - chunked responses are decoded
+    /// - redirects to the backend are mapped back to Gemini URLs
+    fn request() {
+        let backend = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend_addr = backend.local_addr().unwrap();
+        let handle = std::thread::spawn(move || {
+            for response in [
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7\r\n, world\r\n0\r\n\r\n",
+                "HTTP/1.1 302 Found\r\nLocation: /v1/other?x=1\r\nContent-Length: 0\r\n\r\n",
+            ] {
+                let (stream, _) = backend.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                assert_eq!(request_line, "GET /v1/some/path?q HTTP/1.1\r\n");
+                // skip the headers
+                let mut line = String::new();
+                while line != "\r\n" {
+                    line.clear();
+                    reader.read_line(&mut line).unwrap();
+                }
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+        });
+
+        let route = format!("/api/=http://{backend_addr}/v1/");
+        let page = get(
+            &["--http-gateway", &route],
+            "gemini://localhost/api/some/path?q",
+        )
+        .expect("could not get page");
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/plain");
+        assert_eq!(page.content, b"Hello, world");
+
+        // the route can not be avoided by percent-encoding or adding slashes
+        let page = get(
+            &["--http-gateway", &route],
+            "gemini://localhost/%61pi//some/path?q",
+        )
+        .expect("could not get page");
+        assert_eq!(page.status, Status::RedirectTemporary.value());
+        assert_eq!(page.meta, "gemini://localhost/api/other?x=1");
+
+        handle.join().unwrap();
+    }
+}
+
+mod client_certs {
+    use super::*;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs1KeyDer};
+
+    #[test]
+    /// - a client certificate is required in the configured areas
+    fn required() {
+        let page =
+            get(&["--client-cert-area", "/"], "gemini://localhost/").expect("could not get page");
+
+        assert_eq!(page.status, Status::ClientCertificateRequired.value());
+    }
+
+    #[test]
+    /// - areas can not be avoided by percent-encoding the path or adding
+    ///   slashes
+    fn required_normalized() {
+        for url in [
+            "gemini://localhost/testdir/a.gmi",
+            "gemini://localhost/%74estdir/a.gmi",
+            "gemini://localhost//testdir/a.gmi",
+        ] {
+            let page = get(&["--client-cert-area", "/testdir/"], url).expect("could not get page");
+            assert_eq!(
+                page.status,
+                Status::ClientCertificateRequired.value(),
+                "{url}"
+            );
+        }
+    }
+
+    #[test]
+    /// - unknown client certificates are trusted and recorded
+    fn trust_on_first_use() {
+        let store = std::env::temp_dir().join("agate-test-tofu-store");
+        let _ = std::fs::remove_file(&store);
+
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--client-cert-area",
+            "/",
+            "--tofu-store",
+            store.to_str().unwrap(),
+        ]);
+
+        let mut certs = RootCertStore::empty();
+        certs
+            .add(CertificateDer::from(
+                include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+            ))
+            .unwrap();
+        // use the other certificate as client certificate
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_client_auth_cert(
+                vec![CertificateDer::from(
+                    include_bytes!("data/multicert/example.org/cert.der").as_slice(),
+                )],
+                PrivateKeyDer::Pkcs1(PrivatePkcs1KeyDer::from(
+                    include_bytes!("data/multicert/example.org/key.der").as_slice(),
+                )),
+            )
+            .unwrap();
+
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            "example.com".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        write!(tls, "gemini://example.com/\r\n").unwrap();
+
+        let mut buf = [0; 2];
+        tls.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"20");
+
+        server.stop().unwrap();
+
+        let store = std::fs::read_to_string(store).unwrap();
+        assert_eq!(store.lines().count(), 1);
+    }
+}
+
+mod spartan {
+    use super::*;
+
+    /// Sends a raw Spartan request to a server that listens for Spartan on
+    /// the given port and returns the response.
+    fn request(port: u16, request: &[u8]) -> Vec<u8> {
+        // the Spartan listener might start after the Gemini listener
+        let mut tcp = loop {
+            if let Ok(tcp) = TcpStream::connect(("127.0.0.1", port)) {
+                break tcp;
+            }
+            sleep(Duration::from_millis(10));
+        };
+        tcp.write_all(request).unwrap();
+        let mut response = vec![];
+        tcp.read_to_end(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    /// - content is served with the Spartan protocol
+    /// - status codes and redirects are translated
+    fn request_page() {
+        let port = free_port();
+        let mut server = Server::new(&["--spartan", &format!("127.0.0.1:{port}")]);
+
+        let response = request(port, b"localhost / 0\r\n");
+        let mut expected = b"2 text/gemini\r\n".to_vec();
+        expected.extend(include_bytes!("data/content/index.gmi"));
+        assert_eq!(response, expected);
+
+        let response = request(port, b"localhost /testdir 0\r\n");
+        assert_eq!(response, b"3 /testdir/\r\n");
+
+        let response = request(port, b"localhost /nonexistent.gmi 0\r\n");
+        assert_eq!(response, b"4 Not found, sorry.\r\n");
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - requests that are not sent completely in time are rejected
+    fn request_timeout() {
+        let port = free_port();
+        let mut server = Server::new(&[
+            "--spartan",
+            &format!("127.0.0.1:{port}"),
+            "--request-timeout",
+            "1",
+        ]);
+
+        // the request announces more data than is sent
+        let response = request(port, b"localhost / 10\r\nabc");
+        assert_eq!(response, b"4 Request timed out\r\n");
+
+        server.stop().unwrap();
+    }
+}
+
+mod gopher {
+    use super::*;
+
+    /// Sends a raw Gopher request to a server that listens for Gopher on the
+    /// given port and returns the response.
+    fn request(port: u16, selector: &str) -> String {
+        // the Gopher listener might start after the Gemini listener
+        let mut tcp = loop {
+            if let Ok(tcp) = TcpStream::connect(("127.0.0.1", port)) {
+                break tcp;
+            }
+            sleep(Duration::from_millis(10));
+        };
+        write!(tcp, "{selector}\r\n").unwrap();
+        let mut response = String::new();
+        tcp.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    /// - gemtext files are converted to menus
+    /// - directories without index file are listed as menus
+    /// - hidden files are not listed
+    fn menus() {
+        let port = free_port();
+        let mut server = Server::new(&["--gopher", &format!("127.0.0.1:{port}")]);
+
+        let response = request(port, "");
+        assert_eq!(
+            response,
+            "iThis is a test index file.\t\terror.host\t1\r\n.\r\n"
+        );
+
+        let response = request(port, "/testdir");
+        assert_eq!(
+            response,
+            format!(
+                "1a.de.gmi\t/testdir/a.de.gmi\t127.0.0.1\t{port}\r\n\
+                1a.gmi\t/testdir/a.gmi\t127.0.0.1\t{port}\r\n\
+                1a.nl.gmi\t/testdir/a.nl.gmi\t127.0.0.1\t{port}\r\n\
+                .\r\n"
+            )
+        );
+
+        let response = request(port, "/nonexistent.gmi");
+        assert_eq!(response, "3Not found, sorry.\t\terror.host\t1\r\n.\r\n");
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - menus are logged with the media type of Gopher menus
+    fn menus_log() {
+        let log = std::env::temp_dir().join("agate-test-gopher-menus-log");
+        let _ = std::fs::remove_file(&log);
+        let port = free_port();
+        let mut server = Command::new(BINARY_PATH)
+            .current_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data"))
+            .args(["--addr", &format!("127.0.0.1:{}", free_port())])
+            .args(["--gopher", &format!("127.0.0.1:{port}")])
+            .args(["--log-file", log.to_str().unwrap()])
+            .spawn()
+            .expect("failed to start binary");
+
+        // a converted gemtext file and a directory listing
+        request(port, "");
+        request(port, "/testdir");
+
+        // requests are logged after the connection is closed
+        let menus = || {
+            std::fs::read_to_string(&log)
+                .unwrap()
+                .lines()
+                .filter(|line| line.contains(" 20 \"application/gopher-menu\""))
+                .count()
+        };
+        for _ in 0..100 {
+            if menus() == 2 {
+                break;
+            }
+            sleep(Duration::from_millis(10));
+        }
+        server.kill().unwrap();
+        server.wait().unwrap();
+        assert_eq!(menus(), 2);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// - rewrites and redirects apply, redirects are sent as a menu with a link
+    /// - status files and denied directories are answered with an error item
+    /// - paths that need a client certificate, input or a CGI script are
+    ///   refused with an error item
+    fn access() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let content = std::env::temp_dir().join("agate-test-gopher-access");
+        let _ = std::fs::remove_dir_all(&content);
+        for dir in ["area", "private", "members", "closed"] {
+            std::fs::create_dir_all(content.join(dir)).unwrap();
+            std::fs::write(content.join(dir).join("page.gmi"), "# Page\n").unwrap();
+        }
+        std::fs::write(content.join("index.gmi"), "# Index\n").unwrap();
+        std::fs::write(content.join("private/.agate.ini"), "access = deny\n").unwrap();
+        std::fs::write(content.join("members/.agate.ini"), "access = certificate\n").unwrap();
+        std::fs::write(content.join("closed/.status"), "52 Closed for good.\n").unwrap();
+        std::fs::write(content.join("secret.gmi"), "# Secret\n").unwrap();
+        std::fs::write(content.join(".meta"), "secret.gmi: 11 Password\n").unwrap();
+        let script = content.join("run.cgi");
+        std::fs::write(&script, "#!/bin/sh\necho '20 text/plain\r'\necho source\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let redirects = content.join(".redirects");
+        std::fs::write(&redirects, "/moved /index.gmi\n").unwrap();
+
+        let port = free_port();
+        let mut server = Server::new(&[
+            "--content",
+            content.to_str().unwrap(),
+            "--gopher",
+            &format!("127.0.0.1:{port}"),
+            "--client-cert-area",
+            "/area/",
+            "--cgi",
+            "--rewrite",
+            "^/old$ /index.gmi",
+            "--redirects",
+            redirects.to_str().unwrap(),
+        ]);
+        let refused = "3Only available with Gemini.\t\terror.host\t1\r\n.\r\n";
+
+        assert_eq!(request(port, "/old"), "i# Index\t\terror.host\t1\r\n.\r\n");
+        assert_eq!(
+            request(port, "/moved"),
+            format!(
+                "1Moved to gopher://127.0.0.1:{port}/index.gmi\t/index.gmi\t127.0.0.1\t{port}\r\n.\r\n"
+            )
+        );
+        assert_eq!(
+            request(port, "/closed/page.gmi"),
+            "3Closed for good.\t\terror.host\t1\r\n.\r\n"
+        );
+        assert_eq!(
+            request(port, "/private/page.gmi"),
+            "3Not found, sorry.\t\terror.host\t1\r\n.\r\n"
+        );
+        assert_eq!(request(port, "/members/page.gmi"), refused);
+        assert_eq!(request(port, "/area/page.gmi"), refused);
+        assert_eq!(request(port, "/run.cgi"), refused);
+        assert_eq!(request(port, "/secret.gmi"), refused);
+        // a search query answers the prompt
+        assert_eq!(
+            request(port, "/secret.gmi\tpassword"),
+            "i# Secret\t\terror.host\t1\r\n.\r\n"
+        );
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - the client address is taken from PROXY protocol headers in version 1
+    ///   and 2
+    /// - connections without a PROXY protocol header are closed
+    fn proxy_protocol() {
+        let log = std::env::temp_dir().join("agate-test-proxy-protocol-log");
+        let _ = std::fs::remove_file(&log);
+        let port = free_port();
+        let mut server = Server::new(&[
+            "--gopher",
+            &format!("127.0.0.1:{port}"),
+            "--proxy-protocol",
+            "--log-ip",
+            "--access-log",
+            log.to_str().unwrap(),
+        ]);
+
+        let v1 = request(port, "PROXY TCP4 192.0.2.1 192.0.2.2 56324 70\r\n");
+        assert!(v1.starts_with("iThis is a test index file."));
+
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\0\x24".to_vec();
+        v2.extend(
+            "2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v2.extend(
+            "2001:db8::2"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v2.extend([0xdc, 0x04, 0, 70]);
+        v2.extend(b"\r\n");
+        let mut tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        tcp.write_all(&v2).unwrap();
+        let mut v2 = String::new();
+        tcp.read_to_string(&mut v2).unwrap();
+        assert!(v2.starts_with("iThis is a test index file."));
+
+        let mut tcp = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        write!(tcp, "/testdir\r\n").unwrap();
+        let mut missing = String::new();
+        // the connection might be reset because the request was not read
+        let _ = tcp.read_to_string(&mut missing);
+        assert_eq!(missing, "");
+
+        server.stop().unwrap();
+
+        let log = std::fs::read_to_string(log).unwrap();
+        let ips = log
+            .lines()
+            .map(|line| line.split(' ').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(ips, ["192.0.2.1", "2001:db8::1"]);
+    }
+}
+
+mod titan {
+    use super::*;
+    use rustls::pki_types::{PrivateKeyDer, PrivatePkcs1KeyDer};
+
+    /// Sends a raw request with a client certificate and returns the
+    /// response header.
+    fn request(server: &Server, request: &[u8]) -> String {
+        let mut certs = RootCertStore::empty();
+        certs
+            .add(CertificateDer::from(
+                include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+            ))
+            .unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_client_auth_cert(
+                vec![CertificateDer::from(
+                    include_bytes!("data/multicert/example.org/cert.der").as_slice(),
+                )],
+                PrivateKeyDer::Pkcs1(PrivatePkcs1KeyDer::from(
+                    include_bytes!("data/multicert/example.org/key.der").as_slice(),
+                )),
+            )
+            .unwrap();
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            "example.com".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        tls.write_all(request).unwrap();
+
+        let mut header = String::new();
+        BufReader::new(tls).read_line(&mut header).unwrap();
+        header
+    }
+
+    #[test]
+    /// - files can be uploaded to Titan areas
+    /// - the client is redirected to the uploaded file
+    /// - uploads outside of Titan areas are refused, also with a path that
+    ///   only starts with the same characters as the area
+    /// - uploads with size 0 delete the file
+    fn upload() {
+        let content = std::env::temp_dir().join("agate-test-titan");
+        let _ = std::fs::remove_dir_all(&content);
+        std::fs::create_dir(&content).unwrap();
+
+        let server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--content",
+            content.to_str().unwrap(),
+            "--titan-area",
+            "/wiki=145c2cf6ac9c38381e287efe334246ff1c9b98a4f18353fbb5b1db0aa81aa447",
+        ]);
+
+        let header = request(
+            &server,
+            b"titan://example.com/wiki/page.gmi;mime=text/gemini;size=6\r\nHello\n",
+        );
+        assert_eq!(header, "30 gemini://example.com/wiki/page.gmi\r\n");
+        assert_eq!(
+            std::fs::read(content.join("wiki/page.gmi")).unwrap(),
+            b"Hello\n"
+        );
+
+        let header = request(&server, b"titan://example.com/page.gmi;size=6\r\nHello\n");
+        assert_eq!(header, "50 Uploads are not allowed here.\r\n");
+
+        let header = request(
+            &server,
+            b"titan://example.com/wiki-private/page.gmi;size=6\r\nHello\n",
+        );
+        assert_eq!(header, "50 Uploads are not allowed here.\r\n");
+
+        let header = request(&server, b"titan://example.com/wiki/page.gmi;size=0\r\n");
+        assert_eq!(header, "30 gemini://example.com/wiki/page.gmi\r\n");
+        assert!(!content.join("wiki/page.gmi").exists());
+    }
+
+    #[test]
+    /// - uploads follow status files and the access settings of the
+    ///   directory configuration, like requests for the files
+    fn upload_access() {
+        let content = std::env::temp_dir().join("agate-test-titan-access");
+        let _ = std::fs::remove_dir_all(&content);
+        for dir in ["wiki/private", "wiki/closed", "wiki/members"] {
+            std::fs::create_dir_all(content.join(dir)).unwrap();
+        }
+        std::fs::write(content.join("wiki/private/.agate.ini"), "access = deny\n").unwrap();
+        std::fs::write(content.join("wiki/closed/.status"), "44 Come back later.\n").unwrap();
+        std::fs::write(
+            content.join("wiki/members/.agate.ini"),
+            "access = certificate\n",
+        )
+        .unwrap();
+
+        let server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--content",
+            content.to_str().unwrap(),
+            "--titan-area",
+            "/wiki/=145c2cf6ac9c38381e287efe334246ff1c9b98a4f18353fbb5b1db0aa81aa447",
+        ]);
+
+        let header = request(
+            &server,
+            b"titan://example.com/wiki/private/page.gmi;size=6\r\nHello\n",
+        );
+        assert_eq!(header, "51 Not found, sorry.\r\n");
+        assert!(!content.join("wiki/private/page.gmi").exists());
+
+        let header = request(
+            &server,
+            b"titan://example.com/wiki/private/sub/page.gmi;size=6\r\nHello\n",
+        );
+        assert_eq!(header, "51 Not found, sorry.\r\n");
+        assert!(!content.join("wiki/private/sub").exists());
+
+        let header = request(
+            &server,
+            b"titan://example.com/wiki/closed/page.gmi;size=6\r\nHello\n",
+        );
+        assert_eq!(header, "44 Come back later.\r\n");
+        assert!(!content.join("wiki/closed/page.gmi").exists());
+
+        // the client sent a certificate that is trusted
+        let header = request(
+            &server,
+            b"titan://example.com/wiki/members/page.gmi;size=6\r\nHello\n",
+        );
+        assert_eq!(header, "30 gemini://example.com/wiki/members/page.gmi\r\n");
+        assert!(content.join("wiki/members/page.gmi").exists());
+    }
+
+    #[test]
+    /// - uploads that stall for longer than the request timeout fail
+    fn upload_timeout() {
+        let content = std::env::temp_dir().join("agate-test-titan-timeout");
+        let _ = std::fs::remove_dir_all(&content);
+        std::fs::create_dir(&content).unwrap();
+
+        let server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--content",
+            content.to_str().unwrap(),
+            "--titan-area",
+            "/wiki/",
+            "--titan-token",
+            "secret",
+            "--request-timeout",
+            "1",
+        ]);
+
+        // only part of the announced size is sent
+        let header = request(
+            &server,
+            b"titan://example.com/wiki/page.gmi;size=10;token=secret\r\nabc",
+        );
+        assert_eq!(header, "59 Upload timed out.\r\n");
+        assert!(!content.join("wiki/page.gmi").exists());
+    }
+
+    #[test]
+    #[should_panic]
+    /// - Titan areas need certificate fingerprints or a token
+    fn area_without_restriction() {
+        let mut server = Server::new(&["--certs", "multicert", "--titan-area", "/wiki/"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
 }
 
 mod vhosts {
@@ -511,6 +3670,36 @@ mod vhosts {
         );
     }
 
+    #[test]
+    /// - vhosts can have their own content directory
+    fn content_dir() {
+        let page = get(&["--config", "config/vhosts.conf"], "gemini://example.com/")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(
+            page.content,
+            include_bytes!("data/content/example.org/index.gmi")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    #[should_panic]
+    /// - content directories of vhosts must be inside the chroot directory
+    fn content_dir_outside_chroot() {
+        let mut server = Server::new(&[
+            "--config",
+            "config/vhosts.conf",
+            "--content",
+            "content/example.com",
+            "--chroot",
+        ]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
     #[test]
     /// - the vhosts access the correct files
     fn example_org() {
@@ -552,6 +3741,410 @@ mod multicert {
         let _ = server.server.wait();
     }
 
+    /// Sends a request for the root of the given domain, using the
+    /// certificate of that domain from the multicert directory as the only
+    /// trusted certificate, and returns the start of the response.
+    fn request(server: &Server, domain: &str, cert: &[u8]) -> std::io::Result<Vec<u8>> {
+        let mut certs = RootCertStore::empty();
+        certs.add(CertificateDer::from(cert)).unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_no_client_auth();
+
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            domain.to_string().try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        write!(tls, "gemini://{domain}/\r\n")?;
+
+        let mut buf = [0; 2];
+        tls.read_exact(&mut buf)?;
+        Ok(buf.to_vec())
+    }
+
+    #[test]
+    /// - certificates added to the certificate directory are loaded without
+    ///   a restart
+    fn reload() {
+        let certs = std::env::temp_dir().join("agate-test-reload-certs");
+        let _ = std::fs::remove_dir_all(&certs);
+        std::fs::create_dir_all(certs.join("example.com")).unwrap();
+        let source = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/multicert"));
+        for file in ["cert.der", "key.der"] {
+            std::fs::copy(
+                source.join("example.com").join(file),
+                certs.join("example.com").join(file),
+            )
+            .unwrap();
+        }
+
+        let mut server = Server::new(&[
+            "--certs",
+            certs.to_str().unwrap(),
+            "--cert-reload-interval",
+            "1",
+        ]);
+        let example_org = include_bytes!("data/multicert/example.org/cert.der");
+        assert!(request(&server, "example.org", example_org).is_err());
+
+        std::fs::create_dir(certs.join("example.org")).unwrap();
+        for file in ["cert.der", "key.der"] {
+            std::fs::copy(
+                source.join("example.org").join(file),
+                certs.join("example.org").join(file),
+            )
+            .unwrap();
+        }
+        // the files are only loaded once they did not change for an interval,
+        // allow for some delay when the machine is busy
+        let mut response = None;
+        for _ in 0..20 {
+            sleep(Duration::from_millis(500));
+            response = request(&server, "example.org", example_org).ok();
+            if response.is_some() {
+                break;
+            }
+        }
+        assert_eq!(response.unwrap(), b"20");
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    /// - certificates are loaded again on SIGHUP
+    fn reload_on_hangup() {
+        let certs = std::env::temp_dir().join("agate-test-hangup-certs");
+        let _ = std::fs::remove_dir_all(&certs);
+        std::fs::create_dir_all(certs.join("example.com")).unwrap();
+        let source = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/multicert"));
+        for file in ["cert.der", "key.der"] {
+            std::fs::copy(
+                source.join("example.com").join(file),
+                certs.join("example.com").join(file),
+            )
+            .unwrap();
+        }
+
+        let mut server = Server::new(&[
+            "--certs",
+            certs.to_str().unwrap(),
+            "--cert-reload-interval",
+            "0",
+        ]);
+        let example_org = include_bytes!("data/multicert/example.org/cert.der");
+        assert!(request(&server, "example.org", example_org).is_err());
+
+        std::fs::create_dir(certs.join("example.org")).unwrap();
+        for file in ["cert.der", "key.der"] {
+            std::fs::copy(
+                source.join("example.org").join(file),
+                certs.join("example.org").join(file),
+            )
+            .unwrap();
+        }
+        let pid = server.server.id().to_string();
+        let status = Command::new("kill").args(["-HUP", &pid]).status().unwrap();
+        assert!(status.success());
+        // allow for some delay when the machine is busy
+        let mut response = None;
+        for _ in 0..20 {
+            sleep(Duration::from_millis(100));
+            response = request(&server, "example.org", example_org).ok();
+            if response.is_some() {
+                break;
+            }
+        }
+        assert_eq!(response.unwrap(), b"20");
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - PEM encoded keys are loaded
+    fn pem_key() {
+        let mut server = Server::new(&["--certs", "pemkey"]);
+
+        let cert = include_bytes!("data/pemkey/example.com/cert.der");
+        assert_eq!(request(&server, "example.com", cert).unwrap(), b"20");
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - the certificate of a wildcard directory is used for subdomains
+    /// - the certificate of a domain is not used for other domains with the
+    ///   same suffix
+    fn wildcard() {
+        let mut server = Server::new(&["--certs", "wildcard"]);
+
+        let wildcard = include_bytes!("data/wildcard/*.example.com/cert.der");
+        let example_com = include_bytes!("data/wildcard/example.com/cert.der");
+        assert_eq!(request(&server, "a.example.com", wildcard).unwrap(), b"20");
+        assert_eq!(request(&server, "example.com", example_com).unwrap(), b"20");
+        assert!(request(&server, "badexample.com", example_com).is_err());
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - requests for a host with a different certificate than the one for
+    ///   the SNI name are refused
+    fn sni_mismatch() {
+        let mut server = Server::new(&["--certs", "multicert"]);
+
+        let mut certs = RootCertStore::empty();
+        certs
+            .add(CertificateDer::from(
+                include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+            ))
+            .unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs)
+            .with_no_client_auth();
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            "example.com".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+
+        write!(tls, "gemini://example.org/\r\n").unwrap();
+        let mut buf = [0; 2];
+        tls.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"53");
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - session tickets are only sent if resumption is enabled
+    fn session_resumption() {
+        use rustls::{
+            client::{
+                ClientSessionMemoryCache, ClientSessionStore, Resumption, Tls12ClientSessionValue,
+                Tls13ClientSessionValue,
+            },
+            pki_types::ServerName,
+            NamedGroup,
+        };
+        use std::sync::{atomic::AtomicUsize, Arc};
+
+        /// Counts the TLSv1.3 tickets received from the server.
+        #[derive(Debug)]
+        struct CountTickets(ClientSessionMemoryCache, AtomicUsize);
+
+        impl ClientSessionStore for CountTickets {
+            fn set_kx_hint(&self, server_name: ServerName<'static>, group: NamedGroup) {
+                self.0.set_kx_hint(server_name, group)
+            }
+            fn kx_hint(&self, server_name: &ServerName<'_>) -> Option<NamedGroup> {
+                self.0.kx_hint(server_name)
+            }
+            fn set_tls12_session(
+                &self,
+                server_name: ServerName<'static>,
+                value: Tls12ClientSessionValue,
+            ) {
+                self.0.set_tls12_session(server_name, value)
+            }
+            fn tls12_session(
+                &self,
+                server_name: &ServerName<'_>,
+            ) -> Option<Tls12ClientSessionValue> {
+                self.0.tls12_session(server_name)
+            }
+            fn remove_tls12_session(&self, server_name: &ServerName<'static>) {
+                self.0.remove_tls12_session(server_name)
+            }
+            fn insert_tls13_ticket(
+                &self,
+                server_name: ServerName<'static>,
+                value: Tls13ClientSessionValue,
+            ) {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.insert_tls13_ticket(server_name, value)
+            }
+            fn take_tls13_ticket(
+                &self,
+                server_name: &ServerName<'static>,
+            ) -> Option<Tls13ClientSessionValue> {
+                self.0.take_tls13_ticket(server_name)
+            }
+        }
+
+        /// Makes two requests with the same session store and returns the
+        /// number of tickets received.
+        fn tickets(args: &[&str]) -> usize {
+            let mut server = Server::new(&[&["--certs", "multicert"], args].concat());
+
+            let mut certs = RootCertStore::empty();
+            certs
+                .add(CertificateDer::from(
+                    include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+                ))
+                .unwrap();
+            let store = Arc::new(CountTickets(
+                ClientSessionMemoryCache::new(16),
+                AtomicUsize::new(0),
+            ));
+            let mut config = rustls::ClientConfig::builder()
+                .with_root_certificates(certs)
+                .with_no_client_auth();
+            config.resumption = Resumption::store(store.clone());
+            let config = Arc::new(config);
+
+            for _ in 0..2 {
+                let mut session =
+                    ClientConnection::new(config.clone(), "example.com".try_into().unwrap())
+                        .unwrap();
+                let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+                let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+                write!(tls, "gemini://example.com/\r\n").unwrap();
+                let mut buf = [0; 2];
+                tls.read_exact(&mut buf).unwrap();
+                assert_eq!(&buf, b"20");
+            }
+
+            server.stop().unwrap();
+            store.1.load(Ordering::SeqCst)
+        }
+
+        assert!(tickets(&[]) > 0);
+        assert!(tickets(&["--session-tickets"]) > 0);
+        assert_eq!(tickets(&["--no-session-resumption"]), 0);
+    }
+
+    #[test]
+    /// - only the configured cipher suite and key exchange group are used
+    fn cipher_suites() {
+        let mut server = Server::new(&[
+            "--certs",
+            "multicert",
+            "--cipher-suite",
+            "TLS13_CHACHA20_POLY1305_SHA256",
+            "--curve",
+            "secp384r1",
+        ]);
+
+        let mut certs = RootCertStore::empty();
+        certs
+            .add(CertificateDer::from(
+                include_bytes!("data/multicert/example.com/cert.der").as_slice(),
+            ))
+            .unwrap();
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(certs.clone())
+            .with_no_client_auth();
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            "example.com".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        write!(tls, "gemini://example.com/\r\n").unwrap();
+        let mut buf = [0; 2];
+        tls.read_exact(&mut buf).unwrap();
+
+        assert_eq!(
+            session.negotiated_cipher_suite().unwrap().suite(),
+            rustls::CipherSuite::TLS13_CHACHA20_POLY1305_SHA256
+        );
+
+        // a client that does not support the curve can not connect
+        let mut provider = rustls::crypto::ring::default_provider();
+        provider
+            .kx_groups
+            .retain(|group| group.name() == rustls::NamedGroup::X25519);
+        let config = rustls::ClientConfig::builder_with_provider(provider.into())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(certs)
+            .with_no_client_auth();
+        let mut session = ClientConnection::new(
+            std::sync::Arc::new(config),
+            "example.com".try_into().unwrap(),
+        )
+        .unwrap();
+        let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+        let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+        assert!(write!(tls, "gemini://example.com/\r\n").is_err());
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    /// - of several certificates for a domain, one that the client supports is
+    ///   chosen
+    fn key_types() {
+        use rustls::{crypto::ring, SignatureScheme};
+
+        let mut server = Server::new(&["--certs", "dualcert"]);
+
+        // only advertises the signature schemes that match the filter
+        let request = |filter: fn(&SignatureScheme) -> bool, cert: &[u8]| {
+            let mut provider = ring::default_provider();
+            let mapping = provider
+                .signature_verification_algorithms
+                .mapping
+                .iter()
+                .filter(|(scheme, _)| filter(scheme))
+                .copied()
+                .collect::<Vec<_>>();
+            provider.signature_verification_algorithms.mapping = mapping.leak();
+
+            let mut certs = RootCertStore::empty();
+            certs.add(CertificateDer::from(cert)).unwrap();
+            let config = rustls::ClientConfig::builder_with_provider(provider.into())
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(certs)
+                .with_no_client_auth();
+            let mut session = ClientConnection::new(
+                std::sync::Arc::new(config),
+                "example.com".try_into().unwrap(),
+            )
+            .unwrap();
+            let mut tcp = TcpStream::connect(server.get_addr()).unwrap();
+            let mut tls = rustls::Stream::new(&mut session, &mut tcp);
+            write!(tls, "gemini://example.com/\r\n")?;
+            let mut buf = [0; 2];
+            tls.read_exact(&mut buf)?;
+            Ok::<_, std::io::Error>(buf.to_vec())
+        };
+
+        let ecdsa = include_bytes!("data/dualcert/example.com/cert-ecdsa.der");
+        let rsa = include_bytes!("data/dualcert/example.com/cert-rsa.der");
+        fn is_ecdsa(scheme: &SignatureScheme) -> bool {
+            matches!(
+                scheme,
+                SignatureScheme::ECDSA_NISTP256_SHA256
+                    | SignatureScheme::ECDSA_NISTP384_SHA384
+                    | SignatureScheme::ECDSA_NISTP521_SHA512
+            )
+        }
+        assert_eq!(request(is_ecdsa, ecdsa).unwrap(), b"20");
+        assert_eq!(request(|scheme| !is_ecdsa(scheme), rsa).unwrap(), b"20");
+
+        server.stop().unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    /// - encrypted keys are refused
+    fn encrypted_key() {
+        let mut server = Server::new(&["--certs", "encryptedkey"]);
+
+        // wait for the server to stop, it should crash
+        let _ = server.server.wait();
+    }
+
     #[test]
     fn example_com() {
         let mut server = Server::new(&["--certs", "multicert"]);
@@ -641,4 +4234,38 @@ mod directory_listing {
         assert_eq!(page.meta, "text/gemini");
         assert_eq!(page.content, b"=> a\n=> b\n");
     }
+
+    #[test]
+    /// - directory listings can be enabled for all directories
+    fn serve_dir_listings() {
+        let page = get(&["--serve-dir-listings"], "gemini://localhost/testdir/")
+            .expect("could not get page");
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(page.meta, "text/gemini");
+        assert_eq!(page.content, b"=> a.de.gmi\n=> a.gmi\n=> a.nl.gmi\n");
+    }
+
+    #[test]
+    /// - directory listings show sizes and modification dates if enabled
+    fn details() {
+        let page = get(
+            &["--content", "dirlist", "--dir-listing-details"],
+            "gemini://localhost/",
+        )
+        .expect("could not get page");
+
+        let date = |name: &str| {
+            let path = PathBuf::from(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/data/dirlist"))
+                .join(name);
+            let modified = std::fs::metadata(path).unwrap().modified().unwrap();
+            humantime::format_rfc3339_seconds(modified).to_string()[..10].to_string()
+        };
+
+        assert_eq!(page.status, Status::Success.value());
+        assert_eq!(
+            String::from_utf8(page.content).unwrap(),
+            format!("=> a a (0 B, {})\n=> b b (0 B, {})\n", date("a"), date("b"))
+        );
+    }
 }